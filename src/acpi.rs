@@ -0,0 +1,233 @@
+//! ACPI table discovery
+//!
+//! Just enough of ACPI to locate the system description tables that the
+//! device drivers need (HPET, MADT, ...). AML is not interpreted.
+
+use conquer_once::spin::OnceCell;
+use core::{mem, ptr, slice};
+use crate::memory::phys_to_virt;
+use x86_64::PhysAddr;
+
+/// physical address of the RSDT or XSDT and whether it is the XSDT
+static ROOT_TABLE: OnceCell<(PhysAddr, bool)> = OnceCell::uninit();
+
+/// Root System Description Pointer
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header common to all system description tables
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Generic Address Structure
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space_id: u8,
+    pub register_bit_width: u8,
+    pub register_bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// A system description table found in memory
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    phys: PhysAddr,
+    header: SdtHeader,
+}
+
+impl Table {
+    pub fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// The complete table including the header
+    pub fn bytes(&self) -> &'static [u8] {
+        let virt = phys_to_virt(self.phys);
+        unsafe { slice::from_raw_parts(virt.as_ptr(), self.header.length as usize) }
+    }
+
+    /// Read a `T` at `offset` bytes from the start of the table
+    ///
+    /// Panics if `T` does not fit in the table.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        let bytes = self.bytes();
+        assert!(offset + mem::size_of::<T>() <= bytes.len(),
+                "read past the end of an ACPI table");
+        unsafe { ptr::read_unaligned(bytes[offset..].as_ptr() as *const T) }
+    }
+}
+
+/// Locate the RSDP and the root table
+///
+/// Requires `memory::init`. Returns `false` if the firmware provides no
+/// (valid) ACPI tables.
+pub fn init() -> bool {
+    if ROOT_TABLE.is_initialized() {
+        return true;
+    }
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return false,
+    };
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), true)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), false)
+    };
+    ROOT_TABLE.try_init_once(|| root).is_ok()
+}
+
+/// Find the system description table with the given signature
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    tables().find(|table| &table.header.signature == signature)
+}
+
+/// Iterate over all valid tables listed in the RSDT/XSDT
+pub fn tables() -> impl Iterator<Item = Table> {
+    let (root, entries, entry_size) = match ROOT_TABLE.try_get() {
+        Ok(&(phys, is_xsdt)) => match load_table(phys) {
+            Some(root) => {
+                let entry_size = if is_xsdt { 8 } else { 4 };
+                let len = root.header.length as usize - mem::size_of::<SdtHeader>();
+                (Some(root), len / entry_size, entry_size)
+            }
+            None => (None, 0, 4),
+        },
+        Err(_) => (None, 0, 4),
+    };
+
+    (0..entries).filter_map(move |i| {
+        let root = root?;
+        let offset = mem::size_of::<SdtHeader>() + i * entry_size;
+        let phys = if entry_size == 8 {
+            root.read::<u64>(offset)
+        } else {
+            root.read::<u32>(offset) as u64
+        };
+        load_table(PhysAddr::new(phys))
+    })
+}
+
+/// A processor listed in the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// usable now (otherwise it can only be hot plugged)
+    pub enabled: bool,
+}
+
+/// Processors with a local APIC, in MADT order (the BSP comes first)
+pub fn processors() -> impl Iterator<Item = Processor> {
+    // header, local APIC address, flags, then variable length entries
+    const ENTRIES: usize = mem::size_of::<SdtHeader>() + 8;
+    const LOCAL_APIC: u8 = 0;
+
+    let madt = find_table(b"APIC");
+    let end = madt.map(|madt| madt.header.length as usize).unwrap_or(0);
+    let mut offset = ENTRIES;
+
+    core::iter::from_fn(move || {
+        let madt = madt?;
+        while offset + 2 <= end {
+            let entry_type: u8 = madt.read(offset);
+            let length: u8 = madt.read(offset + 1);
+            if length < 2 {
+                return None; // corrupt table
+            }
+            let entry = offset;
+            offset += length as usize;
+            if entry_type == LOCAL_APIC && length >= 8 {
+                let flags: u32 = madt.read(entry + 4);
+                return Some(Processor {
+                    processor_id: madt.read(entry + 2),
+                    apic_id: madt.read(entry + 3),
+                    enabled: flags & 1 != 0,
+                });
+            }
+        }
+        None
+    })
+}
+
+fn load_table(phys: PhysAddr) -> Option<Table> {
+    let virt = phys_to_virt(phys);
+    let header = unsafe { ptr::read_unaligned(virt.as_ptr::<SdtHeader>()) };
+    let table = Table { phys, header };
+    if (header.length as usize) < mem::size_of::<SdtHeader>()
+        || !checksum_ok(table.bytes())
+    {
+        return None;
+    }
+    Some(table)
+}
+
+/// Search the EBDA and the BIOS read-only area for the RSDP
+fn find_rsdp() -> Option<Rsdp> {
+    // the real mode segment of the EBDA is stored at 0x40e
+    let ebda_segment = unsafe {
+        ptr::read_unaligned(phys_to_virt(PhysAddr::new(0x40e)).as_ptr::<u16>())
+    };
+    let ebda = (ebda_segment as u64) << 4;
+
+    let mut areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    if ebda == 0 {
+        areas[0] = (0, 0);
+    }
+
+    for &(start, end) in areas.iter() {
+        for addr in (start..end).step_by(16) {
+            let virt = phys_to_virt(PhysAddr::new(addr));
+            let rsdp = unsafe { ptr::read_unaligned(virt.as_ptr::<Rsdp>()) };
+            if &rsdp.signature != b"RSD PTR " {
+                continue;
+            }
+            // the ACPI 1.0 part is always 20 bytes
+            let bytes = unsafe { slice::from_raw_parts(virt.as_ptr::<u8>(), 20) };
+            if checksum_ok(bytes) {
+                return Some(rsdp);
+            }
+        }
+    }
+    None
+}
+
+/// All bytes of a valid table sum to zero
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+#[test_case]
+fn test_checksum() {
+    assert!(checksum_ok(&[0x10, 0xf0]));
+    assert!(!checksum_ok(&[0x10, 0xef]));
+}
@@ -2,7 +2,10 @@ pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
 
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use crate::sync::{IrqMutex, IrqMutexGuard};
+use linked_list_allocator::Heap;
 
 use x86_64::{
     structures::paging::{
@@ -17,7 +20,7 @@ pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Locked<Heap> = Locked::new(Heap::empty());
 
 //static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
 
@@ -55,20 +58,60 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Usage of the kernel heap in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Usage of the kernel heap, `None` while the heap lock is held
+///
+/// Never waits for the lock, so it is safe where the lock may be held by
+/// the interrupted code (e.g. in the monitor).
+pub fn stats() -> Option<HeapStats> {
+    let heap = ALLOCATOR.try_lock()?;
+    Some(HeapStats { size: heap.size(), used: heap.used(), free: heap.free() })
+}
+
+/// An allocator behind an `IrqMutex`
+///
+/// Interrupts stay disabled while it is locked: a kernel thread preempted
+/// while holding the lock would otherwise deadlock any code that allocates
+/// with interrupts disabled on the same CPU (interrupt handlers, the
+/// scheduler, ...).
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqMutex::named("heap", inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<A> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<A>> {
+        self.inner.try_lock()
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock()
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+    }
 }
 
 /// Align the given address `addr` upwards to alignment `align`.
@@ -0,0 +1,126 @@
+//! Local APIC
+//!
+//! Every CPU has a local APIC at the same physical address (normally
+//! 0xfee00000). It delivers the per-CPU timer and inter-processor
+//! interrupts and needs its own end of interrupt.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::{cpu::{self, Feature}, memory::phys_to_virt};
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// register offsets
+pub(crate) const ID: usize = 0x020;
+pub(crate) const TASK_PRIORITY: usize = 0x080;
+pub(crate) const EOI: usize = 0x0b0;
+pub(crate) const SPURIOUS: usize = 0x0f0;
+pub(crate) const ERROR_STATUS: usize = 0x280;
+pub(crate) const ICR_LOW: usize = 0x300;
+pub(crate) const ICR_HIGH: usize = 0x310;
+pub(crate) const LVT_TIMER: usize = 0x320;
+pub(crate) const LVT_PERFMON: usize = 0x340;
+pub(crate) const LVT_ERROR: usize = 0x370;
+pub(crate) const TIMER_INITIAL: usize = 0x380;
+pub(crate) const TIMER_CURRENT: usize = 0x390;
+pub(crate) const TIMER_DIVIDE: usize = 0x3e0;
+
+/// LVT entry mask bit
+pub(crate) const LVT_MASKED: u32 = 1 << 16;
+/// delivery mode of LVT entries, the ICR and MSIs that raises an NMI
+pub(crate) const DELIVERY_NMI: u32 = 0b100 << 8;
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+
+// interrupt command register
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// virtual address of the register page (0 until `init`)
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Enable the local APIC of the calling CPU
+///
+/// Requires `memory::init`. The first call also maps the registers.
+/// Returns `false` if the CPU has no local APIC.
+pub fn init() -> bool {
+    use crate::interrupts::{InterruptIndex, SPURIOUS_VECTOR};
+
+    if !cpu::has(Feature::Apic) {
+        return false;
+    }
+
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { msr.read() };
+    unsafe { msr.write(base | APIC_BASE_ENABLE) };
+    let phys = PhysAddr::new(base & 0x000f_ffff_ffff_f000);
+    BASE.store(phys_to_virt(phys).as_u64(), Ordering::Release);
+
+    write(TASK_PRIORITY, 0);
+    write(LVT_ERROR, InterruptIndex::ApicError.as_u8() as u32);
+    // clear errors left behind by the firmware (write before read)
+    write(ERROR_STATUS, 0);
+    read(ERROR_STATUS);
+    write(SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+    eoi();
+    true
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// APIC id of the calling CPU
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Signal end of interrupt for the interrupt currently in service
+pub fn eoi() {
+    write(EOI, 0);
+}
+
+/// Send a fixed interrupt with `vector` to the CPU with `apic_id`
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send_icr(apic_id, vector as u32);
+}
+
+/// Send a non-maskable interrupt to the CPU with `apic_id`
+pub fn send_nmi(apic_id: u8) {
+    send_icr(apic_id, DELIVERY_NMI);
+}
+
+/// Send INIT to put the CPU with `apic_id` into wait-for-SIPI state
+pub fn send_init(apic_id: u8) {
+    send_icr(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Send a startup IPI: the CPU starts in real mode at `page * 0x1000`
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_icr(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+fn send_icr(apic_id: u8, command: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write(ICR_HIGH, (apic_id as u32) << 24);
+        write(ICR_LOW, command); // writing the low half sends
+        while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+pub(crate) fn read(reg: usize) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    debug_assert!(base != 0, "local APIC not initialized");
+    unsafe { ptr::read_volatile((base as usize + reg) as *const u32) }
+}
+
+pub(crate) fn write(reg: usize, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    debug_assert!(base != 0, "local APIC not initialized");
+    unsafe { ptr::write_volatile((base as usize + reg) as *mut u32, value) }
+}
@@ -0,0 +1,133 @@
+//! Stack backtraces
+//!
+//! The kernel is built with frame pointers (see `baremetal.json`): every
+//! function pushes `rbp` and points `rbp` at it, so the saved `rbp` values
+//! form a chain through the stack with the return address right above
+//! each. Walking the chain neither locks nor allocates. It stops at a zero
+//! `rbp` (the bottom of a thread stack, see `thread`), at an address that
+//! is not mapped (all of them before `memory::init`) and after `MAX_FRAMES`
+//! frames.
+
+use core::arch::asm;
+use core::fmt;
+use crate::{memory, println, serial_println, symbols::Location};
+use x86_64::{structures::idt::InterruptStackFrame, VirtAddr};
+
+/// frames kept in a `Backtrace`
+pub const MAX_FRAMES: usize = 32;
+
+/// Return addresses of the calls on a stack, innermost first
+#[derive(Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// The calls that led to the caller
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        let mut backtrace = Backtrace::empty();
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /// The calls that led to the code an exception interrupted
+    ///
+    /// Must be called by the handler itself: its frame, right below the
+    /// one the CPU pushed, holds the `rbp` of the interrupted code.
+    #[inline(always)]
+    pub fn from_exception(stack_frame: &InterruptStackFrame) -> Self {
+        let rbp = interrupted_rbp().unwrap_or(0);
+        Backtrace::from_registers(stack_frame.instruction_pointer.as_u64(), rbp)
+    }
+
+    /// The calls that led to `rip`, with `rbp` the frame pointer there
+    pub fn from_registers(rip: u64, rbp: u64) -> Self {
+        let mut backtrace = Backtrace::empty();
+        backtrace.push(rip);
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    fn empty() -> Self {
+        Backtrace { frames: [0; MAX_FRAMES], len: 0 }
+    }
+
+    fn push(&mut self, addr: u64) {
+        if self.len < MAX_FRAMES {
+            self.frames[self.len] = addr;
+            self.len += 1;
+        }
+    }
+
+    fn walk(&mut self, mut rbp: u64) {
+        while rbp != 0 && self.len < MAX_FRAMES {
+            let (next, ret) = match (read(rbp), read(rbp + 8)) {
+                (Some(next), Some(ret)) => (next, ret),
+                _ => break,
+            };
+            if ret == 0 {
+                break;
+            }
+            self.push(ret);
+            // callers' frames are further up the stack
+            if next != 0 && next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+    }
+
+    /// Return addresses, innermost first
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    /// Print on the VGA console and the serial port
+    pub fn print(&self) {
+        println!("{}", self);
+        serial_println!("{}", self);
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "backtrace:")?;
+        for (i, &addr) in self.frames().iter().enumerate() {
+            write!(f, "\n{:>3}: {}", i, Location(addr))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.frames()).finish()
+    }
+}
+
+/// `rbp` of the code an exception interrupted
+///
+/// Must be called by the handler itself, like `Backtrace::from_exception`.
+#[inline(always)]
+pub(crate) fn interrupted_rbp() -> Option<u64> {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    read(rbp)
+}
+
+/// Read the word at `addr` if it is aligned and mapped
+fn read(addr: u64) -> Option<u64> {
+    if addr % 8 != 0 {
+        return None;
+    }
+    let addr = VirtAddr::try_new(addr).ok()?;
+    if !memory::is_mapped(addr) {
+        return None;
+    }
+    Some(unsafe { addr.as_ptr::<u64>().read_volatile() })
+}
+
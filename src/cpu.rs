@@ -0,0 +1,425 @@
+//! CPU identification and features (CPUID)
+//!
+//! `info` decodes the CPUID leaves once, on first use: vendor and brand
+//! string, family and model, the features the kernel cares about, the
+//! caches and the hypervisor, if any. Code that depends on a feature
+//! checks `has` instead of reading CPUID itself.
+//!
+//! All CPUs are assumed to be alike; the leaves are read on the CPU that
+//! asks first (the BSP, `init` does). What differs between CPUs or changes
+//! at run time (`apic_id`, the XSAVE area size) is read on every call.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::{fmt, str};
+use lazy_static::lazy_static;
+
+/// most caches listed
+pub const MAX_CACHES: usize = 8;
+
+lazy_static! {
+    static ref INFO: CpuInfo = CpuInfo::read();
+}
+
+/// Features the kernel checks for (in the order of `ALL`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Tsc,
+    Apic,
+    Fxsr,
+    Sse,
+    Sse2,
+    Pcid,
+    X2Apic,
+    TscDeadline,
+    Xsave,
+    Avx,
+    Rdrand,
+    Smep,
+    Smap,
+    Nx,
+    InvariantTsc,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 15] = [
+        Feature::Tsc, Feature::Apic, Feature::Fxsr, Feature::Sse, Feature::Sse2,
+        Feature::Pcid, Feature::X2Apic, Feature::TscDeadline, Feature::Xsave,
+        Feature::Avx, Feature::Rdrand, Feature::Smep, Feature::Smap, Feature::Nx,
+        Feature::InvariantTsc,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Tsc => "tsc",
+            Feature::Apic => "apic",
+            Feature::Fxsr => "fxsr",
+            Feature::Sse => "sse",
+            Feature::Sse2 => "sse2",
+            Feature::Pcid => "pcid",
+            Feature::X2Apic => "x2apic",
+            Feature::TscDeadline => "tsc-deadline",
+            Feature::Xsave => "xsave",
+            Feature::Avx => "avx",
+            Feature::Rdrand => "rdrand",
+            Feature::Smep => "smep",
+            Feature::Smap => "smap",
+            Feature::Nx => "nx",
+            Feature::InvariantTsc => "invariant-tsc",
+        }
+    }
+
+    /// Where CPUID reports the feature: leaf, register and bit
+    fn location(self) -> (u32, Register, u32) {
+        use Register::*;
+        match self {
+            Feature::Tsc => (1, Edx, 4),
+            Feature::Apic => (1, Edx, 9),
+            Feature::Fxsr => (1, Edx, 24),
+            Feature::Sse => (1, Edx, 25),
+            Feature::Sse2 => (1, Edx, 26),
+            Feature::Pcid => (1, Ecx, 17),
+            Feature::X2Apic => (1, Ecx, 21),
+            Feature::TscDeadline => (1, Ecx, 24),
+            Feature::Xsave => (1, Ecx, 26),
+            Feature::Avx => (1, Ecx, 28),
+            Feature::Rdrand => (1, Ecx, 30),
+            // subleaf 0
+            Feature::Smep => (7, Ebx, 7),
+            Feature::Smap => (7, Ebx, 20),
+            Feature::Nx => (0x8000_0001, Edx, 20),
+            Feature::InvariantTsc => (0x8000_0007, Edx, 8),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// One cache level as CPUID leaf 4 (AMD: 8000_001Dh) describes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// in bytes
+    pub size: usize,
+    pub line_size: usize,
+    pub ways: usize,
+    /// logical CPUs sharing it (at most)
+    pub shared_by: usize,
+}
+
+impl fmt::Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            CacheKind::Data => "d",
+            CacheKind::Instruction => "i",
+            CacheKind::Unified => "",
+        };
+        write!(f, "L{}{} ", self.level, kind)?;
+        if self.size >= 1 << 20 {
+            write!(f, "{}M", self.size >> 20)?;
+        } else {
+            write!(f, "{}K", self.size >> 10)?;
+        }
+        write!(f, " {}-way", self.ways)
+    }
+}
+
+/// Architectural performance monitoring, CPUID leaf 0Ah
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Perfmon {
+    pub version: u8,
+    /// general purpose counters per CPU
+    pub counters: u8,
+    /// bits of a general purpose counter
+    pub width: u8,
+    /// the unhalted core cycles event is available
+    pub unhalted_cycles: bool,
+}
+
+impl Perfmon {
+    fn read(max_leaf: u32) -> Option<Self> {
+        if max_leaf < 0xa {
+            return None;
+        }
+        let regs = unsafe { __cpuid(0xa) };
+        let version = (regs.eax & 0xff) as u8;
+        if version == 0 {
+            return None;
+        }
+        // EAX 31:24: length of the EBX bit vector; a set bit in EBX means
+        // the event is not available
+        let events = regs.eax >> 24;
+        Some(Perfmon {
+            version,
+            counters: (regs.eax >> 8 & 0xff) as u8,
+            width: (regs.eax >> 16 & 0xff) as u8,
+            unhalted_cycles: events > 0 && regs.ebx & 1 == 0,
+        })
+    }
+}
+
+/// What CPUID tells about the CPU
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    /// bit N: the feature with discriminant N is supported
+    features: u32,
+    caches: [Option<Cache>; MAX_CACHES],
+    perfmon: Option<Perfmon>,
+    /// vendor of the hypervisor we run under
+    hypervisor: Option<[u8; 12]>,
+}
+
+impl CpuInfo {
+    fn read() -> Self {
+        let leaf0 = unsafe { __cpuid(0) };
+        let max_leaf = leaf0.eax;
+        let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+        let mut vendor = [0; 12];
+        vendor[..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor[8..].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        let mut brand = [0; 48];
+        if max_extended >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let regs = unsafe { __cpuid(leaf) };
+                for (j, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx].iter().enumerate() {
+                    let at = i * 16 + j * 4;
+                    brand[at..at + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+
+        // EAX: stepping 3:0, model 7:4, family 11:8, extended model 19:16,
+        // extended family 27:20
+        let signature = unsafe { __cpuid(1) }.eax;
+        let base_family = signature >> 8 & 0xf;
+        let mut family = base_family;
+        let mut model = signature >> 4 & 0xf;
+        if base_family == 0xf {
+            family += signature >> 20 & 0xff;
+        }
+        if base_family == 0x6 || base_family == 0xf {
+            model |= (signature >> 16 & 0xf) << 4;
+        }
+
+        let mut features = 0;
+        for feature in Feature::ALL {
+            let (leaf, register, index) = feature.location();
+            let max = if leaf >= 0x8000_0000 { max_extended } else { max_leaf };
+            if leaf > max {
+                continue;
+            }
+            let regs = unsafe { __cpuid_count(leaf, 0) };
+            let value = match register {
+                Register::Ebx => regs.ebx,
+                Register::Ecx => regs.ecx,
+                Register::Edx => regs.edx,
+            };
+            if value & 1 << index != 0 {
+                features |= 1 << feature as u32;
+            }
+        }
+
+        // CPUID 1 ECX bit 31: running under a hypervisor
+        let hypervisor = if unsafe { __cpuid(1) }.ecx & 1 << 31 != 0 {
+            let regs = unsafe { __cpuid(0x4000_0000) };
+            let mut name = [0; 12];
+            name[..4].copy_from_slice(&regs.ebx.to_le_bytes());
+            name[4..8].copy_from_slice(&regs.ecx.to_le_bytes());
+            name[8..].copy_from_slice(&regs.edx.to_le_bytes());
+            Some(name)
+        } else {
+            None
+        };
+
+        let mut info = CpuInfo {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: signature & 0xf,
+            features,
+            caches: [None; MAX_CACHES],
+            perfmon: Perfmon::read(max_leaf),
+            hypervisor,
+        };
+        info.read_caches(max_leaf, max_extended);
+        info
+    }
+
+    /// Deterministic cache parameters: Intel leaf 4, AMD leaf 8000_001Dh
+    /// (with topology extensions, CPUID 8000_0001h ECX bit 22)
+    fn read_caches(&mut self, max_leaf: u32, max_extended: u32) {
+        let leaf = if self.vendor() == "AuthenticAMD" {
+            let extensions = max_extended >= 0x8000_001d
+                && unsafe { __cpuid(0x8000_0001) }.ecx & 1 << 22 != 0;
+            if !extensions {
+                return;
+            }
+            0x8000_001d
+        } else if max_leaf >= 4 {
+            4
+        } else {
+            return;
+        };
+
+        for (index, slot) in self.caches.iter_mut().enumerate() {
+            let regs = unsafe { __cpuid_count(leaf, index as u32) };
+            let kind = match regs.eax & 0x1f {
+                1 => CacheKind::Data,
+                2 => CacheKind::Instruction,
+                3 => CacheKind::Unified,
+                _ => break, // 0: no more caches
+            };
+            let line_size = (regs.ebx & 0xfff) as usize + 1;
+            let partitions = (regs.ebx >> 12 & 0x3ff) as usize + 1;
+            let ways = (regs.ebx >> 22) as usize + 1;
+            let sets = regs.ecx as usize + 1;
+            *slot = Some(Cache {
+                level: (regs.eax >> 5 & 0x7) as u8,
+                kind,
+                size: ways * partitions * line_size * sets,
+                line_size,
+                ways,
+                shared_by: (regs.eax >> 14 & 0xfff) as usize + 1,
+            });
+        }
+    }
+
+    /// "GenuineIntel", "AuthenticAMD", ...
+    pub fn vendor(&self) -> &str {
+        ascii(&self.vendor)
+    }
+
+    /// Model name, empty if the CPU has none
+    pub fn brand(&self) -> &str {
+        ascii(&self.brand).trim()
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features & 1 << feature as u32 != 0
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = Feature> + '_ {
+        Feature::ALL.iter().copied().filter(move |&feature| self.has(feature))
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.caches.iter().flatten()
+    }
+
+    /// Performance monitoring, `None` without a PMU (QEMU without KVM)
+    pub fn perfmon(&self) -> Option<&Perfmon> {
+        self.perfmon.as_ref()
+    }
+
+    /// Vendor of the hypervisor ("KVMKVMKVM", "TCGTCGTCGTCG", ...), `None`
+    /// on bare metal
+    pub fn hypervisor(&self) -> Option<&str> {
+        self.hypervisor.as_ref().map(|name| ascii(name))
+    }
+}
+
+/// The summary printed at boot
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "CPU: {} family {:#x} model {:#x} stepping {}",
+                 self.vendor(), self.family, self.model, self.stepping)?;
+        if !self.brand().is_empty() {
+            writeln!(f, "     {}", self.brand())?;
+        }
+        write!(f, "features:")?;
+        for feature in self.features() {
+            write!(f, " {}", feature.name())?;
+        }
+        write!(f, "\ncaches:")?;
+        for (i, cache) in self.caches().enumerate() {
+            write!(f, "{} {}", if i == 0 { "" } else { "," }, cache)?;
+        }
+        match self.hypervisor() {
+            Some(name) => write!(f, "\nhypervisor: {}", name),
+            None => write!(f, "\nno hypervisor"),
+        }
+    }
+}
+
+/// The bytes up to the first NUL, if they are ASCII
+fn ascii(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    match str::from_utf8(&bytes[..len]) {
+        Ok(text) if text.is_ascii() => text,
+        _ => "",
+    }
+}
+
+/// What CPUID tells about the CPU
+pub fn info() -> &'static CpuInfo {
+    &INFO
+}
+
+/// The CPU supports `feature`
+pub fn has(feature: Feature) -> bool {
+    INFO.has(feature)
+}
+
+/// Initial APIC id of the calling CPU, CPUID 1 EBX bits 31:24
+///
+/// Works before the local APIC is enabled and with any GS base.
+pub fn apic_id() -> u8 {
+    (unsafe { __cpuid(1) }.ebx >> 24) as u8
+}
+
+/// State components XCR0 may enable, CPUID 0Dh subleaf 0 EAX
+pub fn xsave_supported() -> u64 {
+    unsafe { __cpuid_count(0xd, 0) }.eax as u64
+}
+
+/// Bytes XSAVE needs for the components enabled in XCR0 now, CPUID 0Dh
+/// subleaf 0 EBX
+pub fn xsave_size() -> usize {
+    unsafe { __cpuid_count(0xd, 0) }.ebx as usize
+}
+
+/// Read CPUID; later calls of `info` or `has` find it decoded
+pub fn init() {
+    lazy_static::initialize(&INFO);
+}
+
+#[test_case]
+fn test_qemu_cpu_is_decoded() {
+    let info = info();
+    assert_eq!(info.vendor().len(), 12, "vendor {:?}", info.vendor());
+    assert!(info.family != 0);
+    // x86_64 CPUs have all of these, and QEMU emulates a local APIC
+    for feature in [Feature::Tsc, Feature::Apic, Feature::Fxsr, Feature::Sse2, Feature::Nx] {
+        assert!(has(feature), "no {}", feature.name());
+    }
+}
+
+#[test_case]
+fn test_caches_are_sane() {
+    // the tests run QEMU with an Intel vendor id, which gets leaf 4
+    assert!(info().caches().count() > 0, "no caches listed");
+    for cache in info().caches() {
+        assert!((1..=4).contains(&cache.level));
+        assert!(cache.line_size.is_power_of_two());
+        assert!(cache.size >= cache.line_size * cache.ways);
+    }
+}
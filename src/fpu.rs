@@ -0,0 +1,254 @@
+//! FPU, SSE and AVX state
+//!
+//! The kernel is built with SSE, so compiled code may use the XMM
+//! registers anywhere. Every kernel thread has an `FpuState` that is saved
+//! and restored eagerly on each context switch with XSAVE/XRSTOR (or
+//! FXSAVE/FXRSTOR on CPUs without XSAVE).
+//!
+//! Interrupt handlers save the XMM registers they use (the x86-interrupt
+//! calling convention takes care of that), but nothing saves the upper
+//! halves of the YMM registers. Code using AVX (through `target_feature`
+//! functions) therefore has to be wrapped in `kernel_fpu_begin`, which
+//! saves the interrupted state when called from an interrupt handler.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use conquer_once::spin::OnceCell;
+use core::arch::asm;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::{cpu::{self, Feature}, percpu};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+// XCR0 state components
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+/// opmask, upper halves of ZMM0-15, ZMM16-31
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+/// size of the FXSAVE area
+const FXSAVE_SIZE: usize = 512;
+/// XSAVE areas must be 64 byte aligned (FXSAVE areas 16)
+const ALIGN: usize = 64;
+/// default x87 control word and MXCSR (all exceptions masked)
+const DEFAULT_FCW: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// state components saved with XSAVE, 0 if FXSAVE is used
+static XSAVE_MASK: AtomicU64 = AtomicU64::new(0);
+/// bytes needed to save the state
+static STATE_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// Enable SSE, and AVX (and AVX-512) where available, on the calling CPU
+///
+/// SSE must already be on when compiled code first runs, so the bootloader
+/// (`sse` feature) and the AP trampoline enable it. This adds the
+/// extended state and sizes the save area.
+pub fn init() {
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|cr4| {
+            cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        });
+    }
+
+    if !cpu::has(Feature::Xsave) {
+        return;
+    }
+    unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE)) };
+
+    let supported = cpu::xsave_supported();
+    let mut mask = XCR0_X87 | XCR0_SSE;
+    if cpu::has(Feature::Avx) {
+        mask |= supported & XCR0_AVX;
+        if supported & XCR0_AVX512 == XCR0_AVX512 {
+            mask |= XCR0_AVX512;
+        }
+    }
+    unsafe { xsetbv(0, mask) };
+
+    // size needed for the components just enabled
+    let size = cpu::xsave_size();
+    XSAVE_MASK.store(mask, Ordering::Relaxed);
+    STATE_SIZE.fetch_max(size, Ordering::Relaxed);
+}
+
+unsafe fn xsetbv(register: u32, value: u64) {
+    asm!("xsetbv", in("ecx") register,
+         in("eax") value as u32, in("edx") (value >> 32) as u32,
+         options(nostack, preserves_flags));
+}
+
+/// XSAVE/XRSTOR is used
+pub fn has_xsave() -> bool {
+    XSAVE_MASK.load(Ordering::Relaxed) != 0
+}
+
+/// AVX is enabled
+pub fn has_avx() -> bool {
+    XSAVE_MASK.load(Ordering::Relaxed) & XCR0_AVX != 0
+}
+
+/// AVX-512 is enabled
+pub fn has_avx512() -> bool {
+    XSAVE_MASK.load(Ordering::Relaxed) & XCR0_AVX512 != 0
+}
+
+/// Size of an `FpuState` save area in bytes
+pub fn state_size() -> usize {
+    STATE_SIZE.load(Ordering::Relaxed)
+}
+
+/// Saved FPU/SSE/AVX registers
+pub struct FpuState {
+    area: NonNull<u8>,
+    layout: Layout,
+}
+
+// the area is owned, and saving into it needs `unsafe`
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+impl FpuState {
+    /// The state after reset, but with all exceptions masked
+    ///
+    /// Requires `init` (and the heap).
+    pub fn new() -> Self {
+        let layout = Layout::from_size_align(state_size(), ALIGN).unwrap();
+        let area = unsafe { alloc_zeroed(layout) };
+        let area = NonNull::new(area).expect("out of memory for an FPU state");
+        unsafe {
+            // legacy area: FCW at 0, MXCSR at 24; an all zero XSAVE
+            // header means every other component is in its initial state
+            (area.as_ptr() as *mut u16).write(DEFAULT_FCW);
+            (area.as_ptr().add(24) as *mut u32).write(DEFAULT_MXCSR);
+        }
+        FpuState { area, layout }
+    }
+
+    /// Save the registers of the calling CPU
+    ///
+    /// Unsafe because nothing else may access the state at the same time.
+    #[inline]
+    pub unsafe fn save(&self) {
+        let mask = XSAVE_MASK.load(Ordering::Relaxed);
+        if mask != 0 {
+            asm!("xsave64 [{}]", in(reg) self.area.as_ptr(),
+                 in("eax") mask as u32, in("edx") (mask >> 32) as u32,
+                 options(nostack, preserves_flags));
+        } else {
+            asm!("fxsave64 [{}]", in(reg) self.area.as_ptr(),
+                 options(nostack, preserves_flags));
+        }
+    }
+
+    /// Load the saved registers into the calling CPU
+    ///
+    /// Unsafe because it replaces the registers under the feet of the
+    /// compiler; nothing else may access the state at the same time.
+    #[inline]
+    pub unsafe fn restore(&self) {
+        let mask = XSAVE_MASK.load(Ordering::Relaxed);
+        if mask != 0 {
+            asm!("xrstor64 [{}]", in(reg) self.area.as_ptr(),
+                 in("eax") mask as u32, in("edx") (mask >> 32) as u32,
+                 out("xmm0") _, out("xmm1") _, out("xmm2") _, out("xmm3") _,
+                 out("xmm4") _, out("xmm5") _, out("xmm6") _, out("xmm7") _,
+                 out("xmm8") _, out("xmm9") _, out("xmm10") _, out("xmm11") _,
+                 out("xmm12") _, out("xmm13") _, out("xmm14") _, out("xmm15") _,
+                 options(nostack, preserves_flags));
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) self.area.as_ptr(),
+                 out("xmm0") _, out("xmm1") _, out("xmm2") _, out("xmm3") _,
+                 out("xmm4") _, out("xmm5") _, out("xmm6") _, out("xmm7") _,
+                 out("xmm8") _, out("xmm9") _, out("xmm10") _, out("xmm11") _,
+                 out("xmm12") _, out("xmm13") _, out("xmm14") _, out("xmm15") _,
+                 options(nostack, preserves_flags));
+        }
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), self.layout) };
+    }
+}
+
+/// Per-CPU state for `kernel_fpu_begin` (part of the `percpu::PerCpu`
+/// block)
+pub(crate) struct CpuFpu {
+    /// nesting depth of `kernel_fpu_begin`
+    depth: AtomicUsize,
+    /// state of the interrupted code, saved by the outermost
+    /// `kernel_fpu_begin` in an interrupt handler
+    saved: OnceCell<FpuState>,
+}
+
+impl CpuFpu {
+    pub(crate) const fn new() -> Self {
+        CpuFpu {
+            depth: AtomicUsize::new(0),
+            saved: OnceCell::uninit(),
+        }
+    }
+}
+
+/// A section that may use all SIMD registers; ends when dropped
+pub struct KernelFpu {
+    restore: bool,
+}
+
+/// Start a section that uses AVX (or any other extended state)
+///
+/// Thread context needs no saving since context switches save everything.
+/// In an interrupt handler the interrupted state is saved here and
+/// restored by `kernel_fpu_end`.
+pub fn kernel_fpu_begin() -> KernelFpu {
+    let cpu = percpu::current();
+    let depth = cpu.fpu.depth.fetch_add(1, Ordering::Relaxed);
+    let restore = depth == 0 && cpu.in_interrupt();
+    if restore {
+        let saved = cpu.fpu.saved.get_or_init(FpuState::new);
+        unsafe { saved.save() };
+    }
+    KernelFpu { restore }
+}
+
+/// End a section started with `kernel_fpu_begin`
+pub fn kernel_fpu_end(section: KernelFpu) {
+    drop(section);
+}
+
+impl Drop for KernelFpu {
+    fn drop(&mut self) {
+        let cpu = percpu::current();
+        if self.restore {
+            let saved = cpu.fpu.saved.get().expect("FPU state not saved");
+            unsafe { saved.restore() };
+        }
+        cpu.fpu.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_float_math() {
+    let x: f64 = volatile::Volatile::new(2.0).read();
+    assert_eq!(x * 1.5 + 0.25, 3.25);
+}
+
+#[test_case]
+fn test_new_state_masks_exceptions() {
+    let saved = FpuState::new();
+    let fresh = FpuState::new();
+    let mut mxcsr: u32 = 0;
+    unsafe {
+        saved.save();
+        fresh.restore();
+        asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags));
+        saved.restore();
+    }
+    assert_eq!(mxcsr, DEFAULT_MXCSR);
+}
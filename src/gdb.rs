@@ -0,0 +1,744 @@
+//! GDB remote serial protocol stub
+//!
+//! After `init`, breakpoint (`int3`) and debug (single step) exceptions in
+//! kernel code stop the CPU they happen on and hand it to a debugger
+//! talking the GDB remote protocol on COM2. `breakpoint` stops on purpose,
+//! e.g. right after `init` to wait for the debugger to connect. With QEMU:
+//!
+//! ```text
+//! qemu-system-x86_64 ... -serial stdio -serial pty
+//! gdb target/baremetal/debug/blog_os -ex 'target remote /dev/pts/N'
+//! ```
+//!
+//! Supported are reading and writing registers and memory, software
+//! breakpoints (`Z0`), single steps and continuing. Kernel threads show up
+//! as GDB threads; switched out threads only have the registers
+//! `switch_context` saved (rip, rsp, rbp, rbx and r12-r15).
+//!
+//! Other CPUs keep running while one is stopped. The stub neither
+//! allocates nor blocks on anything but the serial port: if the stopped
+//! code held the thread list or the scheduler state, the threads are left
+//! out and the stopped code is reported as thread 1.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{interrupts::TrapFrame, memory, thread};
+use uart_16550::SerialPort;
+use x86_64::registers::{control::{Cr0, Cr0Flags}, rflags::RFlags};
+use x86_64::VirtAddr;
+
+/// I/O port of the second serial port
+pub const COM2: u16 = 0x2f8;
+/// longest packet accepted and sent
+pub const MAX_PACKET: usize = 4096;
+/// software breakpoints set at a time
+pub const MAX_BREAKPOINTS: usize = 32;
+
+/// `int3`
+const BREAKPOINT_INSTRUCTION: u8 = 0xcc;
+/// registers in a `g` packet: rax ... r15, rip, then eflags and the
+/// segment registers with 4 bytes each
+const REGISTERS: usize = 24;
+/// signal reported for every stop (SIGTRAP)
+const SIGTRAP: u8 = 5;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PORT: spin::Mutex<Option<SerialPort>> = spin::Mutex::new(None);
+static STUB: spin::Mutex<Stub> = spin::Mutex::new(Stub::new());
+
+/// A byte stream to the debugger
+pub trait Connection {
+    /// Wait for the next byte
+    fn read(&mut self) -> u8;
+    fn write(&mut self, byte: u8);
+}
+
+impl Connection for SerialPort {
+    fn read(&mut self) -> u8 {
+        self.receive()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.send(byte);
+    }
+}
+
+/// Set up COM2 and let breakpoints stop in the debugger from now on
+pub fn init() {
+    let mut port = unsafe { SerialPort::new(COM2) };
+    port.init();
+    x86_64::instructions::interrupts::without_interrupts(|| *PORT.lock() = Some(port));
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Stop in the debugger (it may not be connected yet)
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Called by the debug and breakpoint exception handlers for kernel code;
+/// returns false if the stub is not enabled
+pub(crate) fn handle_trap(frame: &mut TrapFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let mut port = PORT.lock();
+    match port.as_mut() {
+        Some(port) => {
+            serve(port, frame);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Let the debugger on `connection` inspect and change the stopped code
+/// until it continues or steps
+///
+/// The registers of the calling thread are those in `frame`.
+pub fn serve<C: Connection>(connection: &mut C, frame: &mut TrapFrame) {
+    let mut stub = STUB.lock();
+    stub.stop(frame);
+    if stub.running {
+        // the debugger waits for this since the last `c` or `s`
+        stub.stop_reply();
+        stub.send(connection);
+    }
+    loop {
+        stub.receive(connection);
+        let action = stub.command(frame);
+        match action {
+            Action::Reply => stub.send(connection),
+            Action::Resume { step } => {
+                if step {
+                    frame.rflags |= RFlags::TRAP_FLAG.bits();
+                }
+                stub.running = true;
+                return;
+            }
+            Action::Detach => {
+                stub.send(connection);
+                stub.remove_breakpoints();
+                stub.running = false;
+                return;
+            }
+        }
+    }
+}
+
+enum Action {
+    Reply,
+    Resume { step: bool },
+    /// reply, remove all breakpoints and continue
+    Detach,
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// the byte `int3` replaced
+    saved: u8,
+}
+
+struct Stub {
+    packet: [u8; MAX_PACKET],
+    packet_len: usize,
+    reply: Buffer,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// thread `g` and `p` packets refer to, `None` for the stopped one
+    thread: Option<u64>,
+    /// the stopped thread (as a GDB thread id)
+    current: u64,
+    /// the debugger resumed us and waits for a stop reply
+    running: bool,
+}
+
+impl Stub {
+    const fn new() -> Self {
+        Stub {
+            packet: [0; MAX_PACKET],
+            packet_len: 0,
+            reply: Buffer::new(),
+            breakpoints: [None; MAX_BREAKPOINTS],
+            thread: None,
+            current: 1,
+            running: false,
+        }
+    }
+
+    /// Take over the stopped code
+    fn stop(&mut self, frame: &mut TrapFrame) {
+        frame.rflags &= !RFlags::TRAP_FLAG.bits();
+        // after a breakpoint of ours, point at it rather than behind it
+        if frame.vector == 3 && self.breakpoint_at(frame.rip.wrapping_sub(1)).is_some() {
+            frame.rip -= 1;
+        }
+        self.current = thread::try_current().map_or(1, |thread| gdb_thread_id(thread.id()));
+        self.thread = None;
+    }
+
+    fn stop_reply(&mut self) {
+        self.reply.clear();
+        let _ = write!(self.reply, "T{:02x}thread:{:x};", SIGTRAP, self.current);
+    }
+
+    /// Wait for a valid packet and acknowledge it
+    fn receive<C: Connection>(&mut self, connection: &mut C) {
+        loop {
+            // acknowledgements and interrupt requests are of no interest
+            while connection.read() != b'$' {}
+            self.packet_len = 0;
+            let mut sum = 0u8;
+            let mut overflow = false;
+            loop {
+                match connection.read() {
+                    b'#' => break,
+                    byte => {
+                        sum = sum.wrapping_add(byte);
+                        if self.packet_len < MAX_PACKET {
+                            self.packet[self.packet_len] = byte;
+                            self.packet_len += 1;
+                        } else {
+                            overflow = true;
+                        }
+                    }
+                }
+            }
+            let high = hex_digit(connection.read());
+            let low = hex_digit(connection.read());
+            if !overflow && high.zip(low).map(|(high, low)| high << 4 | low) == Some(sum) {
+                connection.write(b'+');
+                return;
+            }
+            connection.write(b'-');
+        }
+    }
+
+    /// Send the reply until the debugger acknowledges it
+    fn send<C: Connection>(&self, connection: &mut C) {
+        let reply = self.reply.as_bytes();
+        let sum = reply.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        loop {
+            connection.write(b'$');
+            for &byte in reply {
+                connection.write(byte);
+            }
+            connection.write(b'#');
+            connection.write(HEX[usize::from(sum >> 4)]);
+            connection.write(HEX[usize::from(sum & 0xf)]);
+            if connection.read() != b'-' {
+                return;
+            }
+        }
+    }
+
+    /// Carry out the received packet; the reply is left in `self.reply`
+    fn command(&mut self, frame: &mut TrapFrame) -> Action {
+        // a copy, since the handlers need `self`
+        let mut packet = [0u8; MAX_PACKET];
+        let len = self.packet_len;
+        packet[..len].copy_from_slice(&self.packet[..len]);
+        let packet = &packet[..len];
+
+        self.reply.clear();
+        let result = match packet.split_first() {
+            Some((b'?', _)) => {
+                self.stop_reply();
+                Ok(())
+            }
+            Some((b'g', _)) => self.read_registers(frame),
+            Some((b'G', args)) => self.write_registers(frame, args),
+            Some((b'p', args)) => self.read_register(frame, args),
+            Some((b'P', args)) => self.write_register(frame, args),
+            Some((b'm', args)) => self.read_memory(args),
+            Some((b'M', args)) => self.write_memory(args),
+            Some((b'Z', args)) => self.insert_breakpoint(args),
+            Some((b'z', args)) => self.remove_breakpoint(args),
+            Some((b'H', args)) => self.select_thread(args),
+            Some((b'T', args)) => self.thread_alive(args),
+            Some((b'q', args)) => self.query(args),
+            Some((&command, args)) if command == b'c' || command == b's' => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(addr) => frame.rip = addr,
+                        None => return self.error(1),
+                    }
+                }
+                return Action::Resume { step: command == b's' };
+            }
+            Some((b'D' | b'k', _)) => {
+                self.ok();
+                return Action::Detach;
+            }
+            // unsupported: an empty reply
+            _ => Ok(()),
+        };
+        match result {
+            Ok(()) => Action::Reply,
+            Err(errno) => self.error(errno),
+        }
+    }
+
+    fn ok(&mut self) {
+        self.reply.clear();
+        self.reply.push_str("OK");
+    }
+
+    fn error(&mut self, errno: u8) -> Action {
+        self.reply.clear();
+        let _ = write!(self.reply, "E{:02x}", errno);
+        Action::Reply
+    }
+
+    fn read_registers(&mut self, frame: &TrapFrame) -> Result<(), u8> {
+        for n in 0..REGISTERS {
+            self.push_register(frame, n)?;
+        }
+        Ok(())
+    }
+
+    fn read_register(&mut self, frame: &TrapFrame, args: &[u8]) -> Result<(), u8> {
+        let n = parse_hex(args).ok_or(1)? as usize;
+        if n >= REGISTERS {
+            return Err(1);
+        }
+        self.push_register(frame, n)
+    }
+
+    fn push_register(&mut self, frame: &TrapFrame, n: usize) -> Result<(), u8> {
+        let value = match self.thread {
+            None => Some(register(frame, n)),
+            Some(id) if id == self.current => Some(register(frame, n)),
+            Some(id) => switched_out_register(id, n)?,
+        };
+        let size = register_size(n);
+        match value {
+            Some(value) => self.reply.push_hex(&value.to_le_bytes()[..size]),
+            None => (0..size * 2).for_each(|_| self.reply.push(b'x')),
+        }
+        Ok(())
+    }
+
+    fn write_registers(&mut self, frame: &mut TrapFrame, args: &[u8]) -> Result<(), u8> {
+        self.check_writable_thread()?;
+        let mut rest = args;
+        let mut updated = frame.clone();
+        for n in 0..REGISTERS {
+            let size = register_size(n) * 2;
+            if rest.len() < size {
+                break;
+            }
+            let (digits, tail) = rest.split_at(size);
+            set_register(&mut updated, n, parse_le_hex(digits).ok_or(1)?);
+            rest = tail;
+        }
+        *frame = updated;
+        self.ok();
+        Ok(())
+    }
+
+    fn write_register(&mut self, frame: &mut TrapFrame, args: &[u8]) -> Result<(), u8> {
+        self.check_writable_thread()?;
+        let (n, value) = split_at_byte(args, b'=').ok_or(1)?;
+        let n = parse_hex(n).ok_or(1)? as usize;
+        if n >= REGISTERS {
+            return Err(1);
+        }
+        set_register(frame, n, parse_le_hex(value).ok_or(1)?);
+        self.ok();
+        Ok(())
+    }
+
+    /// Only the registers of the stopped thread can be changed
+    fn check_writable_thread(&self) -> Result<(), u8> {
+        match self.thread {
+            Some(id) if id != self.current => Err(1),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_memory(&mut self, args: &[u8]) -> Result<(), u8> {
+        let (addr, len) = parse_range(args).ok_or(1)?;
+        if len > (MAX_PACKET / 2) as u64 {
+            return Err(1);
+        }
+        for offset in 0..len {
+            let byte = read_byte(addr.wrapping_add(offset)).ok_or(14)?;
+            self.reply.push_hex(&[byte]);
+        }
+        Ok(())
+    }
+
+    fn write_memory(&mut self, args: &[u8]) -> Result<(), u8> {
+        let (range, data) = split_at_byte(args, b':').ok_or(1)?;
+        let (addr, len) = parse_range(range).ok_or(1)?;
+        if data.len() % 2 != 0 || (data.len() / 2) as u64 != len {
+            return Err(1);
+        }
+        // a range that wraps around the address space is not written at all
+        addr.checked_add(len).ok_or(14)?;
+        for offset in 0..len {
+            if !is_mapped(addr + offset) {
+                return Err(14);
+            }
+        }
+        for (offset, digits) in data.chunks(2).enumerate() {
+            let byte = parse_hex(digits).ok_or(1)? as u8;
+            unsafe { write_byte(addr + offset as u64, byte) };
+        }
+        self.ok();
+        Ok(())
+    }
+
+    fn breakpoint_at(&self, addr: u64) -> Option<usize> {
+        self.breakpoints.iter()
+            .position(|breakpoint| matches!(breakpoint, Some(b) if b.addr == addr))
+    }
+
+    /// `Z0,addr,kind`; other types (hardware breakpoints, watchpoints)
+    /// are not supported
+    fn insert_breakpoint(&mut self, args: &[u8]) -> Result<(), u8> {
+        let addr = match parse_breakpoint(args) {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        if self.breakpoint_at(addr).is_none() {
+            let slot = self.breakpoints.iter().position(Option::is_none).ok_or(12)?;
+            let saved = read_byte(addr).ok_or(14)?;
+            unsafe { write_byte(addr, BREAKPOINT_INSTRUCTION) };
+            self.breakpoints[slot] = Some(Breakpoint { addr, saved });
+        }
+        self.ok();
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, args: &[u8]) -> Result<(), u8> {
+        let addr = match parse_breakpoint(args) {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        if let Some(slot) = self.breakpoint_at(addr) {
+            let breakpoint = self.breakpoints[slot].take().unwrap();
+            unsafe { write_byte(breakpoint.addr, breakpoint.saved) };
+        }
+        self.ok();
+        Ok(())
+    }
+
+    fn remove_breakpoints(&mut self) {
+        for breakpoint in self.breakpoints.iter_mut().filter_map(Option::take) {
+            unsafe { write_byte(breakpoint.addr, breakpoint.saved) };
+        }
+    }
+
+    /// `Hg<id>` and `Hc<id>`: 0 and -1 stand for any thread
+    fn select_thread(&mut self, args: &[u8]) -> Result<(), u8> {
+        match args.split_first() {
+            Some((b'g', id)) => {
+                self.thread = match id {
+                    b"0" | b"-1" => None,
+                    id => {
+                        let id = parse_hex(id).ok_or(1)?;
+                        find_thread(id, |_| ()).ok_or(1)?;
+                        Some(id)
+                    }
+                };
+            }
+            Some((b'c', _)) => {} // all threads run on continue
+            _ => return Err(1),
+        }
+        self.ok();
+        Ok(())
+    }
+
+    fn thread_alive(&mut self, args: &[u8]) -> Result<(), u8> {
+        let id = parse_hex(args).ok_or(1)?;
+        if id != self.current {
+            find_thread(id, |_| ()).ok_or(1)?;
+        }
+        self.ok();
+        Ok(())
+    }
+
+    fn query(&mut self, args: &[u8]) -> Result<(), u8> {
+        if args.starts_with(b"Supported") {
+            let _ = write!(self.reply, "PacketSize={:x}", MAX_PACKET);
+        } else if args == b"Attached" {
+            self.reply.push_str("1");
+        } else if args == b"C" {
+            let _ = write!(self.reply, "QC{:x}", self.current);
+        } else if args == b"fThreadInfo" {
+            self.reply.push(b'm');
+            let mut first = true;
+            let reply = &mut self.reply;
+            for_each_thread(|id, _| {
+                if !first {
+                    reply.push(b',');
+                }
+                first = false;
+                let _ = write!(reply, "{:x}", id);
+            });
+            if first {
+                // before `thread::init` or with the thread list locked:
+                // just the stopped code
+                let _ = write!(self.reply, "{:x}", self.current);
+            }
+        } else if args == b"sThreadInfo" {
+            self.reply.push_str("l");
+        } else if let Some(id) = args.strip_prefix(b"ThreadExtraInfo,") {
+            let id = parse_hex(id).ok_or(1)?;
+            let reply = &mut self.reply;
+            find_thread(id, |thread| {
+                let _ = write!(HexWriter(reply), "{} ({:?})", thread.name(), thread.state());
+            }).ok_or(1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fixed size text for replies
+struct Buffer {
+    bytes: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Buffer {
+    const fn new() -> Self {
+        Buffer { bytes: [0; MAX_PACKET], len: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Bytes that do not fit are dropped
+    fn push(&mut self, byte: u8) {
+        if self.len < MAX_PACKET {
+            self.bytes[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.push(byte));
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(HEX[usize::from(byte >> 4)]);
+            self.push(HEX[usize::from(byte & 0xf)]);
+        }
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+/// Writes text hex encoded
+struct HexWriter<'a>(&'a mut Buffer);
+
+impl Write for HexWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push_hex(s.as_bytes());
+        Ok(())
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A big endian hex number (addresses, lengths, ids)
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, &digit| Some(value << 4 | u64::from(hex_digit(digit)?)))
+}
+
+/// Hex bytes in target (little endian) order (register values)
+fn parse_le_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || digits.len() % 2 != 0 {
+        return None;
+    }
+    digits.chunks(2).rev().try_fold(0u64, |value, pair| Some(value << 8 | parse_hex(pair)?))
+}
+
+fn split_at_byte(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
+
+/// `addr,len`
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = split_at_byte(args, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+/// The address of a `Z0`/`z0` packet, `None` for other types
+fn parse_breakpoint(args: &[u8]) -> Option<u64> {
+    let rest = args.strip_prefix(b"0,")?;
+    let (addr, _kind) = split_at_byte(rest, b',')?;
+    parse_hex(addr)
+}
+
+fn register_size(n: usize) -> usize {
+    if n <= 16 { 8 } else { 4 }
+}
+
+/// Register `n` in GDB's amd64 numbering
+fn register(frame: &TrapFrame, n: usize) -> u64 {
+    match n {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        // ds, es, fs and gs are not used in long mode
+        _ => 0,
+    }
+}
+
+fn set_register(frame: &mut TrapFrame, n: usize, value: u64) {
+    let register = match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        // the selectors stay as they are
+        _ => return,
+    };
+    *register = value;
+}
+
+/// Register `n` of the switched out thread `id` from what
+/// `switch_context` pushed: r15, r14, r13, r12, rbx, rbp and the return
+/// address; `None` for the others
+fn switched_out_register(id: u64, n: usize) -> Result<Option<u64>, u8> {
+    let rsp = find_thread(id, |thread| thread.saved_rsp()).ok_or(1)?;
+    let rsp = match rsp {
+        Some(rsp) => rsp,
+        // running on another CPU
+        None => return Ok(None),
+    };
+    let slot = match n {
+        15 => 0,
+        14 => 1,
+        13 => 2,
+        12 => 3,
+        1 => 4,
+        6 => 5,
+        16 => 6,
+        7 => return Ok(Some(rsp + 7 * 8)),
+        _ => return Ok(None),
+    };
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = read_byte(rsp + slot * 8 + i as u64).ok_or(14)?;
+    }
+    Ok(Some(u64::from_le_bytes(bytes)))
+}
+
+/// GDB thread ids start at 1
+fn gdb_thread_id(id: thread::ThreadId) -> u64 {
+    id.as_u64() + 1
+}
+
+/// Call `f` for every thread; for none if the thread list is locked
+fn for_each_thread(mut f: impl FnMut(u64, &thread::Thread)) {
+    thread::try_for_each(|thread| f(gdb_thread_id(thread.id()), thread));
+}
+
+/// Run `f` with the thread GDB calls `id`
+fn find_thread<R>(id: u64, f: impl FnOnce(&thread::Thread) -> R) -> Option<R> {
+    let mut f = Some(f);
+    let mut result = None;
+    for_each_thread(|thread_id, thread| {
+        if thread_id == id {
+            if let Some(f) = f.take() {
+                result = Some(f(thread));
+            }
+        }
+    });
+    result
+}
+
+fn is_mapped(addr: u64) -> bool {
+    VirtAddr::try_new(addr).map_or(false, memory::is_mapped)
+}
+
+fn read_byte(addr: u64) -> Option<u8> {
+    if !is_mapped(addr) {
+        return None;
+    }
+    Some(unsafe { (addr as *const u8).read_volatile() })
+}
+
+/// Write to a mapped byte, even in read-only kernel code
+///
+/// Unsafe because it may change any code or data.
+unsafe fn write_byte(addr: u64, byte: u8) {
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    (addr as *mut u8).write_volatile(byte);
+    Cr0::write(cr0);
+}
+
+#[test_case]
+fn test_hex_parsing() {
+    assert_eq!(parse_hex(b"ffff8000"), Some(0xffff_8000));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"12g"), None);
+    assert_eq!(parse_le_hex(b"3412000000000000"), Some(0x1234));
+    assert_eq!(parse_range(b"1000,20"), Some((0x1000, 0x20)));
+    assert_eq!(parse_breakpoint(b"0,abc,1"), Some(0xabc));
+    assert_eq!(parse_breakpoint(b"2,abc,1"), None);
+}
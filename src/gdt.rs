@@ -1,53 +1,229 @@
-use lazy_static::lazy_static;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{memory, percpu};
 use x86_64::structures::gdt::GlobalDescriptorTable;
 use x86_64::structures::gdt::{Descriptor, SegmentSelector};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const DEBUG_IST_INDEX: u16 = 3;
+pub const PAGE_FAULT_IST_INDEX: u16 = 4;
+/// number of interrupt stacks of every CPU
+pub const IST_STACKS: usize = 5;
+pub const IST_STACK_PAGES: u64 = 5;
+pub const IST_STACK_SIZE: usize = 4096 * IST_STACK_PAGES as usize;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
-
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
-}
-
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+const IST_NAMES: [&str; IST_STACKS] =
+    ["double fault", "NMI", "machine check", "debug", "page fault"];
+
+// The order is fixed by SYSCALL/SYSRET (see `syscall::init`): kernel data
+// right after kernel code, user code right after user data.
+pub const KERNEL_CODE_SELECTOR: SegmentSelector =
+    SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector =
+    SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector =
+    SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector =
+    SegmentSelector::new(4, PrivilegeLevel::Ring3);
+const TSS_SELECTOR: SegmentSelector =
+    SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+/// GDT and TSS of one CPU, kept in its per-CPU block
+///
+/// A TSS can only be loaded by one CPU at a time (loading marks it busy),
+/// so every CPU needs its own.
+pub struct Gdt {
+    table: GlobalDescriptorTable,
+    /// the CPU reads the kernel stack for user mode interrupts from here,
+    /// which changes with the running thread
+    tss: UnsafeCell<TaskStateSegment>,
+    /// the interrupt stacks come from `memory::alloc_stack`, with an
+    /// unmapped guard page below each
+    guarded: AtomicBool,
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
-    use x86_64::instructions::tables::load_tss;
+impl Gdt {
+    pub fn new() -> Self {
+        Gdt {
+            table: GlobalDescriptorTable::new(),
+            tss: UnsafeCell::new(TaskStateSegment::new()),
+            guarded: AtomicBool::new(false),
+        }
+    }
+
+    /// Interrupt stack table of the loaded TSS
+    pub fn interrupt_stack_table(&self) -> &[VirtAddr; 7] {
+        unsafe { &(*self.tss.get()).interrupt_stack_table }
+    }
+
+    /// Where the CPU finds the stack for interrupts from ring 3
+    /// (`privilege_stack_table[0]`)
+    pub(crate) fn kernel_stack_ptr(&self) -> *mut u64 {
+        unsafe {
+            core::ptr::addr_of_mut!((*self.tss.get()).privilege_stack_table[0])
+                as *mut u64
+        }
+    }
+
+    /// Fill in the tables and load them on the calling CPU
+    ///
+    /// `stacks` are the tops of the interrupt stacks, by IST index, and
+    /// `guarded` tells whether they have guard pages. This function is
+    /// unsafe because the caller must guarantee that `self` is never moved,
+    /// changed or freed afterwards.
+    pub(crate) unsafe fn load(&mut self, stacks: [VirtAddr; IST_STACKS], guarded: bool) {
+        use x86_64::instructions::segmentation::{load_ss, set_cs};
+        use x86_64::instructions::tables::load_tss;
+
+        let tss = self.tss.get_mut();
+        tss.interrupt_stack_table[..IST_STACKS].copy_from_slice(&stacks);
+        *self.guarded.get_mut() = guarded;
+        let tss: &'static TaskStateSegment = &*(tss as *const _);
+
+        let mut table = GlobalDescriptorTable::new();
+        let selectors = [
+            table.add_entry(Descriptor::kernel_code_segment()),
+            table.add_entry(Descriptor::kernel_data_segment()),
+            table.add_entry(Descriptor::user_data_segment()),
+            table.add_entry(Descriptor::user_code_segment()),
+            table.add_entry(Descriptor::tss_segment(tss)),
+        ];
+        debug_assert_eq!(selectors, [
+            KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_DATA_SELECTOR,
+            USER_CODE_SELECTOR, TSS_SELECTOR,
+        ]);
+        self.table = table;
+
+        let table: &'static GlobalDescriptorTable = &*(&self.table as *const _);
+        table.load();
+        set_cs(KERNEL_CODE_SELECTOR);
+        load_ss(KERNEL_DATA_SELECTOR);
+        load_tss(TSS_SELECTOR);
+    }
+
+    /// Switch the loaded TSS to new interrupt stacks with guard pages
+    ///
+    /// Unsafe because no interrupt handler may be running on the old ones.
+    unsafe fn replace_stacks(&self, stacks: [VirtAddr; IST_STACKS]) {
+        let table = &mut (*self.tss.get()).interrupt_stack_table;
+        table[..IST_STACKS].copy_from_slice(&stacks);
+        self.guarded.store(true, Ordering::Release);
+    }
+
+    /// The interrupt stack whose guard page contains `addr`
+    fn guard_page_owner(&self, addr: VirtAddr) -> Option<u16> {
+        if !self.guarded.load(Ordering::Acquire) {
+            return None;
+        }
+        let table = self.interrupt_stack_table();
+        (0..IST_STACKS).find(|&index| {
+            let bottom = table[index].as_u64() - IST_STACK_SIZE as u64;
+            (bottom - 4096..bottom).contains(&addr.as_u64())
+        }).map(|index| index as u16)
+    }
+}
+
+/// An interrupt stack of one CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptStack {
+    pub cpu: usize,
+    /// IST index
+    pub index: u16,
+}
 
-    GDT.0.load();
-    unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+impl InterruptStack {
+    /// The handler the stack belongs to ("NMI", "page fault", ...)
+    pub fn name(&self) -> &'static str {
+        IST_NAMES[self.index as usize]
     }
 }
+
+impl fmt::Display for InterruptStack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} stack of CPU {}", self.name(), self.cpu)
+    }
+}
+
+/// The interrupt stack that overflowed if `addr` (of a page fault) is in
+/// its guard page
+///
+/// Only stacks from `init_stacks` and of application processors have
+/// guard pages.
+pub fn overflowed_stack(addr: VirtAddr) -> Option<InterruptStack> {
+    percpu::all().find_map(|cpu| {
+        let index = cpu.gdt.guard_page_owner(addr)?;
+        Some(InterruptStack { cpu: cpu.cpu_id, index })
+    })
+}
+
+/// Whether the code that was interrupted at `interrupted_rsp` ran on the
+/// interrupt stack the caller runs on
+///
+/// Then a handler on that stack faulted again, and the CPU started over
+/// at the top of the stack, overwriting the frames of the outer handler.
+/// Uses no GS.
+pub fn interrupted_own_stack(interrupted_rsp: VirtAddr) -> bool {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    let here = VirtAddr::new(rsp);
+    percpu::all().any(|cpu| {
+        cpu.gdt.interrupt_stack_table()[..IST_STACKS].iter().any(|&top| {
+            let stack = (top - IST_STACK_SIZE as u64)..top;
+            stack.contains(&here) && stack.contains(&interrupted_rsp)
+        })
+    })
+}
+
+/// Tops of the interrupt stacks the BSP boots with
+///
+/// Static arrays without guard pages, since the BSP loads its GDT before
+/// memory is set up. `init_stacks` replaces them.
+pub(crate) fn bsp_stacks() -> [VirtAddr; IST_STACKS] {
+    static mut STACKS: [[u8; IST_STACK_SIZE]; IST_STACKS] = [[0; IST_STACK_SIZE]; IST_STACKS];
+
+    let mut tops = [VirtAddr::zero(); IST_STACKS];
+    for (top, stack) in tops.iter_mut().zip(unsafe { STACKS.iter() }) {
+        *top = VirtAddr::from_ptr(stack) + IST_STACK_SIZE;
+    }
+    tops
+}
+
+/// Map interrupt stacks with guard pages and return their tops
+pub(crate) fn alloc_stacks(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<[VirtAddr; IST_STACKS], MapToError<Size4KiB>> {
+    let mut tops = [VirtAddr::zero(); IST_STACKS];
+    for top in tops.iter_mut() {
+        *top = memory::alloc_stack(IST_STACK_PAGES, mapper, frame_allocator)?;
+    }
+    Ok(tops)
+}
+
+/// Move the BSP off its boot interrupt stacks onto ones with guard pages
+///
+/// An overflow of an interrupt stack then page faults (see
+/// `overflowed_stack`) instead of running into whatever lies below.
+pub fn init_stacks(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let stacks = alloc_stacks(mapper, frame_allocator)?;
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        percpu::current().gdt.replace_stacks(stacks)
+    });
+    Ok(())
+}
+
+/// Load the GDT and TSS of the BSP
+///
+/// They live in the per-CPU block of the BSP, so this sets that up too.
+pub fn init() {
+    crate::percpu::init_bsp();
+}
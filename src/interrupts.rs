@@ -1,23 +1,48 @@
-use crate::{gdt, println, hlt_loop};
+use crate::{apic, backtrace::{self, Backtrace}, gdt, irqstat, percpu, println, profile};
+use crate::{serial::RawSerial, serial_println, symbols::Location, thread};
+use crate::{monitor::{self, Reason}, softirq::{Priority, Softirq}, watchdog};
+use crate::{sync::SpinMutex, user::{self, KernelGs}, hlt_loop};
+use core::arch::global_asm;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::{
-    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode
+    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode
 };
 
+global_asm!(include_str!("trap.s"));
+
+extern "C" {
+    fn debug_entry();
+    fn breakpoint_entry();
+}
+
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub const LAPIC_OFFSET: u8 = PIC_2_OFFSET + 8;
+/// local APIC spurious interrupt vector (needs no end of interrupt)
+pub const SPURIOUS_VECTOR: u8 = 0xff;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// printer port, or a spurious interrupt of the master PIC
+    Irq7 = PIC_1_OFFSET + 7,
+    Rtc = PIC_2_OFFSET,
+    /// secondary ATA, or a spurious interrupt of the slave PIC
+    Irq15 = PIC_2_OFFSET + 7,
+    LapicTimer = LAPIC_OFFSET,
+    ApicError,
+    /// inter-processor interrupt that only wakes a halted CPU
+    Wakeup,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub(crate) fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -26,14 +51,33 @@ impl InterruptIndex {
     }
 }
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+/// Taken by interrupt handlers, so only with interrupts disabled
+pub static PICS: SpinMutex<ChainedPics> =
+    SpinMutex::named("PICS", unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        unsafe {
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+        }
+        unsafe {
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        unsafe {
+            idt.debug.set_handler_fn(asm_handler(debug_entry))
+                .set_stack_index(gdt::DEBUG_IST_INDEX);
+            idt.breakpoint.set_handler_fn(asm_handler(breakpoint_entry));
+        }
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        unsafe {
+            idt.page_fault.set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
@@ -42,64 +86,400 @@ lazy_static! {
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Irq7.as_usize()]
+            .set_handler_fn(irq7_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()]
+            .set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::Irq15.as_usize()]
+            .set_handler_fn(irq15_interrupt_handler);
+        idt[InterruptIndex::LapicTimer.as_usize()]
+            .set_handler_fn(lapic_timer_interrupt_handler);
+        idt[InterruptIndex::ApicError.as_usize()]
+            .set_handler_fn(apic_error_interrupt_handler);
+        idt[InterruptIndex::Wakeup.as_usize()]
+            .set_handler_fn(wakeup_interrupt_handler);
+        idt[SPURIOUS_VECTOR as usize]
+            .set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
+/// An entry from `trap.s` as an IDT handler
+///
+/// Unsafe because `entry` must return with `iretq`.
+unsafe fn asm_handler(entry: unsafe extern "C" fn()) -> HandlerFunc {
+    core::mem::transmute(entry)
+}
+
 pub fn init_idt() {
     IDT.load();
 }
 
+/// Mask or unmask a legacy IRQ line at the PICs
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    use x86_64::instructions::{interrupts, port::Port};
+
+    let (port, bit) = if irq < 8 { (0x21, irq) } else { (0xa1, irq - 8) };
+    let mut data: Port<u8> = Port::new(port);
+    interrupts::without_interrupts(|| {
+        let _pics = PICS.lock();
+        unsafe {
+            let mask = data.read();
+            if masked {
+                data.write(mask | 1 << bit);
+            } else {
+                data.write(mask & !(1 << bit));
+            }
+        }
+    });
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
+    let _gs = KernelGs::enter(&stack_frame);
+    let _irq = percpu::irq_enter(InterruptIndex::Keyboard.as_u8());
     use x86_64::instructions::port::Port;
     use crate::task::keyboard;
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-    keyboard::add_scancode(scancode);
+    let hotkey = monitor::hotkey(scancode);
+    if !hotkey {
+        keyboard::add_scancode(scancode);
+    }
 
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
+    if hotkey {
+        let frame = TrapFrame::from_exception(&stack_frame, InterruptIndex::Keyboard as u64);
+        monitor::enter(&frame, Reason::Hotkey);
+    }
 }
 
-// BIOS initializes PIT to interrupt IRQ 0 at 18.2 Hz
+// IRQ 0 is raised by the PIT or the HPET (see `task::timer`)
 extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = KernelGs::enter(&stack_frame);
+    let irq = percpu::irq_enter(InterruptIndex::Timer.as_u8());
+    timer_tick(InterruptIndex::Timer.as_u8(), &stack_frame);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    }
+    drop(irq);
+    thread::preempt();
+}
+
+extern "x86-interrupt" fn lapic_timer_interrupt_handler(
+    stack_frame: InterruptStackFrame)
 {
+    let _gs = KernelGs::enter(&stack_frame);
+    let irq = percpu::irq_enter(InterruptIndex::LapicTimer.as_u8());
+    timer_tick(InterruptIndex::LapicTimer.as_u8(), &stack_frame);
+    apic::eoi();
+    drop(irq);
+    thread::preempt();
+}
+
+/// draws the spinner in the top left corner after timer interrupts
+static SPINNER: Softirq = Softirq::new("spinner", Priority::Low, draw_spinner);
+
+fn timer_tick(vector: u8, stack_frame: &InterruptStackFrame) {
+    // only the clock event device samples, so a CPU that also gets ticks
+    // from the other timer does not count twice
+    if crate::task::timer::clock_event().vector() == vector {
+        profile::sample(stack_frame);
+    }
+    if let Some(late) = crate::task::timer::interrupt() {
+        irqstat::record_latency(vector, late);
+    }
+    SPINNER.raise();
+}
+
+fn draw_spinner() {
     use crate::{task::timer, vga::text};
-    use core::sync::atomic::{AtomicU64, Ordering};
 
-    static TIMER: AtomicU64 = AtomicU64::new(0);
-    let timer = TIMER.fetch_add(1, Ordering::Relaxed);
-    timer::set_timer(timer);
-    let spinner = match timer % 4 {
+    let spinner = match timer::ticks() % 4 {
         0 => "/",
         1 => "-",
         2 => "\\",
         _ => "|",
     };
     text::display(spinner, (1, 1), Default::default());
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = KernelGs::enter(&stack_frame);
+    let _irq = percpu::irq_enter(InterruptIndex::Rtc.as_u8());
+    crate::rtc::interrupt();
 
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+            .notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
     }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(
+extern "x86-interrupt" fn apic_error_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = KernelGs::enter(&stack_frame);
+    let _irq = percpu::irq_enter(InterruptIndex::ApicError.as_u8());
+    // the error status register must be written before it is read
+    apic::write(apic::ERROR_STATUS, 0);
+    let status = apic::read(apic::ERROR_STATUS);
+    println!("APIC ERROR: {:#x}", status);
+    apic::eoi();
+}
+
+extern "x86-interrupt" fn wakeup_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = KernelGs::enter(&stack_frame);
+    let irq = percpu::irq_enter(InterruptIndex::Wakeup.as_u8());
+    percpu!(stats.wakeups).fetch_add(1, Ordering::Relaxed);
+    apic::eoi();
+    drop(irq);
+    thread::preempt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(
     stack_frame: InterruptStackFrame)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    let _gs = KernelGs::enter(&stack_frame);
+    irqstat::count(SPURIOUS_VECTOR);
+}
+
+// nothing uses IRQ 7 and IRQ 15, but the PICs raise them for interrupts
+// that went away before they were acknowledged
+extern "x86-interrupt" fn irq7_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = KernelGs::enter(&stack_frame);
+    if pic_spurious(7) {
+        return;
+    }
+    let _irq = percpu::irq_enter(InterruptIndex::Irq7.as_u8());
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Irq7.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn irq15_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = KernelGs::enter(&stack_frame);
+    if pic_spurious(15) {
+        return;
+    }
+    let _irq = percpu::irq_enter(InterruptIndex::Irq15.as_u8());
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Irq15.as_u8());
+    }
+}
+
+/// Check the in-service register of the PIC that raised IRQ 7 or IRQ 15
+///
+/// A spurious interrupt is counted and gets no end of interrupt, except
+/// at the master for IRQ 15: it did see a real IRQ 2 from the slave.
+fn pic_spurious(irq: u8) -> bool {
+    use x86_64::instructions::port::Port;
+
+    const READ_ISR: u8 = 0x0b;
+    const READ_IRR: u8 = 0x0a;
+    const EOI: u8 = 0x20;
+
+    let _pics = PICS.lock();
+    let mut command: Port<u8> = Port::new(if irq < 8 { 0x20 } else { 0xa0 });
+    let in_service = unsafe {
+        command.write(READ_ISR);
+        let isr = command.read();
+        command.write(READ_IRR); // the power-on default
+        isr & 1 << 7 != 0
+    };
+    if in_service {
+        return false;
+    }
+    if irq >= 8 {
+        let mut master: Port<u8> = Port::new(0x20);
+        unsafe { master.write(EOI) };
+    }
+    irqstat::spurious(irq);
+    true
+}
+
+extern "x86-interrupt" fn divide_error_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let gs = KernelGs::enter(&stack_frame);
+    irqstat::count(0);
+    if user::from_user(&stack_frame) {
+        user::kill(gs, 0, &stack_frame);
+    }
+    monitor::enter(&TrapFrame::from_exception(&stack_frame, 0), Reason::Fault("DIVIDE ERROR"));
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+// an NMI may arrive anywhere, even right after `syscall` before the GS
+// base is swapped, so it checks the GS base itself and takes no lock
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let rbp = backtrace::interrupted_rbp().unwrap_or(0);
+    let _gs = KernelGs::enter_paranoid();
+    irqstat::count_nmi(2);
+    watchdog::nmi(&stack_frame, rbp);
+}
+
+/// Registers of the code interrupted by a debug or breakpoint exception,
+/// saved by `trap.s`
+///
+/// Handlers may change them; the interrupted code continues with the
+/// changed values.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// The registers an exception handler knows: those the CPU pushed and
+    /// `rbp`; the others are 0
+    ///
+    /// Must be called by the handler itself (see `Backtrace::from_exception`).
+    #[inline(always)]
+    pub fn from_exception(stack_frame: &InterruptStackFrame, vector: u64) -> Self {
+        TrapFrame {
+            rbp: backtrace::interrupted_rbp().unwrap_or(0),
+            vector,
+            rip: stack_frame.instruction_pointer.as_u64(),
+            cs: stack_frame.code_segment,
+            rflags: stack_frame.cpu_flags,
+            rsp: stack_frame.stack_pointer.as_u64(),
+            ss: stack_frame.stack_segment,
+            ..TrapFrame::default()
+        }
+    }
+
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+/// Called by `trap.s` with the GS base of the kernel
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    irqstat::count(frame.vector as u8);
+    match frame.vector {
+        1 => debug_handler(frame),
+        3 => breakpoint_handler(frame),
+        vector => panic!("no trap handler for vector {}", vector),
+    }
+}
+
+fn debug_handler(frame: &mut TrapFrame) {
+    if !frame.from_user() && crate::gdb::handle_trap(frame) {
+        return;
+    }
+    // nothing else sets the trap flag or debug registers
+    frame.rflags &= !RFlags::TRAP_FLAG.bits();
+    println!("EXCEPTION: DEBUG\n{:#x?}", frame);
+}
+
+fn breakpoint_handler(frame: &mut TrapFrame) {
+    if !frame.from_user() && crate::gdb::handle_trap(frame) {
+        return;
+    }
+    if !frame.from_user() && monitor::handle_trap(frame) {
+        return;
+    }
+    println!("EXCEPTION: BREAKPOINT\n{:#x?}", frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    double_fault(&stack_frame, "DOUBLE FAULT")
+}
+
+/// Report a fault the kernel cannot recover from
+fn double_fault(stack_frame: &InterruptStackFrame, what: &'static str) -> ! {
+    let _gs = KernelGs::enter_paranoid();
+    irqstat::count_nmi(8);
+    Backtrace::from_exception(stack_frame).print();
+    monitor::enter(&TrapFrame::from_exception(stack_frame, 8), Reason::Fault(what));
+    panic!("EXCEPTION: {}\n{:#?}", what, stack_frame);
+}
+
+// may interrupt code that holds the serial port, so it writes without
+// taking its lock (`RawSerial`)
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame) -> !
+{
+    let _gs = KernelGs::enter_paranoid();
+    irqstat::count_nmi(18);
+    let rip = Location(stack_frame.instruction_pointer.as_u64());
+    let _ = match percpu::find_current() {
+        Some(cpu) => writeln!(RawSerial, "EXCEPTION: MACHINE CHECK on CPU {} at {}",
+                              cpu.cpu_id, rip),
+        None => writeln!(RawSerial, "EXCEPTION: MACHINE CHECK at {}", rip),
+    };
+    monitor::enter(&TrapFrame::from_exception(&stack_frame, 18), Reason::Fault("MACHINE CHECK"));
+    let _ = writeln!(RawSerial, "{:#?}", stack_frame);
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let gs = KernelGs::enter(&stack_frame);
+    irqstat::count(6);
+    if user::from_user(&stack_frame) {
+        user::kill(gs, 6, &stack_frame);
+    }
+    monitor::enter(&TrapFrame::from_exception(&stack_frame, 6), Reason::Fault("INVALID OPCODE"));
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    let gs = KernelGs::enter(&stack_frame);
+    irqstat::count(13);
+    if user::from_user(&stack_frame) {
+        user::kill(gs, 13, &stack_frame);
+    }
+    let frame = TrapFrame::from_exception(&stack_frame, 13);
+    monitor::enter(&frame, Reason::Fault("GENERAL PROTECTION FAULT"));
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}",
+           error_code, stack_frame);
 }
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -108,10 +488,28 @@ extern "x86-interrupt" fn page_fault_handler(
 {
     use x86_64::registers::control::Cr2;
 
+    // the handler faulted itself, on its IST stack whose outer frames
+    // are now gone
+    if gdt::interrupted_own_stack(stack_frame.stack_pointer) {
+        double_fault(&stack_frame, "PAGE FAULT IN THE PAGE FAULT HANDLER");
+    }
+    let gs = KernelGs::enter(&stack_frame);
+    irqstat::count(14);
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        println!("Accessed Address: {:?}", Cr2::read());
+        user::kill(gs, 14, &stack_frame);
+    }
+    if let Some(stack) = gdt::overflowed_stack(Cr2::read()) {
+        println!("EXCEPTION: STACK OVERFLOW on the {}", stack);
+        serial_println!("EXCEPTION: STACK OVERFLOW on the {}", stack);
+    }
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
+    serial_println!("EXCEPTION: PAGE FAULT at {:?} ({:?})", Cr2::read(), error_code);
+    Backtrace::from_exception(&stack_frame).print();
+    monitor::enter(&TrapFrame::from_exception(&stack_frame, 14), Reason::Fault("PAGE FAULT"));
     hlt_loop();
 }
 
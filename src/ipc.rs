@@ -0,0 +1,366 @@
+//! Message passing between processes (and kernel threads and tasks)
+//!
+//! A channel is a pair of endpoints; a message sent on one endpoint is
+//! received on the other. Every endpoint has a bounded queue of incoming
+//! messages, so senders block (or get `Full`) when the receiver falls
+//! behind. A message carries bytes and capabilities, which is how handles
+//! move from one process to another.
+//!
+//! `call` sends a message with a fresh reply endpoint attached and waits
+//! for the answer, which the receiver sends with `Message::reply`.
+//!
+//! Threads use the blocking methods, executor tasks `recv_async` and
+//! `send_async`. Only one task at a time should wait on each endpoint for
+//! receiving (and one for sending), since there is one waker slot each.
+//!
+//! Processes reach endpoints through handles: SEND and RECEIVE rights are
+//! checked by the syscalls, and handles attached to a message need the
+//! TRANSFER right.
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use crate::process::Capability;
+use crate::thread::WaitQueue;
+use futures_util::task::AtomicWaker;
+
+/// messages an endpoint queues before senders have to wait
+pub const QUEUE_CAPACITY: usize = 16;
+/// maximum bytes in a message
+pub const MAX_DATA: usize = 4096;
+/// maximum capabilities in a message
+pub const MAX_HANDLES: usize = 16;
+
+/// Bytes and capabilities sent over a channel
+pub struct Message {
+    pub data: Vec<u8>,
+    pub handles: Vec<Capability>,
+    /// where the answer to a `call` goes
+    reply: Option<Endpoint>,
+}
+
+impl Message {
+    pub fn new(data: Vec<u8>) -> Self {
+        Message { data, handles: Vec::new(), reply: None }
+    }
+
+    pub fn with_handles(data: Vec<u8>, handles: Vec<Capability>) -> Self {
+        Message { data, handles, reply: None }
+    }
+
+    /// The message came from `call` and still waits for an answer
+    pub fn expects_reply(&self) -> bool {
+        self.reply.is_some()
+    }
+
+    /// Take the endpoint the answer to a `call` goes to
+    pub fn take_reply(&mut self) -> Option<Endpoint> {
+        self.reply.take()
+    }
+
+    /// Answer a message that came from `call`
+    pub fn reply(&mut self, answer: Message) -> Result<(), SendError> {
+        match self.reply.take() {
+            Some(endpoint) => endpoint.try_send(answer).map_err(|error| match error {
+                TrySendError::Full(message) | TrySendError::Closed(message)
+                    | TrySendError::TooLarge(message) => SendError(message),
+            }),
+            None => Err(SendError(answer)),
+        }
+    }
+
+    fn too_large(&self) -> bool {
+        self.data.len() > MAX_DATA || self.handles.len() > MAX_HANDLES
+    }
+}
+
+/// The receiving endpoint is gone (or the message is too large); the
+/// message comes back
+pub struct SendError(pub Message);
+
+pub enum TrySendError {
+    Full(Message),
+    Closed(Message),
+    /// more than `MAX_DATA` bytes or `MAX_HANDLES` capabilities
+    TooLarge(Message),
+}
+
+/// The sending endpoint is gone and nothing is left in the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    /// the next message does not fit the limits (see `try_recv_limited`)
+    TooLarge { data: usize, handles: usize },
+}
+
+/// One direction of a channel: the queue of an endpoint
+struct Side {
+    queue: spin::Mutex<VecDeque<Message>>,
+    /// the endpoint reading this queue exists
+    open: AtomicBool,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    reader_waker: AtomicWaker,
+    writer_waker: AtomicWaker,
+}
+
+impl Side {
+    fn new() -> Self {
+        Side {
+            queue: spin::Mutex::new(VecDeque::with_capacity(QUEUE_CAPACITY)),
+            open: AtomicBool::new(true),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            reader_waker: AtomicWaker::new(),
+            writer_waker: AtomicWaker::new(),
+        }
+    }
+
+    fn wake_readers(&self) {
+        self.readers.notify_all();
+        self.reader_waker.wake();
+    }
+
+    fn wake_writers(&self) {
+        self.writers.notify_all();
+        self.writer_waker.wake();
+    }
+}
+
+struct Channel {
+    sides: [Side; 2],
+}
+
+/// One end of a channel
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+/// Create a channel and return its two endpoints
+pub fn channel() -> (Endpoint, Endpoint) {
+    let channel = Arc::new(Channel { sides: [Side::new(), Side::new()] });
+    (Endpoint { channel: channel.clone(), side: 0 }, Endpoint { channel, side: 1 })
+}
+
+impl Endpoint {
+    /// queue of the messages for this endpoint
+    fn incoming(&self) -> &Side {
+        &self.channel.sides[self.side]
+    }
+
+    /// queue of the peer
+    fn outgoing(&self) -> &Side {
+        &self.channel.sides[1 - self.side]
+    }
+
+    /// The other endpoint still exists
+    pub fn is_peer_open(&self) -> bool {
+        self.outgoing().open.load(Ordering::Acquire)
+    }
+
+    /// Send without waiting
+    pub fn try_send(&self, message: Message) -> Result<(), TrySendError> {
+        if message.too_large() {
+            return Err(TrySendError::TooLarge(message));
+        }
+        let side = self.outgoing();
+        {
+            let mut queue = side.queue.lock();
+            if !side.open.load(Ordering::Acquire) {
+                return Err(TrySendError::Closed(message));
+            }
+            if queue.len() >= QUEUE_CAPACITY {
+                return Err(TrySendError::Full(message));
+            }
+            queue.push_back(message);
+        }
+        side.wake_readers();
+        Ok(())
+    }
+
+    /// Send, waiting for room in the queue of the peer
+    pub fn send(&self, message: Message) -> Result<(), SendError> {
+        let mut message = message;
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(returned)) => message = returned,
+                Err(TrySendError::Closed(message)) | Err(TrySendError::TooLarge(message)) => {
+                    return Err(SendError(message));
+                }
+            }
+            let side = self.outgoing();
+            side.writers.wait_until(|| {
+                side.queue.lock().len() < QUEUE_CAPACITY || !side.open.load(Ordering::Acquire)
+            });
+        }
+    }
+
+    /// Receive without waiting
+    pub fn try_recv(&self) -> Result<Message, TryRecvError> {
+        self.try_recv_limited(usize::MAX, usize::MAX)
+    }
+
+    /// Receive without waiting if the next message has at most `max_data`
+    /// bytes and `max_handles` capabilities; a larger one stays queued
+    pub fn try_recv_limited(&self, max_data: usize, max_handles: usize)
+        -> Result<Message, TryRecvError>
+    {
+        let side = self.incoming();
+        let message = {
+            let mut queue = side.queue.lock();
+            match queue.front() {
+                Some(next) if next.data.len() > max_data || next.handles.len() > max_handles => {
+                    return Err(TryRecvError::TooLarge {
+                        data: next.data.len(),
+                        handles: next.handles.len(),
+                    });
+                }
+                Some(_) => queue.pop_front().unwrap(),
+                None if self.is_peer_open() => return Err(TryRecvError::Empty),
+                None => return Err(TryRecvError::Closed),
+            }
+        };
+        side.wake_writers();
+        Ok(message)
+    }
+
+    /// Receive, waiting for a message
+    pub fn recv(&self) -> Result<Message, RecvError> {
+        match self.recv_limited(usize::MAX, usize::MAX) {
+            Ok(message) => Ok(message),
+            Err(_) => Err(RecvError),
+        }
+    }
+
+    /// Like `recv`, with the limits of `try_recv_limited`; never returns
+    /// `Empty`
+    pub fn recv_limited(&self, max_data: usize, max_handles: usize)
+        -> Result<Message, TryRecvError>
+    {
+        loop {
+            match self.try_recv_limited(max_data, max_handles) {
+                Err(TryRecvError::Empty) => {}
+                result => return result,
+            }
+            let side = self.incoming();
+            side.readers.wait_until(|| {
+                !side.queue.lock().is_empty() || !self.is_peer_open()
+            });
+        }
+    }
+
+    /// Send `message` with a reply endpoint attached and wait for the
+    /// answer
+    pub fn call(&self, message: Message) -> Result<Message, RecvError> {
+        let (mine, theirs) = channel();
+        let mut message = message;
+        message.reply = Some(theirs);
+        self.send(message).map_err(|_| RecvError)?;
+        mine.recv()
+    }
+
+    /// Receive as a future (for executor tasks)
+    pub fn recv_async(&self) -> RecvFuture<'_> {
+        RecvFuture { endpoint: self }
+    }
+
+    /// Send as a future that waits for room in the queue of the peer
+    pub fn send_async(&self, message: Message) -> SendFuture<'_> {
+        SendFuture { endpoint: self, message: Some(message) }
+    }
+
+    /// `call` as a future
+    pub async fn call_async(&self, message: Message) -> Result<Message, RecvError> {
+        let (mine, theirs) = channel();
+        let mut message = message;
+        message.reply = Some(theirs);
+        self.send_async(message).await.map_err(|_| RecvError)?;
+        mine.recv_async().await
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let incoming = self.incoming();
+        incoming.open.store(false, Ordering::Release);
+        // queued messages (and the capabilities in them) go away with us
+        let messages = core::mem::take(&mut *incoming.queue.lock());
+        drop(messages);
+        incoming.wake_writers();
+        self.outgoing().wake_readers();
+    }
+}
+
+/// Future of `Endpoint::recv_async`
+pub struct RecvFuture<'a> {
+    endpoint: &'a Endpoint,
+}
+
+impl Future for RecvFuture<'_> {
+    type Output = Result<Message, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let endpoint = self.endpoint;
+        // fast path
+        match endpoint.try_recv() {
+            Ok(message) => return Poll::Ready(Ok(message)),
+            Err(TryRecvError::Empty) => {}
+            Err(_) => return Poll::Ready(Err(RecvError)),
+        }
+
+        endpoint.incoming().reader_waker.register(cx.waker());
+        match endpoint.try_recv() {
+            Ok(message) => {
+                endpoint.incoming().reader_waker.take();
+                Poll::Ready(Ok(message))
+            }
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(_) => Poll::Ready(Err(RecvError)),
+        }
+    }
+}
+
+/// Future of `Endpoint::send_async`
+pub struct SendFuture<'a> {
+    endpoint: &'a Endpoint,
+    message: Option<Message>,
+}
+
+impl Future for SendFuture<'_> {
+    type Output = Result<(), SendError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let endpoint = self.endpoint;
+        let message = self.message.take().expect("SendFuture polled after completion");
+        let message = match endpoint.try_send(message) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TrySendError::Full(message)) => message,
+            Err(TrySendError::Closed(message)) | Err(TrySendError::TooLarge(message)) => {
+                return Poll::Ready(Err(SendError(message)));
+            }
+        };
+
+        endpoint.outgoing().writer_waker.register(cx.waker());
+        match endpoint.try_send(message) {
+            Ok(()) => {
+                endpoint.outgoing().writer_waker.take();
+                Poll::Ready(Ok(()))
+            }
+            Err(TrySendError::Full(message)) => {
+                self.message = Some(message);
+                Poll::Pending
+            }
+            Err(TrySendError::Closed(message)) | Err(TrySendError::TooLarge(message)) => {
+                Poll::Ready(Err(SendError(message)))
+            }
+        }
+    }
+}
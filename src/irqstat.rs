@@ -0,0 +1,269 @@
+//! Interrupt and exception statistics
+//!
+//! Every vector counts how often it arrived on each CPU. Hardware
+//! interrupts also keep two histograms, measured with the TSC: how long
+//! their handler ran (from `percpu::irq_enter` until its scope ends, not
+//! counting softirqs) and, where the due time is known (the timers), how
+//! late the interrupt came. The PICs raise IRQ 7 and IRQ 15 for interrupts
+//! that went away before they were acknowledged; those are counted apart.
+//!
+//! `Table` formats it all like `/proc/interrupts`; `dump` prints it on
+//! the serial port and the monitor shows it with `interrupts`.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::interrupts::{InterruptIndex, SPURIOUS_VECTOR};
+use crate::{percpu, serial_print, smp::MAX_CPUS, time};
+
+pub const VECTORS: usize = 256;
+/// histogram buckets: < 1 us, < 2 us, < 4 us, ... and the rest
+pub const BUCKETS: usize = 10;
+/// first vector of hardware interrupts, the ones with histograms
+const FIRST_IRQ: usize = 32;
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+const NO_COUNTS: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+const NO_SAMPLES: Histogram = Histogram::new();
+
+/// per vector and CPU
+static COUNTS: [[AtomicU64; MAX_CPUS]; VECTORS] = [NO_COUNTS; VECTORS];
+static LATENCY: [Histogram; VECTORS - FIRST_IRQ] = [NO_SAMPLES; VECTORS - FIRST_IRQ];
+static DURATION: [Histogram; VECTORS - FIRST_IRQ] = [NO_SAMPLES; VECTORS - FIRST_IRQ];
+/// spurious IRQ 7 and IRQ 15
+static SPURIOUS: [AtomicU64; 2] = [ZERO; 2];
+
+const BUCKET_LABELS: [&str; BUCKETS] = [
+    "<1", "<2", "<4", "<8", "<16", "<32", "<64", "<128", "<256", "more",
+];
+
+const EXCEPTIONS: [&str; 32] = [
+    "divide error", "debug", "NMI", "breakpoint", "overflow", "bound range",
+    "invalid opcode", "device not available", "double fault", "", "invalid TSS",
+    "segment not present", "stack segment fault", "general protection", "page fault", "",
+    "x87 floating point", "alignment check", "machine check", "SIMD floating point",
+    "virtualization", "control protection", "", "", "", "", "", "", "hypervisor injection",
+    "VMM communication", "security", "",
+];
+
+/// Nanosecond samples of one vector
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram { buckets: [ZERO; BUCKETS], total_ns: ZERO, max_ns: ZERO }
+    }
+
+    fn record(&self, nanos: u64) {
+        self.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(nanos, Ordering::Relaxed);
+        self.max_ns.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn samples(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+}
+
+/// Bucket of a sample: 0 below 1 us, N below 2^N us, the last one above
+fn bucket(nanos: u64) -> usize {
+    let micros = nanos / 1000;
+    let bits = (u64::BITS - micros.leading_zeros()) as usize;
+    bits.min(BUCKETS - 1)
+}
+
+/// Count an exception or interrupt on the calling CPU
+///
+/// Needs the GS base of the kernel (see `user::KernelGs`).
+pub(crate) fn count(vector: u8) {
+    count_on(percpu::current().cpu_id, vector);
+}
+
+/// `count` for handlers that may run with the GS base of user mode (NMI,
+/// double fault); finds the CPU by APIC id
+pub(crate) fn count_nmi(vector: u8) {
+    if let Some(cpu) = percpu::find_current() {
+        count_on(cpu.cpu_id, vector);
+    }
+}
+
+fn count_on(cpu: usize, vector: u8) {
+    COUNTS[vector as usize][cpu].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record that interrupt `vector` came `nanos` after it was due
+pub(crate) fn record_latency(vector: u8, nanos: u64) {
+    if let Some(histogram) = LATENCY.get((vector as usize).wrapping_sub(FIRST_IRQ)) {
+        histogram.record(nanos);
+    }
+}
+
+/// Record that the handler of `vector` ran from TSC `start` until now
+pub(crate) fn record_duration(vector: u8, start: u64) {
+    let hz = match time::measured_tsc_frequency() {
+        Some(hz) => hz,
+        None => return,
+    };
+    let cycles = time::rdtsc().saturating_sub(start);
+    let nanos = (cycles as u128 * 1_000_000_000 / hz as u128) as u64;
+    if let Some(histogram) = DURATION.get((vector as usize).wrapping_sub(FIRST_IRQ)) {
+        histogram.record(nanos);
+    }
+}
+
+/// Count a spurious IRQ 7 or IRQ 15 of the PICs
+pub(crate) fn spurious(irq: u8) {
+    SPURIOUS[usize::from(irq == 15)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Times `vector` arrived on `cpu`
+pub fn count_of(vector: u8, cpu: usize) -> u64 {
+    COUNTS[vector as usize].get(cpu).map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Times `vector` arrived on any CPU
+pub fn total(vector: u8) -> u64 {
+    (0..MAX_CPUS).map(|cpu| count_of(vector, cpu)).sum()
+}
+
+/// Handler times recorded for hardware interrupt `vector`
+pub fn duration_samples(vector: u8) -> u64 {
+    DURATION.get((vector as usize).wrapping_sub(FIRST_IRQ)).map_or(0, Histogram::samples)
+}
+
+/// Spurious interrupts raised by the PICs as IRQ 7 and IRQ 15
+pub fn spurious_count() -> (u64, u64) {
+    (SPURIOUS[0].load(Ordering::Relaxed), SPURIOUS[1].load(Ordering::Relaxed))
+}
+
+/// What `vector` is used for, empty if unknown
+pub fn name(vector: u8) -> &'static str {
+    const TIMER: u8 = InterruptIndex::Timer as u8;
+    const KEYBOARD: u8 = InterruptIndex::Keyboard as u8;
+    const IRQ7: u8 = InterruptIndex::Irq7 as u8;
+    const RTC: u8 = InterruptIndex::Rtc as u8;
+    const IRQ15: u8 = InterruptIndex::Irq15 as u8;
+    const LAPIC_TIMER: u8 = InterruptIndex::LapicTimer as u8;
+    const APIC_ERROR: u8 = InterruptIndex::ApicError as u8;
+    const WAKEUP: u8 = InterruptIndex::Wakeup as u8;
+
+    match vector {
+        0..=31 => EXCEPTIONS[vector as usize],
+        TIMER => "IRQ 0 timer",
+        KEYBOARD => "IRQ 1 keyboard",
+        IRQ7 => "IRQ 7",
+        RTC => "IRQ 8 RTC",
+        IRQ15 => "IRQ 15",
+        LAPIC_TIMER => "local APIC timer",
+        APIC_ERROR => "APIC error",
+        WAKEUP => "wakeup IPI",
+        SPURIOUS_VECTOR => "APIC spurious",
+        _ => "",
+    }
+}
+
+/// The statistics as a table like `/proc/interrupts`
+///
+/// Only reads counters, so it can be printed from anywhere.
+pub struct Table;
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cpus = percpu::all().count().max(1);
+        write!(f, "VEC")?;
+        for cpu in 0..cpus {
+            // right-aligned over the counts like "CPU12" would be
+            let digits = if cpu < 10 { 1 } else { 2 };
+            write!(f, " {:>width$}{}", "CPU", cpu, width = 10 - digits)?;
+        }
+        writeln!(f)?;
+        for vector in 0..VECTORS {
+            let vector = vector as u8;
+            if total(vector) == 0 {
+                continue;
+            }
+            write!(f, "{:>3}", vector)?;
+            for cpu in 0..cpus {
+                write!(f, " {:>10}", count_of(vector, cpu))?;
+            }
+            writeln!(f, "  {}", name(vector))?;
+        }
+        let (irq7, irq15) = spurious_count();
+        writeln!(f, "SPU {:>10}  PIC spurious IRQ 7", irq7)?;
+        writeln!(f, "SPU {:>10}  PIC spurious IRQ 15", irq15)?;
+
+        write_histograms(f, "latency", &LATENCY)?;
+        write_histograms(f, "handler time", &DURATION)
+    }
+}
+
+fn write_histograms(f: &mut fmt::Formatter, what: &str, histograms: &[Histogram]) -> fmt::Result {
+    if histograms.iter().all(|histogram| histogram.samples() == 0) {
+        return Ok(());
+    }
+    write!(f, "{} (us)\nVEC", what)?;
+    for label in BUCKET_LABELS {
+        write!(f, " {:>5}", label)?;
+    }
+    writeln!(f, "   avg   max")?;
+    for (index, histogram) in histograms.iter().enumerate() {
+        if histogram.samples() == 0 {
+            continue;
+        }
+        write!(f, "{:>3}", index + FIRST_IRQ)?;
+        for bucket in &histogram.buckets {
+            write!(f, " {:>5}", bucket.load(Ordering::Relaxed))?;
+        }
+        let avg_ns = histogram.total_ns.load(Ordering::Relaxed) / histogram.samples();
+        writeln!(f, " {:>5} {:>5}", avg_ns / 1000,
+                 histogram.max_ns.load(Ordering::Relaxed) / 1000)?;
+    }
+    Ok(())
+}
+
+/// Print the table on the serial port
+pub fn dump() {
+    serial_print!("{}", Table);
+}
+
+#[test_case]
+fn test_buckets() {
+    assert_eq!(bucket(0), 0);
+    assert_eq!(bucket(999), 0);
+    assert_eq!(bucket(1_000), 1);
+    assert_eq!(bucket(3_999), 2);
+    assert_eq!(bucket(4_000), 3);
+    assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+}
+
+#[test_case]
+fn test_breakpoints_are_counted() {
+    let before = total(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(total(3), before + 1);
+}
+
+#[test_case]
+fn test_timer_interrupts_are_measured() {
+    let timer = InterruptIndex::Timer.as_u8();
+    let (count, samples) = (total(timer), duration_samples(timer));
+    while total(timer) < count + 2 {
+        x86_64::instructions::hlt();
+    }
+    assert!(duration_samples(timer) >= samples + 2, "no handler times for the timer");
+}
+
+#[test_case]
+fn test_spurious_pic_interrupts_are_detected() {
+    let (irq7, irq15) = spurious_count();
+    let counts = (total(InterruptIndex::Irq7.as_u8()), total(InterruptIndex::Irq15.as_u8()));
+    // raised by software, so neither PIC has them in service
+    unsafe { core::arch::asm!("int 39", "int 47") };
+    assert_eq!(spurious_count(), (irq7 + 1, irq15 + 1));
+    // and they do not count as interrupts
+    assert_eq!(counts,
+               (total(InterruptIndex::Irq7.as_u8()), total(InterruptIndex::Irq15.as_u8())));
+}
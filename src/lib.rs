@@ -9,13 +9,36 @@
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod cpu;
+pub mod fpu;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
+pub mod ipc;
+pub mod irqstat;
+pub mod lockdep;
 pub mod memory;
+pub mod monitor;
+pub mod percpu;
+pub mod process;
+pub mod profile;
+pub mod rtc;
 pub mod serial;
+pub mod smp;
+pub mod softirq;
+pub mod symbols;
+pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod user;
 pub mod vga;
+pub mod watchdog;
 
 #[cfg(test)]
 use bootloader::{BootInfo, entry_point};
@@ -26,17 +49,32 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     init();
+    // some of the tests below allocate
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
     test_main();
     // unreachable code: since exit_qemu already called by test runner
     hlt_loop(); // just in case test runner is broken
 }
 
 pub fn init() {
+    cpu::init();
     gdt::init();
+    syscall::init();
+    fpu::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -72,6 +110,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     print_test_failed();
     serial_println!("\x1b[31;1mError\x1b[0m: {}", info);
+    serial_println!("{}", backtrace::Backtrace::capture());
     serial_println!();
     exit_qemu(QemuExitCode::Failed);
 }
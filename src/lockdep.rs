@@ -0,0 +1,496 @@
+//! Lock dependency validator (with the `lockdep` feature)
+//!
+//! Every lock of `sync` has a `Class`. The first time a lock is taken its
+//! class gets a number, and from then on each thread keeps a stack of the
+//! classes it holds, and each CPU one for its interrupt handlers (and one
+//! for its boot context until `thread::init`). The scheduler hands the
+//! stack of the next thread to the CPU on every switch (`switch_to`).
+//! Taking B while holding A records the edge A -> B of
+//! the lock order graph together with both call sites. Two kinds of
+//! mistakes get reported on the serial port, each once per pair:
+//!
+//! - an edge that closes a cycle, a deadlock waiting for the wrong timing:
+//!   the acquisition that closed it and the one recorded the other way;
+//! - a lock taken in an interrupt handler that was also held with
+//!   interrupts enabled outside of one: the handler can interrupt the
+//!   holder on the same CPU and spin forever.
+//!
+//! Reports go through `RawSerial`, so lockdep takes no locks itself.
+//! Without the feature, `Class` only holds a name and the hooks compile
+//! to nothing.
+
+use core::panic::Location;
+#[cfg(feature = "lockdep")]
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+#[cfg(feature = "lockdep")]
+pub use validator::{classes, reports, MAX_CLASSES};
+
+/// The lock class of one lock, named for reports
+pub struct Class {
+    name: &'static str,
+    #[cfg(feature = "lockdep")]
+    id: core::sync::atomic::AtomicUsize,
+}
+
+impl Class {
+    pub const fn new(name: &'static str) -> Self {
+        Class {
+            name,
+            #[cfg(feature = "lockdep")]
+            id: core::sync::atomic::AtomicUsize::new(validator::UNASSIGNED),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Called after the lock was taken at `location`; `irqs_enabled`
+    /// tells whether interrupts stay enabled while it is held
+    #[allow(unused_variables)]
+    #[inline(always)]
+    pub(crate) fn acquired(&self, location: &'static Location<'static>, irqs_enabled: bool) {
+        #[cfg(feature = "lockdep")]
+        validator::acquired(self, location, irqs_enabled);
+    }
+
+    /// Called before the lock is released
+    #[inline(always)]
+    pub(crate) fn released(&self) {
+        #[cfg(feature = "lockdep")]
+        validator::released(self);
+    }
+}
+
+/// Locks held by one context: a thread, or the interrupt handlers of a CPU
+pub(crate) struct HeldLocks {
+    #[cfg(feature = "lockdep")]
+    stack: validator::Held,
+}
+
+impl HeldLocks {
+    pub(crate) const fn new() -> Self {
+        HeldLocks {
+            #[cfg(feature = "lockdep")]
+            stack: validator::Held::new(),
+        }
+    }
+}
+
+/// Lockdep state of one CPU (part of its `percpu::PerCpu` block)
+pub(crate) struct CpuLockdep {
+    /// held by the interrupt handlers running on the CPU
+    #[cfg(feature = "lockdep")]
+    irq: HeldLocks,
+    /// held by the boot context before it became a thread
+    #[cfg(feature = "lockdep")]
+    boot: HeldLocks,
+    /// those of the current thread, null before `thread::init`
+    #[cfg(feature = "lockdep")]
+    thread: AtomicPtr<HeldLocks>,
+}
+
+impl CpuLockdep {
+    pub(crate) const fn new() -> Self {
+        CpuLockdep {
+            #[cfg(feature = "lockdep")]
+            irq: HeldLocks::new(),
+            #[cfg(feature = "lockdep")]
+            boot: HeldLocks::new(),
+            #[cfg(feature = "lockdep")]
+            thread: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+}
+
+/// Called by the scheduler (with interrupts disabled) before the calling
+/// CPU runs the thread whose locks are `held`
+#[allow(unused_variables)]
+#[inline(always)]
+pub(crate) fn switch_to(held: &HeldLocks) {
+    #[cfg(feature = "lockdep")]
+    crate::percpu::current().lockdep.thread
+        .store(held as *const HeldLocks as *mut HeldLocks, Ordering::Relaxed);
+}
+
+#[cfg(feature = "lockdep")]
+mod validator {
+    use super::Class;
+    use core::fmt::Write;
+    use core::panic::Location;
+    use core::ptr;
+    use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+    use crate::{percpu, serial::RawSerial};
+    use x86_64::instructions::interrupts;
+
+    /// classes lockdep keeps track of; later ones are ignored
+    pub const MAX_CLASSES: usize = 64;
+    /// locks one context can hold at a time
+    const MAX_HELD: usize = 16;
+
+    pub(super) const UNASSIGNED: usize = usize::MAX;
+    /// the class table was full
+    const UNTRACKED: usize = usize::MAX - 1;
+
+    type Site = AtomicPtr<Location<'static>>;
+
+    const NO_SITE: Site = AtomicPtr::new(ptr::null_mut());
+    const NO_SITES: [Site; MAX_CLASSES] = [NO_SITE; MAX_CLASSES];
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    const EMPTY: AtomicUsize = AtomicUsize::new(0);
+    const NO_NAME: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    /// names of the classes, as pointer and length of a `&'static str`
+    static NAMES: [AtomicPtr<u8>; MAX_CLASSES] = [NO_NAME; MAX_CLASSES];
+    static NAME_LENS: [AtomicUsize; MAX_CLASSES] = [EMPTY; MAX_CLASSES];
+
+    /// bit B of entry A: B was taken while A was held
+    static EDGES: [AtomicU64; MAX_CLASSES] = [ZERO; MAX_CLASSES];
+    /// where A was taken and where B was taken for the first A -> B
+    static EDGE_HELD: [[Site; MAX_CLASSES]; MAX_CLASSES] = [NO_SITES; MAX_CLASSES];
+    static EDGE_TAKEN: [[Site; MAX_CLASSES]; MAX_CLASSES] = [NO_SITES; MAX_CLASSES];
+    /// bit B of entry A: the cycle through A -> B was reported
+    static REPORTED_CYCLES: [AtomicU64; MAX_CLASSES] = [ZERO; MAX_CLASSES];
+
+    /// first acquisition in an interrupt handler
+    static IRQ_SITE: [Site; MAX_CLASSES] = NO_SITES;
+    /// first acquisition outside of one that left interrupts enabled
+    static IRQS_ENABLED_SITE: [Site; MAX_CLASSES] = NO_SITES;
+    /// bit N: the interrupt usage of class N was reported
+    static REPORTED_IRQ: AtomicU64 = AtomicU64::new(0);
+
+    static REPORTS: AtomicU64 = AtomicU64::new(0);
+
+    /// The stack of a `HeldLocks`, innermost last
+    pub(super) struct Held {
+        depth: AtomicUsize,
+        classes: [AtomicUsize; MAX_HELD],
+        sites: [Site; MAX_HELD],
+    }
+
+    impl Held {
+        pub(super) const fn new() -> Self {
+            Held {
+                depth: AtomicUsize::new(0),
+                classes: [EMPTY; MAX_HELD],
+                sites: [NO_SITE; MAX_HELD],
+            }
+        }
+    }
+
+    /// Number of problems reported so far
+    pub fn reports() -> u64 {
+        REPORTS.load(Ordering::Relaxed)
+    }
+
+    /// Number of lock classes seen so far
+    pub fn classes() -> usize {
+        COUNT.load(Ordering::Relaxed).min(MAX_CLASSES)
+    }
+
+    pub(super) fn acquired(class: &Class, location: &'static Location<'static>, irqs_enabled: bool) {
+        interrupts::without_interrupts(|| {
+            let id = match class_id(class) {
+                Some(id) => id,
+                None => return,
+            };
+            let held = match held() {
+                Some(held) => held,
+                None => return,
+            };
+            let depth = held.depth.load(Ordering::Relaxed);
+            for index in 0..depth.min(MAX_HELD) {
+                let outer = held.classes[index].load(Ordering::Relaxed);
+                let outer_site = held.sites[index].load(Ordering::Relaxed);
+                add_edge(outer, outer_site, id, location);
+            }
+            check_irq_usage(id, location, irqs_enabled);
+
+            if depth < MAX_HELD {
+                held.classes[depth].store(id, Ordering::Relaxed);
+                held.sites[depth].store(site_ptr(location), Ordering::Relaxed);
+            }
+            held.depth.store(depth + 1, Ordering::Relaxed);
+        });
+    }
+
+    pub(super) fn released(class: &Class) {
+        interrupts::without_interrupts(|| {
+            let id = class.id.load(Ordering::Relaxed);
+            let held = match held() {
+                Some(held) => held,
+                None => return,
+            };
+            let depth = held.depth.load(Ordering::Relaxed);
+            if id >= MAX_CLASSES || depth == 0 {
+                return;
+            }
+            if depth > MAX_HELD {
+                held.depth.store(depth - 1, Ordering::Relaxed);
+                return;
+            }
+            // usually the innermost, but locks may be released in any order
+            let found = (0..depth).rev()
+                .find(|&index| held.classes[index].load(Ordering::Relaxed) == id);
+            if let Some(index) = found {
+                for next in index + 1..depth {
+                    let class = held.classes[next].load(Ordering::Relaxed);
+                    let site = held.sites[next].load(Ordering::Relaxed);
+                    held.classes[next - 1].store(class, Ordering::Relaxed);
+                    held.sites[next - 1].store(site, Ordering::Relaxed);
+                }
+                held.depth.store(depth - 1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// The number of `class`, assigned on first use
+    fn class_id(class: &Class) -> Option<usize> {
+        let id = class.id.load(Ordering::Acquire);
+        match id {
+            UNTRACKED => return None,
+            UNASSIGNED => {}
+            id => return Some(id),
+        }
+        let reserved = COUNT.fetch_add(1, Ordering::Relaxed);
+        let id = if reserved < MAX_CLASSES {
+            NAMES[reserved].store(class.name.as_ptr() as *mut u8, Ordering::Relaxed);
+            NAME_LENS[reserved].store(class.name.len(), Ordering::Relaxed);
+            reserved
+        } else {
+            UNTRACKED
+        };
+        // another CPU may be numbering the class too; its number counts
+        // and the one reserved here stays unused
+        let id = match class.id.compare_exchange(UNASSIGNED, id, Ordering::AcqRel,
+                                                 Ordering::Acquire) {
+            Ok(_) => id,
+            Err(winner) => winner,
+        };
+        if id == UNTRACKED {
+            if reserved == MAX_CLASSES {
+                let _ = writeln!(RawSerial, "lockdep: more than {} lock classes, {} and \
+                                             later ones are not checked", MAX_CLASSES, class.name);
+            }
+            return None;
+        }
+        Some(id)
+    }
+
+    fn name(id: usize) -> &'static str {
+        let ptr = NAMES[id].load(Ordering::Relaxed);
+        let len = NAME_LENS[id].load(Ordering::Relaxed);
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len)) }
+    }
+
+    /// The held stack of the calling context; `None` before the per-CPU
+    /// block of the CPU is set up
+    ///
+    /// Only valid with interrupts disabled: a thread's stack goes away
+    /// with the thread.
+    fn held() -> Option<&'static Held> {
+        let cpu = percpu::find_current()?;
+        let state = &cpu.lockdep;
+        let locks = if in_interrupt() {
+            &state.irq
+        } else {
+            match unsafe { state.thread.load(Ordering::Relaxed).as_ref() } {
+                Some(thread) => thread,
+                None => &state.boot,
+            }
+        };
+        Some(&locks.stack)
+    }
+
+    fn in_interrupt() -> bool {
+        #[cfg(test)]
+        if AS_INTERRUPT.load(Ordering::Relaxed) {
+            return true;
+        }
+        // the per-CPU blocks exist once `gdt::init` ran; nothing before
+        // that runs in an interrupt handler
+        percpu::find_current().map_or(false, |cpu| cpu.in_interrupt())
+    }
+
+    /// a test runs code as if in an interrupt handler
+    #[cfg(test)]
+    static AS_INTERRUPT: core::sync::atomic::AtomicBool =
+        core::sync::atomic::AtomicBool::new(false);
+
+    /// Run `f` as if in an interrupt handler, for lockdep only: nothing
+    /// is counted and no softirqs run
+    #[cfg(test)]
+    pub(super) fn as_interrupt(f: impl FnOnce()) {
+        interrupts::without_interrupts(|| {
+            AS_INTERRUPT.store(true, Ordering::Relaxed);
+            f();
+            AS_INTERRUPT.store(false, Ordering::Relaxed);
+        });
+    }
+
+    fn site_ptr(location: &'static Location<'static>) -> *mut Location<'static> {
+        location as *const _ as *mut _
+    }
+
+    fn site(site: &Site) -> Option<&'static Location<'static>> {
+        unsafe { site.load(Ordering::Relaxed).as_ref() }
+    }
+
+    /// Record `from` -> `to` and report it if it closes a cycle
+    fn add_edge(
+        from: usize,
+        from_site: *mut Location<'static>,
+        to: usize,
+        to_site: &'static Location<'static>,
+    ) {
+        let bit = 1 << to;
+        if from == to || EDGES[from].load(Ordering::Relaxed) & bit != 0 {
+            return; // recursion is `sync`'s business; known edges are fine
+        }
+        let mut path = [0; MAX_CLASSES];
+        if let Some(len) = find_path(to, from, &mut path) {
+            if REPORTED_CYCLES[from].fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+                report_cycle(from, from_site, to, to_site, &path[..len]);
+            }
+            return; // keep the graph acyclic so the search terminates
+        }
+        EDGE_HELD[from][to].store(from_site, Ordering::Relaxed);
+        EDGE_TAKEN[from][to].store(site_ptr(to_site), Ordering::Relaxed);
+        EDGES[from].fetch_or(bit, Ordering::Relaxed);
+    }
+
+    /// Breadth-first search from `start` to `goal`; fills `path` with the
+    /// classes on the way (both ends included) and returns their number
+    fn find_path(start: usize, goal: usize, path: &mut [usize; MAX_CLASSES]) -> Option<usize> {
+        let mut parent = [usize::MAX; MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = start;
+        parent[start] = start;
+        while head < tail {
+            let class = queue[head];
+            head += 1;
+            if class == goal {
+                let mut len = 0;
+                let mut at = goal;
+                loop {
+                    path[len] = at;
+                    len += 1;
+                    if at == start {
+                        break;
+                    }
+                    at = parent[at];
+                }
+                path[..len].reverse();
+                return Some(len);
+            }
+            let edges = EDGES[class].load(Ordering::Relaxed);
+            for next in 0..MAX_CLASSES {
+                if edges & 1 << next != 0 && parent[next] == usize::MAX {
+                    parent[next] = class;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+
+    fn report_cycle(
+        from: usize,
+        from_site: *mut Location<'static>,
+        to: usize,
+        to_site: &'static Location<'static>,
+        path: &[usize],
+    ) {
+        REPORTS.fetch_add(1, Ordering::Relaxed);
+        let out = &mut RawSerial;
+        let _ = writeln!(out, "lockdep: possible deadlock, lock order cycle");
+        let _ = writeln!(out, "  {} taken at {}", name(to), to_site);
+        let _ = writeln!(out, "  while holding {} taken at {}", name(from),
+                         OrUnknown(unsafe { from_site.as_ref() }));
+        let _ = writeln!(out, "  but earlier:");
+        for pair in path.windows(2) {
+            let (held, taken) = (pair[0], pair[1]);
+            let _ = writeln!(out, "  {} taken at {}", name(taken),
+                             OrUnknown(site(&EDGE_TAKEN[held][taken])));
+            let _ = writeln!(out, "  while holding {} taken at {}", name(held),
+                             OrUnknown(site(&EDGE_HELD[held][taken])));
+        }
+    }
+
+    /// Report a lock used in interrupt handlers and with interrupts enabled
+    fn check_irq_usage(id: usize, location: &'static Location<'static>, irqs_enabled: bool) {
+        if in_interrupt() {
+            let _ = IRQ_SITE[id].compare_exchange(ptr::null_mut(), site_ptr(location),
+                                                  Ordering::Relaxed, Ordering::Relaxed);
+        } else if irqs_enabled {
+            let _ = IRQS_ENABLED_SITE[id].compare_exchange(ptr::null_mut(), site_ptr(location),
+                                                           Ordering::Relaxed, Ordering::Relaxed);
+        } else {
+            return;
+        }
+        let (irq, enabled) = match (site(&IRQ_SITE[id]), site(&IRQS_ENABLED_SITE[id])) {
+            (Some(irq), Some(enabled)) => (irq, enabled),
+            _ => return,
+        };
+        let bit = 1 << id;
+        if REPORTED_IRQ.fetch_or(bit, Ordering::Relaxed) & bit != 0 {
+            return;
+        }
+        REPORTS.fetch_add(1, Ordering::Relaxed);
+        let out = &mut RawSerial;
+        let _ = writeln!(out, "lockdep: possible deadlock, {} is taken in an interrupt handler",
+                         name(id));
+        let _ = writeln!(out, "  at {}", irq);
+        let _ = writeln!(out, "  and held with interrupts enabled at {}", enabled);
+    }
+
+    struct OrUnknown(Option<&'static Location<'static>>);
+
+    impl core::fmt::Display for OrUnknown {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            match self.0 {
+                Some(location) => write!(f, "{}", location),
+                None => f.write_str("<unknown>"),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "lockdep")]
+#[test_case]
+fn test_order_cycle_is_reported() {
+    use crate::sync::IrqMutex;
+
+    static A: IrqMutex<()> = IrqMutex::named("test A", ());
+    static B: IrqMutex<()> = IrqMutex::named("test B", ());
+
+    let before = reports();
+    {
+        let _a = A.lock();
+        let _b = B.lock();
+    }
+    assert_eq!(reports(), before);
+    {
+        let _b = B.lock();
+        let _a = A.lock();
+    }
+    assert_eq!(reports(), before + 1);
+}
+
+#[cfg(feature = "lockdep")]
+#[test_case]
+fn test_irq_unsafe_lock_is_reported() {
+    use crate::sync::SpinMutex;
+
+    static LOCK: SpinMutex<()> = SpinMutex::named("test irq unsafe", ());
+
+    let before = reports();
+    drop(LOCK.lock()); // interrupts enabled
+    assert_eq!(reports(), before);
+    validator::as_interrupt(|| drop(LOCK.lock()));
+    assert_eq!(reports(), before + 1);
+}
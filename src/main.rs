@@ -9,7 +9,7 @@
 
 #[macro_use]    // for format! macro
 extern crate alloc;
-use blog_os::{println, task::timer};
+use blog_os::{println, task::timer, time::Duration};
 use blog_os::vga::text;
 use bootloader::{BootInfo, entry_point};
 use core::panic::PanicInfo;
@@ -27,6 +27,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // load GDT, IDT and enable interrupts
     println!("\n\nloading GDT and enabling interrupts...");
     blog_os::init();
+    println!("{}", blog_os::cpu::info());
+    // breakpoints, Ctrl+Alt+SysRq and fatal faults enter the monitor
+    #[cfg(not(test))]
+    blog_os::monitor::enable();
 
     // initialize global allocator
     println!("initializing heap allocator...");
@@ -37,9 +41,34 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    blog_os::gdt::init_stacks(&mut mapper, &mut frame_allocator)
+        .expect("interrupt stack allocation failed");
+
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    match timer::init() {
+        timer::ClockEvent::Lapic => println!("tickless timer using local APIC"),
+        timer::ClockEvent::Hpet => println!("tickless timer using HPET"),
+        timer::ClockEvent::Pit => println!("tickless timer using PIT"),
+        timer::ClockEvent::Periodic =>
+            println!("timer tick at {} Hz", timer::PERIODIC_HZ),
+    }
+    println!("RTC time is {}", blog_os::rtc::init());
+
+    println!("starting application processors...");
+    match blog_os::smp::init(&mut mapper, &mut frame_allocator) {
+        Ok(online) => println!("{} of {} CPUs online",
+                               online, blog_os::smp::found_count()),
+        Err(err) => println!("SMP bring-up failed: {:?}", err),
+    }
 
-    println!("setting timer tick to 18.2 Hz");
-    timer::pit::set_divider(timer::pit::Chan::CH0, u16::MAX);
+    // from here on kernel_main is a thread like any other
+    memory::install(mapper, frame_allocator);
+    blog_os::thread::init();
+    match blog_os::watchdog::start() {
+        Some(source) => println!("NMI watchdog using {:?}", source),
+        None => println!("no NMI source for the watchdog"),
+    }
 
     #[cfg(test)]
     test_main();
@@ -57,20 +86,34 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     executor.spawn(Task::new(serial_sender(5)));
     executor.spawn(Task::new(display_random(6)));
     executor.spawn(Task::new(display_seconds(7)));
+    executor.spawn(Task::new(display_clock()));
+    blog_os::thread::spawn(display_thread_counter);
 
     executor.run();
 }
 
+/// Counts in a kernel thread that blocks instead of awaiting
+fn display_thread_counter() {
+    use blog_os::thread;
+    use text::Color;
+    let color = text::Attribute::new(Color::Pink, Color::Black);
+
+    for count in 0..u32::MAX {
+        text::display(&format!("{:>6}", count), (2, 3), color);
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
 async fn display_timer(id: usize) {
     use text::Color;
     let color = text::Attribute::new(Color::LightCyan, Color::Black);
     let scrn_pos = (1, 3 + 8 * id as u8);
+    // each task counts at a different rate
+    let period = Duration::from_millis(100 * (id as u64 + 1));
 
-    loop {
-        let timer = timer::Timer::Tick(id).await;
-        text::display(&format!("{:>6}", timer), scrn_pos, color);
-        let timer = timer::Timer::Tock(id).await;
-        text::display(&format!("{:>6}", timer), scrn_pos, color);
+    for count in 0..u32::MAX {
+        text::display(&format!("{:>6}", count), scrn_pos, color);
+        timer::sleep(period).await;
     }
 }
 
@@ -81,7 +124,24 @@ async fn display_seconds(id: usize) {
 
     for seconds in 0..u32::MAX {
         text::display(&format!("{:>6}", seconds), scrn_pos, color);
-        timer::sleep(id, 18).await;
+        timer::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn display_clock() {
+    use blog_os::{rtc::DateTime, time::{Instant, SystemTime}};
+    use text::Color;
+    let color = text::Attribute::new(Color::White, Color::Black);
+
+    loop {
+        let now = SystemTime::now();
+        text::display(&format!("{}", DateTime::from(now)), (2, 62), color);
+        // wake up right after the next full second
+        let into_second = now.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let until_next = Duration::from_nanos(1_000_000_000 - into_second as u64);
+        timer::sleep_until(Instant::now() + until_next).await;
     }
 }
 
@@ -96,7 +156,7 @@ async fn display_random(id: usize) {
     loop {
         let num: u8 = rng.gen();
         text::display(&format!("{:>6}", num), scrn_pos, color);
-        timer::sleep(id, 9).await;
+        timer::sleep(Duration::from_millis(500)).await;
     }
 }
 
@@ -109,7 +169,7 @@ async fn serial_sender(id: usize) {
     for seconds in 0..u32::MAX {
         serial_println!("greetings {}", seconds);
         text::display(&format!("{:>6}", seconds), scrn_pos, color);
-        timer::sleep(id, 18).await;
+        timer::sleep(Duration::from_secs(1)).await;
     }
 }
 
@@ -117,7 +177,10 @@ async fn serial_sender(id: usize) {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    blog_os::process::kill_on_panic(info);
     println!("{}", info);
+    blog_os::serial_println!("{}", info);
+    blog_os::backtrace::Backtrace::capture().print();
 
     blog_os::hlt_loop();
 }
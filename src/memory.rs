@@ -1,12 +1,33 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::cpu::{self, Feature};
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable,
-        Page, PageTable, PhysFrame, Size4KiB,
+        mapper::MapToError,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable,
+        Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+/// frames below this address are never handed out (the AP trampoline and
+/// other real mode structures live there)
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// virtual region for kernel stacks
+pub const STACK_REGION_START: u64 = 0x_5555_0000_0000;
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACK_REGION_START);
+
+/// virtual address where the bootloader mapped the complete physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// physical address of the level 4 table set up by the bootloader
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// page table and frame allocator for allocations after boot (see `install`)
+static KERNEL_MEMORY: spin::Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> =
+    spin::Mutex::new(None);
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -14,6 +35,10 @@ use x86_64::{
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_LEVEL_4_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -36,6 +61,159 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
+/// Returns the virtual address of the given physical address.
+///
+/// Relies on the bootloader's mapping of the complete physical memory, so
+/// `init` must have been called first. Memory mapped device registers are
+/// reachable the same way.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "memory::init has not been called");
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+/// The entries the active page table maps an address through
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    /// entries from the level 4 table down; unused ones are 0
+    pub entries: [u64; 4],
+    /// number of entries used
+    pub levels: usize,
+    /// the physical address, `None` if not mapped
+    pub phys: Option<PhysAddr>,
+}
+
+/// Walk the active page table for `addr`
+///
+/// Takes no lock, so fault handlers, the panic handler and the monitor
+/// may use it. `None` before `init`.
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return None;
+    }
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    // bits of the address that index into a page mapped at each level
+    let page_bits = [39, 30, 21, 12];
+    let mut translation = Translation { entries: [0; 4], levels: 0, phys: None };
+    let mut table_addr = x86_64::registers::control::Cr3::read().0.start_address();
+    for (level, &index) in indices.iter().enumerate() {
+        let table = unsafe { &*(phys_to_virt(table_addr).as_ptr::<PageTable>()) };
+        let entry = &table[index];
+        translation.entries[level] = entry.flags().bits() | entry.addr().as_u64();
+        translation.levels = level + 1;
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            break;
+        }
+        if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let in_page = addr.as_u64() & ((1 << page_bits[level]) - 1);
+            translation.phys = Some(entry.addr() + in_page);
+            break;
+        }
+        table_addr = entry.addr();
+    }
+    Some(translation)
+}
+
+/// `NO_EXECUTE`, or no flag on CPUs without NX (the bit is reserved there)
+pub fn no_execute() -> PageTableFlags {
+    if cpu::has(Feature::Nx) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// `addr` is mapped in the active page table
+///
+/// Walks the tables without taking any lock, so fault handlers and the
+/// panic handler may use it. False before `init`.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    translate(addr).map_or(false, |translation| translation.phys.is_some())
+}
+
+/// The level 4 table of the kernel (the one `init` found active)
+pub fn kernel_level_4_table() -> PhysFrame {
+    let addr = KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed);
+    assert!(addr != 0, "memory::init has not been called");
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+/// Hand the page table and frame allocator over to the kernel
+///
+/// Everything that maps memory after boot (kernel thread stacks, ...) goes
+/// through `with_kernel_memory` from then on.
+///
+/// User address spaces share the level 3 tables of the kernel (see
+/// `user::AddressSpace`), so the one for the stack region is created here,
+/// before any address space copies the level 4 entries.
+pub fn install(mut mapper: OffsetPageTable<'static>, mut frame_allocator: BootInfoFrameAllocator) {
+    let entry = &mut mapper.level_4_table()[VirtAddr::new(STACK_REGION_START).p4_index()];
+    if entry.is_unused() {
+        let frame = frame_allocator.allocate_frame().expect("no frame for the stack region");
+        unsafe { zero_frame(frame) };
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
+    });
+}
+
+/// Fill a frame with zeros through the physical memory mapping
+///
+/// Unsafe because the frame must not be in use.
+pub unsafe fn zero_frame(frame: PhysFrame) {
+    let virt = phys_to_virt(frame.start_address());
+    ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, Page::<Size4KiB>::SIZE as usize);
+}
+
+/// Run `f` with the page table and frame allocator passed to `install`
+///
+/// Panics if `install` has not been called.
+pub fn with_kernel_memory<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut memory = KERNEL_MEMORY.lock();
+        let (mapper, frame_allocator) = memory.as_mut()
+            .expect("memory::install has not been called");
+        f(mapper, frame_allocator)
+    })
+}
+
+/// Number of frames handed out by the kernel frame allocator (see
+/// `install`) and not given back
+pub fn frames_in_use() -> usize {
+    with_kernel_memory(|_, frame_allocator| frame_allocator.frames_in_use())
+}
+
+/// Map a kernel stack of `pages` pages and return its top
+///
+/// The page below each stack is left unmapped, so an overflow page faults
+/// instead of silently corrupting the neighbouring stack.
+pub fn alloc_stack(
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let size = (pages + 1) * Page::<Size4KiB>::SIZE;
+    let guard = NEXT_STACK.fetch_add(size, Ordering::Relaxed);
+    let bottom = Page::containing_address(VirtAddr::new(guard)) + 1;
+    let top = bottom + pages;
+
+    for page in Page::range(bottom, top) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
+        };
+    }
+    Ok(top.start_address())
+}
+
 /// Creates an example mapping for the given page to frame `0xb8000`.
 pub fn create_example_mapping(
     page: Page,
@@ -64,9 +242,16 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 }
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Deallocated frames go on a free list (linked through their first 8
+/// bytes, reached with `phys_to_virt`) and are handed out first.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// first frame of the free list
+    free: Option<PhysFrame>,
+    /// frames allocated and not deallocated
+    in_use: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -79,16 +264,25 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free: None,
+            in_use: 0,
         }
     }
 
+    /// Number of frames allocated and not deallocated
+    pub fn frames_in_use(&self) -> usize {
+        self.in_use
+    }
+
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        // map each region to its address range, leaving low memory alone
+        let addr_ranges = usable_regions.map(|r| {
+            r.range.start_addr().max(LOW_MEMORY_END)..r.range.end_addr()
+        });
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
@@ -98,8 +292,33 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
+        let frame = match self.free {
+            Some(frame) => {
+                let next = unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() };
+                self.free = match next {
+                    0 => None,
+                    addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+                };
+                Some(frame)
+            }
+            None => {
+                let frame = self.usable_frames().nth(self.next);
+                self.next += 1;
+                frame
+            }
+        };
+        if frame.is_some() {
+            self.in_use += 1;
+        }
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self.free.map_or(0, |next| next.start_address().as_u64());
+        *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = next;
+        self.free = Some(frame);
+        self.in_use -= 1;
+    }
+}
@@ -0,0 +1,873 @@
+//! Interactive kernel monitor
+//!
+//! After `enable`, the monitor takes over the console of a CPU that hits a
+//! breakpoint (`int3` in kernel code the GDB stub does not claim), that
+//! gets Ctrl+Alt+SysRq typed or that takes a fatal fault in kernel code.
+//! It reads commands from the PS/2 keyboard and COM1 and answers on the
+//! VGA screen and COM1 (see `help` for the commands).
+//!
+//! The monitor neither allocates nor waits for locks the stopped code may
+//! hold: input and output go straight to the hardware, the VGA screen is
+//! skipped while `WRITER` is locked, and the heap statistics and thread
+//! list are skipped while their locks are taken. Other CPUs keep running.
+//!
+//! Only breakpoints save all registers; the others show rip, rsp, rbp and
+//! rflags. Fatal faults cannot be continued from.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use crate::{allocator, backtrace::Backtrace, interrupts::TrapFrame, irqstat, memory, percpu};
+use crate::{serial::RawSerial, softirq, symbols::{self, Location}, task::timer, thread, vga};
+use crate::{cpu, watchdog};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// longest command line
+pub const MAX_LINE: usize = 78;
+
+/// bytes shown by `mem` without a length
+const DEFAULT_BYTES: u64 = 64;
+/// instructions shown by `dis` without a count
+const DEFAULT_INSTRUCTIONS: u64 = 16;
+/// longest x86 instruction
+const MAX_INSTRUCTION: usize = 15;
+
+/// set 1 scancodes of the hotkey
+const CTRL: u8 = 0x1d;
+const ALT: u8 = 0x38;
+const RELEASED: u8 = 0x80;
+/// SysRq is what Print Screen sends while Alt is held
+const SYSRQ: u8 = 0x54;
+
+/// `MODIFIERS` bits
+const CTRL_DOWN: u8 = 1;
+const ALT_DOWN: u8 = 2;
+
+const NO_OWNER: usize = usize::MAX;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static MODIFIERS: AtomicU8 = AtomicU8::new(0);
+/// APIC id of the CPU in the monitor, `NO_OWNER` if none
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
+/// Why the monitor was entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Breakpoint,
+    Hotkey,
+    /// a fatal fault in kernel code (its name)
+    Fault(&'static str),
+}
+
+impl Reason {
+    /// The frame has all registers
+    fn complete(self) -> bool {
+        self == Reason::Breakpoint
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::Breakpoint => write!(f, "breakpoint"),
+            Reason::Hotkey => write!(f, "SysRq"),
+            Reason::Fault(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// A terminal to talk to the user on
+pub trait Console: Write {
+    /// Wait for the next typed character
+    fn read(&mut self) -> u8;
+}
+
+/// Enter the monitor on breakpoints, the hotkey and fatal faults from now on
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Called by the breakpoint exception handler for kernel code; returns
+/// false if the monitor did not handle it
+pub(crate) fn handle_trap(frame: &TrapFrame) -> bool {
+    enter(frame, Reason::Breakpoint)
+}
+
+/// Called by the keyboard interrupt handler with every scancode; true if
+/// it completed the hotkey (and should not be passed on)
+pub(crate) fn hotkey(scancode: u8) -> bool {
+    let bit = match scancode & !RELEASED {
+        CTRL => CTRL_DOWN,
+        ALT => ALT_DOWN,
+        SYSRQ => {
+            let both = CTRL_DOWN | ALT_DOWN;
+            return scancode == SYSRQ && is_enabled()
+                && MODIFIERS.load(Ordering::Relaxed) & both == both;
+        }
+        _ => return false,
+    };
+    if scancode & RELEASED == 0 {
+        MODIFIERS.fetch_or(bit, Ordering::Relaxed);
+    } else {
+        MODIFIERS.fetch_and(!bit, Ordering::Relaxed);
+    }
+    false
+}
+
+/// Run the monitor on the screen, keyboard and COM1 until the user
+/// continues
+///
+/// Returns false without doing anything if the monitor is not enabled or
+/// the calling CPU is in it already (it faulted in the monitor). Does not
+/// return after a fault.
+pub(crate) fn enter(frame: &TrapFrame, reason: Reason) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    // the APIC id works even if GS is not the kernel's (double fault)
+    let me = usize::from(cpu::apic_id());
+    if OWNER.load(Ordering::Acquire) == me {
+        return false;
+    }
+    while OWNER.compare_exchange(NO_OWNER, me, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        core::hint::spin_loop();
+    }
+    let mut terminal = Terminal {
+        keyboard: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+    };
+    run(&mut terminal, frame, reason);
+    // the releases of Ctrl and Alt went to the monitor
+    MODIFIERS.store(0, Ordering::Relaxed);
+    OWNER.store(NO_OWNER, Ordering::Release);
+    true
+}
+
+/// Read and run commands on `console` until the user continues
+///
+/// `frame` has the registers of the stopped code.
+pub fn run<C: Console>(console: &mut C, frame: &TrapFrame, reason: Reason) {
+    let _ = writeln!(console, "\nmonitor: {} at {} on CPU {} (type help)",
+                     reason, Location(frame.rip), cpu::apic_id());
+    let mut line = [0; MAX_LINE];
+    loop {
+        let _ = write!(console, "> ");
+        let len = read_line(console, &mut line);
+        let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+        let mut words = line.split_ascii_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        let args = [words.next(), words.next()];
+        let _ = match command {
+            "c" | "continue" => {
+                if let Reason::Fault(_) = reason {
+                    writeln!(console, "cannot continue after a fatal fault")
+                } else {
+                    return;
+                }
+            }
+            "h" | "help" => help(console),
+            "r" | "regs" => registers(console, frame, reason),
+            "m" | "mem" => memory_dump(console, frame, args),
+            "d" | "dis" => disassemble(console, frame, args),
+            "pt" => page_table(console, frame, args),
+            "t" | "threads" => threads(console),
+            "heap" => heap(console),
+            "irq" => irqs(console),
+            "i" | "interrupts" => write!(console, "{}", irqstat::Table),
+            "bt" => writeln!(console, "{}", Backtrace::from_registers(frame.rip, frame.rbp)),
+            _ => writeln!(console, "unknown command {} (type help)", command),
+        };
+    }
+}
+
+/// Read a line with echo and backspace, returns its length
+fn read_line<C: Console>(console: &mut C, line: &mut [u8; MAX_LINE]) -> usize {
+    let mut len = 0;
+    loop {
+        match console.read() {
+            b'\r' | b'\n' => {
+                let _ = writeln!(console);
+                return len;
+            }
+            0x08 | 0x7f => {
+                if len > 0 {
+                    len -= 1;
+                    let _ = write!(console, "\x08 \x08");
+                }
+            }
+            byte @ b' '..=b'~' if len < MAX_LINE => {
+                line[len] = byte;
+                len += 1;
+                let _ = console.write_char(char::from(byte));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn help<C: Console>(console: &mut C) -> fmt::Result {
+    writeln!(console, "c, continue          continue the stopped code")?;
+    writeln!(console, "r, regs              show the registers")?;
+    writeln!(console, "m, mem ADDR [LEN]    show memory as hex and ascii")?;
+    writeln!(console, "d, dis [ADDR [N]]    disassemble N instructions (from rip)")?;
+    writeln!(console, "pt ADDR              show how the page table maps ADDR")?;
+    writeln!(console, "t, threads           list the threads")?;
+    writeln!(console, "heap                 show the heap usage")?;
+    writeln!(console, "irq                  show the events per CPU and the softirqs")?;
+    writeln!(console, "i, interrupts        show the counts, latency and handler time per vector")?;
+    writeln!(console, "bt                   show a backtrace")?;
+    writeln!(console, "ADDR is hex, a register or a function; LEN and N are decimal")
+}
+
+fn registers<C: Console>(console: &mut C, frame: &TrapFrame, reason: Reason) -> fmt::Result {
+    if reason.complete() {
+        let registers = [
+            ("rax", frame.rax), ("rbx", frame.rbx), ("rcx", frame.rcx), ("rdx", frame.rdx),
+            ("rsi", frame.rsi), ("rdi", frame.rdi), ("rbp", frame.rbp), ("rsp", frame.rsp),
+            ("r8", frame.r8), ("r9", frame.r9), ("r10", frame.r10), ("r11", frame.r11),
+            ("r12", frame.r12), ("r13", frame.r13), ("r14", frame.r14), ("r15", frame.r15),
+        ];
+        for pair in registers.chunks(2) {
+            writeln!(console, "{:>3} {:#018x}  {:>3} {:#018x}",
+                     pair[0].0, pair[0].1, pair[1].0, pair[1].1)?;
+        }
+    } else {
+        writeln!(console, "rbp {:#018x}  rsp {:#018x}", frame.rbp, frame.rsp)?;
+    }
+    writeln!(console, "rip {}", Location(frame.rip))?;
+    writeln!(console, "rflags {:#x}  cs {:#x}  ss {:#x}", frame.rflags, frame.cs, frame.ss)
+}
+
+fn memory_dump<C: Console>(
+    console: &mut C,
+    frame: &TrapFrame,
+    args: [Option<&str>; 2],
+) -> fmt::Result {
+    let start = match args[0].and_then(|arg| parse_address(arg, frame)) {
+        Some(start) => start,
+        None => return writeln!(console, "usage: mem ADDR [LEN]"),
+    };
+    let len = match args[1] {
+        Some(arg) => match parse_number(arg) {
+            Some(len) => len,
+            None => return writeln!(console, "bad length {}", arg),
+        },
+        None => DEFAULT_BYTES,
+    };
+    let mut line = start;
+    while line < start.saturating_add(len) {
+        let count = (start.saturating_add(len) - line).min(16);
+        write!(console, "{:016x} ", line)?;
+        for i in 0..16 {
+            match read_byte(line.wrapping_add(i)) {
+                _ if i >= count => write!(console, "   ")?,
+                Some(byte) => write!(console, " {:02x}", byte)?,
+                None => write!(console, " ??")?,
+            }
+        }
+        write!(console, "  ")?;
+        for i in 0..count {
+            let c = match read_byte(line.wrapping_add(i)) {
+                Some(byte @ b' '..=b'~') => char::from(byte),
+                _ => '.',
+            };
+            console.write_char(c)?;
+        }
+        writeln!(console)?;
+        line = match line.checked_add(16) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+fn disassemble<C: Console>(
+    console: &mut C,
+    frame: &TrapFrame,
+    args: [Option<&str>; 2],
+) -> fmt::Result {
+    let mut addr = match args[0] {
+        Some(arg) => match parse_address(arg, frame) {
+            Some(addr) => addr,
+            None => return writeln!(console, "bad address {}", arg),
+        },
+        None => frame.rip,
+    };
+    let count = match args[1] {
+        Some(arg) => match parse_number(arg) {
+            Some(count) => count,
+            None => return writeln!(console, "bad count {}", arg),
+        },
+        None => DEFAULT_INSTRUCTIONS,
+    };
+    for _ in 0..count {
+        let mut code = [0; MAX_INSTRUCTION];
+        let mut available = 0;
+        while available < MAX_INSTRUCTION {
+            match read_byte(addr.wrapping_add(available as u64)) {
+                Some(byte) => code[available] = byte,
+                None => break,
+            }
+            available += 1;
+        }
+        if available == 0 {
+            return writeln!(console, "{:016x}  not mapped", addr);
+        }
+        if let Some((symbol, 0)) = symbols::lookup(addr) {
+            writeln!(console, "{}:", symbol.name)?;
+        }
+        let code = &code[..available];
+        // the first pass only finds the length
+        let len = decode(code, addr, &mut Discard);
+        write!(console, "{:016x}  ", addr)?;
+        for i in 0..8 {
+            match code.get(i) {
+                Some(byte) if i < len => write!(console, "{:02x}", byte)?,
+                _ => write!(console, "  ")?,
+            }
+        }
+        write!(console, "{}  ", if len > 8 { '+' } else { ' ' })?;
+        decode(code, addr, console);
+        writeln!(console)?;
+        addr = addr.wrapping_add(len as u64);
+    }
+    Ok(())
+}
+
+fn page_table<C: Console>(
+    console: &mut C,
+    frame: &TrapFrame,
+    args: [Option<&str>; 2],
+) -> fmt::Result {
+    let addr = match args[0].and_then(|arg| parse_address(arg, frame)) {
+        Some(addr) => addr,
+        None => return writeln!(console, "usage: pt ADDR"),
+    };
+    let addr = match VirtAddr::try_new(addr) {
+        Ok(addr) => addr,
+        Err(_) => return writeln!(console, "{:#x} is not canonical", addr),
+    };
+    let translation = match memory::translate(addr) {
+        Some(translation) => translation,
+        None => return writeln!(console, "no page table yet"),
+    };
+    let indices = [
+        u16::from(addr.p4_index()), u16::from(addr.p3_index()),
+        u16::from(addr.p2_index()), u16::from(addr.p1_index()),
+    ];
+    for level in 0..translation.levels {
+        let entry = translation.entries[level];
+        writeln!(console, "P{}[{:>3}] = {:#018x} {:?}", 4 - level, indices[level], entry,
+                 PageTableFlags::from_bits_truncate(entry))?;
+    }
+    match translation.phys {
+        Some(phys) => writeln!(console, "{:#x} -> {:#x}", addr.as_u64(), phys.as_u64()),
+        None => writeln!(console, "{:#x} is not mapped", addr.as_u64()),
+    }
+}
+
+fn threads<C: Console>(console: &mut C) -> fmt::Result {
+    let mut result = Ok(());
+    let listed = thread::try_for_each(|thread| {
+        if result.is_err() {
+            return;
+        }
+        result = write!(console, "{:>4} {:<16} {:?}",
+                        thread.id().as_u64(), thread.name(), thread.state());
+        // switched out threads return into `switch_context` (see `switch.s`)
+        if let Some(ret) = thread.saved_rsp().and_then(|rsp| read_word(rsp + 6 * 8)) {
+            result = result.and_then(|_| write!(console, " {}", Location(ret)));
+        }
+        result = result.and_then(|_| writeln!(console));
+    });
+    if !listed {
+        return writeln!(console, "the thread list is locked");
+    }
+    result
+}
+
+fn heap<C: Console>(console: &mut C) -> fmt::Result {
+    match allocator::stats() {
+        Some(stats) => writeln!(console, "heap: {} bytes, {} used, {} free",
+                                stats.size, stats.used, stats.free),
+        None => writeln!(console, "the heap is locked"),
+    }
+}
+
+fn irqs<C: Console>(console: &mut C) -> fmt::Result {
+    writeln!(console, "CPU APIC  INTERRUPTS     WAKEUPS       POLLS")?;
+    for cpu in percpu::all() {
+        writeln!(console, "{:>3} {:>4} {:>11} {:>11} {:>11}", cpu.cpu_id, cpu.apic_id,
+                 cpu.stats.interrupts.load(Ordering::Relaxed),
+                 cpu.stats.wakeups.load(Ordering::Relaxed),
+                 cpu.stats.polls.load(Ordering::Relaxed))?;
+    }
+    writeln!(console, "timer ticks: {}", timer::ticks())?;
+    writeln!(console, "SOFTIRQ      PRIORITY     RAISED        RUNS   AVG ns   MAX ns")?;
+    for softirq in softirq::all() {
+        let stats = softirq.stats();
+        writeln!(console, "{:<12} {:<8} {:>10} {:>11} {:>8} {:>8}", stats.name,
+                 stats.priority, stats.raised, stats.runs,
+                 stats.total_ns / stats.runs.max(1), stats.max_ns)?;
+    }
+    Ok(())
+}
+
+/// A hex number (`0x` is optional), a register of `frame` or a function
+fn parse_address(arg: &str, frame: &TrapFrame) -> Option<u64> {
+    let register = match arg {
+        "rip" => Some(frame.rip),
+        "rsp" => Some(frame.rsp),
+        "rbp" => Some(frame.rbp),
+        "rax" => Some(frame.rax),
+        "rbx" => Some(frame.rbx),
+        "rcx" => Some(frame.rcx),
+        "rdx" => Some(frame.rdx),
+        "rsi" => Some(frame.rsi),
+        "rdi" => Some(frame.rdi),
+        _ => None,
+    };
+    let hex = arg.strip_prefix("0x").unwrap_or(arg);
+    register
+        .or_else(|| u64::from_str_radix(hex, 16).ok())
+        .or_else(|| symbols::iter()
+            .find(|symbol| symbol.name == arg || is_path_suffix(symbol.name, arg))
+            .map(|symbol| symbol.addr))
+}
+
+/// `name` is `path::suffix`
+fn is_path_suffix(name: &str, suffix: &str) -> bool {
+    name.strip_suffix(suffix).map_or(false, |path| path.ends_with("::"))
+}
+
+/// A decimal or `0x` prefixed hex number
+fn parse_number(arg: &str) -> Option<u64> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+fn read_byte(addr: u64) -> Option<u8> {
+    let addr = VirtAddr::try_new(addr).ok()?;
+    if !memory::is_mapped(addr) {
+        return None;
+    }
+    Some(unsafe { addr.as_ptr::<u8>().read_volatile() })
+}
+
+fn read_word(addr: u64) -> Option<u64> {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = read_byte(addr + i as u64)?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// The screen, the keyboard and COM1, used without their locks
+struct Terminal {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+}
+
+impl Console for Terminal {
+    fn read(&mut self) -> u8 {
+        let mut status: Port<u8> = Port::new(0x64);
+        let mut data: Port<u8> = Port::new(0x60);
+        loop {
+            // waiting here with interrupts disabled is no lockup
+            watchdog::touch();
+            if let Some(byte) = RawSerial::try_receive() {
+                return byte;
+            }
+            // output buffer full, and not from the mouse
+            if unsafe { status.read() } & 0x21 == 0x01 {
+                let scancode = unsafe { data.read() };
+                if let Ok(Some(event)) = self.keyboard.add_byte(scancode) {
+                    if let Some(DecodedKey::Unicode(c)) = self.keyboard.process_keyevent(event) {
+                        if c.is_ascii() {
+                            return c as u8;
+                        }
+                    }
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        vga::try_print(format_args!("{}", s));
+        RawSerial.write_str(s)
+    }
+}
+
+/// Output of the length-finding pass of `decode`
+struct Discard;
+
+impl Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+const REGISTERS_64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+const REGISTERS_32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+    "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d",
+];
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+/// `0x81` and `0x83` by the reg field of the ModRM byte
+const ARITHMETIC: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+
+/// Reads the bytes of one instruction
+struct Decoder<'a> {
+    code: &'a [u8],
+    pos: usize,
+    /// address of the instruction
+    addr: u64,
+    /// REX prefix, 0 if none
+    rex: u8,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.code.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn i8(&mut self) -> Option<i64> {
+        self.byte().map(|byte| i64::from(byte as i8))
+    }
+
+    fn i32(&mut self) -> Option<i64> {
+        let mut bytes = [0; 4];
+        for byte in bytes.iter_mut() {
+            *byte = self.byte()?;
+        }
+        Some(i64::from(i32::from_le_bytes(bytes)))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        let low = self.i32()? as u32;
+        let high = self.i32()? as u32;
+        Some((u64::from(high) << 32 | u64::from(low)) as i64)
+    }
+
+    fn wide(&self) -> bool {
+        self.rex & 8 != 0
+    }
+
+    fn register(&self, number: u8) -> &'static str {
+        if self.wide() {
+            REGISTERS_64[usize::from(number)]
+        } else {
+            REGISTERS_32[usize::from(number)]
+        }
+    }
+
+    /// Address after the instruction so far plus `offset`
+    fn target(&self, offset: i64) -> u64 {
+        self.addr.wrapping_add(self.pos as u64).wrapping_add(offset as u64)
+    }
+}
+
+/// A decoded ModRM byte and the operand it names
+enum Operand {
+    Register(u8),
+    Memory {
+        base: Option<u8>,
+        index: Option<(u8, u8)>,
+        /// the displacement is relative to the next instruction
+        rip: bool,
+        displacement: i64,
+    },
+}
+
+/// Read a ModRM byte, returns its reg field and its r/m operand
+fn modrm(d: &mut Decoder) -> Option<(u8, Operand)> {
+    let byte = d.byte()?;
+    let mode = byte >> 6;
+    let reg = (byte >> 3 & 7) | (d.rex & 4) << 1;
+    let rm = byte & 7;
+    if mode == 3 {
+        return Some((reg, Operand::Register(rm | (d.rex & 1) << 3)));
+    }
+    let mut base = Some(rm | (d.rex & 1) << 3);
+    let mut index = None;
+    let mut rip = false;
+    if rm == 4 {
+        let sib = d.byte()?;
+        let scale = 1 << (sib >> 6);
+        let index_register = (sib >> 3 & 7) | (d.rex & 2) << 2;
+        if index_register != 4 {
+            index = Some((index_register, scale));
+        }
+        base = Some(sib & 7 | (d.rex & 1) << 3);
+        if sib & 7 == 5 && mode == 0 {
+            base = None;
+        }
+    } else if rm == 5 && mode == 0 {
+        base = None;
+        rip = true;
+    }
+    let displacement = match mode {
+        1 => d.i8()?,
+        2 => d.i32()?,
+        _ if base.is_none() => d.i32()?,
+        _ => 0,
+    };
+    Some((reg, Operand::Memory { base, index, rip, displacement }))
+}
+
+/// Write `operand`; immediates that follow must be read before calling
+/// (rip relative addresses count from the end of the instruction)
+fn write_operand(d: &Decoder, operand: &Operand, out: &mut impl Write) -> fmt::Result {
+    match *operand {
+        Operand::Register(number) => write!(out, "{}", d.register(number)),
+        Operand::Memory { rip: true, displacement, .. } => {
+            write!(out, "[{}]", Location(d.target(displacement)))
+        }
+        Operand::Memory { base, index, displacement, .. } => {
+            write!(out, "[")?;
+            let mut first = true;
+            if let Some(base) = base {
+                write!(out, "{}", REGISTERS_64[usize::from(base)])?;
+                first = false;
+            }
+            if let Some((index, scale)) = index {
+                if !first {
+                    write!(out, "+")?;
+                }
+                write!(out, "{}*{}", REGISTERS_64[usize::from(index)], scale)?;
+                first = false;
+            }
+            if displacement < 0 {
+                write!(out, "-{:#x}", -displacement)?;
+            } else if displacement > 0 || first {
+                write!(out, "{}{:#x}", if first { "" } else { "+" }, displacement)?;
+            }
+            write!(out, "]")
+        }
+    }
+}
+
+/// Write the instruction at the start of `code` (at `addr`) to `out`,
+/// returns its length
+///
+/// Knows only the instructions compiled kernel code uses most; others
+/// show up as a single `db`.
+fn decode(code: &[u8], addr: u64, out: &mut impl Write) -> usize {
+    let mut d = Decoder { code, pos: 0, addr, rex: 0 };
+    match decode_instruction(&mut d, out) {
+        Some(Ok(())) => d.pos,
+        _ => {
+            let _ = write!(out, "db {:#04x}", code[0]);
+            1
+        }
+    }
+}
+
+fn decode_instruction(d: &mut Decoder, out: &mut impl Write) -> Option<fmt::Result> {
+    let mut opcode = d.byte()?;
+    if opcode & 0xf0 == 0x40 {
+        d.rex = opcode;
+        opcode = d.byte()?;
+    }
+    let low = opcode & 7 | (d.rex & 1) << 3;
+    let result = match opcode {
+        0x50..=0x57 => write!(out, "push {}", REGISTERS_64[usize::from(low)]),
+        0x58..=0x5f => write!(out, "pop {}", REGISTERS_64[usize::from(low)]),
+        0x90 if d.rex == 0 => write!(out, "nop"),
+        0xc3 => write!(out, "ret"),
+        0xc9 => write!(out, "leave"),
+        0xcc => write!(out, "int3"),
+        0xcf => write!(out, "{}", if d.wide() { "iretq" } else { "iret" }),
+        0xf4 => write!(out, "hlt"),
+        0xfa => write!(out, "cli"),
+        0xfb => write!(out, "sti"),
+        0xe8 | 0xe9 => {
+            let target = d.i32().map(|offset| d.target(offset))?;
+            let name = if opcode == 0xe8 { "call" } else { "jmp" };
+            write!(out, "{} {}", name, Location(target))
+        }
+        0xeb => {
+            let target = d.i8().map(|offset| d.target(offset))?;
+            write!(out, "jmp {:#x}", target)
+        }
+        0x70..=0x7f => {
+            let target = d.i8().map(|offset| d.target(offset))?;
+            write!(out, "j{} {:#x}", CONDITIONS[usize::from(opcode & 15)], target)
+        }
+        0xb8..=0xbf => {
+            let value = if d.wide() { d.i64()? } else { d.i32()? & 0xffff_ffff };
+            write!(out, "mov {}, {:#x}", d.register(low), value)
+        }
+        // op r/m, reg
+        0x01 | 0x09 | 0x21 | 0x29 | 0x31 | 0x39 | 0x85 | 0x89 => {
+            let name = match opcode {
+                0x01 => "add",
+                0x09 => "or",
+                0x21 => "and",
+                0x29 => "sub",
+                0x31 => "xor",
+                0x39 => "cmp",
+                0x85 => "test",
+                _ => "mov",
+            };
+            let (reg, operand) = modrm(d)?;
+            write_operand_register(d, name, &operand, reg, out)
+        }
+        // op reg, r/m
+        0x03 | 0x2b | 0x3b | 0x8b | 0x8d => {
+            let name = match opcode {
+                0x03 => "add",
+                0x2b => "sub",
+                0x3b => "cmp",
+                0x8b => "mov",
+                _ => "lea",
+            };
+            let (reg, operand) = modrm(d)?;
+            write!(out, "{} {}, ", name, d.register(reg))
+                .and_then(|_| write_operand(d, &operand, out))
+        }
+        0x81 | 0x83 => {
+            let (reg, operand) = modrm(d)?;
+            let value = if opcode == 0x83 { d.i8()? } else { d.i32()? };
+            write_operand_immediate(d, ARITHMETIC[usize::from(reg & 7)], &operand, value, out)
+        }
+        0xff => {
+            let (reg, operand) = modrm(d)?;
+            let name = match reg & 7 {
+                2 => "call",
+                4 => "jmp",
+                6 => "push",
+                _ => return None,
+            };
+            // the target is always 64 bit
+            d.rex |= 8;
+            write!(out, "{} ", name).and_then(|_| write_operand(d, &operand, out))
+        }
+        0x0f => {
+            let opcode = d.byte()?;
+            match opcode {
+                0x05 => write!(out, "syscall"),
+                0x0b => write!(out, "ud2"),
+                0x1f => {
+                    modrm(d)?;
+                    write!(out, "nop")
+                }
+                0x80..=0x8f => {
+                    let target = d.i32().map(|offset| d.target(offset))?;
+                    write!(out, "j{} {}", CONDITIONS[usize::from(opcode & 15)], Location(target))
+                }
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// `name operand, reg`
+fn write_operand_register(
+    d: &Decoder,
+    name: &str,
+    operand: &Operand,
+    reg: u8,
+    out: &mut impl Write,
+) -> fmt::Result {
+    write!(out, "{} ", name)?;
+    write_operand(d, operand, out)?;
+    write!(out, ", {}", d.register(reg))
+}
+
+/// `name operand, value`
+fn write_operand_immediate(
+    d: &Decoder,
+    name: &str,
+    operand: &Operand,
+    value: i64,
+    out: &mut impl Write,
+) -> fmt::Result {
+    write!(out, "{} ", name)?;
+    write_operand(d, operand, out)?;
+    if value < 0 {
+        write!(out, ", -{:#x}", -value)
+    } else {
+        write!(out, ", {:#x}", value)
+    }
+}
+
+#[test_case]
+fn test_decode() {
+    let mut text = Text::new();
+    // push rbp; mov rbp, rsp; sub rsp, 0x10; ret
+    let code = [0x55, 0x48, 0x89, 0xe5, 0x48, 0x83, 0xec, 0x10, 0xc3];
+    let mut pos = 0;
+    let mut lengths = [0; 4];
+    for length in lengths.iter_mut() {
+        *length = decode(&code[pos..], pos as u64, &mut text);
+        text.write_str(";").unwrap();
+        pos += *length;
+    }
+    assert_eq!(lengths, [1, 3, 4, 1]);
+    assert_eq!(text.as_str(), "push rbp;mov rbp, rsp;sub rsp, 0x10;ret;");
+
+    let mut text = Text::new();
+    // mov rax, [rbp-0x8]
+    assert_eq!(decode(&[0x48, 0x8b, 0x45, 0xf8], 0, &mut text), 4);
+    assert_eq!(text.as_str(), "mov rax, [rbp-0x8]");
+
+    let mut text = Text::new();
+    // a truncated call
+    assert_eq!(decode(&[0xe8, 0x00], 0, &mut text), 1);
+    assert_eq!(text.as_str(), "db 0xe8");
+}
+
+/// Collects text for `test_decode` without the heap
+#[cfg(test)]
+struct Text {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+#[cfg(test)]
+impl Text {
+    fn new() -> Self {
+        Text { bytes: [0; 128], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for Text {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
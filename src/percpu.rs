@@ -0,0 +1,273 @@
+//! Per-CPU data
+//!
+//! Every CPU owns a `PerCpu` block. `IA32_GS_BASE` points at it while the
+//! CPU runs kernel code, and its first field holds its own address, so the
+//! block of the calling CPU is a single `mov reg, gs:[0]` away. In ring 3
+//! the GS base is the user's (always 0) and the kernel's waits in
+//! `IA32_KERNEL_GS_BASE`: every way in and out of user mode does a
+//! `swapgs` (see `user`). Use the `percpu!` macro to reach a field:
+//!
+//! ```ignore
+//! let cpu = percpu!(cpu_id);
+//! percpu!(stats.interrupts).fetch_add(1, Ordering::Relaxed);
+//! ```
+//!
+//! Other CPUs may read a block through `get`, so everything that changes
+//! after `init` is atomic.
+
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use crate::{cpu, fpu::CpuFpu, gdt::{self, Gdt}, profile::CpuProfile, smp::MAX_CPUS};
+use crate::{irqstat, lockdep::CpuLockdep, softirq::{self, CpuSoftirq}, time};
+use crate::{task::timer::CpuTimer, thread::CpuSched, watchdog::CpuWatchdog};
+use x86_64::{registers::model_specific::Msr, VirtAddr};
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// `current_task` while no task is running
+pub const NO_TASK: u64 = u64::MAX;
+
+const NO_CPU: AtomicPtr<PerCpu> = AtomicPtr::new(ptr::null_mut());
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+/// the BSP has its block, so every CPU that takes locks has one
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// The data of one CPU
+#[repr(C)]
+pub struct PerCpu {
+    /// address of this block, read through `gs:[0]`
+    this: *const PerCpu,
+    // the syscall entry (`user/entry.s`) relies on the next three offsets
+    /// kernel stack for syscalls of the running thread (`gs:[8]`)
+    pub(crate) kernel_stack: AtomicU64,
+    /// user stack pointer while a syscall switches stacks (`gs:[16]`)
+    pub(crate) user_rsp: AtomicU64,
+    /// kernel stack entry of the TSS of this CPU (`gs:[24]`)
+    pub(crate) tss_kernel_stack: AtomicPtr<u64>,
+    /// index of the CPU (0 is the BSP)
+    pub cpu_id: usize,
+    /// initial APIC id reported by CPUID
+    pub apic_id: u8,
+    /// id of the task being polled, `NO_TASK` if none
+    pub current_task: AtomicU64,
+    /// number of interrupt handlers running (more than 1 when nested)
+    pub irq_depth: AtomicUsize,
+    pub stats: Stats,
+    pub(crate) timer: CpuTimer,
+    pub(crate) sched: CpuSched,
+    pub(crate) fpu: CpuFpu,
+    pub(crate) profile: CpuProfile,
+    pub(crate) watchdog: CpuWatchdog,
+    pub(crate) softirq: CpuSoftirq,
+    pub(crate) lockdep: CpuLockdep,
+    pub(crate) gdt: Gdt,
+}
+
+// the raw pointer only ever points at the block itself, and everything
+// that changes after `init` is atomic
+unsafe impl Sync for PerCpu {}
+
+/// Per-CPU event counters
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// hardware interrupts handled
+    pub interrupts: AtomicU64,
+    /// wakeup IPIs received
+    pub wakeups: AtomicU64,
+    /// tasks polled
+    pub polls: AtomicU64,
+}
+
+/// Access a field of the calling CPU's `PerCpu` block
+#[macro_export]
+macro_rules! percpu {
+    ($($field:ident).+) => {
+        &$crate::percpu::current().$($field).+
+    };
+}
+
+impl PerCpu {
+    fn new(cpu_id: usize) -> Self {
+        PerCpu {
+            this: ptr::null(),
+            kernel_stack: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            tss_kernel_stack: AtomicPtr::new(ptr::null_mut()),
+            cpu_id,
+            apic_id: 0, // set by `install` on the CPU itself
+            current_task: AtomicU64::new(NO_TASK),
+            irq_depth: AtomicUsize::new(0),
+            stats: Stats::default(),
+            timer: CpuTimer::new(),
+            sched: CpuSched::new(),
+            fpu: CpuFpu::new(),
+            profile: CpuProfile::new(),
+            watchdog: CpuWatchdog::new(),
+            softirq: CpuSoftirq::new(),
+            lockdep: CpuLockdep::new(),
+            gdt: Gdt::new(),
+        }
+    }
+
+    /// Inside an interrupt handler
+    pub fn in_interrupt(&self) -> bool {
+        self.irq_depth.load(Ordering::Relaxed) != 0
+    }
+
+    /// Set the stack that syscalls and interrupts from ring 3 start on
+    pub(crate) fn set_kernel_stack(&self, top: u64) {
+        self.kernel_stack.store(top, Ordering::Relaxed);
+        unsafe { self.tss_kernel_stack.load(Ordering::Relaxed).write_volatile(top) };
+    }
+}
+
+/// Set up the block of the BSP and load its GDT and TSS
+///
+/// Called by `gdt::init` (the first thing `blog_os::init` does). The BSP
+/// block is static since there is no heap yet.
+pub(crate) fn init_bsp() {
+    static mut BSP: MaybeUninit<PerCpu> = MaybeUninit::uninit();
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    if DONE.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    unsafe {
+        let cpu = BSP.write(PerCpu::new(0));
+        install(cpu, gdt::bsp_stacks(), false);
+    }
+}
+
+/// A block for application processor `cpu`, for `init_ap`
+///
+/// Allocated by the BSP, so an AP installs its block before it can take
+/// a lock (the heap has one).
+pub(crate) fn alloc_ap(cpu: usize) -> &'static mut PerCpu {
+    Box::leak(Box::new(PerCpu::new(cpu)))
+}
+
+/// Make `cpu` (from `alloc_ap`) like new for the next application
+/// processor to get its index, after the one it was for failed to start
+///
+/// Unsafe because that processor must be stopped (INIT), and nobody may
+/// use the block it may have installed.
+pub(crate) unsafe fn reset_ap(cpu: &'static mut PerCpu) -> &'static mut PerCpu {
+    let id = cpu.cpu_id;
+    let addr = cpu as *mut PerCpu;
+    let _ = CPUS[id].compare_exchange(addr, ptr::null_mut(), Ordering::AcqRel, Ordering::Relaxed);
+    *cpu = PerCpu::new(id);
+    cpu
+}
+
+/// Set up `cpu` (from `alloc_ap`) as the block of the calling application
+/// processor and load its GDT and TSS
+///
+/// `stacks` are the tops of the interrupt stacks (see `gdt::alloc_stacks`).
+pub(crate) fn init_ap(cpu: &'static mut PerCpu, stacks: [VirtAddr; gdt::IST_STACKS]) {
+    unsafe { install(cpu, stacks, true) };
+}
+
+/// Load the tables in `cpu` and point the GS base registers at it
+///
+/// Unsafe because `cpu` must never move or be freed.
+unsafe fn install(cpu: &'static mut PerCpu, stacks: [VirtAddr; gdt::IST_STACKS], guarded: bool) {
+    cpu.this = cpu as *const PerCpu;
+    cpu.apic_id = crate::cpu::apic_id();
+    cpu.gdt.load(stacks, guarded);
+    *cpu.tss_kernel_stack.get_mut() = cpu.gdt.kernel_stack_ptr();
+
+    let addr = cpu as *mut PerCpu;
+    Msr::new(IA32_GS_BASE).write(addr as u64);
+    Msr::new(IA32_KERNEL_GS_BASE).write(0); // GS base of user mode
+    CPUS[cpu.cpu_id].store(addr, Ordering::Release);
+    READY.store(true, Ordering::Release);
+}
+
+/// `current` works: the BSP has set up its block, and APs set up theirs
+/// before they take locks
+///
+/// Handlers that may run with the GS base of user mode must switch it
+/// first (see `user::KernelGs::enter_paranoid`).
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// The GS base is the kernel's (the user's is always 0)
+pub(crate) fn gs_is_kernel() -> bool {
+    unsafe { Msr::new(IA32_GS_BASE).read() != 0 }
+}
+
+/// The block of the calling CPU
+///
+/// Must not be called before `gdt::init` (or, on an AP, `init_ap`).
+#[inline]
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this,
+             options(nostack, preserves_flags, readonly));
+        &*this
+    }
+}
+
+/// The block of `cpu`, if that CPU has been set up
+pub fn get(cpu: usize) -> Option<&'static PerCpu> {
+    let addr = CPUS.get(cpu)?.load(Ordering::Acquire);
+    unsafe { addr.as_ref() }
+}
+
+/// Blocks of all CPUs set up so far
+pub fn all() -> impl Iterator<Item = &'static PerCpu> {
+    (0..MAX_CPUS).filter_map(get)
+}
+
+/// The block of the calling CPU, found by its APIC id instead of GS
+///
+/// For handlers that may interrupt code running with the GS base of user
+/// mode (NMI, machine check, double fault). `None` before the CPU is set
+/// up.
+pub fn find_current() -> Option<&'static PerCpu> {
+    let apic_id = cpu::apic_id();
+    all().find(|cpu| cpu.apic_id == apic_id)
+}
+
+/// Marks an interrupt handler as running on the calling CPU until dropped
+///
+/// Dropping the outermost one runs the pending softirqs, so handlers drop
+/// it after the end of interrupt.
+pub(crate) struct IrqScope {
+    cpu: &'static PerCpu,
+    vector: u8,
+    /// TSC when the handler started
+    start: u64,
+}
+
+impl Drop for IrqScope {
+    fn drop(&mut self) {
+        irqstat::record_duration(self.vector, self.start);
+        if self.cpu.irq_depth.fetch_sub(1, Ordering::Relaxed) == 1 {
+            softirq::irq_exit(self.cpu);
+        }
+    }
+}
+
+/// Called first thing by the handler of hardware interrupt `vector`
+pub(crate) fn irq_enter(vector: u8) -> IrqScope {
+    let start = time::rdtsc();
+    let cpu = current();
+    cpu.irq_depth.fetch_add(1, Ordering::Relaxed);
+    cpu.stats.interrupts.fetch_add(1, Ordering::Relaxed);
+    irqstat::count(vector);
+    IrqScope { cpu, vector, start }
+}
+
+#[test_case]
+fn test_current_is_bsp() {
+    assert_eq!(current().cpu_id, 0);
+    assert!(ptr::eq(current(), get(0).unwrap()));
+    assert_eq!(percpu!(irq_depth).load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,140 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::any::Any;
+use core::ops::BitOr;
+
+/// Names a kernel object in the handle table of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Handle(u32);
+
+impl Handle {
+    pub const fn from_raw(raw: u32) -> Self {
+        Handle(raw)
+    }
+
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
+/// A kernel object a handle can refer to
+pub type Object = Arc<dyn Any + Send + Sync>;
+
+/// What a handle allows its holder to do with the object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rights(u32);
+
+impl Rights {
+    pub const NONE: Rights = Rights(0);
+    /// send messages on an IPC endpoint
+    pub const SEND: Rights = Rights(1 << 0);
+    /// receive messages on an IPC endpoint
+    pub const RECEIVE: Rights = Rights(1 << 1);
+    /// pass the handle on to another process
+    pub const TRANSFER: Rights = Rights(1 << 2);
+    pub const ALL: Rights = Rights(0b111);
+
+    pub fn contains(self, other: Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Rights {
+    type Output = Rights;
+
+    fn bitor(self, other: Rights) -> Rights {
+        Rights(self.0 | other.0)
+    }
+}
+
+/// An object together with the rights to it
+#[derive(Clone)]
+pub struct Capability {
+    pub object: Object,
+    pub rights: Rights,
+}
+
+impl Capability {
+    pub fn new(object: Object, rights: Rights) -> Self {
+        Capability { object, rights }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleError {
+    /// no open handle with that number
+    BadHandle,
+    /// the object is of another type
+    WrongType,
+    /// the handle lacks a right
+    AccessDenied,
+}
+
+/// Kernel objects held by a process
+///
+/// Handles are indices into the table; the slots of closed handles are
+/// reused.
+pub struct HandleTable {
+    slots: Vec<Option<Capability>>,
+}
+
+impl HandleTable {
+    pub const fn new() -> Self {
+        HandleTable { slots: Vec::new() }
+    }
+
+    /// Add `capability` and return its handle
+    pub fn insert(&mut self, capability: Capability) -> Handle {
+        match self.slots.iter().position(Option::is_none) {
+            Some(i) => {
+                self.slots[i] = Some(capability);
+                Handle(i as u32)
+            }
+            None => {
+                self.slots.push(Some(capability));
+                Handle(self.slots.len() as u32 - 1)
+            }
+        }
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&Capability> {
+        self.slots.get(handle.0 as usize)?.as_ref()
+    }
+
+    /// The object of `handle` if it is a `T` and the handle has `rights`
+    pub fn get_as<T: Any + Send + Sync>(&self, handle: Handle, rights: Rights)
+        -> Result<Arc<T>, HandleError>
+    {
+        let capability = self.get(handle).ok_or(HandleError::BadHandle)?;
+        let object = capability.object.clone().downcast()
+            .map_err(|_| HandleError::WrongType)?;
+        if !capability.rights.contains(rights) {
+            return Err(HandleError::AccessDenied);
+        }
+        Ok(object)
+    }
+
+    /// Close `handle` and return its capability
+    pub fn remove(&mut self, handle: Handle) -> Option<Capability> {
+        self.slots.get_mut(handle.0 as usize)?.take()
+    }
+
+    /// Close all handles
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    /// Number of open handles
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for HandleTable {
+    fn default() -> Self {
+        HandleTable::new()
+    }
+}
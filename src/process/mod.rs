@@ -0,0 +1,343 @@
+//! User processes
+//!
+//! A process is a program running in its own `AddressSpace` on a kernel
+//! thread, with a process id, a parent (the process that spawned it, or
+//! none if the kernel did) and a table of handles to kernel objects.
+//!
+//! When its program exits or is killed, a process gives back its address
+//! space and closes its handles right away, but stays in the process
+//! table with its exit code until its parent collects it with `wait`. Its
+//! children are handed over to the kernel.
+
+mod handle;
+
+pub use handle::{Capability, Handle, HandleError, HandleTable, Object, Rights};
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::thread::{self, ThreadId, WaitQueue};
+use crate::user::{self, elf::{self, ElfError}, AddressSpace};
+use crate::time::{Duration, Instant};
+use crate::{percpu, println};
+use x86_64::instructions::interrupts;
+
+/// exit code of a process killed by a kernel panic in one of its syscalls
+pub const PANIC_EXIT_CODE: i64 = -512;
+/// how long `kill_on_panic` waits for the locks `Process::exit` takes
+const EXIT_LOCK_WAIT: Duration = Duration::from_millis(10);
+
+lazy_static::lazy_static! {
+    static ref PROCESSES: spin::Mutex<BTreeMap<Pid, Arc<Process>>> =
+        spin::Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        // 0 stands for "no process" in `Thread::process`
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// exited with the code, waiting for the parent to collect it
+    Exited(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// no such process, or not a child of the caller
+    NotChild,
+}
+
+/// A user process
+pub struct Process {
+    pid: Pid,
+    name: String,
+    parent: spin::Mutex<Option<Pid>>,
+    thread: spin::Mutex<Option<ThreadId>>,
+    state: spin::Mutex<State>,
+    space: spin::Mutex<Option<Arc<AddressSpace>>>,
+    handles: spin::Mutex<HandleTable>,
+    exited: WaitQueue,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        *self.parent.lock()
+    }
+
+    pub fn state(&self) -> State {
+        *self.state.lock()
+    }
+
+    /// Run `f` with the handle table; `None` once the process has exited,
+    /// so nothing is added to a table that was already closed
+    pub fn with_handles<R>(&self, f: impl FnOnce(&mut HandleTable) -> R) -> Option<R> {
+        let mut handles = self.handles.lock();
+        // `exit` changes the state while it holds the table
+        if *self.state.lock() != State::Running {
+            return None;
+        }
+        Some(f(&mut handles))
+    }
+
+    /// None of the locks of the process that `exit` takes is held
+    fn exit_locks_free(&self) -> bool {
+        self.space.try_lock().is_some()
+            && self.handles.try_lock().is_some()
+            && self.state.try_lock().is_some()
+    }
+
+    /// Release the resources and record the exit code
+    fn exit(&self, code: i64) {
+        // the thread still runs in the address space until `user::run`
+        // returns, which holds on to it until then
+        self.space.lock().take();
+        for child in PROCESSES.lock().values() {
+            let mut parent = child.parent.lock();
+            if *parent == Some(self.pid) {
+                *parent = None;
+            }
+        }
+
+        // close the handles and change the state in one go: `with_handles`
+        // adds nothing afterwards, and `wait` returns with all closed
+        let mut handles = self.handles.lock();
+        handles.clear();
+        *self.state.lock() = State::Exited(code);
+        drop(handles);
+        self.exited.notify_all();
+    }
+}
+
+/// Start the executable `image` as a new process, a child of the calling
+/// one, and return its id
+pub fn spawn(name: &str, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, ElfError> {
+    spawn_with_handles(name, image, argv, envp, Vec::new())
+}
+
+/// Like `spawn`, with `handles` open in the new process as handles 0, 1, …
+pub fn spawn_with_handles(name: &str, image: &[u8], argv: &[&str], envp: &[&str],
+                          handles: Vec<Capability>) -> Result<Pid, ElfError>
+{
+    let program = elf::load(image, argv, envp)?;
+    let space = program.space.clone();
+    Ok(start(name, Some(space), handles, move || {
+        unsafe { user::run(program.space, program.entry, program.stack) }
+    }))
+}
+
+/// Start a process whose thread runs `f` in the kernel, with no address
+/// space of its own, and `handles` open like `spawn_with_handles` does;
+/// it exits with the code `f` returns
+pub fn spawn_kernel<F>(name: &str, handles: Vec<Capability>, f: F) -> Pid
+where
+    F: FnOnce() -> i64 + Send + 'static,
+{
+    start(name, None, handles, f)
+}
+
+fn start<F>(name: &str, space: Option<Arc<AddressSpace>>, handles: Vec<Capability>, f: F) -> Pid
+where
+    F: FnOnce() -> i64 + Send + 'static,
+{
+    let mut table = HandleTable::new();
+    for capability in handles {
+        table.insert(capability);
+    }
+    let process = Arc::new(Process {
+        pid: Pid::new(),
+        name: name.into(),
+        parent: spin::Mutex::new(current_pid()),
+        thread: spin::Mutex::new(None),
+        state: spin::Mutex::new(State::Running),
+        space: spin::Mutex::new(space),
+        handles: spin::Mutex::new(table),
+        exited: WaitQueue::new(),
+    });
+    let pid = process.pid;
+    PROCESSES.lock().insert(pid, process.clone());
+
+    let this = process.clone();
+    let handle = thread::spawn_named(name.into(), move || {
+        thread::current().process.store(pid.0, Ordering::Relaxed);
+        let code = f();
+        this.exit(code);
+    });
+    *process.thread.lock() = Some(handle.thread().id());
+    pid
+}
+
+/// The process with id `pid`, if it exists (or has exited and was not
+/// collected yet)
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// The id of the process of the calling thread, `None` for kernel threads
+pub fn current_pid() -> Option<Pid> {
+    if !thread::is_initialized() {
+        return None;
+    }
+    match thread::current().process.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+/// The process of the calling thread
+pub fn current() -> Option<Arc<Process>> {
+    current_pid().and_then(get)
+}
+
+/// Wait for the child `pid` to exit, remove it from the process table and
+/// return its exit code
+///
+/// Kernel threads may wait for the processes the kernel spawned or
+/// inherited.
+pub fn wait(pid: Pid) -> Result<i64, WaitError> {
+    let process = get(pid).filter(|process| process.parent() == current_pid())
+        .ok_or(WaitError::NotChild)?;
+    process.exited.wait_until(|| process.state() != State::Running);
+
+    let mut processes = PROCESSES.lock();
+    // another thread of the parent may have been faster
+    if processes.remove(&pid).is_none() {
+        return Err(WaitError::NotChild);
+    }
+    match process.state() {
+        State::Exited(code) => Ok(code),
+        State::Running => unreachable!("waited for a running process"),
+    }
+}
+
+/// A line of `ps`
+#[derive(Debug, Clone)]
+pub struct Info {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub thread: Option<ThreadId>,
+    pub state: State,
+    pub name: String,
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>5} ", self.pid)?;
+        match self.parent {
+            Some(parent) => write!(f, "{:>5} ", parent)?,
+            None => write!(f, "{:>5} ", "-")?,
+        }
+        match self.thread {
+            Some(thread) => write!(f, "{:>6} ", thread.as_u64())?,
+            None => write!(f, "{:>6} ", "-")?,
+        }
+        match self.state {
+            State::Running => write!(f, "{:<12} ", "running")?,
+            State::Exited(code) => write!(f, "{:<12} ", alloc::format!("exited({})", code))?,
+        }
+        write!(f, "{}", self.name)
+    }
+}
+
+/// All processes, ordered by id
+pub fn list() -> Vec<Info> {
+    PROCESSES.lock().values()
+        .map(|process| Info {
+            pid: process.pid,
+            parent: process.parent(),
+            thread: *process.thread.lock(),
+            state: process.state(),
+            name: process.name.clone(),
+        })
+        .collect()
+}
+
+/// Print the process list on the console
+pub fn ps() {
+    println!("{:>5} {:>5} {:>6} {:<12} NAME", "PID", "PPID", "THREAD", "STATE");
+    for info in list() {
+        println!("{}", info);
+    }
+}
+
+/// Kill the process of the calling thread instead of halting the machine
+/// if the panic happened in one of its syscalls; returns if it did not
+///
+/// Called by the panic handler. Panics in interrupt handlers or with
+/// interrupts disabled (likely holding a spin lock) are left alone, and so
+/// are panics while the thread holds a lock `exit` takes (the process
+/// table, or the handle table inside `with_handles`): killing would
+/// deadlock.
+///
+/// There is no unwinding. What the frames of the thread own is leaked,
+/// its reference to the process among them, and any other spin lock it
+/// holds (an IPC queue, say) stays locked for good. Killing keeps the
+/// machine running; it does not leave it clean.
+pub fn kill_on_panic(info: &PanicInfo) {
+    if !interrupts::are_enabled() || percpu::current().in_interrupt() {
+        return;
+    }
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => return,
+    };
+    // other CPUs let go of them in a moment, the panicking thread never
+    if !wait_for_locks(|| PROCESSES.try_lock().is_some()) {
+        return;
+    }
+    let process = match get(pid) {
+        Some(process) => process,
+        None => return,
+    };
+    if !wait_for_locks(|| process.exit_locks_free()) {
+        return;
+    }
+    println!("process {} ({}) killed: {}", process.pid, process.name, info);
+    if user::in_user_context() {
+        // `user::run` returns to the process thread, which exits
+        drop(process);
+        user::exit(PANIC_EXIT_CODE);
+    }
+    process.exit(PANIC_EXIT_CODE);
+    drop(process);
+    thread::exit();
+}
+
+/// Wait up to `EXIT_LOCK_WAIT` for `free` to return true
+fn wait_for_locks(free: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while !free() {
+        if start.elapsed() >= EXIT_LOCK_WAIT {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
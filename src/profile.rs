@@ -0,0 +1,197 @@
+//! Sampling profiler
+//!
+//! While the profiler runs, every interrupt of the clock event device (see
+//! `timer::clock_event`) records the instruction pointer it interrupted in
+//! a ring buffer of the CPU it arrived on, and
+//! the tickless timer makes sure an interrupt comes at least every
+//! `SAMPLE_PERIOD` (the periodic PIT only ticks at `timer::PERIODIC_HZ`).
+//! Once a ring is full the oldest samples are overwritten.
+//!
+//! `report` adds up the samples of all CPUs and prints a flat profile on
+//! the serial port: the functions (see `symbols`) seen most often first.
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crate::{percpu, serial_println, smp, symbols::{self, Location}, task::timer};
+use crate::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// time between samples
+pub const SAMPLE_PERIOD: Duration = Duration::from_millis(1);
+/// samples each CPU keeps
+pub const RING_SIZE: usize = 4096;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Profiler state of one CPU (part of its `percpu::PerCpu` block)
+pub(crate) struct CpuProfile {
+    /// kernel instruction pointers, allocated by the first `start`
+    ring: OnceCell<Box<[AtomicU64]>>,
+    /// number of samples recorded so far, including overwritten ones
+    head: AtomicUsize,
+    /// samples taken while the CPU ran user code
+    user: AtomicU64,
+}
+
+impl CpuProfile {
+    pub(crate) const fn new() -> Self {
+        CpuProfile {
+            ring: OnceCell::uninit(),
+            head: AtomicUsize::new(0),
+            user: AtomicU64::new(0),
+        }
+    }
+
+    fn clear(&self) {
+        if let Some(ring) = self.ring.get() {
+            for sample in ring.iter() {
+                sample.store(0, Ordering::Relaxed);
+            }
+        }
+        self.head.store(0, Ordering::Relaxed);
+        self.user.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Forget all samples and start taking new ones
+///
+/// CPUs that come online later are not sampled.
+pub fn start() {
+    for cpu in percpu::all() {
+        cpu.profile.ring.init_once(|| {
+            (0..RING_SIZE).map(|_| AtomicU64::new(0)).collect()
+        });
+        cpu.profile.clear();
+    }
+    RUNNING.store(true, Ordering::Release);
+
+    // idle CPUs may wait for a far away deadline
+    interrupts::without_interrupts(timer::kick);
+    for cpu in 1..smp::cpu_count() {
+        if smp::is_online(cpu) {
+            smp::run_on(cpu, || interrupts::without_interrupts(timer::kick));
+        }
+    }
+}
+
+/// Stop taking samples; the samples taken stay until the next `start`
+pub fn stop() {
+    RUNNING.store(false, Ordering::Release);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// When the timer of the calling CPU should interrupt next for a sample
+/// (in ns since boot), `None` if the profiler is not running
+pub(crate) fn next_sample(now: u64) -> Option<u64> {
+    if is_running() {
+        Some(now + SAMPLE_PERIOD.as_nanos() as u64)
+    } else {
+        None
+    }
+}
+
+/// Called by the timer interrupt handlers
+///
+/// Must not block or allocate.
+pub(crate) fn sample(stack_frame: &InterruptStackFrame) {
+    if is_running() {
+        record(stack_frame.instruction_pointer.as_u64(), crate::user::from_user(stack_frame));
+    }
+}
+
+fn record(rip: u64, user: bool) {
+    let profile = &percpu::current().profile;
+    if user {
+        profile.user.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if let Some(ring) = profile.ring.get() {
+        let head = profile.head.fetch_add(1, Ordering::Relaxed);
+        ring[head % ring.len()].store(rip, Ordering::Relaxed);
+    }
+}
+
+/// Samples per kernel address, most frequent first
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub hits: Vec<(u64, u64)>,
+    /// all kernel samples
+    pub kernel: u64,
+    /// samples in user code
+    pub user: u64,
+}
+
+/// Add up the samples of all CPUs
+pub fn collect() -> Profile {
+    let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut profile = Profile::default();
+    for cpu in percpu::all() {
+        profile.user += cpu.profile.user.load(Ordering::Relaxed);
+        let ring = match cpu.profile.ring.get() {
+            Some(ring) => ring,
+            None => continue,
+        };
+        let recorded = cpu.profile.head.load(Ordering::Relaxed).min(ring.len());
+        for sample in &ring[..recorded] {
+            match sample.load(Ordering::Relaxed) {
+                0 => {}
+                rip => {
+                    *counts.entry(rip).or_default() += 1;
+                    profile.kernel += 1;
+                }
+            }
+        }
+    }
+    profile.hits = counts.into_iter().collect();
+    profile.hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    profile
+}
+
+/// Samples per function (per address outside known functions), most
+/// frequent first
+pub fn by_function(profile: &Profile) -> Vec<(u64, u64)> {
+    let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+    for &(rip, count) in &profile.hits {
+        let start = symbols::lookup(rip).map_or(rip, |(symbol, _)| symbol.addr);
+        *counts.entry(start).or_default() += count;
+    }
+    let mut hits: Vec<(u64, u64)> = counts.into_iter().collect();
+    hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    hits
+}
+
+/// Print the flat profile on the serial port, at most `lines` functions
+pub fn report(lines: usize) {
+    let profile = collect();
+    let total = profile.kernel + profile.user;
+    serial_println!("{} samples ({} in user mode)", total, profile.user);
+    if total == 0 {
+        return;
+    }
+    serial_println!("{:>8} {:>6}  FUNCTION", "SAMPLES", "%");
+    for (addr, count) in by_function(&profile).into_iter().take(lines) {
+        // no floating point in the kernel outside `fpu::kernel_fpu_begin`
+        let permille = count * 1000 / total;
+        serial_println!("{:>8} {:>3}.{}%  {}", count, permille / 10, permille % 10,
+                        Location(addr));
+    }
+}
+
+#[test_case]
+fn test_samples_are_counted() {
+    start();
+    stop();
+    interrupts::without_interrupts(|| {
+        record(0xffff_8000_0000_1234, false);
+        record(0xffff_8000_0000_1234, false);
+        record(0x7000_0000_0000, true);
+    });
+    let profile = collect();
+    assert!(profile.hits.contains(&(0xffff_8000_0000_1234, 2)));
+    assert!(profile.user >= 1);
+}
@@ -0,0 +1,404 @@
+/* CMOS real-time clock (MC146818)
+ *
+ * The battery backed clock keeps the date and time in either BCD or binary
+ * and either 12 or 24 hour format (status register B). While the update
+ * in progress flag is set the time registers must not be read.
+ *
+ * ports:
+ * 70h register index (bit 7 disables NMI)
+ * 71h register data
+ *
+ * registers:
+ * 00h seconds    01h seconds alarm
+ * 02h minutes    03h minutes alarm
+ * 04h hours      05h hours alarm
+ * 07h day of month, 08h month, 09h year
+ * 0ah status A   bit 7 update in progress, bits 0-3 periodic rate
+ * 0bh status B   bit 6 periodic irq, bit 5 alarm irq, bit 4 update irq,
+ *                bit 2 binary mode, bit 1 24 hour mode
+ * 0ch status C   interrupt flags, reading acknowledges IRQ 8
+ */
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::task::{Context, Poll};
+use crate::sync::IrqMutex;
+use crate::time::{Duration, SystemTime};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOUR_PM: u8 = 1 << 7;
+const MODE_24_HOUR: u8 = 1 << 1;
+const MODE_BINARY: u8 = 1 << 2;
+const ALARM_IRQ: u8 = 1 << 5;
+const PERIODIC_IRQ: u8 = 1 << 6;
+/// alarm "don't care" value
+const ALARM_ANY: u8 = 0xc0;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// serializes access to the index/data port pair
+static CMOS: IrqMutex<()> = IrqMutex::named("CMOS", ());
+/// CMOS register holding the century (from the ACPI FADT, 0 if none)
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+static PERIODIC_COUNT: AtomicU64 = AtomicU64::new(0);
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Calendar date and time (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch, 0 for earlier dates
+    pub fn unix_seconds(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as u32, self.day as u32);
+        let seconds = days * SECS_PER_DAY as i64
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+        seconds.max(0) as u64
+    }
+}
+
+impl From<SystemTime> for DateTime {
+    fn from(time: SystemTime) -> Self {
+        let secs = time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let secs = secs % SECS_PER_DAY;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+impl From<DateTime> for SystemTime {
+    fn from(date: DateTime) -> Self {
+        SystemTime::from_unix(Duration::from_secs(date.unix_seconds()))
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day,
+               self.hour, self.minute, self.second)
+    }
+}
+
+/// Read the RTC and set the wall clock from it
+///
+/// Call after `acpi::init` so the century register can be found.
+pub fn init() -> DateTime {
+    if let Some(fadt) = crate::acpi::find_table(b"FACP") {
+        // the century field is at offset 108 in the FADT
+        if fadt.header().length > 108 {
+            CENTURY_REGISTER.store(fadt.read::<u8>(108), Ordering::Relaxed);
+        }
+    }
+    let now = read_time();
+    crate::time::set_system_time(now.into());
+    now
+}
+
+/// Read the current date and time from the RTC
+pub fn read_time() -> DateTime {
+    // read until two consecutive reads agree, so an update in between
+    // cannot leave us with a torn value
+    let mut last = read_raw();
+    loop {
+        let current = read_raw();
+        if current == last {
+            break;
+        }
+        last = current;
+    }
+    let (raw, status_b) = last;
+
+    let decode = |value: u8| if status_b & MODE_BINARY != 0 {
+        value
+    } else {
+        bcd_to_binary(value)
+    };
+
+    let mut hour = decode(raw[2] & !HOUR_PM);
+    if status_b & MODE_24_HOUR == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if raw[2] & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = match raw[6] {
+        0 => 20,
+        century => decode(century) as u16,
+    };
+
+    DateTime {
+        year: century * 100 + decode(raw[5]) as u16,
+        month: decode(raw[4]),
+        day: decode(raw[3]),
+        hour,
+        minute: decode(raw[1]),
+        second: decode(raw[0]),
+    }
+}
+
+/// Undecoded seconds, minutes, hours, day, month, year, century
+/// and status register B
+fn read_raw() -> ([u8; 7], u8) {
+    let _cmos = CMOS.lock();
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    let century = if century_register != 0 {
+        read_register(century_register)
+    } else {
+        0
+    };
+    let raw = [
+        read_register(SECONDS),
+        read_register(MINUTES),
+        read_register(HOURS),
+        read_register(DAY),
+        read_register(MONTH),
+        read_register(YEAR),
+        century,
+    ];
+    (raw, read_register(STATUS_B))
+}
+
+/// Enable the periodic interrupt at 32768 >> (rate - 1) Hz
+///
+/// `rate` must be in 3..=15 (8192 Hz down to 2 Hz).
+pub fn enable_periodic(rate: u8) -> bool {
+    assert!((3..=15).contains(&rate), "invalid RTC periodic rate");
+    if !irq_available() {
+        return false;
+    }
+    {
+        let _cmos = CMOS.lock();
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b | PERIODIC_IRQ);
+        read_register(STATUS_C);
+    }
+    unmask_irq();
+    true
+}
+
+pub fn disable_periodic() {
+    update_status_b(|status_b| status_b & !PERIODIC_IRQ);
+}
+
+/// Number of periodic interrupts so far
+pub fn periodic_count() -> u64 {
+    PERIODIC_COUNT.load(Ordering::Relaxed)
+}
+
+/// Raise the alarm interrupt the next time the clock reads
+/// `hour:minute:second`
+pub fn set_alarm(hour: u8, minute: u8, second: u8) -> bool {
+    if !irq_available() {
+        return false;
+    }
+    ALARM_FIRED.store(false, Ordering::Relaxed);
+    {
+        let _cmos = CMOS.lock();
+        let status_b = read_register(STATUS_B);
+        let encode = |value: u8| if status_b & MODE_BINARY != 0 {
+            value
+        } else {
+            binary_to_bcd(value)
+        };
+        let hour = if status_b & MODE_24_HOUR != 0 {
+            encode(hour)
+        } else {
+            let pm = if hour >= 12 { HOUR_PM } else { 0 };
+            let hour = match hour % 12 { 0 => 12, hour => hour };
+            encode(hour) | pm
+        };
+        write_register(SECONDS_ALARM, encode(second));
+        write_register(MINUTES_ALARM, encode(minute));
+        write_register(HOURS_ALARM, hour);
+        write_register(STATUS_B, status_b | ALARM_IRQ);
+        read_register(STATUS_C);
+    }
+    unmask_irq();
+    true
+}
+
+pub fn cancel_alarm() {
+    update_status_b(|status_b| status_b & !ALARM_IRQ);
+    let _cmos = CMOS.lock();
+    write_register(HOURS_ALARM, ALARM_ANY);
+}
+
+/// Future that completes when the alarm interrupt fires
+pub struct Alarm {
+    _private: (),
+}
+
+/// Wait for the alarm set with `set_alarm`
+pub fn alarm() -> Alarm {
+    Alarm { _private: () }
+}
+
+impl Future for Alarm {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        ALARM_WAKER.register(cx.waker());
+        if ALARM_FIRED.swap(false, Ordering::Acquire) {
+            ALARM_WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Called by the IRQ 8 handler
+///
+/// Must not allocate. Only waits for `CMOS` while another CPU uses the
+/// ports; `IrqMutex` keeps this CPU from holding it under the handler.
+pub(crate) fn interrupt() {
+    // reading status C acknowledges the interrupt; without that the RTC
+    // never raises IRQ 8 again
+    let flags = {
+        let _cmos = CMOS.lock();
+        read_register(STATUS_C)
+    };
+    if flags & PERIODIC_IRQ != 0 {
+        PERIODIC_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    if flags & ALARM_IRQ != 0 {
+        ALARM_FIRED.store(true, Ordering::Release);
+        ALARM_WAKER.wake();
+    }
+}
+
+/// With HPET legacy replacement routing IRQ 8 belongs to the HPET
+fn irq_available() -> bool {
+    use crate::task::timer::{self, ClockEvent};
+    timer::clock_event() != ClockEvent::Hpet
+}
+
+fn unmask_irq() {
+    use crate::interrupts::set_irq_masked;
+    set_irq_masked(2, false); // cascade
+    set_irq_masked(8, false);
+}
+
+fn update_status_b<F: FnOnce(u8) -> u8>(f: F) {
+    let _cmos = CMOS.lock();
+    let status_b = read_register(STATUS_B);
+    write_register(STATUS_B, f(status_b));
+    read_register(STATUS_C);
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::new(0x70).write(reg);
+        Port::new(0x71).read()
+    }
+}
+
+fn write_register(reg: u8, value: u8) {
+    unsafe {
+        Port::new(0x70).write(reg);
+        Port::new(0x71).write(value);
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+fn binary_to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = (year - era * 400) as u64;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march as u64 + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100
+        + day_of_year;
+    era * 146_097 + day_of_era as i64 - 719_468
+}
+
+/// Inverse of `days_from_civil`: (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = (if days >= 0 { days } else { days - 146_096 }) / 146_097;
+    let day_of_era = (days - era * 146_097) as u64;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+        - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era
+        - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = (if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    }) as u32;
+    let year = year_of_era as i64 + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[test_case]
+fn test_bcd() {
+    assert_eq!(bcd_to_binary(0x59), 59);
+    assert_eq!(binary_to_bcd(59), 0x59);
+}
+
+#[test_case]
+fn test_civil_dates() {
+    assert_eq!(days_from_civil(1970, 1, 1), 0);
+    assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+    assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+    assert_eq!(civil_from_days(19_051), (2022, 2, 28));
+    let date = DateTime {
+        year: 2021, month: 12, day: 31, hour: 23, minute: 59, second: 58,
+    };
+    assert_eq!(DateTime::from(SystemTime::from(date)), date);
+    let before_epoch = DateTime { year: 1969, ..date };
+    assert_eq!(before_epoch.unix_seconds(), 0);
+}
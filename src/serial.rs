@@ -1,26 +1,82 @@
+use crate::sync::IrqMutex;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqMutex::named("SERIAL1", serial_port)
     };
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+
+    if SERIAL1.is_held_by_current_cpu() {
+        // a panic while printing (e.g. a recursive lock): get the message out
+        let _ = RawSerial.write_fmt(args);
+        return;
+    }
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// COM1 without the lock of `SERIAL1`, for code that may have interrupted
+/// its holder (the monitor, the NMI watchdog)
+///
+/// Needs `SERIAL1` to have been used once, which sets up the port.
+pub(crate) struct RawSerial;
+
+impl RawSerial {
+    /// I/O port of COM1
+    pub(crate) const PORT: u16 = 0x3f8;
+    const LINE_STATUS: u16 = Self::PORT + 5;
+
+    fn send(byte: u8) {
+        use x86_64::instructions::port::Port;
+
+        let mut line_status: Port<u8> = Port::new(Self::LINE_STATUS);
+        let mut transmit: Port<u8> = Port::new(Self::PORT);
+        unsafe {
+            // transmitter holding register empty
+            while line_status.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            transmit.write(byte);
+        }
+    }
+
+    /// The next received byte, if there is one
+    pub(crate) fn try_receive() -> Option<u8> {
+        use x86_64::instructions::port::Port;
+
+        let mut line_status: Port<u8> = Port::new(Self::LINE_STATUS);
+        let mut receive: Port<u8> = Port::new(Self::PORT);
+        unsafe {
+            // data ready
+            if line_status.read() & 1 != 0 {
+                Some(receive.read())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+impl core::fmt::Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                Self::send(b'\r');
+            }
+            Self::send(byte);
+        }
+        Ok(())
+    }
 }
 
 /// Prints to the host through the serial interface.
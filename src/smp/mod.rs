@@ -0,0 +1,290 @@
+//! Symmetric multiprocessing
+//!
+//! The application processors (APs) listed in the MADT are started one at
+//! a time with INIT-SIPI-SIPI. Each runs `trampoline.s` from low memory
+//! up to long mode, gets its own stacks and per-CPU block (with its GDT
+//! and TSS), loads the shared IDT and enables its local APIC. It then
+//! parks in an idle loop that runs work sent to it with `run_on` and,
+//! once the executor runs, async tasks.
+
+use alloc::{boxed::Box, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use crate::{acpi, apic, fpu, gdt, interrupts, memory, percpu::{self, PerCpu}, syscall, thread};
+use crate::task::{executor, timer::{lapic, pit}};
+use crate::time::{Duration, Instant};
+use crossbeam_queue::ArrayQueue;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+global_asm!(include_str!("trampoline.s"));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// physical address the trampoline is copied to (must match trampoline.s)
+const TRAMPOLINE: u64 = 0x8000;
+pub const MAX_CPUS: usize = 16;
+/// pages per AP kernel stack
+const STACK_PAGES: u64 = 8;
+const WORK_QUEUE_SIZE: usize = 32;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+static CPUS: OnceCell<Vec<Cpu>> = OnceCell::uninit();
+/// CPUs started so far; they have the indices below
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+struct Cpu {
+    /// set before the CPU is started (a CPU that fails to start leaves its
+    /// index to the next one)
+    apic_id: AtomicU8,
+    online: AtomicBool,
+    /// tops of the interrupt stacks, set before the CPU is started
+    ist_stacks: [AtomicU64; gdt::IST_STACKS],
+    /// per-CPU block, set before the CPU is started
+    block: AtomicPtr<PerCpu>,
+    work: ArrayQueue<Work>,
+}
+
+/// Values the trampoline reads (see the end of trampoline.s)
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+/// Enumerate the CPUs and start all enabled application processors
+///
+/// Requires the heap, `acpi::init` and `apic::init` on the BSP. Returns the
+/// number of CPUs online. A CPU that does not check in is put back to
+/// sleep with INIT; the next one gets its index and stacks.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, MapToError<Size4KiB>> {
+    let bsp = apic::id();
+    let mut cpus = Vec::new();
+    cpus.push(Cpu::new(bsp));
+    for processor in acpi::processors() {
+        if processor.enabled && processor.apic_id != bsp && cpus.len() < MAX_CPUS {
+            cpus.push(Cpu::new(processor.apic_id));
+        }
+    }
+    cpus[0].online.store(true, Ordering::Relaxed);
+    if CPUS.try_init_once(|| cpus).is_err() {
+        return Ok(online_count()); // already started
+    }
+
+    install_trampoline(mapper, frame_allocator)?;
+    let mut unused_stacks = None;
+    for candidate in 1..found_count() {
+        let index = online_count();
+        let apic_id = cpus()[candidate].apic_id.load(Ordering::Relaxed);
+        let (stack, ist_stacks) = match unused_stacks.take() {
+            Some(stacks) => stacks,
+            None => (memory::alloc_stack(STACK_PAGES, mapper, frame_allocator)?,
+                     gdt::alloc_stacks(mapper, frame_allocator)?),
+        };
+        let cpu = &cpus()[index];
+        cpu.apic_id.store(apic_id, Ordering::Relaxed);
+        for (slot, top) in cpu.ist_stacks.iter().zip(ist_stacks) {
+            slot.store(top.as_u64(), Ordering::Relaxed);
+        }
+        // one per index: that of a CPU that failed to start is reset and
+        // goes to the next one
+        let block = match unsafe { cpu.block.load(Ordering::Acquire).as_mut() } {
+            Some(used) => unsafe { percpu::reset_ap(used) },
+            None => percpu::alloc_ap(index),
+        };
+        cpu.block.store(block, Ordering::Release);
+
+        if start_ap(index, stack) {
+            ONLINE.fetch_add(1, Ordering::AcqRel);
+        } else {
+            // it must not wake up later on stacks handed to the next one
+            apic::send_init(apic_id);
+            cpu.online.store(false, Ordering::Release);
+            unused_stacks = Some((stack, ist_stacks));
+        }
+    }
+    Ok(online_count())
+}
+
+impl Cpu {
+    fn new(apic_id: u8) -> Self {
+        Cpu {
+            apic_id: AtomicU8::new(apic_id),
+            online: AtomicBool::new(false),
+            ist_stacks: Default::default(),
+            block: AtomicPtr::new(core::ptr::null_mut()),
+            work: ArrayQueue::new(WORK_QUEUE_SIZE),
+        }
+    }
+}
+
+fn cpus() -> &'static [Cpu] {
+    CPUS.try_get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+/// Number of enabled CPUs the MADT lists (the BSP alone before `init`)
+pub fn found_count() -> usize {
+    cpus().len().max(1)
+}
+
+/// Number of CPUs in use: indices `0..cpu_count()` are valid
+pub fn cpu_count() -> usize {
+    online_count()
+}
+
+/// Number of CPUs that have checked in
+pub fn online_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+pub fn is_online(cpu: usize) -> bool {
+    cpu < online_count() && (cpu == 0 || checked_in(cpu))
+}
+
+/// `cpu` reached `ap_entry` (it may not be counted yet)
+fn checked_in(cpu: usize) -> bool {
+    cpus().get(cpu).map_or(false, |cpu| cpu.online.load(Ordering::Acquire))
+}
+
+/// Index of the calling CPU (0 is the BSP)
+pub fn current_cpu() -> usize {
+    percpu::current().cpu_id
+}
+
+/// APIC id of `cpu`
+pub fn apic_id(cpu: usize) -> u8 {
+    cpus()[cpu].apic_id.load(Ordering::Relaxed)
+}
+
+/// Run `work` on the idle loop of application processor `cpu`
+///
+/// Panics if the CPU is not online or its work queue is full.
+pub fn run_on<F>(cpu: usize, work: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    assert!(cpu != 0, "the BSP has no idle loop");
+    assert!(is_online(cpu), "CPU {} is not online", cpu);
+    let target = &cpus()[cpu];
+    if target.work.push(Box::new(work)).is_err() {
+        panic!("work queue of CPU {} is full", cpu);
+    }
+    apic::send_ipi(apic_id(cpu), interrupts::InterruptIndex::Wakeup.as_u8());
+}
+
+/// Copy the trampoline to low memory and identity map it, since paging
+/// is switched on while executing from there
+fn install_trampoline(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start = unsafe { &ap_trampoline_start as *const u8 };
+    let end = unsafe { &ap_trampoline_end as *const u8 };
+    let len = end as usize - start as usize;
+    assert!(len <= 4096, "AP trampoline does not fit in one page");
+
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE));
+    let page = Page::containing_address(VirtAddr::new(TRAMPOLINE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+        Err(err) => return Err(err),
+    }
+
+    let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE)).as_mut_ptr::<u8>();
+    unsafe { core::ptr::copy_nonoverlapping(start, dest, len) };
+    Ok(())
+}
+
+fn trampoline_data() -> *mut TrampolineData {
+    let offset = unsafe {
+        &ap_trampoline_data as *const u8 as usize
+            - &ap_trampoline_start as *const u8 as usize
+    };
+    memory::phys_to_virt(PhysAddr::new(TRAMPOLINE + offset as u64)).as_mut_ptr()
+}
+
+/// Send INIT-SIPI-SIPI to `cpu` and wait for it to check in
+fn start_ap(cpu: usize, stack: VirtAddr) -> bool {
+    use x86_64::registers::control::Cr3;
+
+    unsafe {
+        trampoline_data().write(TrampolineData {
+            cr3: Cr3::read().0.start_address().as_u64(),
+            stack: stack.as_u64(),
+            entry: ap_entry as usize as u64,
+            cpu: cpu as u64,
+        });
+    }
+
+    let apic_id = apic_id(cpu);
+    let vector = (TRAMPOLINE >> 12) as u8;
+    apic::send_init(apic_id);
+    pit::busy_wait((pit::CLK_FREQ / 100) as u16); // 10 ms
+    for _ in 0..2 {
+        apic::send_startup(apic_id, vector);
+        pit::busy_wait((pit::CLK_FREQ / 5000) as u16); // 200 us
+        if checked_in(cpu) {
+            return true;
+        }
+    }
+
+    let start = Instant::now();
+    while !checked_in(cpu) {
+        if start.elapsed() > Duration::from_millis(100) {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Rust entry point of an application processor, called by trampoline.s
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    let mut ist_stacks = [VirtAddr::zero(); gdt::IST_STACKS];
+    for (top, slot) in ist_stacks.iter_mut().zip(&cpus()[cpu].ist_stacks) {
+        *top = VirtAddr::new(slot.load(Ordering::Relaxed));
+    }
+    let block = cpus()[cpu].block.load(Ordering::Acquire);
+    percpu::init_ap(unsafe { &mut *block }, ist_stacks);
+    syscall::init();
+    fpu::init();
+    interrupts::init_idt();
+    apic::init();
+    lapic::init();
+    thread::init();
+
+    let me = &cpus()[cpu];
+    // counted by `init`, unless it gave up on us already
+    me.online.store(true, Ordering::Release);
+
+    idle_loop(me)
+}
+
+/// Run queued work and executor tasks, halt when there are none
+fn idle_loop(me: &Cpu) -> ! {
+    loop {
+        while let Ok(work) = me.work.pop() {
+            work();
+        }
+        executor::poll_ready();
+        executor::sleep_if_idle(|| !me.work.is_empty());
+    }
+}
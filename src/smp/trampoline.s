@@ -0,0 +1,109 @@
+# Application processor startup code
+#
+# Copied to TRAMPOLINE (below 1 MiB) and entered in real mode through the
+# startup IPI. Switches to long mode with the kernel's page tables and
+# calls the Rust entry point on the stack the BSP prepared. Everything is
+# addressed relative to TRAMPOLINE since the code does not run where it
+# was linked.
+
+.set TRAMPOLINE, 0x8000
+.set EFER, 0xc0000080
+
+.section .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_data
+.global ap_trampoline_end
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    lgdt [TRAMPOLINE + (ap_gdt_pointer - ap_trampoline_start)]
+    mov eax, cr0
+    or eax, 1                       # protection enable
+    mov cr0, eax
+
+    # ljmp 0x08:protected_mode with a 32-bit offset
+    .byte 0x66, 0xea
+    .long TRAMPOLINE + (ap_protected_mode - ap_trampoline_start)
+    .word 0x08
+
+.code32
+ap_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov eax, cr4
+    or eax, 1 << 5                  # physical address extension
+    or eax, (1 << 9) | (1 << 10)    # SSE (the kernel is built with it)
+    mov cr4, eax
+
+    mov eax, dword ptr [TRAMPOLINE + (ap_cr3 - ap_trampoline_start)]
+    mov cr3, eax
+
+    mov ecx, EFER
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)    # long mode enable, no-execute enable
+    wrmsr
+
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)   # paging, write protect
+    or eax, 1 << 1                  # monitor coprocessor
+    and eax, ~(1 << 2)              # no FPU emulation
+    mov cr0, eax
+
+    # ljmp 0x18:long_mode
+    .byte 0xea
+    .long TRAMPOLINE + (ap_long_mode - ap_trampoline_start)
+    .word 0x18
+
+.code64
+ap_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, qword ptr [TRAMPOLINE + (ap_stack - ap_trampoline_start)]
+    mov rdi, qword ptr [TRAMPOLINE + (ap_cpu - ap_trampoline_start)]
+    mov rax, qword ptr [TRAMPOLINE + (ap_entry - ap_trampoline_start)]
+    xor rbp, rbp
+    call rax
+2:
+    hlt
+    jmp 2b
+
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff        # 0x08: 32-bit code
+    .quad 0x00cf92000000ffff        # 0x10: data
+    .quad 0x00af9a000000ffff        # 0x18: 64-bit code
+ap_gdt_end:
+
+ap_gdt_pointer:
+    .word ap_gdt_end - ap_gdt - 1
+    .long TRAMPOLINE + (ap_gdt - ap_trampoline_start)
+
+# filled in by the BSP before each startup IPI
+.balign 8
+ap_trampoline_data:
+ap_cr3:
+    .quad 0
+ap_stack:
+    .quad 0
+ap_entry:
+    .quad 0
+ap_cpu:
+    .quad 0
+ap_trampoline_end:
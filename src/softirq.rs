@@ -0,0 +1,288 @@
+//! Deferred interrupt work (softirqs)
+//!
+//! Interrupt handlers should only talk to their device and acknowledge the
+//! interrupt. Anything else (drawing, printing, waking up) goes into a
+//! `Softirq`: a static work item the handler raises on its CPU. When the
+//! outermost interrupt handler of a CPU is done (see `percpu::irq_enter`)
+//! it runs the pending items of that CPU with interrupts enabled, highest
+//! `Priority` first.
+//!
+//! A softirq raised while it is pending runs once. Softirqs interrupted by
+//! another interrupt are not restarted; new ones raised meanwhile run in
+//! the same pass, up to `MAX_RESTARTS` rounds, and the rest at the next
+//! interrupt exit.
+//!
+//! Softirq functions run in interrupt context: they must not block, and
+//! the locks they take must disable interrupts like `println!` does. The
+//! thread running a pass is not preempted until it is done.
+
+use core::{fmt, ptr};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use crate::{percpu::{self, PerCpu}, time::Instant};
+use x86_64::instructions::interrupts;
+
+/// maximum number of distinct softirqs
+pub const MAX_SOFTIRQS: usize = 32;
+/// rounds one interrupt exit runs before leaving work for the next one
+pub const MAX_RESTARTS: usize = 8;
+
+const NO_SOFTIRQ: AtomicPtr<Softirq> = AtomicPtr::new(ptr::null_mut());
+static REGISTERED: [AtomicPtr<Softirq>; MAX_SOFTIRQS] = [NO_SOFTIRQ; MAX_SOFTIRQS];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Order in which pending softirqs run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        })
+    }
+}
+
+/// A deferred work item, meant to be a `static`
+pub struct Softirq {
+    name: &'static str,
+    priority: Priority,
+    func: fn(),
+    registered: AtomicBool,
+    /// bit N: pending on CPU N
+    pending: AtomicU64,
+    raised: AtomicU64,
+    runs: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+/// Counters of a softirq, summed over all CPUs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub name: &'static str,
+    pub priority: Priority,
+    /// calls to `raise`, including those merged into a pending run
+    pub raised: u64,
+    pub runs: u64,
+    /// time spent running, in nanoseconds
+    pub total_ns: u64,
+    /// longest run, in nanoseconds
+    pub max_ns: u64,
+}
+
+impl Softirq {
+    pub const fn new(name: &'static str, priority: Priority, func: fn()) -> Self {
+        Softirq {
+            name,
+            priority,
+            func,
+            registered: AtomicBool::new(false),
+            pending: AtomicU64::new(0),
+            raised: AtomicU64::new(0),
+            runs: AtomicU64::new(0),
+            total_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Run the softirq on the calling CPU once the interrupt handler is done
+    ///
+    /// Outside of interrupt handlers it runs right away, unless interrupts
+    /// are disabled; then it waits for `run_pending` or the next interrupt.
+    /// Never blocks or allocates.
+    pub fn raise(&'static self) {
+        self.register();
+        let enabled = interrupts::are_enabled();
+        // pinned to one CPU from picking it until it ran: a thread that
+        // moved in between would run another CPU's softirqs
+        interrupts::without_interrupts(|| {
+            let cpu = percpu::current();
+            self.raised.fetch_add(1, Ordering::Relaxed);
+            self.pending.fetch_or(1 << cpu.cpu_id, Ordering::AcqRel);
+            cpu.softirq.pending.store(true, Ordering::Release);
+
+            if enabled && !cpu.in_interrupt() {
+                run(cpu);
+            }
+        });
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            name: self.name,
+            priority: self.priority,
+            raised: self.raised.load(Ordering::Relaxed),
+            runs: self.runs.load(Ordering::Relaxed),
+            total_ns: self.total_ns.load(Ordering::Relaxed),
+            max_ns: self.max_ns.load(Ordering::Relaxed),
+        }
+    }
+
+    fn register(&'static self) {
+        if self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let index = COUNT.fetch_add(1, Ordering::AcqRel);
+        assert!(index < MAX_SOFTIRQS, "too many softirqs");
+        REGISTERED[index].store(self as *const _ as *mut _, Ordering::Release);
+    }
+
+    /// Run the softirq if it is pending on `cpu`
+    fn run_on(&self, cpu: usize) {
+        let bit = 1 << cpu;
+        if self.pending.fetch_and(!bit, Ordering::AcqRel) & bit == 0 {
+            return;
+        }
+        let start = Instant::now();
+        (self.func)();
+        let nanos = Instant::now().as_nanos().saturating_sub(start.as_nanos());
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(nanos, Ordering::Relaxed);
+        self.max_ns.fetch_max(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Softirq state of one CPU (part of its `percpu::PerCpu` block)
+pub(crate) struct CpuSoftirq {
+    /// some softirq was raised since the last pass
+    pending: AtomicBool,
+    /// a pass is running (maybe interrupted)
+    running: AtomicBool,
+}
+
+impl CpuSoftirq {
+    pub(crate) const fn new() -> Self {
+        CpuSoftirq {
+            pending: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// A pass is running on this CPU, maybe interrupted
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+}
+
+/// The softirqs raised so far
+pub fn all() -> impl Iterator<Item = &'static Softirq> {
+    let count = COUNT.load(Ordering::Acquire).min(MAX_SOFTIRQS);
+    REGISTERED[..count].iter().filter_map(|softirq| {
+        unsafe { softirq.load(Ordering::Acquire).as_ref() }
+    })
+}
+
+/// Run the softirqs pending on the calling CPU
+///
+/// For code that raised softirqs with interrupts disabled.
+pub fn run_pending() {
+    run(percpu::current());
+}
+
+/// Called by the outermost interrupt handler on its way out (interrupts
+/// are disabled, and again when this returns)
+pub(crate) fn irq_exit(cpu: &PerCpu) {
+    if cpu.softirq.pending.load(Ordering::Acquire) {
+        run(cpu);
+    }
+}
+
+fn run(cpu: &PerCpu) {
+    let state = &cpu.softirq;
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    if state.running.swap(true, Ordering::Acquire) {
+        // a pass below us picks up what was raised
+        if enabled {
+            interrupts::enable();
+        }
+        return;
+    }
+
+    for _ in 0..MAX_RESTARTS {
+        // checked with interrupts disabled, so nothing raised on this CPU
+        // gets lost between here and clearing `running`
+        if !state.pending.swap(false, Ordering::AcqRel) {
+            break;
+        }
+        interrupts::enable();
+        for priority in Priority::ALL {
+            for softirq in all().filter(|softirq| softirq.priority == priority) {
+                softirq.run_on(cpu.cpu_id);
+            }
+        }
+        interrupts::disable();
+    }
+
+    state.running.store(false, Ordering::Release);
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+#[cfg(test)]
+static ORDER: spin::Mutex<[Option<&str>; 3]> = spin::Mutex::new([None; 3]);
+
+#[cfg(test)]
+fn record(name: &'static str) {
+    interrupts::without_interrupts(|| {
+        let mut order = ORDER.lock();
+        if let Some(slot) = order.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(name);
+        }
+    });
+}
+
+#[cfg(test)]
+static LOW: Softirq = Softirq::new("test low", Priority::Low, || record("low"));
+#[cfg(test)]
+static NORMAL: Softirq = Softirq::new("test normal", Priority::Normal, || record("normal"));
+#[cfg(test)]
+static HIGH: Softirq = Softirq::new("test high", Priority::High, || record("high"));
+
+#[test_case]
+fn test_raise_runs_right_away() {
+    let before = NORMAL.stats();
+    NORMAL.raise();
+    let after = NORMAL.stats();
+    assert_eq!(after.raised, before.raised + 1);
+    assert_eq!(after.runs, before.runs + 1);
+    *ORDER.lock() = [None; 3];
+}
+
+#[test_case]
+fn test_priorities_and_merged_raises() {
+    let before = LOW.stats();
+    interrupts::without_interrupts(|| {
+        LOW.raise();
+        LOW.raise();
+        NORMAL.raise();
+        HIGH.raise();
+        assert_eq!(ORDER.lock()[0], None, "softirq ran with interrupts disabled");
+    });
+    run_pending();
+
+    assert_eq!(*ORDER.lock(), [Some("high"), Some("normal"), Some("low")]);
+    let after = LOW.stats();
+    assert_eq!(after.raised, before.raised + 2);
+    assert_eq!(after.runs, before.runs + 1);
+    assert!(all().any(|softirq| softirq.name() == "test high"));
+}
@@ -0,0 +1,117 @@
+//! Kernel symbol table
+//!
+//! The kernel reserves the `.ksyms` section, and `tools/ksyms.sh` (run by
+//! `tools/run.sh`, the cargo runner) fills it with the function symbols of
+//! the linked kernel ELF before the boot image is built. It holds one line
+//! per symbol, sorted by address:
+//!
+//! ```text
+//! <address, 16 hex digits> <size, 16 hex digits> <demangled name>\n
+//! ```
+//!
+//! followed by zeros. A kernel that did not go through the script has an
+//! empty table, and addresses are shown as plain numbers.
+//!
+//! Looking up symbols neither locks nor allocates, so the panic and fault
+//! handlers can use it.
+
+use core::arch::asm;
+use core::fmt;
+
+/// room for the table (the script fails if it does not fit)
+const TABLE_SIZE: usize = 512 * 1024;
+/// length of the address and size fields and the spaces after them
+const NAME_START: usize = 34;
+
+#[used]
+#[link_section = ".ksyms"]
+static TABLE: [u8; TABLE_SIZE] = [0; TABLE_SIZE];
+
+/// A kernel function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub addr: u64,
+    /// 0 if unknown
+    pub size: u64,
+}
+
+/// The filled part of the table
+fn table() -> &'static [u8] {
+    let mut ptr = TABLE.as_ptr();
+    // the compiler sees a table of zeros, the script changes it later
+    unsafe {
+        asm!("/* {} */", inout(reg) ptr, options(nomem, nostack, preserves_flags));
+        let table = core::slice::from_raw_parts(ptr, TABLE_SIZE);
+        let len = table.iter().position(|&byte| byte == 0).unwrap_or(TABLE_SIZE);
+        &table[..len]
+    }
+}
+
+fn parse(line: &'static [u8]) -> Option<Symbol> {
+    let line = core::str::from_utf8(line).ok()?;
+    Some(Symbol {
+        addr: u64::from_str_radix(line.get(0..16)?, 16).ok()?,
+        size: u64::from_str_radix(line.get(17..33)?, 16).ok()?,
+        name: line.get(NAME_START..)?,
+    })
+}
+
+/// All symbols, sorted by address
+pub fn iter() -> impl Iterator<Item = Symbol> {
+    table().split(|&byte| byte == b'\n').filter_map(parse)
+}
+
+/// The table was filled in
+pub fn is_loaded() -> bool {
+    !table().is_empty()
+}
+
+/// The function containing `addr` and the offset of `addr` in it
+///
+/// Symbols without a size extend to the next one.
+pub fn lookup(addr: u64) -> Option<(Symbol, u64)> {
+    let mut found = None;
+    for symbol in iter() {
+        if symbol.addr > addr {
+            break;
+        }
+        if symbol.size == 0 || addr < symbol.addr + symbol.size {
+            found = Some(symbol);
+        } else {
+            found = None;
+        }
+    }
+    found.map(|symbol| (symbol, addr - symbol.addr))
+}
+
+/// The symbol called `name`
+pub fn find(name: &str) -> Option<Symbol> {
+    iter().find(|symbol| symbol.name == name)
+}
+
+/// Shows an address as `name+offset` if it is in a known function
+#[derive(Debug, Clone, Copy)]
+pub struct Location(pub u64);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((symbol, offset)) = lookup(self.0) {
+            write!(f, " {}+{:#x}", symbol.name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_functions_are_found() {
+    // the cargo runner (tools/run.sh) embeds it before booting
+    assert!(is_loaded(), "no symbol table, was the kernel started by tools/run.sh?");
+    let addr = test_functions_are_found as usize as u64;
+    let (symbol, offset) = lookup(addr + 1).expect("test function not found");
+    assert!(symbol.name.ends_with("test_functions_are_found"));
+    assert_eq!(offset, 1);
+    assert_eq!(find(symbol.name), Some(symbol));
+    assert!(iter().zip(iter().skip(1)).all(|(a, b)| a.addr <= b.addr));
+}
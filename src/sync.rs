@@ -0,0 +1,498 @@
+//! Spinlocks that disable interrupts while held
+//!
+//! A lock that an interrupt handler takes deadlocks if the handler
+//! interrupts its holder on the same CPU. `IrqMutex` and `IrqRwLock` save
+//! the interrupt flag and disable interrupts on acquire and restore it when
+//! the guard is dropped, so callers need no `without_interrupts`.
+//!
+//! In debug builds they remember the CPU and the call site of the holder
+//! (the writer, for `IrqRwLock`, which also counts the readers of every
+//! CPU) and panic when that CPU tries to acquire the lock again, naming
+//! both call sites, where the plain lock would spin forever. That can
+//! still happen from NMIs and exceptions, which `try_lock` is for.
+//!
+//! `SpinMutex` is the same without touching interrupts, for locks that are
+//! only ever taken with interrupts disabled anyway. Every lock has a
+//! `lockdep::Class`; name the global ones (`named`) for lockdep reports.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU8, Ordering};
+use crate::{lockdep::Class, percpu, smp::MAX_CPUS};
+use x86_64::instructions::interrupts;
+
+const NO_CPU: u32 = u32::MAX;
+const NO_READS: AtomicU8 = AtomicU8::new(0);
+
+/// Interrupt flag saved by a guard
+struct IrqState {
+    enabled: bool,
+    /// the guard must be dropped on the CPU that took it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl IrqState {
+    fn save() -> Self {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqState { enabled, _not_send: PhantomData }
+    }
+
+    fn restore(&self) {
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Index of the calling CPU in debug builds, `NO_CPU` in release builds
+///
+/// 0 until `gdt::init` sets up the per-CPU block of the BSP (the first
+/// `println!` comes earlier), when no other CPU runs yet.
+fn current_cpu() -> u32 {
+    if !cfg!(debug_assertions) {
+        NO_CPU
+    } else if percpu::is_ready() {
+        percpu::current().cpu_id as u32
+    } else {
+        0
+    }
+}
+
+/// Holder of a lock, tracked in debug builds only
+struct Owner {
+    cpu: AtomicU32,
+    location: AtomicPtr<Location<'static>>,
+}
+
+impl Owner {
+    const fn new() -> Self {
+        Owner {
+            cpu: AtomicU32::new(NO_CPU),
+            location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn is_held_by(&self, cpu: u32) -> bool {
+        cpu != NO_CPU && self.cpu.load(Ordering::Relaxed) == cpu
+    }
+
+    /// Panic if `cpu`, the calling CPU, holds the lock
+    ///
+    /// Interrupts are disabled, so the holder cannot change under us.
+    #[track_caller]
+    fn check_recursion(&self, kind: &str, cpu: u32) {
+        if self.is_held_by(cpu) {
+            let held = unsafe { self.location.load(Ordering::Relaxed).as_ref() };
+            panic!("recursive {} at {} on CPU {}, already held since {}",
+                   kind, Location::caller(), cpu, OrUnknown(held));
+        }
+    }
+
+    fn set(&self, location: &'static Location<'static>, cpu: u32) {
+        if cpu != NO_CPU {
+            self.location.store(location as *const _ as *mut _, Ordering::Relaxed);
+            self.cpu.store(cpu, Ordering::Relaxed);
+        }
+    }
+
+    fn clear(&self) {
+        if cfg!(debug_assertions) {
+            self.cpu.store(NO_CPU, Ordering::Relaxed);
+            self.location.store(ptr::null_mut(), Ordering::Relaxed);
+        }
+    }
+}
+
+struct OrUnknown(Option<&'static Location<'static>>);
+
+impl fmt::Display for OrUnknown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(location) => write!(f, "{}", location),
+            None => f.write_str("<unknown>"),
+        }
+    }
+}
+
+/// A spinlock that keeps interrupts disabled while it is held
+pub struct IrqMutex<T: ?Sized> {
+    class: Class,
+    owner: Owner,
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: ?Sized> {
+    lock: &'a IrqMutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    irq: IrqState,
+    /// taken with `lock`, `read` or `write`, which lockdep saw; a `try_`
+    /// is not tracked, so its release must not be either
+    tracked: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::named("IrqMutex", value)
+    }
+
+    pub const fn named(name: &'static str, value: T) -> Self {
+        IrqMutex { class: Class::new(name), owner: Owner::new(), inner: spin::Mutex::new(value) }
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    /// Disable interrupts and spin until the lock is ours
+    ///
+    /// Panics in debug builds if the calling CPU holds it already.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let irq = IrqState::save();
+        let cpu = current_cpu();
+        self.owner.check_recursion("IrqMutex::lock", cpu);
+        let guard = self.inner.lock();
+        self.owner.set(Location::caller(), cpu);
+        self.class.acquired(Location::caller(), false);
+        IrqMutexGuard { lock: self, guard: ManuallyDrop::new(guard), irq, tracked: true }
+    }
+
+    /// Take the lock if it is free, without waiting
+    ///
+    /// Never panics, for code that may have interrupted the holder. A try
+    /// cannot deadlock, so lockdep does not look at it.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let irq = IrqState::save();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                self.owner.set(Location::caller(), current_cpu());
+                let guard = ManuallyDrop::new(guard);
+                Some(IrqMutexGuard { lock: self, guard, irq, tracked: false })
+            }
+            None => {
+                irq.restore();
+                None
+            }
+        }
+    }
+
+    /// Does the calling CPU hold the lock? Always false in release builds.
+    pub fn is_held_by_current_cpu(&self) -> bool {
+        self.owner.is_held_by(current_cpu())
+    }
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.tracked {
+            self.lock.class.released();
+        }
+        self.lock.owner.clear();
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        self.irq.restore();
+    }
+}
+
+/// A reader-writer spinlock that keeps interrupts disabled while it is held
+///
+/// In debug builds a CPU that holds it for reading and asks to write
+/// panics too.
+pub struct IrqRwLock<T: ?Sized> {
+    class: Class,
+    writer: Owner,
+    /// read locks held by each CPU (nested reads count twice)
+    readers: [AtomicU8; MAX_CPUS],
+    inner: spin::RwLock<T>,
+}
+
+pub struct IrqReadGuard<'a, T: ?Sized> {
+    lock: &'a IrqRwLock<T>,
+    guard: ManuallyDrop<spin::RwLockReadGuard<'a, T>>,
+    irq: IrqState,
+    tracked: bool,
+}
+
+pub struct IrqWriteGuard<'a, T: ?Sized> {
+    lock: &'a IrqRwLock<T>,
+    guard: ManuallyDrop<spin::RwLockWriteGuard<'a, T>>,
+    irq: IrqState,
+    tracked: bool,
+}
+
+impl<T> IrqRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::named("IrqRwLock", value)
+    }
+
+    pub const fn named(name: &'static str, value: T) -> Self {
+        IrqRwLock {
+            class: Class::new(name),
+            writer: Owner::new(),
+            readers: [NO_READS; MAX_CPUS],
+            inner: spin::RwLock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqRwLock<T> {
+    /// Disable interrupts and spin until there is no writer
+    ///
+    /// Panics in debug builds if the calling CPU holds the write lock.
+    #[track_caller]
+    pub fn read(&self) -> IrqReadGuard<T> {
+        let irq = IrqState::save();
+        let cpu = current_cpu();
+        self.writer.check_recursion("IrqRwLock::read", cpu);
+        let guard = self.inner.read();
+        self.add_reader(cpu, true);
+        self.class.acquired(Location::caller(), false);
+        IrqReadGuard { lock: self, guard: ManuallyDrop::new(guard), irq, tracked: true }
+    }
+
+    /// Disable interrupts and spin until the lock is ours alone
+    ///
+    /// Panics in debug builds if the calling CPU holds it already.
+    #[track_caller]
+    pub fn write(&self) -> IrqWriteGuard<T> {
+        let irq = IrqState::save();
+        let cpu = current_cpu();
+        self.writer.check_recursion("IrqRwLock::write", cpu);
+        if self.reads_of(cpu) != 0 {
+            panic!("IrqRwLock::write at {} on CPU {} while holding a read lock",
+                   Location::caller(), cpu);
+        }
+        let guard = self.inner.write();
+        self.writer.set(Location::caller(), cpu);
+        self.class.acquired(Location::caller(), false);
+        IrqWriteGuard { lock: self, guard: ManuallyDrop::new(guard), irq, tracked: true }
+    }
+
+    pub fn try_read(&self) -> Option<IrqReadGuard<T>> {
+        let irq = IrqState::save();
+        match self.inner.try_read() {
+            Some(guard) => {
+                self.add_reader(current_cpu(), true);
+                let guard = ManuallyDrop::new(guard);
+                Some(IrqReadGuard { lock: self, guard, irq, tracked: false })
+            }
+            None => {
+                irq.restore();
+                None
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<IrqWriteGuard<T>> {
+        let irq = IrqState::save();
+        match self.inner.try_write() {
+            Some(guard) => {
+                self.writer.set(Location::caller(), current_cpu());
+                let guard = ManuallyDrop::new(guard);
+                Some(IrqWriteGuard { lock: self, guard, irq, tracked: false })
+            }
+            None => {
+                irq.restore();
+                None
+            }
+        }
+    }
+
+    /// Read locks `cpu` holds, 0 in release builds
+    fn reads_of(&self, cpu: u32) -> u8 {
+        self.readers.get(cpu as usize).map_or(0, |reads| reads.load(Ordering::Relaxed))
+    }
+
+    /// Count a read lock of `cpu` taken or released
+    fn add_reader(&self, cpu: u32, taken: bool) {
+        if let Some(reads) = self.readers.get(cpu as usize) {
+            if taken {
+                reads.fetch_add(1, Ordering::Relaxed);
+            } else {
+                reads.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqReadGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.tracked {
+            self.lock.class.released();
+        }
+        self.lock.add_reader(current_cpu(), false);
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        self.irq.restore();
+    }
+}
+
+impl<T: ?Sized> Deref for IrqWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.tracked {
+            self.lock.class.released();
+        }
+        self.lock.writer.clear();
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        self.irq.restore();
+    }
+}
+
+/// A spinlock that leaves interrupts alone, with the checks of `IrqMutex`
+///
+/// For locks that interrupt handlers take and that everybody else takes
+/// with interrupts disabled (lockdep checks that).
+pub struct SpinMutex<T: ?Sized> {
+    class: Class,
+    owner: Owner,
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinMutexGuard<'a, T: ?Sized> {
+    lock: &'a SpinMutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    tracked: bool,
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::named("SpinMutex", value)
+    }
+
+    pub const fn named(name: &'static str, value: T) -> Self {
+        SpinMutex { class: Class::new(name), owner: Owner::new(), inner: spin::Mutex::new(value) }
+    }
+}
+
+impl<T: ?Sized> SpinMutex<T> {
+    /// Spin until the lock is ours
+    ///
+    /// Panics in debug builds if the calling CPU holds it already (with
+    /// interrupts enabled, that may also be an interrupt handler that
+    /// interrupted the holder).
+    #[track_caller]
+    pub fn lock(&self) -> SpinMutexGuard<T> {
+        let cpu = current_cpu();
+        self.owner.check_recursion("SpinMutex::lock", cpu);
+        let guard = self.inner.lock();
+        self.owner.set(Location::caller(), cpu);
+        self.class.acquired(Location::caller(), interrupts::are_enabled());
+        SpinMutexGuard { lock: self, guard: ManuallyDrop::new(guard), tracked: true }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        self.owner.set(Location::caller(), current_cpu());
+        Some(SpinMutexGuard { lock: self, guard: ManuallyDrop::new(guard), tracked: false })
+    }
+}
+
+impl<T: ?Sized> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for SpinMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.tracked {
+            self.lock.class.released();
+        }
+        self.lock.owner.clear();
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+    }
+}
+
+#[test_case]
+fn test_lock_disables_interrupts() {
+    let lock = IrqMutex::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut value = lock.lock();
+        *value += 1;
+        assert!(!interrupts::are_enabled());
+        assert_eq!(lock.is_held_by_current_cpu(), cfg!(debug_assertions));
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+    assert!(!lock.is_held_by_current_cpu());
+    assert_eq!(*lock.try_lock().unwrap(), 1);
+}
+
+#[test_case]
+fn test_nested_locks_restore_interrupts() {
+    let outer = IrqMutex::new(());
+    let inner = IrqRwLock::new(());
+    let outer_guard = outer.lock();
+    {
+        let _read = inner.read();
+        let _another = inner.read();
+    }
+    assert!(!interrupts::are_enabled(), "inner guard enabled interrupts");
+    let _write = inner.try_write().expect("write lock while unlocked");
+    drop(_write);
+    drop(outer_guard);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_nested_reads_are_counted() {
+    let lock = IrqRwLock::new(());
+    let cpu = current_cpu();
+    let expected = |reads: u8| if cfg!(debug_assertions) { reads } else { 0 };
+    let first = lock.read();
+    let second = lock.read();
+    assert_eq!(lock.reads_of(cpu), expected(2));
+    drop(second);
+    assert_eq!(lock.reads_of(cpu), expected(1));
+    drop(first);
+    assert_eq!(lock.reads_of(cpu), 0);
+}
@@ -0,0 +1,395 @@
+//! System calls
+//!
+//! User code enters the kernel with `SYSCALL`: the number goes in `rax`,
+//! up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, and
+//! the result comes back in `rax`. Negative results are errors (`-EFAULT`
+//! and so on, as on Linux). `rcx` and `r11` are clobbered by the
+//! instruction, and the vector registers by the kernel.
+//!
+//! The entry code is in `user/entry.s`; it runs `syscall_dispatch` on the
+//! kernel stack of the thread with interrupts enabled.
+
+use crate::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::time::{Duration, Instant, SystemTime};
+use alloc::{sync::Arc, vec::Vec};
+use core::mem::size_of;
+use crate::ipc::{self, Endpoint, Message, TryRecvError};
+use crate::process::{self, Capability, Handle, HandleError, HandleTable, Process, Rights};
+use crate::{print, thread, user};
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+extern "C" {
+    fn syscall_entry();
+}
+
+pub const WRITE: u64 = 0;
+pub const EXIT: u64 = 1;
+pub const SLEEP: u64 = 2;
+pub const YIELD: u64 = 3;
+pub const TIME: u64 = 4;
+pub const GETPID: u64 = 5;
+pub const CHANNEL: u64 = 6;
+pub const SEND: u64 = 7;
+pub const RECV: u64 = 8;
+pub const CALL: u64 = 9;
+pub const REPLY: u64 = 10;
+pub const CLOSE: u64 = 11;
+
+/// clocks for `TIME`
+pub const CLOCK_MONOTONIC: u64 = 0;
+pub const CLOCK_REALTIME: u64 = 1;
+
+pub const EBADF: i64 = 9;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EINVAL: i64 = 22;
+pub const EPIPE: i64 = 32;
+pub const ENOSYS: i64 = 38;
+pub const EMSGSIZE: i64 = 90;
+
+/// `MessageBuf::reply` when there is no reply endpoint
+pub const NO_HANDLE: u64 = u64::MAX;
+
+/// A message in user memory, for the IPC syscalls
+///
+/// `data` points at `data_len` bytes, `handles` at `handles_len` 32-bit
+/// handles. For `RECV` (and the answer of `CALL`) the lengths give the
+/// room in the buffers and are set to what arrived; `reply` is set to the
+/// handle to answer a `CALL` with, or `NO_HANDLE`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MessageBuf {
+    pub data: u64,
+    pub data_len: u64,
+    pub handles: u64,
+    pub handles_len: u64,
+    pub reply: u64,
+}
+
+/// Registers saved by the entry code, in the order they are pushed
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+type Handler = fn(&mut SyscallFrame) -> i64;
+
+/// indexed by syscall number
+static TABLE: [Handler; 12] = [
+    sys_write, sys_exit, sys_sleep, sys_yield, sys_time, sys_getpid,
+    sys_channel, sys_send, sys_recv, sys_call, sys_reply, sys_close,
+];
+
+/// Enable `SYSCALL` on the calling CPU
+pub fn init() {
+    Star::write(USER_CODE_SELECTOR, USER_DATA_SELECTOR,
+                KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR)
+        .expect("GDT layout does not fit SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // enter with interrupts off (until the kernel stack is set up) and
+    // sane flags for kernel code
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG
+                  | RFlags::TRAP_FLAG | RFlags::ALIGNMENT_CHECK);
+    unsafe { Efer::update(|efer| efer.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) -> i64 {
+    match TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => -ENOSYS,
+    }
+}
+
+/// write(ptr, len): print UTF-8 text on the console, returns `len`
+fn sys_write(frame: &mut SyscallFrame) -> i64 {
+    let (ptr, len) = (frame.rdi, frame.rsi);
+    if !user::check_access(ptr, len, false) {
+        return -EFAULT;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    match core::str::from_utf8(bytes) {
+        Ok(text) => {
+            print!("{}", text);
+            len as i64
+        }
+        Err(_) => -EINVAL,
+    }
+}
+
+/// exit(code): never returns; `user::run` returns `code`
+fn sys_exit(frame: &mut SyscallFrame) -> i64 {
+    user::exit(frame.rdi as i64)
+}
+
+/// sleep(nanoseconds)
+fn sys_sleep(frame: &mut SyscallFrame) -> i64 {
+    thread::sleep(Duration::from_nanos(frame.rdi));
+    0
+}
+
+/// yield()
+fn sys_yield(_frame: &mut SyscallFrame) -> i64 {
+    thread::yield_now();
+    0
+}
+
+/// time(clock): nanoseconds since boot or since the Unix epoch
+fn sys_time(frame: &mut SyscallFrame) -> i64 {
+    let nanos = match frame.rdi {
+        CLOCK_MONOTONIC => Instant::now().since_boot().as_nanos(),
+        CLOCK_REALTIME => match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Some(since_epoch) => since_epoch.as_nanos(),
+            None => return -EINVAL,
+        },
+        _ => return -EINVAL,
+    };
+    i64::try_from(nanos).unwrap_or(i64::MAX)
+}
+
+/// getpid(): id of the calling process
+fn sys_getpid(_frame: &mut SyscallFrame) -> i64 {
+    process::current_pid().map_or(0, |pid| pid.as_u64() as i64)
+}
+
+/// Turn the result of a handler written with `?` into a syscall result
+fn flatten(result: Result<i64, i64>) -> i64 {
+    result.unwrap_or_else(|error| error)
+}
+
+/// Read a `T` from user memory
+fn read_user<T: Copy>(addr: u64) -> Result<T, i64> {
+    if !user::check_access(addr, size_of::<T>() as u64, false) {
+        return Err(-EFAULT);
+    }
+    Ok(unsafe { (addr as *const T).read_unaligned() })
+}
+
+/// Write a `T` to user memory
+fn write_user<T: Copy>(addr: u64, value: T) -> Result<(), i64> {
+    if !user::check_access(addr, size_of::<T>() as u64, true) {
+        return Err(-EFAULT);
+    }
+    unsafe { (addr as *mut T).write_unaligned(value) };
+    Ok(())
+}
+
+fn handle_error(error: HandleError) -> i64 {
+    match error {
+        HandleError::BadHandle => -EBADF,
+        HandleError::WrongType => -EINVAL,
+        HandleError::AccessDenied => -EACCES,
+    }
+}
+
+fn current_process() -> Result<Arc<Process>, i64> {
+    process::current().ok_or(-EBADF)
+}
+
+/// Run `f` with the handle table of `process`, which must not have exited
+fn with_handles<R>(process: &Process, f: impl FnOnce(&mut HandleTable) -> R) -> Result<R, i64> {
+    process.with_handles(f).ok_or(-EBADF)
+}
+
+fn endpoint(process: &Process, handle: u64, rights: Rights) -> Result<Arc<Endpoint>, i64> {
+    let handle = u32::try_from(handle).map_err(|_| -EBADF)?;
+    with_handles(process, |handles| handles.get_as(Handle::from_raw(handle), rights))?
+        .map_err(handle_error)
+}
+
+/// Build a message from `buf`, taking the handles it lists out of the
+/// handle table of `process` (they are gone even if sending fails)
+fn take_message(process: &Process, buf: &MessageBuf) -> Result<Message, i64> {
+    if buf.data_len > ipc::MAX_DATA as u64 || buf.handles_len > ipc::MAX_HANDLES as u64 {
+        return Err(-EMSGSIZE);
+    }
+    if !user::check_access(buf.data, buf.data_len, false)
+        || !user::check_access(buf.handles, buf.handles_len * 4, false)
+    {
+        return Err(-EFAULT);
+    }
+    let data = unsafe {
+        core::slice::from_raw_parts(buf.data as *const u8, buf.data_len as usize)
+    };
+    let raw: Vec<u32> = (0..buf.handles_len)
+        .map(|i| unsafe { (buf.handles as *const u32).add(i as usize).read_unaligned() })
+        .collect();
+
+    let capabilities: Vec<Capability> = with_handles(process, |handles| {
+        for (i, &handle) in raw.iter().enumerate() {
+            let capability = handles.get(Handle::from_raw(handle)).ok_or(-EBADF)?;
+            if !capability.rights.contains(Rights::TRANSFER) {
+                return Err(-EACCES);
+            }
+            if raw[..i].contains(&handle) {
+                return Err(-EINVAL);
+            }
+        }
+        Ok(raw.iter().map(|&handle| handles.remove(Handle::from_raw(handle)).unwrap())
+            .collect())
+    })??;
+    Ok(Message::with_handles(data.into(), capabilities))
+}
+
+/// Copy `message` into the buffers described by the `MessageBuf` at
+/// `buf_addr` and its capabilities into the handle table of `process`
+fn deliver(process: &Process, buf_addr: u64, buf: &MessageBuf, mut message: Message)
+    -> Result<i64, i64>
+{
+    let data_len = message.data.len() as u64;
+    let handles_len = message.handles.len() as u64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(message.data.as_ptr(), buf.data as *mut u8,
+                                       message.data.len());
+    }
+    let reply = message.take_reply();
+    with_handles(process, |handles| {
+        for (i, capability) in message.handles.drain(..).enumerate() {
+            let handle = handles.insert(capability);
+            unsafe { (buf.handles as *mut u32).add(i).write_unaligned(handle.as_u32()) };
+        }
+    })?;
+    let reply = match reply {
+        Some(endpoint) => with_handles(process, |handles| {
+            handles.insert(Capability::new(Arc::new(endpoint), Rights::SEND)).as_u32() as u64
+        })?,
+        None => NO_HANDLE,
+    };
+    write_user(buf_addr, MessageBuf { data_len, handles_len, reply, ..*buf })?;
+    Ok(data_len as i64)
+}
+
+/// Check that the buffers of a `MessageBuf` for receiving are writable
+fn check_receive_buffers(buf: &MessageBuf) -> Result<(), i64> {
+    let handles_size = buf.handles_len.checked_mul(4).ok_or(-EFAULT)?;
+    if user::check_access(buf.data, buf.data_len, true)
+        && user::check_access(buf.handles, handles_size, true)
+    {
+        Ok(())
+    } else {
+        Err(-EFAULT)
+    }
+}
+
+/// channel(handles: *mut [u32; 2]): create a channel, returns 0
+fn sys_channel(frame: &mut SyscallFrame) -> i64 {
+    flatten((|| {
+        let process = current_process()?;
+        // fail before creating anything
+        write_user(frame.rdi, [0u32; 2])?;
+        let (a, b) = ipc::channel();
+        let pair = with_handles(&process, |handles| [
+            handles.insert(Capability::new(Arc::new(a), Rights::ALL)).as_u32(),
+            handles.insert(Capability::new(Arc::new(b), Rights::ALL)).as_u32(),
+        ])?;
+        write_user(frame.rdi, pair)?;
+        Ok(0)
+    })())
+}
+
+/// send(handle, buf: *const MessageBuf): send, waiting for room; returns 0
+fn sys_send(frame: &mut SyscallFrame) -> i64 {
+    flatten((|| {
+        let process = current_process()?;
+        let endpoint = endpoint(&process, frame.rdi, Rights::SEND)?;
+        let buf: MessageBuf = read_user(frame.rsi)?;
+        let message = take_message(&process, &buf)?;
+        drop(process);
+        endpoint.send(message).map_err(|_| -EPIPE)?;
+        Ok(0)
+    })())
+}
+
+/// recv(handle, buf: *mut MessageBuf): wait for a message, returns the
+/// number of bytes; `-EMSGSIZE` with the needed lengths in `buf` if it
+/// does not fit (the message stays queued)
+fn sys_recv(frame: &mut SyscallFrame) -> i64 {
+    flatten((|| {
+        let process = current_process()?;
+        let endpoint = endpoint(&process, frame.rdi, Rights::RECEIVE)?;
+        let buf: MessageBuf = read_user(frame.rsi)?;
+        check_receive_buffers(&buf)?;
+        match endpoint.recv_limited(buf.data_len as usize, buf.handles_len as usize) {
+            Ok(message) => deliver(&process, frame.rsi, &buf, message),
+            Err(TryRecvError::TooLarge { data, handles }) => {
+                let needed = MessageBuf {
+                    data_len: data as u64,
+                    handles_len: handles as u64,
+                    reply: NO_HANDLE,
+                    ..buf
+                };
+                write_user(frame.rsi, needed)?;
+                Err(-EMSGSIZE)
+            }
+            Err(_) => Err(-EPIPE),
+        }
+    })())
+}
+
+/// call(handle, request: *const MessageBuf, answer: *mut MessageBuf):
+/// send a request and wait for the answer; returns its number of bytes
+/// (an answer too large for the buffers is lost, with `-EMSGSIZE`)
+fn sys_call(frame: &mut SyscallFrame) -> i64 {
+    flatten((|| {
+        let process = current_process()?;
+        let endpoint = endpoint(&process, frame.rdi, Rights::SEND)?;
+        let request: MessageBuf = read_user(frame.rsi)?;
+        let answer_buf: MessageBuf = read_user(frame.rdx)?;
+        check_receive_buffers(&answer_buf)?;
+        let request = take_message(&process, &request)?;
+        let answer = endpoint.call(request).map_err(|_| -EPIPE)?;
+        if answer.data.len() as u64 > answer_buf.data_len
+            || answer.handles.len() as u64 > answer_buf.handles_len
+        {
+            return Err(-EMSGSIZE);
+        }
+        deliver(&process, frame.rdx, &answer_buf, answer)
+    })())
+}
+
+/// reply(reply_handle, answer: *const MessageBuf): answer a call and
+/// close the reply handle; returns 0
+fn sys_reply(frame: &mut SyscallFrame) -> i64 {
+    flatten((|| {
+        let process = current_process()?;
+        let endpoint = endpoint(&process, frame.rdi, Rights::SEND)?;
+        let buf: MessageBuf = read_user(frame.rsi)?;
+        let answer = take_message(&process, &buf)?;
+        with_handles(&process, |handles| handles.remove(Handle::from_raw(frame.rdi as u32)))?;
+        endpoint.try_send(answer).map_err(|_| -EPIPE)?;
+        Ok(0)
+    })())
+}
+
+/// close(handle), returns 0
+fn sys_close(frame: &mut SyscallFrame) -> i64 {
+    flatten((|| {
+        let process = current_process()?;
+        let handle = u32::try_from(frame.rdi).map_err(|_| -EBADF)?;
+        with_handles(&process, |handles| handles.remove(Handle::from_raw(handle)))?
+            .ok_or(-EBADF)?;
+        Ok(0)
+    })())
+}
@@ -1,111 +1,256 @@
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+//! Multi-core executor
+//!
+//! Every core that runs the executor has its own run queue. A woken task
+//! goes back onto the queue of its home core (the core that polled it
+//! last). A core whose queue is empty steals a task from another core
+//! before it halts, and halted cores are woken with an IPI when there is
+//! work for them.
+//!
+//! The BSP runs the executor in `Executor::run`; the application
+//! processors poll tasks from their idle loop (see `smp`) once it started,
+//! between the work sent to them with `smp::run_on`.
+//!
+//! The executor can run as a kernel thread (see `thread`): instead of
+//! halting, an idle core first lets ready threads run.
+
+use alloc::{sync::Arc, task::Wake, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
+use crate::{interrupts::InterruptIndex, percpu, smp, thread};
 use super::{Task, TaskId};
 
-pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+/// capacity of each run queue
+const QUEUE_SIZE: usize = 100;
+
+static CORES: OnceCell<Vec<Core>> = OnceCell::uninit();
+/// `Executor::run` was called
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Scheduling state of one core
+struct Core {
+    queue: ArrayQueue<Arc<TaskWaker>>,
+    /// halted (or about to halt) waiting for work
+    idle: AtomicBool,
 }
 
+fn cores() -> &'static [Core] {
+    CORES.get_or_init(|| {
+        (0..smp::MAX_CPUS)
+            .map(|_| Core {
+                queue: ArrayQueue::new(QUEUE_SIZE),
+                idle: AtomicBool::new(false),
+            })
+            .collect()
+    })
+}
+
+#[derive(Default)]
+pub struct Executor;
+
 impl Executor {
     pub fn new() -> Self {
-        Self {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
-        }
+        cores();
+        Executor
     }
 
+    /// Let the application processors poll tasks and run the executor on
+    /// the calling core
+    ///
+    /// Should be called after `smp::init` on the BSP.
     pub fn run(&mut self) -> ! {
+        STARTED.store(true, Ordering::SeqCst);
+        for cpu in 1..smp::cpu_count() {
+            if smp::is_online(cpu) {
+                crate::apic::send_ipi(smp::apic_id(cpu), InterruptIndex::Wakeup.as_u8());
+            }
+        }
         loop {
-            self.run_ready_tasks();
-            self.sleep_if_idle();
+            poll_ready();
+            sleep_if_idle(|| false);
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
-            }
-        }
+    /// Queue `task` on the calling core
+    pub fn spawn(&mut self, task: Task) {
+        let task = Arc::new(TaskWaker {
+            task_id: task.id,
+            task: spin::Mutex::new(Some(task)),
+            home: AtomicUsize::new(smp::current_cpu()),
+            state: AtomicU8::new(IDLE),
+        });
+        task.wake_task();
     }
+}
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task_id, task).is_some() {
-            panic!("task with same ID already in tasks");
-        }
-        self.task_queue.push(task_id).expect("queue full");
+/// Poll the tasks of the calling core, and those it can steal, until there
+/// are none; nothing before `Executor::run`
+pub(crate) fn poll_ready() {
+    if !STARTED.load(Ordering::SeqCst) {
+        return;
+    }
+    let me = smp::current_cpu();
+    let core = &cores()[me];
+    while let Some(task) = core.queue.pop().ok().or_else(|| steal(me)) {
+        run_task(task, me);
     }
+}
 
-    fn display_thread_is_running(running: bool) {
-        use crate::vga::text::{self, Color};
-        let green = text::Attribute::new(Color::LightGreen, Color::Black);
-        let cyan = text::Attribute::new(Color::LightCyan, Color::Black);
-        let gray = text::Attribute::new(Color::DarkGray, Color::Black);
+/// Take a task from the queue of another core
+fn steal(me: usize) -> Option<Arc<TaskWaker>> {
+    let cores = cores();
+    let count = smp::cpu_count();
+    // start at the next core so not everyone robs the BSP first
+    (1..count)
+        .map(|offset| (me + offset) % count)
+        .find_map(|victim| cores[victim].queue.pop().ok())
+}
 
-        if running {
-            text::display("[    / SLEEP]", (1, 68), gray);
-            text::display("RUN", (1, 69), green);
-        } else {
-            text::display("[RUN /      ]", (1, 68), gray);
-            text::display("SLEEP", (1, 75), cyan);
-        }
+fn run_task(task: Arc<TaskWaker>, me: usize) {
+    // wakes from now on mark the task instead of queueing it
+    if task.state.compare_exchange(QUEUED, RUNNING, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return; // task already completed
     }
+    task.home.store(me, Ordering::Relaxed);
 
-    fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts;
+    // only the core that set `RUNNING` touches the future, so the lock is
+    // not held while polling
+    let mut inner = task.task.lock().take().expect("task polled twice at once");
+    let waker = Waker::from(task.clone());
+    let mut context = Context::from_waker(&waker);
+    percpu!(stats.polls).fetch_add(1, Ordering::Relaxed);
+    percpu!(current_task).store(task.task_id.0, Ordering::Relaxed);
+    let poll = inner.poll(&mut context);
+    percpu!(current_task).store(percpu::NO_TASK, Ordering::Relaxed);
+    if let Poll::Ready(()) = poll {
+        // task done -> drop its future
+        task.state.store(DONE, Ordering::SeqCst);
+        return;
+    }
 
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            Self::display_thread_is_running(false);
-            interrupts::enable_and_hlt();
-            Self::display_thread_is_running(true);
-        } else {
-            interrupts::enable();
+    *task.task.lock() = Some(inner);
+    if task.state.compare_exchange(RUNNING, IDLE, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        // woken while it was polled
+        task.state.store(IDLE, Ordering::SeqCst);
+        task.wake_task();
+    }
+}
+
+fn display_thread_is_running(running: bool) {
+    use crate::vga::text::{self, Color};
+    let green = text::Attribute::new(Color::LightGreen, Color::Black);
+    let cyan = text::Attribute::new(Color::LightCyan, Color::Black);
+    let gray = text::Attribute::new(Color::DarkGray, Color::Black);
+
+    if running {
+        text::display("[    / SLEEP]", (1, 68), gray);
+        text::display("RUN", (1, 69), green);
+    } else {
+        text::display("[RUN /      ]", (1, 68), gray);
+        text::display("SLEEP", (1, 75), cyan);
+    }
+}
+
+/// Halt the calling core until the next interrupt, unless there are tasks
+/// to poll or `pending` (other work of the caller) returns true
+pub(crate) fn sleep_if_idle(pending: impl Fn() -> bool) {
+    use x86_64::instructions::interrupts;
+
+    // when running as a kernel thread, let other threads have the CPU
+    if thread::yield_if_ready() {
+        return;
+    }
+    let me = smp::current_cpu();
+    let core = &cores()[me];
+    // publish `idle` before checking for work, wakers do the reverse
+    core.idle.store(true, Ordering::SeqCst);
+    interrupts::disable();
+    let tasks = STARTED.load(Ordering::SeqCst)
+        && (!core.queue.is_empty() || work_elsewhere(me));
+    if !tasks && !pending() {
+        if me == 0 {
+            display_thread_is_running(false);
         }
+        interrupts::enable_and_hlt();
+        if me == 0 {
+            display_thread_is_running(true);
+        }
+    } else {
+        interrupts::enable();
     }
+    core.idle.store(false, Ordering::SeqCst);
+}
+
+/// Whether another core has tasks waiting
+fn work_elsewhere(me: usize) -> bool {
+    let cores = cores();
+    (0..smp::cpu_count()).any(|cpu| cpu != me && !cores[cpu].queue.is_empty())
 }
 
+/// `TaskWaker::state`: waiting to be woken
+const IDLE: u8 = 0;
+/// in a run queue
+const QUEUED: u8 = 1;
+/// being polled
+const RUNNING: u8 = 2;
+/// woken while being polled; the polling core queues it again
+const RUNNING_WOKEN: u8 = 3;
+/// completed
+const DONE: u8 = 4;
+
+/// A spawned task together with its scheduling state
+///
+/// It is its own waker, so waking a task never allocates.
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// `None` while being polled and once the task has completed
+    task: spin::Mutex<Option<Task>>,
+    /// core whose queue the task goes back onto when woken
+    home: AtomicUsize,
+    state: AtomicU8,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
-    }
+    fn wake_task(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::SeqCst);
+        loop {
+            let next = match state {
+                IDLE => QUEUED,
+                RUNNING => RUNNING_WOKEN,
+                _ => return, // queued already, or done
+            };
+            match self.state.compare_exchange(state, next, Ordering::SeqCst,
+                                              Ordering::SeqCst) {
+                Ok(_) if next == QUEUED => break,
+                Ok(_) => return,
+                Err(actual) => state = actual,
+            }
+        }
+        let cores = cores();
+        let home = self.home.load(Ordering::Relaxed);
+        if cores[home].queue.push(self.clone()).is_err() {
+            panic!("run queue of core {} full", home);
+        }
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id)
-            .expect("task_queue full");
+        // a halted home core needs an IPI; if it is busy, an idle core
+        // may steal the task instead
+        let target = if cores[home].idle.load(Ordering::SeqCst) {
+            Some(home)
+        } else {
+            (0..smp::cpu_count())
+                .find(|&cpu| cores[cpu].idle.load(Ordering::SeqCst))
+        };
+        if let Some(cpu) = target {
+            if cpu != smp::current_cpu() {
+                crate::apic::send_ipi(smp::apic_id(cpu),
+                                      InterruptIndex::Wakeup.as_u8());
+            }
+        }
     }
 }
 
@@ -1,9 +1,10 @@
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
-use crate::{print, println};
+use crate::{print, println, softirq::{Priority, Softirq}};
 use crossbeam_queue::ArrayQueue;
 use futures_util::{
     stream::{Stream, StreamExt},
@@ -13,19 +14,35 @@ use pc_keyboard::{DecodedKey, HandleControl, Keyboard, layouts, ScancodeSet1};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
+/// scancodes dropped since the last warning
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static WARN_DROPPED: Softirq = Softirq::new("keyboard", Priority::Normal, warn_dropped);
 
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
-        }
+    let pushed = match SCANCODE_QUEUE.try_get() {
+        Ok(queue) => queue.push(scancode).is_ok(),
+        Err(_) => false,
+    };
+    if pushed {
+        WAKER.wake();
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        WARN_DROPPED.raise();
+    }
+}
+
+fn warn_dropped() {
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped == 0 {
+        return;
+    }
+    if SCANCODE_QUEUE.is_initialized() {
+        println!("WARNING: scancode queue full; dropped {} keyboard scancodes", dropped);
     } else {
-        println!("WARNING: scancode queue uninitialized");
+        println!("WARNING: scancode queue uninitialized; dropped {} scancodes", dropped);
     }
 }
 
@@ -0,0 +1,255 @@
+/* High Precision Event Timer
+ *
+ * A free running 64-bit main counter (at least 10 MHz) plus a number of
+ * comparators. Each comparator fires once or periodically when the main
+ * counter reaches its value.
+ *
+ * In legacy replacement mode comparator 0 is wired to IRQ 0 (in place of
+ * the PIT) and comparator 1 to IRQ 8 (in place of the RTC). Without an
+ * I/O APIC driver that is the only routing we can use.
+ *
+ * registers (memory mapped, found through the ACPI HPET table):
+ * 000h general capabilities and id
+ * 010h general configuration
+ * 020h general interrupt status
+ * 0f0h main counter value
+ * 100h + 20h * N  timer N configuration and capabilities
+ * 108h + 20h * N  timer N comparator value
+ * 110h + 20h * N  timer N FSB interrupt route (MSI data and address)
+ */
+
+use conquer_once::spin::OnceCell;
+use core::ptr;
+use crate::{acpi, memory::phys_to_virt, time::Duration};
+use x86_64::{PhysAddr, VirtAddr};
+
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const INTERRUPT_STATUS: usize = 0x020;
+const MAIN_COUNTER: usize = 0x0f0;
+
+// general capabilities
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const LEG_RT_CAP: u64 = 1 << 15;
+
+// general configuration
+const ENABLE_CNF: u64 = 1 << 0;
+const LEG_RT_CNF: u64 = 1 << 1;
+
+// timer N configuration
+const TN_INT_TYPE_CNF: u64 = 1 << 1;
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3;
+const TN_PER_INT_CAP: u64 = 1 << 4;
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+const TN_32MODE_CNF: u64 = 1 << 8;
+const TN_FSB_EN_CNF: u64 = 1 << 14;
+const TN_FSB_INT_DEL_CAP: u64 = 1 << 15;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// Comparator interrupt mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    OneShot,
+    Periodic,
+}
+
+struct Hpet {
+    base: VirtAddr,
+    /// main counter period in femtoseconds
+    period_fs: u64,
+    num_timers: u8,
+    legacy_capable: bool,
+}
+
+impl Hpet {
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base.as_u64() as usize + reg) as *const u64) }
+    }
+
+    fn write(&self, reg: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base.as_u64() as usize + reg) as *mut u64, value) }
+    }
+
+    fn timer_config(timer: u8) -> usize {
+        0x100 + 0x20 * timer as usize
+    }
+
+    fn timer_comparator(timer: u8) -> usize {
+        0x108 + 0x20 * timer as usize
+    }
+
+    fn timer_fsb_route(timer: u8) -> usize {
+        0x110 + 0x20 * timer as usize
+    }
+}
+
+/// Discover the HPET through ACPI and start the main counter
+///
+/// Requires `acpi::init`. Returns `false` if there is no usable HPET.
+pub fn init() -> bool {
+    if HPET.is_initialized() {
+        return true;
+    }
+    let table = match acpi::find_table(b"HPET") {
+        Some(table) => table,
+        None => return false,
+    };
+    // header, event timer block id, then the base address
+    let address: acpi::GenericAddress = table.read(36 + 4);
+    if address.address_space_id != 0 {
+        return false; // not memory mapped
+    }
+
+    let base = phys_to_virt(PhysAddr::new(address.address));
+    let capabilities = unsafe { ptr::read_volatile(base.as_ptr::<u64>()) };
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > 100_000_000 {
+        return false; // the spec requires at least 10 MHz
+    }
+    if capabilities & COUNT_SIZE_CAP == 0 {
+        // a 32-bit main counter wraps every few minutes; not worth it
+        return false;
+    }
+
+    let hpet = Hpet {
+        base,
+        period_fs,
+        num_timers: ((capabilities >> 8) & 0x1f) as u8 + 1,
+        legacy_capable: capabilities & LEG_RT_CAP != 0,
+    };
+
+    // stop, reset the main counter, mask all comparators, then restart
+    let config = hpet.read(CONFIGURATION) & !(ENABLE_CNF | LEG_RT_CNF);
+    hpet.write(CONFIGURATION, config);
+    hpet.write(MAIN_COUNTER, 0);
+    for timer in 0..hpet.num_timers {
+        let reg = Hpet::timer_config(timer);
+        let value = hpet.read(reg) & !(TN_INT_ENB_CNF | TN_32MODE_CNF);
+        hpet.write(reg, value);
+    }
+    hpet.write(INTERRUPT_STATUS, !0);
+    hpet.write(CONFIGURATION, config | ENABLE_CNF);
+
+    HPET.try_init_once(|| hpet).is_ok()
+}
+
+fn hpet() -> &'static Hpet {
+    HPET.try_get().expect("HPET not initialized")
+}
+
+pub fn is_present() -> bool {
+    HPET.is_initialized()
+}
+
+/// Number of comparators
+pub fn num_timers() -> u8 {
+    hpet().num_timers
+}
+
+/// Main counter value
+pub fn counter() -> u64 {
+    hpet().read(MAIN_COUNTER)
+}
+
+/// Main counter frequency in Hz
+pub fn frequency() -> u64 {
+    FEMTOS_PER_SEC / hpet().period_fs
+}
+
+/// Convert main counter ticks to nanoseconds
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * hpet().period_fs as u128 / 1_000_000) as u64
+}
+
+/// Convert a duration to main counter ticks (rounded up, at least 1)
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let femtos = duration.as_nanos() * 1_000_000;
+    let period = hpet().period_fs as u128;
+    ((femtos + period - 1) / period).max(1) as u64
+}
+
+/// Route comparator 0 to IRQ 0 and comparator 1 to IRQ 8
+///
+/// This disconnects the PIT and the RTC from the interrupt controller.
+pub fn enable_legacy_route() -> bool {
+    let hpet = hpet();
+    if !hpet.legacy_capable {
+        return false;
+    }
+    let config = hpet.read(CONFIGURATION);
+    hpet.write(CONFIGURATION, config | LEG_RT_CNF);
+    true
+}
+
+/// Give IRQ 0 and IRQ 8 back to the PIT and the RTC
+pub fn disable_legacy_route() {
+    let hpet = hpet();
+    let config = hpet.read(CONFIGURATION);
+    hpet.write(CONFIGURATION, config & !LEG_RT_CNF);
+}
+
+/// Does the comparator support periodic mode?
+pub fn supports_periodic(timer: u8) -> bool {
+    let hpet = hpet();
+    timer < hpet.num_timers
+        && hpet.read(Hpet::timer_config(timer)) & TN_PER_INT_CAP != 0
+}
+
+/// Program comparator `timer`
+///
+/// `Mode::OneShot` fires once when the main counter reaches `ticks`
+/// (an absolute counter value). `Mode::Periodic` fires every `ticks`,
+/// starting `ticks` from now.
+pub fn set_comparator(timer: u8, mode: Mode, ticks: u64) {
+    let hpet = hpet();
+    assert!(timer < hpet.num_timers, "no such HPET comparator");
+    let reg = Hpet::timer_config(timer);
+    // edge triggered, 64-bit
+    let config = hpet.read(reg)
+        & !(TN_INT_TYPE_CNF | TN_TYPE_CNF | TN_32MODE_CNF | TN_VAL_SET_CNF);
+
+    match mode {
+        Mode::OneShot => {
+            hpet.write(reg, config | TN_INT_ENB_CNF);
+            hpet.write(Hpet::timer_comparator(timer), ticks);
+        }
+        Mode::Periodic => {
+            assert!(supports_periodic(timer), "comparator is not periodic capable");
+            // the first write sets the comparator, the second the period
+            hpet.write(reg, config | TN_INT_ENB_CNF | TN_TYPE_CNF | TN_VAL_SET_CNF);
+            hpet.write(Hpet::timer_comparator(timer), hpet.read(MAIN_COUNTER) + ticks);
+            hpet.write(Hpet::timer_comparator(timer), ticks);
+        }
+    }
+}
+
+/// Stop comparator `timer` from raising interrupts
+pub fn disable_comparator(timer: u8) {
+    let hpet = hpet();
+    let reg = Hpet::timer_config(timer);
+    let config = hpet.read(reg);
+    hpet.write(reg, config & !TN_INT_ENB_CNF);
+}
+
+/// Can the comparator deliver its interrupt as a message on the FSB
+/// (an MSI) instead of through an interrupt controller?
+pub fn supports_fsb(timer: u8) -> bool {
+    let hpet = hpet();
+    timer < hpet.num_timers
+        && hpet.read(Hpet::timer_config(timer)) & TN_FSB_INT_DEL_CAP != 0
+}
+
+/// Deliver the interrupts of comparator `timer` by writing `data` to
+/// `address` (an MSI, see `supports_fsb`)
+pub fn set_fsb_route(timer: u8, address: u32, data: u32) {
+    let hpet = hpet();
+    assert!(supports_fsb(timer), "comparator cannot deliver on the FSB");
+    hpet.write(Hpet::timer_fsb_route(timer), u64::from(address) << 32 | u64::from(data));
+    let reg = Hpet::timer_config(timer);
+    let config = hpet.read(reg);
+    hpet.write(reg, config | TN_FSB_EN_CNF);
+}
@@ -0,0 +1,127 @@
+/* Local APIC timer
+ *
+ * A 32-bit down counter clocked by the bus (or core crystal) clock through
+ * a divider. Its rate is unknown, so it is measured against the PIT.
+ *
+ * modes (LVT timer bits 17-18):
+ * 00 one-shot      interrupt once when the count reaches zero
+ * 01 periodic      reload the initial count after each interrupt
+ * 10 TSC-deadline  interrupt when the TSC reaches IA32_TSC_DEADLINE
+ */
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::{apic, cpu::{self, Feature}, interrupts::InterruptIndex, time::{self, Duration}};
+use super::pit;
+use x86_64::registers::model_specific::Msr;
+
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+const MODE_ONESHOT: u32 = 0b00 << 17;
+const MODE_PERIODIC: u32 = 0b01 << 17;
+const MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// divide configuration value for divide by 16
+const DIVIDE_BY_16: u32 = 0b0011;
+/// PIT clocks to measure over (10 ms)
+const CALIBRATE_COUNT: u16 = (pit::CLK_FREQ / 100) as u16;
+
+/// counter frequency in Hz after the divider (0 until calibrated)
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Timer mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    OneShot,
+    Periodic,
+    TscDeadline,
+}
+
+/// Set up the timer of the calling CPU
+///
+/// Requires `apic::init`. The first call measures the counter frequency
+/// against PIT channel 2; the other CPUs share the result. Leaves the
+/// timer masked. Returns `false` without a local APIC.
+pub fn init() -> bool {
+    if !apic::is_enabled() {
+        return false;
+    }
+    apic::write(apic::TIMER_DIVIDE, DIVIDE_BY_16);
+    stop();
+    if FREQUENCY.load(Ordering::Relaxed) == 0 {
+        FREQUENCY.store(calibrate(), Ordering::Relaxed);
+    }
+    true
+}
+
+/// Measure the counter frequency with the PIT
+fn calibrate() -> u64 {
+    let lvt = InterruptIndex::LapicTimer.as_u8() as u32 | apic::LVT_MASKED;
+    apic::write(apic::LVT_TIMER, lvt | MODE_ONESHOT);
+
+    // take the fastest round; slower ones were disturbed by something
+    let mut best = u32::MAX;
+    for _ in 0..3 {
+        apic::write(apic::TIMER_INITIAL, u32::MAX);
+        pit::busy_wait(CALIBRATE_COUNT);
+        let elapsed = u32::MAX - apic::read(apic::TIMER_CURRENT);
+        best = best.min(elapsed);
+    }
+    apic::write(apic::TIMER_INITIAL, 0);
+
+    best as u64 * pit::CLK_FREQ as u64 / CALIBRATE_COUNT as u64
+}
+
+/// Counter frequency in Hz (after the divider)
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// TSC-deadline mode needs CPU support and a calibrated invariant TSC
+pub fn supports_tsc_deadline() -> bool {
+    cpu::has(Feature::TscDeadline) && time::tsc_frequency().is_some()
+}
+
+/// Convert a duration to counter ticks, clamped to the 32-bit counter
+pub fn duration_to_count(duration: Duration) -> u32 {
+    let count = duration.as_nanos() * frequency() as u128 / 1_000_000_000;
+    count.max(1).min(u32::MAX as u128) as u32
+}
+
+/// Interrupt every `period`
+pub fn set_periodic(period: Duration) {
+    set_lvt(Mode::Periodic);
+    apic::write(apic::TIMER_INITIAL, duration_to_count(period));
+}
+
+/// Interrupt once after `delay` (longer delays are clamped to what the
+/// counter can hold)
+pub fn set_oneshot(delay: Duration) {
+    set_lvt(Mode::OneShot);
+    apic::write(apic::TIMER_INITIAL, duration_to_count(delay));
+}
+
+/// Interrupt once when the TSC reaches `deadline`
+pub fn set_tsc_deadline(deadline: u64) {
+    set_lvt(Mode::TscDeadline);
+    // the LVT write must be ordered before the MSR write
+    unsafe {
+        core::arch::asm!("mfence", options(nostack, preserves_flags));
+        Msr::new(IA32_TSC_DEADLINE).write(deadline.max(1));
+    }
+}
+
+/// Mask the timer and cancel the pending count
+pub fn stop() {
+    let lvt = InterruptIndex::LapicTimer.as_u8() as u32 | apic::LVT_MASKED;
+    apic::write(apic::LVT_TIMER, lvt);
+    apic::write(apic::TIMER_INITIAL, 0);
+}
+
+fn set_lvt(mode: Mode) {
+    let mode = match mode {
+        Mode::OneShot => MODE_ONESHOT,
+        Mode::Periodic => MODE_PERIODIC,
+        Mode::TscDeadline => MODE_TSC_DEADLINE,
+    };
+    apic::write(apic::LVT_TIMER, InterruptIndex::LapicTimer.as_u8() as u32 | mode);
+}
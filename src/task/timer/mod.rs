@@ -1,72 +1,388 @@
+/* Tickless timer core
+ *
+ * Sleeping tasks park their deadline and waker in one of a fixed number of
+ * slots. The clock event device is programmed for the nearest deadline
+ * only, so no timer interrupts fire while nothing is waiting. When an
+ * interrupt arrives, expired slots are woken and the device is rearmed for
+ * the next deadline (if any).
+ *
+ * The slots belong to the device that serves them: with the local APIC
+ * timer every CPU has its own and a task sleeps in those of the CPU it
+ * went to sleep on; IRQ 0 only goes to the BSP, so the PIT and the HPET
+ * use the BSP's. Either way only one CPU arms a deadline.
+ *
+ * One-shot operation needs a clock source that runs without interrupts
+ * (invariant TSC or HPET). Without one the PIT stays periodic and every
+ * tick checks the deadlines.
+ *
+ * The local APIC timer is preferred since every CPU has its own; the
+ * legacy PIT and HPET on IRQ 0 are only used without one.
+ */
+
+pub mod hpet;
+pub mod lapic;
 pub mod pit;
 
 use core::{
     future::Future,
     pin::Pin,
-    sync::atomic::{self, AtomicU64},
+    sync::atomic::{self, AtomicBool, AtomicU64, AtomicU8},
     task::{Context, Poll},
 };
 use futures_util::task::AtomicWaker;
+use crate::{interrupts::InterruptIndex, percpu, time::{self, Duration, Instant}};
+use x86_64::instructions::interrupts;
+
+/// maximum number of concurrently sleeping tasks per CPU
+const MAX_SLEEPERS: usize = 64;
+/// PIT rate when it has to run periodically
+pub const PERIODIC_HZ: u32 = 100;
+/// no deadline pending
+const NEVER: u64 = u64::MAX;
+
+const SLOT_DEFAULT: Slot = Slot::new();
+
+/// current `ClockEvent`
+static EVENT: AtomicU8 = AtomicU8::new(ClockEvent::Periodic as u8);
+
+/// Timer state of one CPU (part of its `percpu::PerCpu` block)
+pub(crate) struct CpuTimer {
+    /// number of timer interrupts
+    interrupts: AtomicU64,
+    /// deadline the clock event device is programmed for (ns since boot)
+    armed: AtomicU64,
+    /// end of the time slice of the running thread (see `thread`)
+    slice_end: AtomicU64,
+    /// sleepers the device of this CPU wakes (see `served`)
+    slots: [Slot; MAX_SLEEPERS],
+}
+
+impl CpuTimer {
+    pub(crate) fn new() -> Self {
+        CpuTimer {
+            interrupts: AtomicU64::new(0),
+            armed: AtomicU64::new(NEVER),
+            slice_end: AtomicU64::new(NEVER),
+            slots: [SLOT_DEFAULT; MAX_SLEEPERS],
+        }
+    }
+
+    /// Number of timer interrupts so far
+    pub(crate) fn interrupts(&self) -> u64 {
+        self.interrupts.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// A sleeping task: its deadline and how to wake it
+struct Slot {
+    in_use: AtomicBool,
+    deadline: AtomicU64,
+    waker: AtomicWaker,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Slot {
+            in_use: AtomicBool::new(false),
+            deadline: AtomicU64::new(NEVER),
+            waker: AtomicWaker::new(),
+        }
+    }
+}
+
+/// Hardware raising the timer interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockEvent {
+    /// PIT channel 0 rate generator at `PERIODIC_HZ`
+    Periodic,
+    /// PIT channel 0 one-shot (at most about 55 ms per interrupt)
+    Pit,
+    /// HPET comparator 0 one-shot, routed to IRQ 0
+    Hpet,
+    /// local APIC timer one-shot (TSC-deadline when available)
+    Lapic,
+}
+
+impl ClockEvent {
+    /// The interrupt vector the device raises
+    pub fn vector(self) -> u8 {
+        match self {
+            ClockEvent::Lapic => InterruptIndex::LapicTimer.as_u8(),
+            _ => InterruptIndex::Timer.as_u8(),
+        }
+    }
+}
 
-const MAX_TIMERS: usize = 8;
-const WAKER_DEFAULT: AtomicWaker = AtomicWaker::new();
+pub fn clock_event() -> ClockEvent {
+    match EVENT.load(atomic::Ordering::Acquire) {
+        3 => ClockEvent::Lapic,
+        2 => ClockEvent::Hpet,
+        1 => ClockEvent::Pit,
+        _ => ClockEvent::Periodic,
+    }
+}
+
+/// Select the clock event device
+///
+/// Should be called after `acpi::init` and `apic::init` so the HPET and
+/// the local APIC can be found. The HPET main counter also becomes the
+/// clock source unless the TSC already is.
+pub fn init() -> ClockEvent {
+    let has_hpet = hpet::init();
+    if has_hpet {
+        time::use_hpet();
+    }
+    let one_shot = time::clock_source() != time::ClockSource::Pit;
 
-/// timer value
-static TIMER: AtomicU64 = AtomicU64::new(0);
-/// synchronized task wakeup for each timer
-static WAKER: [AtomicWaker; MAX_TIMERS] = [WAKER_DEFAULT; MAX_TIMERS];
+    let event = if one_shot && lapic::init() {
+        ClockEvent::Lapic
+    } else if has_hpet && hpet::enable_legacy_route() {
+        ClockEvent::Hpet
+    } else if one_shot {
+        ClockEvent::Pit
+    } else {
+        ClockEvent::Periodic
+    };
+
+    interrupts::without_interrupts(|| {
+        match event {
+            ClockEvent::Periodic => pit::set_frequency(pit::Chan::CH0, PERIODIC_HZ),
+            // loading only the command stops channel 0
+            ClockEvent::Pit => pit::set_oneshot(pit::Chan::CH0, 0),
+            ClockEvent::Hpet => hpet::disable_comparator(0),
+            ClockEvent::Lapic => {
+                // IRQ 0 is not needed anymore
+                pit::set_oneshot(pit::Chan::CH0, 0);
+                crate::interrupts::set_irq_masked(0, true);
+            }
+        }
+        EVENT.store(event as u8, atomic::Ordering::Release);
+        armed().store(NEVER, atomic::Ordering::Relaxed);
+        rearm(next_deadline());
+    });
+    event
+}
+
+/// Number of timer interrupts so far on all CPUs
+pub fn ticks() -> u64 {
+    percpu::all()
+        .map(|cpu| cpu.timer.interrupts())
+        .sum()
+}
+
+/// Timer state of the clock event device that serves the calling CPU
+///
+/// The local APIC timer belongs to the calling CPU. IRQ 0 always goes to
+/// the BSP, so the PIT and the HPET are tracked in its block.
+fn served() -> &'static CpuTimer {
+    match clock_event() {
+        ClockEvent::Lapic => percpu!(timer),
+        _ => &percpu::get(0).expect("BSP not set up").timer,
+    }
+}
+
+/// Deadline the clock event device that serves the calling CPU is armed for
+fn armed() -> &'static AtomicU64 {
+    &served().armed
+}
 
 /// Called by the timer interrupt handler
 ///
+/// Wakes every expired sleeper and programs the next deadline. Returns how
+/// many nanoseconds after the armed deadline the interrupt came, `None` if
+/// none was armed or it came early (far deadlines take several interrupts).
 /// Must not block or allocate.
-pub(crate) fn set_timer(timer: u64) {
-    TIMER.store(timer, atomic::Ordering::Relaxed);
+pub(crate) fn interrupt() -> Option<u64> {
+    percpu!(timer.interrupts).fetch_add(1, atomic::Ordering::Relaxed);
+    if clock_event() == ClockEvent::Periodic {
+        time::pit_tick();
+    }
+
+    let deadline = armed().swap(NEVER, atomic::Ordering::Relaxed);
+    let late = match deadline {
+        NEVER => None,
+        deadline => Instant::now().as_nanos().checked_sub(deadline),
+    };
+    rearm(next_deadline());
+    late
+}
 
-    // notify each task that is waiting for a timer tick
-    for waker in WAKER.iter() {
-        waker.wake();
+/// Check the deadlines and rearm the clock event device of the calling
+/// CPU, for when something changed that `rearm` alone does not cover
+///
+/// Must be called with interrupts disabled.
+pub(crate) fn kick() {
+    rearm(next_deadline());
+}
+
+/// Set the end of the time slice of the calling CPU, `None` to cancel it
+///
+/// The timer interrupts at that point so the scheduler can preempt.
+/// Must be called with interrupts disabled.
+pub(crate) fn set_slice_end(end: Option<Instant>) {
+    let end = end.map_or(NEVER, Instant::as_nanos);
+    percpu!(timer.slice_end).store(end, atomic::Ordering::Relaxed);
+    if end != NEVER {
+        rearm(end);
     }
 }
 
-pub enum Timer {
-    Tick(usize),
-    Tock(usize),
+/// Time slice of the calling CPU set and over
+pub(crate) fn slice_expired() -> bool {
+    let end = percpu!(timer.slice_end).load(atomic::Ordering::Relaxed);
+    end != NEVER && Instant::now().as_nanos() >= end
 }
 
-impl Timer {
-    fn id(&self) -> (usize, u8) {
-        match *self {
-            Timer::Tick(id) => (id, 1),
-            Timer::Tock(id) => (id, 0),
+/// Time slice of the calling CPU set
+pub(crate) fn slice_set() -> bool {
+    percpu!(timer.slice_end).load(atomic::Ordering::Relaxed) != NEVER
+}
+
+/// Wake expired sleepers and return the earliest remaining deadline
+fn next_deadline() -> u64 {
+    let now = Instant::now().as_nanos();
+    // an expired slice is left for the scheduler to deal with
+    let slice_end = percpu!(timer.slice_end).load(atomic::Ordering::Relaxed);
+    let mut next = if slice_end > now { slice_end } else { NEVER };
+    if let Some(sample) = crate::profile::next_sample(now) {
+        next = next.min(sample);
+    }
+    if let Some(heartbeat) = crate::watchdog::next_heartbeat(now) {
+        next = next.min(heartbeat);
+    }
+    for slot in served().slots.iter() {
+        let deadline = slot.deadline.load(atomic::Ordering::Acquire);
+        if deadline == NEVER {
+            continue;
+        }
+        if deadline <= now {
+            slot.deadline.store(NEVER, atomic::Ordering::Release);
+            slot.waker.wake();
+        } else {
+            next = next.min(deadline);
         }
     }
+    next
 }
 
-impl Future for Timer {
-    type Output = u64;
+/// Program the clock event device for `deadline` if it is earlier than
+/// the one it is armed for
+///
+/// Must be called with interrupts disabled.
+fn rearm(deadline: u64) {
+    let armed = armed();
+    if deadline >= armed.load(atomic::Ordering::Relaxed) {
+        return;
+    }
+    armed.store(deadline, atomic::Ordering::Relaxed);
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u64> {
-        let (id, tick) = self.id(); // (timer id, tick/tock = 1/0)
+    let now = Instant::now().as_nanos();
+    let delta = deadline.saturating_sub(now);
+    match clock_event() {
+        ClockEvent::Periodic => {} // the next tick checks anyway
+        ClockEvent::Pit => {
+            // deadlines too far away for one count take several interrupts
+            pit::set_oneshot(pit::Chan::CH0, pit::ns_to_count(delta));
+        }
+        ClockEvent::Lapic => match time::tsc_frequency() {
+            Some(hz) if lapic::supports_tsc_deadline() => {
+                let cycles = delta as u128 * hz as u128 / 1_000_000_000;
+                lapic::set_tsc_deadline(time::rdtsc() + cycles as u64);
+            }
+            // deadlines beyond the 32-bit count take several interrupts
+            _ => lapic::set_oneshot(Duration::from_nanos(delta)),
+        },
+        ClockEvent::Hpet => {
+            // the comparator only matches on equality, so make sure the
+            // counter has not already passed it
+            let mut ticks = hpet::duration_to_ticks(Duration::from_nanos(delta));
+            loop {
+                let target = hpet::counter() + ticks;
+                hpet::set_comparator(0, hpet::Mode::OneShot, target);
+                if hpet::counter() < target {
+                    break;
+                }
+                ticks *= 2;
+            }
+        }
+    }
+}
+
+/// Future that completes once its deadline has passed
+pub struct Sleep {
+    deadline: Instant,
+    /// the device that serves it is armed for the deadline
+    slot: Option<&'static Slot>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Must be called with interrupts disabled, so the task stays on the
+    /// CPU whose slot it gets until the device is armed
+    fn claim_slot() -> &'static Slot {
+        served().slots.iter()
+            .find(|slot| {
+                slot.in_use.compare_exchange(
+                    false, true,
+                    atomic::Ordering::Acquire,
+                    atomic::Ordering::Relaxed,
+                ).is_ok()
+            })
+            .expect("too many sleeping tasks")
+    }
+}
 
-        // clock is the lsb of TIMER
-        WAKER[id].register(cx.waker()); // call before checking result
-        let clock = TIMER.load(atomic::Ordering::Relaxed) as u8 & 1;
+impl Future for Sleep {
+    type Output = ();
 
-        if tick == clock {
-            Poll::Ready(TIMER.load(atomic::Ordering::Relaxed))
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let deadline = self.deadline.as_nanos();
+        match self.slot {
+            // the device that serves the slot is armed already, even if
+            // the task now runs on another CPU
+            Some(slot) => slot.waker.register(cx.waker()),
+            None => interrupts::without_interrupts(|| {
+                let slot = Self::claim_slot();
+                slot.waker.register(cx.waker()); // call before publishing deadline
+                slot.deadline.store(deadline, atomic::Ordering::Release);
+                rearm(deadline);
+                self.slot = Some(slot);
+            }),
+        }
+
+        // the deadline may have passed before the device was armed
+        if Instant::now() >= self.deadline {
+            Poll::Ready(())
         } else {
             Poll::Pending
         }
     }
 }
 
-pub async fn sleep(id: usize, ticks: u32) -> u64 {
-    assert!(ticks >= 2);
-    let hticks = ticks / 2;
-    let mut timer: u64 = 0;
-    for _ in 0..hticks {
-        Timer::Tick(id).await;
-        timer = Timer::Tock(id).await;
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            slot.deadline.store(NEVER, atomic::Ordering::Release);
+            slot.waker.take();
+            slot.in_use.store(false, atomic::Ordering::Release);
+        }
     }
-    timer
+}
+
+/// Sleep until `deadline`
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, slot: None }
+}
+
+/// Sleep for at least `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
 }
@@ -9,9 +9,13 @@
  */
 
 use core::convert::TryInto;
+use core::sync::atomic::{AtomicU32, Ordering};
 use x86_64::instructions::port::Port;
 
-const CLK_FREQ: u32 = 1193182;
+pub const CLK_FREQ: u32 = 1193182;
+
+/// channel 0 divider (the BIOS programs 0 which the PIT treats as 65536)
+static CH0_DIVIDER: AtomicU32 = AtomicU32::new(65536);
 
 pub fn set_frequency(ch: Chan, freq: u32) {
     let clk_div = (CLK_FREQ / freq).try_into()
@@ -24,6 +28,11 @@ pub fn set_divider(ch: Chan, div: u16) {
     let mut data = Port::new(0x40 + ch as u16);
     let mut cmd = Port::new(0x43);  // mode/command (write only)
 
+    if let Chan::CH0 = ch {
+        let div = if div == 0 { 65536 } else { div as u32 };
+        CH0_DIVIDER.store(div, Ordering::Relaxed);
+    }
+
     unsafe {
         cmd.write(0x34 as u8);      // lobyte/highbyte, rate generator
         data.write(div as u8);
@@ -31,6 +40,58 @@ pub fn set_divider(ch: Chan, div: u16) {
     }
 }
 
+/// Interrupt once after `count` clocks (mode 0, interrupt on terminal count)
+///
+/// The output stays high until the channel is reprogrammed, so IRQ 0
+/// fires exactly once. Writing the command alone stops the count.
+pub fn set_oneshot(ch: Chan, count: u16) {
+    let mut data = Port::new(0x40 + ch as u16);
+    let mut cmd = Port::new(0x43);
+
+    unsafe {
+        cmd.write((ch as u8) << 6 | 0x30); // lobyte/highbyte, mode 0
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+    }
+}
+
+/// Convert nanoseconds to PIT clocks, clamped to what fits in a count
+pub fn ns_to_count(ns: u64) -> u16 {
+    let count = ns as u128 * CLK_FREQ as u128 / 1_000_000_000;
+    count.max(1).min(u16::MAX as u128) as u16
+}
+
+/// Length of one channel 0 period in nanoseconds
+pub fn period_ns() -> u64 {
+    CH0_DIVIDER.load(Ordering::Relaxed) as u64 * 1_000_000_000
+        / CLK_FREQ as u64
+}
+
+/// Busy wait for `count` PIT clocks (at most 65535, about 55 ms)
+///
+/// Uses channel 2 in one-shot mode with the speaker output disabled,
+/// so it works with interrupts disabled and does not disturb channel 0.
+pub fn busy_wait(count: u16) {
+    let mut ctrl: Port<u8> = Port::new(0x61);
+    let mut data = Port::new(0x42);
+    let mut cmd = Port::new(0x43);
+
+    unsafe {
+        // gate high, speaker off
+        let value = ctrl.read();
+        ctrl.write((value & !0x02) | 0x01);
+
+        cmd.write(0xb0 as u8);      // ch2, lobyte/highbyte, one-shot
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // OUT2 (bit 5) goes high when the count reaches zero
+        while ctrl.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(u8)]
 pub enum Chan {
@@ -0,0 +1,620 @@
+//! Preemptive kernel threads
+//!
+//! Every thread has its own stack (with a guard page below it) and is
+//! switched by saving its callee-saved registers on that stack. Ready
+//! threads wait in one queue shared by all CPUs. A thread runs until it
+//! blocks, yields or its time slice ends: the timer interrupt then
+//! switches to the next ready thread right inside the interrupt handler,
+//! and the preempted thread returns from the handler once it is resumed.
+//!
+//! The boot context of each CPU becomes a thread too. On the BSP that is
+//! `kernel_main`, on the APs it is their idle loop. A CPU runs its idle
+//! thread only when nothing else is ready.
+//!
+//! The FPU/SSE/AVX registers are saved and restored on every switch too
+//! (see `fpu`).
+//!
+//! Blocking is built on `park`/`unpark` with a permit, so an unpark that
+//! comes before the park is not lost. `WaitQueue` and `block_on` (which
+//! lets a thread wait for a future) are built on top.
+
+mod wait;
+
+pub use wait::WaitQueue;
+
+use alloc::{boxed::Box, collections::VecDeque, format, string::String, task::Wake, vec::Vec};
+use alloc::sync::{Arc, Weak};
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use crate::{apic, fpu::FpuState, interrupts::InterruptIndex, memory, percpu, smp};
+use crate::lockdep::{self, HeldLocks};
+use crate::user::{self, UserContext};
+use crate::task::timer;
+use crate::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+global_asm!(include_str!("switch.s"));
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// pages per thread stack
+const STACK_PAGES: u64 = 16;
+/// maximum number of threads, so the ready queue never has to grow
+/// (it is used with interrupts disabled)
+pub const MAX_THREADS: usize = 256;
+/// how long a thread runs before others get a turn
+pub const TIME_SLICE: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    static ref READY: spin::Mutex<VecDeque<Arc<Thread>>> =
+        spin::Mutex::new(VecDeque::with_capacity(MAX_THREADS));
+}
+
+lazy_static! {
+    /// every thread, for debuggers
+    static ref ALL: spin::Mutex<Vec<Weak<Thread>>> = spin::Mutex::new(Vec::new());
+}
+
+/// number of threads that have not exited
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+/// tops of the stacks of exited threads, for reuse
+static STACK_POOL: spin::Mutex<Vec<VirtAddr>> = spin::Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    Running,
+    Ready,
+    Blocked,
+    Exited,
+}
+
+/// A kernel thread
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: AtomicU8,
+    /// saved stack pointer while switched out
+    rsp: UnsafeCell<u64>,
+    /// top of the stack (`None` for the boot context of a CPU)
+    stack: Option<VirtAddr>,
+    /// still on a CPU: its registers are not completely saved yet
+    on_cpu: AtomicBool,
+    /// a pending unpark
+    permit: AtomicBool,
+    /// the idle thread of some CPU, never in the ready queue
+    idle: bool,
+    /// FPU/SSE/AVX registers while switched out
+    fpu: FpuState,
+    /// where user mode returns to (see `user::run`)
+    pub(crate) user: UserContext,
+    /// id of the process the thread belongs to, 0 for kernel threads
+    pub(crate) process: AtomicU64,
+    /// the locks it holds, for `lockdep`
+    locks: HeldLocks,
+    entry: spin::Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    joiners: WaitQueue,
+}
+
+// `rsp` is only touched by the CPU switching the thread in or out
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    /// An empty `name` is replaced by one made from the id
+    fn new(name: String, stack: Option<VirtAddr>, idle: bool) -> Self {
+        let id = ThreadId::new();
+        Thread {
+            id,
+            name: if name.is_empty() { format!("thread/{}", id.0) } else { name },
+            state: AtomicU8::new(State::Ready as u8),
+            rsp: UnsafeCell::new(0),
+            stack,
+            on_cpu: AtomicBool::new(false),
+            permit: AtomicBool::new(false),
+            idle,
+            fpu: FpuState::new(),
+            user: UserContext::new(),
+            process: AtomicU64::new(0),
+            locks: HeldLocks::new(),
+            entry: spin::Mutex::new(None),
+            joiners: WaitQueue::new(),
+        }
+    }
+
+    /// The boot context of the calling CPU
+    fn boot(name: String, idle: bool) -> Self {
+        let thread = Thread::new(name, None, idle);
+        thread.set_state(State::Running);
+        thread.on_cpu.store(true, Ordering::Relaxed);
+        thread
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> State {
+        match self.state.load(Ordering::Acquire) {
+            0 => State::Running,
+            1 => State::Ready,
+            2 => State::Blocked,
+            _ => State::Exited,
+        }
+    }
+
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Make the thread runnable again if it is parked, otherwise make its
+    /// next `park` return immediately
+    pub fn unpark(self: &Arc<Self>) {
+        let became_ready = interrupts::without_interrupts(|| {
+            let mut ready = READY.lock();
+            self.permit.store(true, Ordering::Release);
+            if self.state() == State::Blocked {
+                self.set_state(State::Ready);
+                ready.push_back(self.clone());
+                true
+            } else {
+                false
+            }
+        });
+        if became_ready {
+            kick();
+        }
+    }
+
+    /// Stack pointer saved by `switch_context` (see `switch.s`) while the
+    /// thread is switched out
+    pub(crate) fn saved_rsp(&self) -> Option<u64> {
+        if self.on_cpu.load(Ordering::Acquire) || self.state() == State::Exited {
+            return None;
+        }
+        Some(unsafe { *self.rsp.get() })
+    }
+
+    /// Lay out a fresh stack as if the thread had been switched away from
+    /// right before calling `thread_start`
+    fn prepare_stack(&self) {
+        let top = self.stack.expect("thread without stack").as_u64();
+        // return address, then 6 callee-saved registers
+        let frame = [0u64, 0, 0, 0, 0, 0, thread_start as usize as u64, 0];
+        let rsp = top - 8 * frame.len() as u64;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+            *self.rsp.get() = rsp;
+        }
+    }
+}
+
+impl Wake for Thread {
+    fn wake(self: Arc<Self>) {
+        self.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.unpark();
+    }
+}
+
+/// Scheduler state of one CPU (part of its `percpu::PerCpu` block)
+///
+/// Apart from `in_idle`, only touched by its own CPU with interrupts
+/// disabled.
+pub(crate) struct CpuSched {
+    current: spin::Mutex<Option<Arc<Thread>>>,
+    idle: spin::Mutex<Option<Arc<Thread>>>,
+    /// thread switched away from and whether to queue it again, handled by
+    /// `finish_switch` once its registers are saved
+    prev: spin::Mutex<Option<(Arc<Thread>, bool)>>,
+    /// running the idle thread (read by other CPUs)
+    in_idle: AtomicBool,
+}
+
+impl CpuSched {
+    pub(crate) fn new() -> Self {
+        CpuSched {
+            current: spin::Mutex::new(None),
+            idle: spin::Mutex::new(None),
+            prev: spin::Mutex::new(None),
+            in_idle: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Turn the boot context of the calling CPU into a thread
+///
+/// On the BSP it stays an ordinary thread and gets a separate idle thread,
+/// which needs `memory::install`. On an AP it becomes the idle thread.
+pub fn init() {
+    let sched = percpu!(sched);
+    if sched.current.lock().is_some() {
+        return;
+    }
+    let cpu = smp::current_cpu();
+    let (boot, idle) = if cpu == 0 {
+        let boot = Arc::new(Thread::boot(String::from("main"), false));
+        let idle = Arc::new(Thread::new(String::from("idle/0"), Some(alloc_stack()), true));
+        *idle.entry.lock() = Some(Box::new(idle_loop));
+        idle.prepare_stack();
+        (boot, idle)
+    } else {
+        let idle = Arc::new(Thread::boot(format!("idle/{}", cpu), true));
+        (idle.clone(), idle)
+    };
+    THREADS.fetch_add(1, Ordering::Relaxed);
+    register(&boot);
+    if !Arc::ptr_eq(&boot, &idle) {
+        register(&idle);
+    }
+    interrupts::without_interrupts(|| {
+        sched.in_idle.store(boot.idle, Ordering::Release);
+        lockdep::switch_to(&boot.locks);
+        *sched.current.lock() = Some(boot);
+        *sched.idle.lock() = Some(idle);
+    });
+}
+
+fn register(thread: &Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        let mut all = ALL.lock();
+        all.retain(|thread| thread.strong_count() != 0);
+        all.push(Arc::downgrade(thread));
+    });
+}
+
+/// Call `f` for every thread that has not exited, oldest first
+pub fn for_each(mut f: impl FnMut(&Arc<Thread>)) {
+    interrupts::without_interrupts(|| {
+        for thread in ALL.lock().iter().filter_map(Weak::upgrade) {
+            if thread.state() != State::Exited {
+                f(&thread);
+            }
+        }
+    });
+}
+
+/// Like `for_each`, but returns false instead of waiting if the thread
+/// list is locked (for the monitor, which may have stopped its owner)
+pub(crate) fn try_for_each(mut f: impl FnMut(&Arc<Thread>)) -> bool {
+    interrupts::without_interrupts(|| {
+        let all = match ALL.try_lock() {
+            Some(all) => all,
+            None => return false,
+        };
+        for thread in all.iter().filter_map(Weak::upgrade) {
+            if thread.state() != State::Exited {
+                f(&thread);
+            }
+        }
+        true
+    })
+}
+
+/// Threads are running on the calling CPU
+pub fn is_initialized() -> bool {
+    interrupts::without_interrupts(|| percpu!(sched).current.lock().is_some())
+}
+
+/// The calling thread
+///
+/// Panics if `init` has not been called on this CPU.
+pub fn current() -> Arc<Thread> {
+    interrupts::without_interrupts(|| {
+        percpu!(sched).current.lock().clone()
+            .expect("thread::init has not been called")
+    })
+}
+
+/// Like `current`, but `None` instead of waiting if the scheduler state of
+/// the calling CPU is locked (for the debugger, which may have stopped
+/// its owner), and before `init`
+pub(crate) fn try_current() -> Option<Arc<Thread>> {
+    interrupts::without_interrupts(|| percpu!(sched).current.try_lock()?.clone())
+}
+
+/// Owns the right to join a thread
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<spin::Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    /// Wait for the thread to exit and return what it returned
+    ///
+    /// Panics if the thread ended with `exit` instead of returning.
+    pub fn join(self) -> T {
+        let thread = &self.thread;
+        thread.joiners.wait_until(|| thread.state() == State::Exited);
+        self.result.lock().take().expect("thread exited without a result")
+    }
+}
+
+/// Start a new thread running `f`
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_named(String::new(), f)
+}
+
+/// Start a new thread called `name` running `f`
+///
+/// An empty `name` is replaced by one made from the thread id.
+/// Panics if there are already `MAX_THREADS` threads.
+pub fn spawn_named<F, T>(name: String, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    if THREADS.fetch_add(1, Ordering::Relaxed) >= MAX_THREADS {
+        THREADS.fetch_sub(1, Ordering::Relaxed);
+        panic!("too many threads");
+    }
+
+    let result = Arc::new(spin::Mutex::new(None));
+    let thread = Arc::new(Thread::new(name, Some(alloc_stack()), false));
+    let slot = result.clone();
+    *thread.entry.lock() = Some(Box::new(move || {
+        *slot.lock() = Some(f());
+    }));
+    thread.prepare_stack();
+    register(&thread);
+
+    interrupts::without_interrupts(|| READY.lock().push_back(thread.clone()));
+    kick();
+    JoinHandle { thread, result }
+}
+
+/// Give up the CPU to the next ready thread, if there is one
+pub fn yield_now() {
+    yield_if_ready();
+}
+
+/// Like `yield_now`; returns whether another thread ran in between
+pub(crate) fn yield_if_ready() -> bool {
+    if !is_initialized() {
+        return false;
+    }
+    interrupts::without_interrupts(|| {
+        if READY.lock().is_empty() {
+            return false;
+        }
+        switch(true);
+        true
+    })
+}
+
+/// Block until `unpark` is called (or return right away if it already was)
+pub fn park() {
+    interrupts::without_interrupts(|| {
+        let me = current();
+        {
+            let _ready = READY.lock();
+            if me.permit.swap(false, Ordering::AcqRel) {
+                return;
+            }
+            me.set_state(State::Blocked);
+        }
+        switch(false);
+        me.permit.store(false, Ordering::Release);
+    });
+}
+
+/// Block the calling thread until `future` completes
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(current());
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        park();
+    }
+}
+
+/// Block the calling thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// Block the calling thread until `deadline`
+pub fn sleep_until(deadline: Instant) {
+    block_on(timer::sleep_until(deadline));
+}
+
+/// Called by the timer and wakeup interrupt handlers (after their end of
+/// interrupt): switch threads if the time slice of the current one is over
+/// or the CPU is idle
+pub(crate) fn preempt() {
+    let cpu = percpu::current();
+    // a softirq pass or a handler below us holds on to this CPU's block:
+    // leave the switch to the next interrupt after it is done
+    if cpu.in_interrupt() || cpu.softirq.is_running() {
+        return;
+    }
+    let sched = &cpu.sched;
+    let current = match sched.current.lock().clone() {
+        Some(current) => current,
+        None => return,
+    };
+    if !current.idle && !timer::slice_expired() {
+        return;
+    }
+    if READY.lock().is_empty() {
+        if timer::slice_expired() {
+            timer::set_slice_end(None); // nobody to hand over to
+        }
+        return;
+    }
+    drop(current);
+    switch(true);
+}
+
+/// Run the next ready thread (or the idle thread) instead of the current
+/// one, which goes back into the ready queue if `requeue` is set
+///
+/// Must be called with interrupts disabled. Returns once the current
+/// thread is switched back in.
+fn switch(requeue: bool) {
+    let sched = percpu!(sched);
+    let current = sched.current.lock().clone().expect("thread::init has not been called");
+    let next = match READY.lock().pop_front() {
+        Some(next) => next,
+        None if requeue || current.state() == State::Running => return,
+        None => sched.idle.lock().clone().expect("no idle thread"),
+    };
+    if Arc::ptr_eq(&next, &current) {
+        // unparked before it got to switch away
+        current.set_state(State::Running);
+        return;
+    }
+
+    // the idle thread only runs when nothing else is ready
+    let requeue = requeue && !current.idle;
+    if current.state() == State::Running {
+        current.set_state(State::Ready);
+    }
+    // it may still be switching out on the CPU it ran on last
+    while next.on_cpu.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    next.on_cpu.store(true, Ordering::Relaxed);
+    next.set_state(State::Running);
+
+    timer::set_slice_end(if next.idle { None } else { Some(Instant::now() + TIME_SLICE) });
+    sched.in_idle.store(next.idle, Ordering::Release);
+    user::switch_to(&next.user);
+    lockdep::switch_to(&next.locks);
+
+    let old_rsp = current.rsp.get();
+    let new_rsp = unsafe { *next.rsp.get() };
+    // stays valid: the thread is current again when the switch returns
+    let fpu = &current.fpu as *const FpuState;
+    *sched.prev.lock() = Some((current, requeue));
+    *sched.current.lock() = Some(next);
+    unsafe {
+        (*fpu).save();
+        switch_context(old_rsp, new_rsp);
+        (*fpu).restore();
+    }
+    finish_switch();
+}
+
+/// Finish a switch on the new thread: now that the registers of the
+/// previous thread are saved, it may run elsewhere
+fn finish_switch() {
+    let prev = percpu!(sched).prev.lock().take();
+    if let Some((prev, requeue)) = prev {
+        prev.on_cpu.store(false, Ordering::Release);
+        if prev.state() == State::Exited {
+            if let Some(stack) = prev.stack {
+                STACK_POOL.lock().push(stack);
+            }
+        } else if requeue {
+            READY.lock().push_back(prev);
+        }
+    }
+}
+
+/// First code a new thread runs (see `prepare_stack`)
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    let me = current();
+    unsafe { me.fpu.restore() };
+    interrupts::enable();
+
+    let entry = me.entry.lock().take().expect("thread started twice");
+    drop(me);
+    entry();
+    exit();
+}
+
+/// End the current thread without returning from its closure
+///
+/// Values owned by the frames of the thread are not dropped, and there is
+/// no result for `JoinHandle::join`.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let me = current();
+    me.set_state(State::Exited);
+    me.joiners.notify_all();
+    THREADS.fetch_sub(1, Ordering::Relaxed);
+    drop(me);
+    switch(false);
+    unreachable!("exited thread switched back in");
+}
+
+/// Body of the idle thread of the BSP
+fn idle_loop() {
+    loop {
+        interrupts::disable();
+        if READY.lock().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+            yield_now();
+        }
+    }
+}
+
+/// Wake an idle CPU to run newly ready threads, or make sure the current
+/// thread gets preempted at the end of its time slice
+fn kick() {
+    interrupts::without_interrupts(|| {
+        let me = smp::current_cpu();
+        let idle_cpu = (0..smp::cpu_count()).find(|&cpu| {
+            cpu != me && percpu::get(cpu)
+                .map_or(false, |other| other.sched.in_idle.load(Ordering::Acquire))
+        });
+        match idle_cpu {
+            Some(cpu) => apic::send_ipi(smp::apic_id(cpu), InterruptIndex::Wakeup.as_u8()),
+            None if is_initialized() && !timer::slice_set() => {
+                timer::set_slice_end(Some(Instant::now() + TIME_SLICE));
+            }
+            None => {}
+        }
+    });
+}
+
+/// Map a new stack or reuse one of an exited thread
+fn alloc_stack() -> VirtAddr {
+    if let Some(stack) = interrupts::without_interrupts(|| STACK_POOL.lock().pop()) {
+        return stack;
+    }
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        memory::alloc_stack(STACK_PAGES, mapper, frame_allocator)
+    })
+    .expect("out of memory for a thread stack")
+}
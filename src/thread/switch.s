@@ -0,0 +1,28 @@
+# Kernel thread context switch
+#
+# void switch_context(uint64_t *old_rsp, uint64_t new_rsp)
+#
+# Saves the callee-saved registers on the current stack, stores the stack
+# pointer in *old_rsp, then restores the registers saved on the stack at
+# new_rsp. Everything else is saved by the caller according to the System V
+# ABI. The stack of a new thread is prepared to look like it was switched
+# away from right before entering `thread_start`.
+
+.section .text
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
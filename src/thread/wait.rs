@@ -0,0 +1,69 @@
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::instructions::interrupts;
+use super::Thread;
+
+/// Threads blocked until some condition holds
+///
+/// Whoever makes the condition true calls `notify_one` or `notify_all`.
+/// Waiters check the condition again after every wakeup, so spurious
+/// wakeups are harmless.
+pub struct WaitQueue {
+    waiters: spin::Mutex<Vec<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Block the calling thread until `condition` returns `true`
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let me = super::current();
+        loop {
+            if condition() {
+                return;
+            }
+            interrupts::without_interrupts(|| {
+                let mut waiters = self.waiters.lock();
+                if !waiters.iter().any(|thread| Arc::ptr_eq(thread, &me)) {
+                    waiters.push(me.clone());
+                }
+            });
+            // a notify after this check leaves a permit, so park returns
+            if condition() {
+                return;
+            }
+            super::park();
+        }
+    }
+
+    /// Wake the longest waiting thread; returns `false` if there was none
+    pub fn notify_one(&self) -> bool {
+        let thread = interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                None
+            } else {
+                Some(waiters.remove(0))
+            }
+        });
+        match thread {
+            Some(thread) => {
+                thread.unpark();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake all waiting threads and return how many there were
+    pub fn notify_all(&self) -> usize {
+        let mut count = 0;
+        while self.notify_one() {
+            count += 1;
+        }
+        count
+    }
+}
@@ -0,0 +1,306 @@
+//! Monotonic clock
+//!
+//! The TSC is calibrated against the PIT at boot and used as the clock
+//! source when it is invariant. Otherwise the HPET main counter is used
+//! once it has been found, and before that (or without an HPET) the clock
+//! advances by the channel 0 period on every timer interrupt.
+
+use core::arch::x86_64::_rdtsc;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use crate::{cpu::{self, Feature}, task::timer::{hpet, pit}};
+
+pub use core::time::Duration;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// PIT clocks per calibration round (10 ms)
+const CALIBRATE_COUNT: u16 = (pit::CLK_FREQ / 100) as u16;
+const CALIBRATE_ROUNDS: usize = 3;
+
+/// current `ClockSource`
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+/// TSC frequency in Hz, measured even if the TSC is not invariant
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// TSC value at boot
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+/// nanoseconds counted by the timer interrupt (fallback clock)
+static TICK_NS: AtomicU64 = AtomicU64::new(0);
+/// nanoseconds to add to the HPET main counter
+static HPET_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Hardware backing `Instant::now`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// timer interrupts counted at the PIT channel 0 rate
+    Pit,
+    /// HPET main counter
+    Hpet,
+    /// invariant time stamp counter
+    Tsc,
+}
+
+pub fn clock_source() -> ClockSource {
+    match SOURCE.load(Ordering::Acquire) {
+        2 => ClockSource::Tsc,
+        1 => ClockSource::Hpet,
+        _ => ClockSource::Pit,
+    }
+}
+
+/// A measurement of the monotonic clock, in nanoseconds since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        match clock_source() {
+            ClockSource::Tsc => {
+                let cycles = rdtsc() - TSC_BASE.load(Ordering::Relaxed);
+                Instant(cycles_to_ns(cycles, TSC_HZ.load(Ordering::Relaxed)))
+            }
+            ClockSource::Hpet => {
+                let nanos = hpet::ticks_to_ns(hpet::counter());
+                Instant(nanos + HPET_OFFSET_NS.load(Ordering::Relaxed))
+            }
+            ClockSource::Pit => Instant(TICK_NS.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Creates an instant from nanoseconds since boot
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Instant(nanos)
+    }
+
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed since boot
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(*self)
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier)
+            .expect("supplied instant is later than self")
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = duration.as_nanos();
+        if nanos > u64::MAX as u128 {
+            return None;
+        }
+        self.0.checked_add(nanos as u64).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = duration.as_nanos();
+        if nanos > u64::MAX as u128 {
+            return None;
+        }
+        self.0.checked_sub(nanos as u64).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, other: Duration) -> Instant {
+        self.checked_add(other)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, other: Duration) {
+        *self = *self + other;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, other: Duration) -> Instant {
+        self.checked_sub(other)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, other: Duration) {
+        *self = *self - other;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
+    }
+}
+
+/// Wall clock time, measured from the Unix epoch
+///
+/// Based on the RTC reading at boot plus the monotonic clock, so it does
+/// not go backwards, but it is only as accurate as the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+/// Unix time of `Instant` zero (0 until `rtc::init`)
+static BOOT_TIME_NS: AtomicU64 = AtomicU64::new(0);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+    pub fn now() -> Self {
+        let boot = Duration::from_nanos(BOOT_TIME_NS.load(Ordering::Relaxed));
+        SystemTime(boot + Instant::now().since_boot())
+    }
+
+    pub const fn from_unix(since_epoch: Duration) -> Self {
+        SystemTime(since_epoch)
+    }
+
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, other: Duration) -> SystemTime {
+        SystemTime(self.0 + other)
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, other: Duration) -> SystemTime {
+        SystemTime(self.0 - other)
+    }
+}
+
+/// Anchor the wall clock: `now` is the current wall clock time
+pub(crate) fn set_system_time(now: SystemTime) {
+    let since_boot = Instant::now().since_boot();
+    let boot = now.0.checked_sub(since_boot).unwrap_or_default();
+    BOOT_TIME_NS.store(boot.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Calibrate the TSC and select the clock source
+///
+/// Must be called with channel 2 of the PIT unused.
+pub fn init() {
+    // take the fastest round; slower ones were disturbed by something
+    let count = CALIBRATE_COUNT as u64;
+    let mut best = u64::MAX;
+    for _ in 0..CALIBRATE_ROUNDS {
+        let start = rdtsc();
+        pit::busy_wait(CALIBRATE_COUNT);
+        best = best.min(rdtsc() - start);
+    }
+    let hz = best * pit::CLK_FREQ as u64 / count;
+
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    if !cpu::has(Feature::InvariantTsc) {
+        return;
+    }
+    TSC_BASE.store(rdtsc(), Ordering::Relaxed);
+    SOURCE.store(ClockSource::Tsc as u8, Ordering::Release);
+}
+
+/// Switch from counting timer interrupts to the HPET main counter
+///
+/// Called once the HPET has been initialized. Does nothing if the TSC is
+/// already the clock source. The clock does not jump backwards.
+pub(crate) fn use_hpet() {
+    if clock_source() != ClockSource::Pit {
+        return;
+    }
+    let now = TICK_NS.load(Ordering::Relaxed);
+    let hpet_now = hpet::ticks_to_ns(hpet::counter());
+    HPET_OFFSET_NS.store(now.saturating_sub(hpet_now), Ordering::Relaxed);
+    SOURCE.store(ClockSource::Hpet as u8, Ordering::Release);
+}
+
+/// Called by the timer interrupt handler
+///
+/// Advances the fallback clock by one channel 0 period.
+pub(crate) fn pit_tick() {
+    TICK_NS.fetch_add(pit::period_ns(), Ordering::Relaxed);
+}
+
+/// Calibrated TSC frequency in Hz, if the TSC is the clock source
+pub fn tsc_frequency() -> Option<u64> {
+    if clock_source() == ClockSource::Tsc {
+        Some(TSC_HZ.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// TSC frequency measured by `init`, also if the TSC is not invariant
+///
+/// Good enough for short intervals on one CPU, such as how long an
+/// interrupt handler ran. `None` before `init`.
+pub fn measured_tsc_frequency() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Read the time stamp counter
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn cycles_to_ns(cycles: u64, hz: u64) -> u64 {
+    (cycles as u128 * NANOS_PER_SEC as u128 / hz as u128) as u64
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    let earlier = Instant::now();
+    let later = Instant::now();
+    assert!(later >= earlier);
+}
+
+#[test_case]
+fn test_instant_advances() {
+    let start = Instant::now();
+    // the next timer interrupt (or the TSC) moves the clock forward
+    while start.elapsed() == Duration::ZERO {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_instant_arithmetic() {
+    let start = Instant::from_nanos(1_000);
+    let later = start + Duration::from_micros(2);
+    assert_eq!(later.as_nanos(), 3_000);
+    assert_eq!(later - start, Duration::from_micros(2));
+    assert_eq!(start.checked_duration_since(later), None);
+    assert_eq!(later - Duration::from_nanos(500), Instant::from_nanos(2_500));
+}
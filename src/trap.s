@@ -0,0 +1,106 @@
+# Entries for exceptions whose handlers need all registers of the
+# interrupted code (the debug and breakpoint exceptions, for the GDB stub)
+#
+# Saves the general purpose registers as a TrapFrame (see interrupts.rs)
+# on top of the frame the CPU pushed and calls
+#
+#     void trap_dispatch(TrapFrame *frame)
+#
+# which may change them before they are restored. xmm0-15 are saved too,
+# since Rust code uses them freely and the interrupted code may be in the
+# middle of using them. The GS base is swapped when coming from ring 3.
+
+.section .text
+
+.global debug_entry
+debug_entry:
+    push 1                          # vector
+    jmp trap_common
+
+.global breakpoint_entry
+breakpoint_entry:
+    push 3                          # vector
+    jmp trap_common
+
+trap_common:
+    test qword ptr [rsp + 16], 3    # cs
+    jz 1f
+    swapgs
+1:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+
+    # 5 words from the CPU, the vector and 15 registers leave the stack
+    # 8 bytes off the 16 byte alignment the CPU started with
+    sub rsp, 16 * 16 + 8
+    movdqu [rsp + 0 * 16], xmm0
+    movdqu [rsp + 1 * 16], xmm1
+    movdqu [rsp + 2 * 16], xmm2
+    movdqu [rsp + 3 * 16], xmm3
+    movdqu [rsp + 4 * 16], xmm4
+    movdqu [rsp + 5 * 16], xmm5
+    movdqu [rsp + 6 * 16], xmm6
+    movdqu [rsp + 7 * 16], xmm7
+    movdqu [rsp + 8 * 16], xmm8
+    movdqu [rsp + 9 * 16], xmm9
+    movdqu [rsp + 10 * 16], xmm10
+    movdqu [rsp + 11 * 16], xmm11
+    movdqu [rsp + 12 * 16], xmm12
+    movdqu [rsp + 13 * 16], xmm13
+    movdqu [rsp + 14 * 16], xmm14
+    movdqu [rsp + 15 * 16], xmm15
+    cld
+    call trap_dispatch
+    movdqu xmm0, [rsp + 0 * 16]
+    movdqu xmm1, [rsp + 1 * 16]
+    movdqu xmm2, [rsp + 2 * 16]
+    movdqu xmm3, [rsp + 3 * 16]
+    movdqu xmm4, [rsp + 4 * 16]
+    movdqu xmm5, [rsp + 5 * 16]
+    movdqu xmm6, [rsp + 6 * 16]
+    movdqu xmm7, [rsp + 7 * 16]
+    movdqu xmm8, [rsp + 8 * 16]
+    movdqu xmm9, [rsp + 9 * 16]
+    movdqu xmm10, [rsp + 10 * 16]
+    movdqu xmm11, [rsp + 11 * 16]
+    movdqu xmm12, [rsp + 12 * 16]
+    movdqu xmm13, [rsp + 13 * 16]
+    movdqu xmm14, [rsp + 14 * 16]
+    movdqu xmm15, [rsp + 15 * 16]
+    add rsp, 16 * 16 + 8
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 8                      # vector
+    test qword ptr [rsp + 8], 3     # cs
+    jz 2f
+    swapgs
+2:
+    iretq
@@ -0,0 +1,241 @@
+//! User address spaces
+//!
+//! An address space is a level 4 table of its own. Its user part (from
+//! `USER_START` to `USER_END`) belongs to it alone; all other level 4
+//! entries are copied from the kernel table, so the kernel is mapped the
+//! same everywhere and its later mappings show up in every address space.
+//! Dropping an address space frees the frames of its user part and its
+//! page tables.
+
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use super::{USER_END, USER_START};
+use crate::memory;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = Page::<Size4KiB>::SIZE;
+
+/// An address space for user code
+pub struct AddressSpace {
+    /// the level 4 table; the lock serializes changes to the tables
+    level_4_table: spin::Mutex<PhysFrame>,
+}
+
+impl AddressSpace {
+    /// A new address space with nothing mapped in the user part
+    ///
+    /// Requires `memory::install`.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let frame = memory::with_kernel_memory(|_, frame_allocator| {
+            frame_allocator.allocate_frame()
+        }).ok_or(MapToError::FrameAllocationFailed)?;
+
+        let kernel = unsafe { &*table(memory::kernel_level_4_table()) };
+        let table = unsafe { &mut *table(frame) };
+        let user = user_entries();
+        for (i, entry) in table.iter_mut().enumerate() {
+            if user.contains(&i) {
+                entry.set_unused();
+            } else {
+                *entry = kernel[i].clone();
+            }
+        }
+        Ok(AddressSpace { level_4_table: spin::Mutex::new(frame) })
+    }
+
+    /// Physical frame of the level 4 table (what goes into CR3)
+    pub fn level_4_table(&self) -> PhysFrame {
+        *self.level_4_table.lock()
+    }
+
+    /// Load the address space on the calling CPU
+    ///
+    /// Unsafe because the running code must stay mapped: any code outside
+    /// of the user part is.
+    pub unsafe fn activate(&self) {
+        let (current, flags) = Cr3::read();
+        let frame = self.level_4_table();
+        if current != frame {
+            Cr3::write(frame, flags);
+        }
+    }
+
+    /// Map `pages` zeroed pages starting at `start` with `flags` (plus
+    /// `PRESENT` and `USER_ACCESSIBLE`)
+    ///
+    /// Panics outside the user part.
+    pub fn map(&self, start: VirtAddr, pages: u64, flags: PageTableFlags)
+        -> Result<(), MapToError<Size4KiB>>
+    {
+        let first = Page::<Size4KiB>::containing_address(start);
+        let end = first + pages;
+        assert!(first.start_address().as_u64() >= USER_START
+                && end.start_address().as_u64() <= USER_END,
+                "not a user address range");
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE;
+        let level_4_table = self.level_4_table.lock();
+        let mut mapper = unsafe { mapper(*level_4_table) };
+        memory::with_kernel_memory(|_, frame_allocator| {
+            for page in Page::range(first, end) {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe {
+                    memory::zero_frame(frame);
+                    // not flushed: the entry was not present before
+                    mapper.map_to_with_table_flags(
+                        page, frame, flags, table_flags, frame_allocator)?.ignore();
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Flags of the page containing `addr`, if it is mapped
+    pub fn flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        let level_4_table = self.level_4_table.lock();
+        let mapper = unsafe { mapper(*level_4_table) };
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    /// Change the flags of the mapped page containing `addr` (`PRESENT`
+    /// and `USER_ACCESSIBLE` are kept)
+    pub fn update_flags(&self, addr: VirtAddr, flags: PageTableFlags) -> bool {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let level_4_table = self.level_4_table.lock();
+        let mut mapper = unsafe { mapper(*level_4_table) };
+        let page = Page::<Size4KiB>::containing_address(addr);
+        match unsafe { mapper.update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Check that user code may access `len` bytes at `addr` (and write
+    /// them if `write`)
+    pub fn check_access(&self, addr: u64, len: u64, write: bool) -> bool {
+        let end = match addr.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        if addr < USER_START || end > USER_END {
+            return false;
+        }
+        if len == 0 {
+            return true;
+        }
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+        Page::range_inclusive(first, last).all(|page| {
+            match self.flags(page.start_address()) {
+                Some(flags) => {
+                    flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                        && (!write || flags.contains(PageTableFlags::WRITABLE))
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Copy `bytes` to `addr` in this address space, whether it is active
+    /// or not and regardless of the page flags
+    ///
+    /// Returns false if part of the range is not mapped.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> bool {
+        let level_4_table = self.level_4_table.lock();
+        let mapper = unsafe { mapper(*level_4_table) };
+        let mut addr = addr;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let phys = match mapper.translate(addr) {
+                TranslateResult::Mapped { frame, offset, .. } => frame.start_address() + offset,
+                _ => return false,
+            };
+            let room = PAGE_SIZE - u64::from(addr.page_offset());
+            let n = bytes.len().min(room as usize);
+            unsafe {
+                let dest = memory::phys_to_virt(phys).as_mut_ptr::<u8>();
+                core::ptr::copy_nonoverlapping(bytes.as_ptr(), dest, n);
+            }
+            addr += n as u64;
+            bytes = &bytes[n..];
+        }
+        true
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Give back the frames of the user part and the page tables
+    ///
+    /// The address space must not be active anywhere (`user::run` switches
+    /// back to the kernel's before letting go of it).
+    fn drop(&mut self) {
+        let level_4_table = *self.level_4_table.get_mut();
+        let level_4 = unsafe { &mut *table(level_4_table) };
+        let mut frames = Vec::new();
+        for entry in level_4[user_entries()].iter_mut() {
+            if !entry.is_unused() {
+                collect_frames(entry.frame().unwrap(), 3, &mut frames);
+                entry.set_unused();
+            }
+        }
+        frames.push(level_4_table);
+        memory::with_kernel_memory(|_, frame_allocator| {
+            for frame in frames {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+/// Indices of the level 4 entries of the user part
+fn user_entries() -> RangeInclusive<usize> {
+    usize::from(VirtAddr::new(USER_START).p4_index())
+        ..=usize::from(VirtAddr::new(USER_END - 1).p4_index())
+}
+
+/// Add the frames mapped by the table in `frame` at `level` (and the
+/// tables below it) to `frames`, followed by the table itself
+fn collect_frames(frame: PhysFrame, level: u8, frames: &mut Vec<PhysFrame>) {
+    let table = unsafe { &*table(frame) };
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        // user mappings are never huge pages
+        let below = entry.frame().expect("huge page in a user address space");
+        if level > 1 {
+            collect_frames(below, level - 1, frames);
+        } else {
+            frames.push(below);
+        }
+    }
+    frames.push(frame);
+}
+
+/// The page table in `frame`, through the physical memory mapping
+unsafe fn table(frame: PhysFrame) -> *mut PageTable {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// A mapper for the level 4 table in `frame`
+///
+/// Unsafe because only one may exist for a table at a time.
+unsafe fn mapper(frame: PhysFrame) -> OffsetPageTable<'static> {
+    let offset = memory::phys_to_virt(x86_64::PhysAddr::new(0));
+    OffsetPageTable::new(&mut *table(frame), offset)
+}
@@ -0,0 +1,318 @@
+//! ELF64 program loader
+//!
+//! Loads statically linked x86_64 executables (`ET_EXEC`) into a new
+//! `AddressSpace`: every `PT_LOAD` segment is mapped with the permissions
+//! of its flags, the file part is copied and the rest (BSS) left zeroed.
+//! The stack is laid out as the System V ABI prescribes for process entry:
+//! `argc` at the stack pointer, then the `argv` and `envp` pointers (each
+//! list ends with a null pointer) and the auxiliary vector.
+
+use alloc::{sync::Arc, vec::Vec};
+use crate::memory;
+use super::{AddressSpace, USER_END, USER_START};
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = Page::<Size4KiB>::SIZE;
+
+/// pages of the user stack
+pub const STACK_PAGES: u64 = 16;
+/// end of the user stack; the page above it stays unmapped
+pub const STACK_TOP: u64 = USER_END - PAGE_SIZE;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Why an image could not be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// not an ELF file, or cut short
+    BadHeader,
+    /// not a 64-bit little endian x86_64 executable
+    Unsupported,
+    /// a program header that does not fit the file or the user part
+    BadSegment,
+    /// `argv` and `envp` do not fit on the stack
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(_: MapToError<Size4KiB>) -> Self {
+        ElfError::OutOfMemory
+    }
+}
+
+/// A loaded program, ready for `user::run`
+pub struct Program {
+    pub space: Arc<AddressSpace>,
+    pub entry: VirtAddr,
+    /// initial stack pointer (pointing at `argc`)
+    pub stack: VirtAddr,
+}
+
+/// The parts of the file header the loader needs
+#[derive(Debug)]
+struct Header {
+    entry: u64,
+    phoff: u64,
+    phnum: u16,
+}
+
+/// A program header
+#[derive(Debug)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+fn u16_at(image: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(image[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(image: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(image[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(image: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(image[at..at + 8].try_into().unwrap())
+}
+
+impl Header {
+    fn parse(image: &[u8]) -> Result<Self, ElfError> {
+        if image.len() < HEADER_SIZE || image[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadHeader);
+        }
+        if image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB || image[6] != EV_CURRENT
+            || u16_at(image, 16) != ET_EXEC || u16_at(image, 18) != EM_X86_64
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let header = Header {
+            entry: u64_at(image, 24),
+            phoff: u64_at(image, 32),
+            phnum: u16_at(image, 56),
+        };
+        let phentsize = u16_at(image, 54) as usize;
+        let table_end = (header.phnum as u64)
+            .checked_mul(PROGRAM_HEADER_SIZE as u64)
+            .and_then(|size| size.checked_add(header.phoff));
+        if phentsize != PROGRAM_HEADER_SIZE
+            || table_end.map_or(true, |end| end > image.len() as u64)
+        {
+            return Err(ElfError::BadHeader);
+        }
+        Ok(header)
+    }
+
+    fn program_headers<'a>(&self, image: &'a [u8]) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let phoff = self.phoff as usize;
+        (0..self.phnum as usize).map(move |i| {
+            let at = phoff + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: u32_at(image, at),
+                flags: u32_at(image, at + 4),
+                offset: u64_at(image, at + 8),
+                vaddr: u64_at(image, at + 16),
+                filesz: u64_at(image, at + 32),
+                memsz: u64_at(image, at + 40),
+            }
+        })
+    }
+}
+
+impl ProgramHeader {
+    fn validate(&self, image: &[u8]) -> Result<(), ElfError> {
+        let file_end = self.offset.checked_add(self.filesz);
+        let mem_end = self.vaddr.checked_add(self.memsz);
+        let fits = file_end.map_or(false, |end| end <= image.len() as u64)
+            && mem_end.map_or(false, |end| end <= STACK_TOP - STACK_PAGES * PAGE_SIZE)
+            && self.vaddr >= USER_START
+            && self.filesz <= self.memsz
+            && self.vaddr % PAGE_SIZE == self.offset % PAGE_SIZE;
+        if fits { Ok(()) } else { Err(ElfError::BadSegment) }
+    }
+
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= memory::no_execute();
+        }
+        flags
+    }
+}
+
+/// Load the executable `image` into a new address space, with `argv` and
+/// `envp` on its stack
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let header = Header::parse(image)?;
+    let space = AddressSpace::new()?;
+    let mut phdr = None;
+    let mut entry_executable = false;
+
+    for segment in header.program_headers(image) {
+        match segment.kind {
+            PT_LOAD => {}
+            PT_PHDR => {
+                phdr = Some(segment.vaddr);
+                continue;
+            }
+            _ => continue,
+        }
+        segment.validate(image)?;
+        if segment.memsz == 0 {
+            continue;
+        }
+        load_segment(&space, image, &segment)?;
+        if segment.flags & PF_X != 0 && segment.vaddr <= header.entry
+            && header.entry - segment.vaddr < segment.memsz
+        {
+            entry_executable = true;
+        }
+        // the program headers are usually part of the first segment
+        if phdr.is_none() && segment.offset <= header.phoff
+            && header.phoff < segment.offset + segment.filesz
+        {
+            phdr = Some(segment.vaddr + (header.phoff - segment.offset));
+        }
+    }
+    // jumping to a non-executable entry would only fault in ring 3
+    if !entry_executable || !space.check_access(header.entry, 1, false) {
+        return Err(ElfError::BadHeader);
+    }
+
+    let stack_bottom = VirtAddr::new(STACK_TOP - STACK_PAGES * PAGE_SIZE);
+    space.map(stack_bottom, STACK_PAGES,
+              PageTableFlags::WRITABLE | memory::no_execute())?;
+    let mut auxv = Vec::new();
+    if let Some(phdr) = phdr {
+        auxv.push((AT_PHDR, phdr));
+        auxv.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+        auxv.push((AT_PHNUM, header.phnum as u64));
+    }
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, header.entry));
+    let stack = build_stack(&space, argv, envp, &auxv)?;
+
+    Ok(Program {
+        space: Arc::new(space),
+        entry: VirtAddr::new(header.entry),
+        stack,
+    })
+}
+
+/// Map the pages of `segment` and copy its file part
+///
+/// Segments may share a page at their ends; such a page gets the
+/// permissions of both.
+fn load_segment(space: &AddressSpace, image: &[u8], segment: &ProgramHeader)
+    -> Result<(), ElfError>
+{
+    let flags = segment.page_flags();
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr));
+    let last = Page::<Size4KiB>::containing_address(
+        VirtAddr::new(segment.vaddr + segment.memsz - 1));
+    for page in Page::range_inclusive(first, last) {
+        match space.flags(page.start_address()) {
+            Some(old) => {
+                let mut merged = (old | flags) & !PageTableFlags::NO_EXECUTE;
+                if old.contains(PageTableFlags::NO_EXECUTE)
+                    && flags.contains(PageTableFlags::NO_EXECUTE)
+                {
+                    merged |= PageTableFlags::NO_EXECUTE;
+                }
+                if !space.update_flags(page.start_address(), merged) {
+                    return Err(ElfError::BadSegment);
+                }
+            }
+            None => space.map(page.start_address(), 1, flags)?,
+        }
+    }
+    let data = &image[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+    if !space.write(VirtAddr::new(segment.vaddr), data) {
+        return Err(ElfError::BadSegment);
+    }
+    Ok(())
+}
+
+/// Lay out the strings, `argc`, `argv`, `envp` and `auxv` at the top of the
+/// stack and return the initial stack pointer
+fn build_stack(space: &AddressSpace, argv: &[&str], envp: &[&str], auxv: &[(u64, u64)])
+    -> Result<VirtAddr, ElfError>
+{
+    // the strings go right below the top
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let strings_start = (STACK_TOP - strings_size as u64) & !0xf;
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut pointers = Vec::with_capacity(argv.len() + envp.len() + 2);
+    for list in [argv, envp] {
+        for s in list {
+            pointers.push(strings_start + strings.len() as u64);
+            strings.extend_from_slice(s.as_bytes());
+            strings.push(0);
+        }
+    }
+    let (argv_pointers, envp_pointers) = pointers.split_at(argv.len());
+
+    // then argc, the pointer lists and auxv, with the stack pointer 16 byte
+    // aligned at argc
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend_from_slice(argv_pointers);
+    words.push(0);
+    words.extend_from_slice(envp_pointers);
+    words.push(0);
+    for &(kind, value) in auxv {
+        words.push(kind);
+        words.push(value);
+    }
+    words.push(AT_NULL);
+    words.push(0);
+    let size = words.len() as u64 * 8;
+    let stack = (strings_start - size) & !0xf;
+    if stack < STACK_TOP - STACK_PAGES * PAGE_SIZE {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let written = space.write(VirtAddr::new(stack), &bytes)
+        && space.write(VirtAddr::new(strings_start), &strings);
+    assert!(written, "user stack not mapped");
+    Ok(VirtAddr::new(stack))
+}
+
+/// Load `image` and run it on the current thread; returns its exit code
+pub fn exec(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<i64, ElfError> {
+    let program = load(image, argv, envp)?;
+    Ok(unsafe { super::run(program.space, program.entry, program.stack) })
+}
@@ -0,0 +1,127 @@
+# Ways in and out of ring 3
+#
+# PerCpu offsets used here (see percpu.rs):
+.set PERCPU_KERNEL_STACK, 8
+.set PERCPU_USER_RSP, 16
+.set PERCPU_TSS_KERNEL_STACK, 24
+# user mode selectors (see gdt.rs)
+.set USER_DATA, 0x18 | 3
+.set USER_CODE, 0x20 | 3
+
+.section .text
+
+# i64 enter_user(u64 entry, u64 user_stack, UserContext *context)
+#
+# Saves the callee-saved registers, records the stack pointer in
+# context->return_rsp for leave_user and irets to entry in ring 3. The
+# kernel stack for syscalls and interrupts from ring 3 starts right below
+# the saved registers (context->kernel_stack). Returns the exit code
+# passed to leave_user, with interrupts disabled.
+.global enter_user
+enter_user:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    cli
+    mov [rdx], rsp                  # context->return_rsp
+    mov rax, rsp
+    and rax, -16
+    mov [rdx + 8], rax              # context->kernel_stack
+    mov gs:[PERCPU_KERNEL_STACK], rax
+    mov rcx, gs:[PERCPU_TSS_KERNEL_STACK]
+    mov [rcx], rax
+
+    push USER_DATA                  # ss
+    push rsi                        # rsp
+    push 0x202                      # rflags: interrupts enabled
+    push USER_CODE                  # cs
+    push rdi                        # rip
+
+    # leave no kernel values behind
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    swapgs
+    iretq
+
+# void leave_user(u64 return_rsp, i64 code) -> !
+#
+# Returns from enter_user with code. Called on the kernel stack of the
+# thread (from a syscall or a fault handler) with the kernel GS base.
+.global leave_user
+leave_user:
+    mov rsp, rdi
+    mov rax, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+# SYSCALL lands here (IA32_LSTAR) with the user rip in rcx, the user
+# rflags in r11 and interrupts masked (IA32_FMASK). Saves the registers as
+# a SyscallFrame on the kernel stack and calls syscall_dispatch, whose
+# result ends up in rax.
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[PERCPU_USER_RSP], rsp
+    mov rsp, gs:[PERCPU_KERNEL_STACK]
+    push qword ptr gs:[PERCPU_USER_RSP]
+    push rcx
+    push r11
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    sti
+
+    mov rdi, rsp
+    call syscall_dispatch
+    mov [rsp], rax
+
+    cli
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
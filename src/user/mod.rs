@@ -0,0 +1,199 @@
+//! User mode (ring 3)
+//!
+//! A kernel thread runs user code with `run`, which returns once the code
+//! calls the `exit` syscall or is killed by a fault. While it is in user
+//! mode, syscalls and interrupts enter the kernel on the thread's own
+//! stack right below the frame of `run`.
+//!
+//! User memory lives between `USER_START` and `USER_END` in an
+//! `AddressSpace`, which is active while the thread runs. Programs are
+//! loaded from ELF images by `elf`. The GS base is 0 in ring 3 (see
+//! `percpu`).
+
+mod address_space;
+pub mod elf;
+
+pub use address_space::AddressSpace;
+
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::{memory, percpu, println, thread};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::{idt::InterruptStackFrame, paging::PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+global_asm!(include_str!("entry.s"));
+
+extern "C" {
+    fn enter_user(entry: u64, user_stack: u64, context: *const UserContext) -> i64;
+    fn leave_user(return_rsp: u64, code: i64) -> !;
+}
+
+/// lowest user address
+pub const USER_START: u64 = 0x_7000_0000_0000;
+/// end of user addresses (the end of the lower half)
+pub const USER_END: u64 = 0x_8000_0000_0000;
+
+/// Where a thread in user mode comes back to (part of `thread::Thread`)
+#[repr(C)]
+pub(crate) struct UserContext {
+    /// stack pointer `leave_user` returns to, 0 outside of user mode
+    return_rsp: AtomicU64,
+    /// start of the kernel stack for syscalls and interrupts
+    kernel_stack: AtomicU64,
+    /// the address space of the user code, if it runs any
+    space: spin::Mutex<Option<Arc<AddressSpace>>>,
+    /// its level 4 table, for context switches (0 for the kernel's)
+    level_4_table: AtomicU64,
+}
+
+impl UserContext {
+    pub(crate) const fn new() -> Self {
+        UserContext {
+            return_rsp: AtomicU64::new(0),
+            kernel_stack: AtomicU64::new(0),
+            space: spin::Mutex::new(None),
+            level_4_table: AtomicU64::new(0),
+        }
+    }
+
+    /// Kernel stack to switch to when the thread runs, if it is in user mode
+    pub(crate) fn kernel_stack(&self) -> Option<u64> {
+        match self.kernel_stack.load(Ordering::Relaxed) {
+            0 => None,
+            stack => Some(stack),
+        }
+    }
+}
+
+/// Run user code at `entry` in `space` with the stack pointer at `stack`
+/// until it exits, and return its exit code
+///
+/// Faults in user mode end it with `fault_exit_code`. This function is
+/// unsafe because the caller must make sure that `entry` and `stack` are
+/// mapped in `space`.
+pub unsafe fn run(space: Arc<AddressSpace>, entry: VirtAddr, stack: VirtAddr) -> i64 {
+    let me = thread::current();
+    let context = &me.user;
+    assert!(context.return_rsp.load(Ordering::Relaxed) == 0,
+            "thread is already running user code");
+    interrupts::without_interrupts(|| {
+        context.level_4_table.store(space.level_4_table().start_address().as_u64(),
+                                    Ordering::Relaxed);
+        space.activate();
+    });
+    *context.space.lock() = Some(space);
+
+    let code = enter_user(entry.as_u64(), stack.as_u64(), context);
+
+    context.return_rsp.store(0, Ordering::Relaxed);
+    context.kernel_stack.store(0, Ordering::Relaxed);
+    context.level_4_table.store(0, Ordering::Relaxed);
+    switch_to(context);
+    interrupts::enable();
+    context.space.lock().take();
+    code
+}
+
+/// Leave user mode for good: `run` returns `code`
+///
+/// Must be called in a syscall or fault handler of the current thread.
+pub(crate) fn exit(code: i64) -> ! {
+    let return_rsp = thread::current().user.return_rsp.load(Ordering::Relaxed);
+    assert!(return_rsp != 0, "not running user code");
+    interrupts::disable();
+    unsafe { leave_user(return_rsp, code) }
+}
+
+/// The current thread is running user code (and is in a syscall or fault
+/// handler if it gets to ask)
+pub(crate) fn in_user_context() -> bool {
+    thread::current().user.return_rsp.load(Ordering::Relaxed) != 0
+}
+
+/// Exit code of user code killed by the exception `vector`
+pub fn fault_exit_code(vector: u8) -> i64 {
+    -128 - vector as i64
+}
+
+/// The exception interrupted ring 3
+pub(crate) fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Called by interrupt handlers first thing: switches to the kernel GS
+/// base if the interrupt came from ring 3, and back when dropped
+pub(crate) struct KernelGs(bool);
+
+impl KernelGs {
+    pub(crate) fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let from_user = from_user(stack_frame);
+        if from_user {
+            unsafe { swapgs() };
+        }
+        KernelGs(from_user)
+    }
+
+    /// Like `enter`, for handlers that may interrupt the kernel before it
+    /// switched the GS base, right after `syscall` (NMI, machine check,
+    /// double fault): looks at the GS base itself rather than at the
+    /// interrupted code segment
+    pub(crate) fn enter_paranoid() -> Self {
+        let user_gs = !percpu::gs_is_kernel();
+        if user_gs {
+            unsafe { swapgs() };
+        }
+        KernelGs(user_gs)
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.0 {
+            unsafe { swapgs() };
+        }
+    }
+}
+
+unsafe fn swapgs() {
+    core::arch::asm!("swapgs", options(nostack, preserves_flags));
+}
+
+/// Kill the user code of the current thread after an exception in ring 3
+///
+/// `gs` is the guard the handler started with.
+pub(crate) fn kill(gs: KernelGs, vector: u8, stack_frame: &InterruptStackFrame) -> ! {
+    // `run` returns to kernel code, which keeps the kernel GS base
+    core::mem::forget(gs);
+    println!("user code killed by exception {} at {:?}",
+             vector, stack_frame.instruction_pointer);
+    exit(fault_exit_code(vector))
+}
+
+/// Check that the user code of the current thread may access `len` bytes
+/// at `addr` (and write them if `write`)
+pub fn check_access(addr: u64, len: u64, write: bool) -> bool {
+    let space = thread::current().user.space.lock().clone();
+    space.map_or(false, |space| space.check_access(addr, len, write))
+}
+
+/// Called by `thread` when switching to a thread: syscalls and interrupts
+/// from ring 3 have to land on its stack, and its address space (or the
+/// kernel's) has to be active
+pub(crate) fn switch_to(context: &UserContext) {
+    if let Some(stack) = context.kernel_stack() {
+        percpu::current().set_kernel_stack(stack);
+    }
+    let table = match context.level_4_table.load(Ordering::Relaxed) {
+        0 => memory::kernel_level_4_table(),
+        addr => PhysFrame::containing_address(PhysAddr::new(addr)),
+    };
+    let (current, flags) = Cr3::read();
+    if current != table {
+        unsafe { Cr3::write(table, flags) };
+    }
+}
@@ -23,11 +23,22 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        writer::WRITER.lock().write_fmt(args).unwrap();
-    });
+    if writer::WRITER.is_held_by_current_cpu() {
+        return; // a panic while printing; the serial port has the message
+    }
+    writer::WRITER.lock().write_fmt(args).unwrap();
+}
+
+/// Prints to the VGA text buffer unless `WRITER` is locked, for code that
+/// may have interrupted its holder; returns false if it did not print
+pub(crate) fn try_print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    match writer::WRITER.try_lock() {
+        Some(mut writer) => writer.write_fmt(args).is_ok(),
+        None => false,
+    }
 }
 
 #[test_case]
@@ -157,9 +157,7 @@ pub fn display(s: &str, pos: (u8, u8), attr: Attribute) {
 /// Do not call if you already have a mutex lock on WRITER
 /// use the equivalent method on the WRITER instead
 pub fn clear_screen(attr: Attribute) {
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        WRITER.lock().clear_screen(attr);
-    });
+    WRITER.lock().clear_screen(attr);
 }
 
 /// Set the text buffer attribute for the writer to use (Synchronized)
@@ -167,7 +165,5 @@ pub fn clear_screen(attr: Attribute) {
 /// Do not call if you already have a mutex lock on WRITER
 /// use the equivalent ethod on the WRITER instead
 pub fn set_attribute(attr: Attribute) {
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        WRITER.lock().set_attribute(attr);
-    });
+    WRITER.lock().set_attribute(attr);
 }
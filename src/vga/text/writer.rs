@@ -1,16 +1,15 @@
 use core::fmt;
 use core::num::ParseIntError;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use alloc::vec::Vec;
-use crate::vga::text;
+use crate::{sync::IrqMutex, vga::text};
 
 lazy_static! {
     /// A global 'Writer' instance that can be used for printing to the
     /// VGA text buffer
     ///
     /// Used by the `print!` and `println!` macros.
-    pub(crate) static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub(crate) static ref WRITER: IrqMutex<Writer> = IrqMutex::named("WRITER", Writer {
         column: 0,
         row: text::BUFFER_HEIGHT - 1,
         attr: Default::default(),
@@ -111,10 +110,12 @@ impl Writer {
 
     /// Writes an ASCII byte to the text buffer.
     ///
-    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character.
+    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character
+    /// and backspace (which moves left on the current line).
     fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            0x08 => self.column = self.column.saturating_sub(1),
             0x20..=0x7e =>
                 self.write_screen(text::Char::new(byte, self.attr)),
             _ =>
@@ -233,17 +234,14 @@ enum Ansi {
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
     use text::BUFFER_HEIGHT;
 
     let s = "Some test string that fits on a single line";
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let scrn_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i]
-                .read();
-            assert_eq!(char::from(scrn_char.code), c);
-        }
-    });
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
+    for (i, c) in s.chars().enumerate() {
+        let scrn_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i]
+            .read();
+        assert_eq!(char::from(scrn_char.code), c);
+    }
 }
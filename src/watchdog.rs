@@ -0,0 +1,423 @@
+//! NMI watchdog
+//!
+//! Finds CPUs that stopped taking timer interrupts, typically because they
+//! spin with interrupts disabled, which otherwise freezes the machine
+//! without a word. While the watchdog runs, the tickless timer interrupts
+//! every CPU at least four times per period (`PERIOD` unless given to
+//! `start_with`), and a non-maskable interrupt arrives about every period
+//! from one of:
+//!
+//! - the first performance counter of every local APIC, counting unhalted
+//!   core cycles (architectural performance monitoring, CPUID leaf 0xa).
+//!   Every CPU checks itself. A halted CPU counts no cycles, which is fine
+//!   since it cannot be spinning.
+//! - an HPET comparator that delivers NMIs to the BSP as messages on the
+//!   FSB. The BSP checks every CPU and sends an NMI to those that are
+//!   stuck so they report themselves.
+//!
+//! A CPU whose timer interrupt count did not change over `STRIKES` (or as
+//! many as given to `start_with`) watchdog NMIs in a row is reported once
+//! per lockup: its NMI handler (on its own IST stack, see `gdt`) prints the
+//! interrupted instruction and a backtrace on the serial port and, unless
+//! `WRITER` is locked, the screen. The handler takes no lock and does not
+//! allocate.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use crate::{apic, backtrace::Backtrace, cpu, percpu::{self, PerCpu}, serial::RawSerial, smp};
+use crate::{symbols::Location, task::timer::{self, hpet}, time::{self, Duration}, vga};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// time between watchdog NMIs (at most 2^31 cycles with a performance
+/// counter)
+pub const PERIOD: Duration = Duration::from_secs(1);
+/// watchdog NMIs in a row without a timer interrupt that make a lockup
+pub const STRIKES: u32 = 4;
+/// timer interrupts a CPU gets at least per period while the watchdog runs
+const HEARTBEATS: u64 = 4;
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+// event select bits
+const UNHALTED_CORE_CYCLES: u64 = 0x3c;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// cycles per second assumed without a known TSC frequency
+const ASSUMED_HZ: u64 = 1_000_000_000;
+/// counters only take 32-bit writes, sign extended
+const MAX_PERIOD_CYCLES: u64 = 0x7fff_ffff;
+/// where MSIs to the local APICs go
+const MSI_ADDRESS: u32 = 0xfee0_0000;
+
+/// current `Source`, 0 while stopped
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+/// counter value that overflows after a period
+static RELOAD: AtomicU64 = AtomicU64::new(0);
+/// HPET comparator in use
+static COMPARATOR: AtomicU8 = AtomicU8::new(0);
+/// HPET main counter ticks per period
+static HPET_TICKS: AtomicU64 = AtomicU64::new(0);
+/// the period in ns
+static PERIOD_NS: AtomicU64 = AtomicU64::new(0);
+/// strikes that make a lockup
+static STRIKE_LIMIT: AtomicU32 = AtomicU32::new(STRIKES);
+static LOCKUPS: AtomicU64 = AtomicU64::new(0);
+static REPORTS: AtomicU64 = AtomicU64::new(0);
+
+/// Hardware raising the watchdog NMIs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Source {
+    PerformanceCounter = 1,
+    Hpet,
+}
+
+/// Watchdog state of one CPU (part of its `percpu::PerCpu` block)
+pub(crate) struct CpuWatchdog {
+    /// timer interrupts at the last check
+    last_ticks: AtomicU64,
+    /// checks in a row that found no new timer interrupt
+    strikes: AtomicU32,
+    /// the current lockup has been reported
+    reported: AtomicBool,
+    /// the NMI that is on its way asks for a report
+    report_requested: AtomicBool,
+}
+
+impl CpuWatchdog {
+    pub(crate) const fn new() -> Self {
+        CpuWatchdog {
+            last_ticks: AtomicU64::new(0),
+            strikes: AtomicU32::new(0),
+            reported: AtomicBool::new(false),
+            report_requested: AtomicBool::new(false),
+        }
+    }
+
+    /// Count a watchdog NMI with `ticks` timer interrupts so far; true if
+    /// it just reached the strike limit without a new one
+    fn check(&self, ticks: u64) -> bool {
+        if self.last_ticks.swap(ticks, Ordering::Relaxed) != ticks {
+            self.strikes.store(0, Ordering::Relaxed);
+            self.reported.store(false, Ordering::Relaxed);
+            return false;
+        }
+        let strikes = self.strikes.fetch_add(1, Ordering::Relaxed) + 1;
+        strikes >= STRIKE_LIMIT.load(Ordering::Relaxed)
+            && !self.reported.swap(true, Ordering::Relaxed)
+    }
+}
+
+/// Start checking all CPUs online, returns the NMI source or `None` if
+/// there is none (or no local APIC)
+///
+/// Requires `timer::init`, and `thread::init` on the APs.
+pub fn start() -> Option<Source> {
+    start_with(PERIOD, STRIKES)
+}
+
+/// Like `start`, but with an NMI every `period` and a lockup after
+/// `strikes` of them (e.g. for tests, which cannot wait long)
+///
+/// Does nothing but return the source if the watchdog runs already.
+pub fn start_with(period: Duration, strikes: u32) -> Option<Source> {
+    if let Some(source) = source() {
+        return Some(source);
+    }
+    if !apic::is_enabled() {
+        return None;
+    }
+    let source = if counter_width().is_some() {
+        Source::PerformanceCounter
+    } else if hpet_comparator().is_some() {
+        Source::Hpet
+    } else {
+        return None;
+    };
+    PERIOD_NS.store(period.as_nanos() as u64, Ordering::Relaxed);
+    STRIKE_LIMIT.store(strikes.max(1), Ordering::Relaxed);
+    for cpu in percpu::all() {
+        touch_cpu(cpu);
+    }
+    SOURCE.store(source as u8, Ordering::Release);
+
+    // idle CPUs may wait for a far away deadline
+    interrupts::without_interrupts(timer::kick);
+    for cpu in 1..smp::cpu_count() {
+        if smp::is_online(cpu) {
+            smp::run_on(cpu, || interrupts::without_interrupts(timer::kick));
+        }
+    }
+
+    match source {
+        Source::PerformanceCounter => {
+            let hz = time::tsc_frequency().unwrap_or(ASSUMED_HZ);
+            let cycles = (hz as u128 * period.as_nanos() / 1_000_000_000) as u64;
+            RELOAD.store(0u64.wrapping_sub(cycles.min(MAX_PERIOD_CYCLES)), Ordering::Relaxed);
+            interrupts::without_interrupts(arm_counter);
+            for cpu in 1..smp::cpu_count() {
+                if smp::is_online(cpu) {
+                    smp::run_on(cpu, || interrupts::without_interrupts(arm_counter));
+                }
+            }
+        }
+        Source::Hpet => {
+            let comparator = hpet_comparator().expect("HPET comparator went away");
+            COMPARATOR.store(comparator, Ordering::Relaxed);
+            HPET_TICKS.store(hpet::duration_to_ticks(period), Ordering::Relaxed);
+            let bsp = percpu::get(0).expect("BSP not set up").apic_id;
+            hpet::set_fsb_route(comparator, MSI_ADDRESS | u32::from(bsp) << 12,
+                                apic::DELIVERY_NMI);
+            arm_comparator(comparator);
+        }
+    }
+    Some(source)
+}
+
+/// Stop the NMIs
+pub fn stop() {
+    match source() {
+        Some(Source::PerformanceCounter) => {
+            SOURCE.store(0, Ordering::Release);
+            interrupts::without_interrupts(disarm_counter);
+            for cpu in 1..smp::cpu_count() {
+                if smp::is_online(cpu) {
+                    smp::run_on(cpu, || interrupts::without_interrupts(disarm_counter));
+                }
+            }
+        }
+        Some(Source::Hpet) => {
+            SOURCE.store(0, Ordering::Release);
+            hpet::disable_comparator(COMPARATOR.load(Ordering::Relaxed));
+        }
+        None => {}
+    }
+}
+
+pub fn source() -> Option<Source> {
+    match SOURCE.load(Ordering::Acquire) {
+        1 => Some(Source::PerformanceCounter),
+        2 => Some(Source::Hpet),
+        _ => None,
+    }
+}
+
+pub fn is_running() -> bool {
+    source().is_some()
+}
+
+/// Lockups reported so far
+pub fn lockups() -> u64 {
+    LOCKUPS.load(Ordering::Relaxed)
+}
+
+/// Reports printed so far, lockups and those asked for by `request_report`
+pub fn reports() -> u64 {
+    REPORTS.load(Ordering::Relaxed)
+}
+
+/// Tell the watchdog that the calling CPU is fine although it takes no
+/// timer interrupts (e.g. while it waits in the monitor)
+pub fn touch() {
+    if let Some(cpu) = percpu::find_current() {
+        touch_cpu(cpu);
+    }
+}
+
+/// Make `cpu` print where it is and how it got there from its NMI
+/// handler, even if it runs with interrupts disabled
+///
+/// Returns false if the CPU is not set up or there is no local APIC.
+pub fn request_report(cpu: usize) -> bool {
+    match percpu::get(cpu) {
+        Some(cpu) if apic::is_enabled() => {
+            cpu.watchdog.report_requested.store(true, Ordering::Release);
+            apic::send_nmi(cpu.apic_id);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// When the timer of the calling CPU should interrupt next to show that
+/// it is alive (in ns since boot), `None` if the watchdog is not running
+pub(crate) fn next_heartbeat(now: u64) -> Option<u64> {
+    if is_running() {
+        Some(now + PERIOD_NS.load(Ordering::Relaxed) / HEARTBEATS)
+    } else {
+        None
+    }
+}
+
+/// Called by the NMI handler with the `rbp` of the interrupted code
+///
+/// Prints a note for NMIs that neither the watchdog nor `request_report`
+/// sent (hardware errors, usually).
+pub(crate) fn nmi(stack_frame: &InterruptStackFrame, rbp: u64) {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let cpu = match percpu::find_current() {
+        Some(cpu) => cpu,
+        None => {
+            print(format_args!("NMI at {}\n", Location(rip)));
+            return;
+        }
+    };
+    let mut handled = false;
+    if cpu.watchdog.report_requested.swap(false, Ordering::AcqRel) {
+        report(cpu, "stopped", rip, rbp);
+        handled = true;
+    }
+    match source() {
+        Some(Source::PerformanceCounter) if counter_overflowed() => {
+            rearm_counter();
+            if check(cpu) {
+                LOCKUPS.fetch_add(1, Ordering::Relaxed);
+                report(cpu, "locked up", rip, rbp);
+            }
+            handled = true;
+        }
+        // NMIs do not tell where they come from: the HPET is assumed (two
+        // NMIs at once arrive as one, so a requested report may be it too)
+        Some(Source::Hpet) if cpu.cpu_id == 0 => {
+            let comparator = COMPARATOR.load(Ordering::Relaxed);
+            if !hpet::supports_periodic(comparator) {
+                arm_comparator(comparator);
+            }
+            for other in percpu::all() {
+                if !check(other) {
+                    continue;
+                }
+                LOCKUPS.fetch_add(1, Ordering::Relaxed);
+                if other.cpu_id == cpu.cpu_id {
+                    report(cpu, "locked up", rip, rbp);
+                } else {
+                    print(format_args!("WATCHDOG: CPU {} locked up\n", other.cpu_id));
+                    other.watchdog.report_requested.store(true, Ordering::Release);
+                    apic::send_nmi(other.apic_id);
+                }
+            }
+            handled = true;
+        }
+        _ => {}
+    }
+    if !handled {
+        print(format_args!("NMI on CPU {} at {}\n", cpu.cpu_id, Location(rip)));
+    }
+}
+
+/// Count a watchdog NMI for `cpu`; true if it just reached the strike
+/// limit without a timer interrupt
+fn check(cpu: &PerCpu) -> bool {
+    cpu.watchdog.check(cpu.timer.interrupts())
+}
+
+fn touch_cpu(cpu: &PerCpu) {
+    cpu.watchdog.last_ticks.store(cpu.timer.interrupts(), Ordering::Relaxed);
+    cpu.watchdog.strikes.store(0, Ordering::Relaxed);
+}
+
+fn report(cpu: &PerCpu, what: &str, rip: u64, rbp: u64) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    print(format_args!("WATCHDOG: CPU {} {} at {}\n{}\n",
+                       cpu.cpu_id, what, Location(rip), Backtrace::from_registers(rip, rbp)));
+}
+
+/// Print without waiting for the locks of the screen or the serial port
+fn print(args: fmt::Arguments) {
+    vga::try_print(args);
+    let _ = RawSerial.write_fmt(args);
+}
+
+/// Bits of the performance counters, `None` without a counter for
+/// unhalted core cycles
+fn counter_width() -> Option<u32> {
+    cpu::info().perfmon()
+        .filter(|pmu| pmu.counters > 0 && pmu.unhalted_cycles)
+        .map(|pmu| u32::from(pmu.width))
+}
+
+fn counter_version() -> u8 {
+    cpu::info().perfmon().map_or(0, |pmu| pmu.version)
+}
+
+/// Start the first performance counter of the calling CPU
+fn arm_counter() {
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(0);
+        Msr::new(IA32_PMC0).write(RELOAD.load(Ordering::Relaxed));
+        if counter_version() >= 2 {
+            let mut global = Msr::new(IA32_PERF_GLOBAL_CTRL);
+            let enabled = global.read();
+            global.write(enabled | 1);
+        }
+        Msr::new(IA32_PERFEVTSEL0)
+            .write(UNHALTED_CORE_CYCLES | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN);
+    }
+    apic::write(apic::LVT_PERFMON, apic::DELIVERY_NMI);
+}
+
+fn disarm_counter() {
+    unsafe { Msr::new(IA32_PERFEVTSEL0).write(0) };
+    apic::write(apic::LVT_PERFMON, apic::LVT_MASKED);
+}
+
+/// The counter counts up from a negative value: past zero it overflowed
+fn counter_overflowed() -> bool {
+    let width = counter_width().unwrap_or(32);
+    let value = unsafe { Msr::new(IA32_PMC0).read() };
+    value & 1 << (width - 1) == 0
+}
+
+fn rearm_counter() {
+    unsafe {
+        Msr::new(IA32_PMC0).write(RELOAD.load(Ordering::Relaxed));
+        if counter_version() >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+        }
+    }
+    // delivering the NMI masked the entry
+    apic::write(apic::LVT_PERFMON, apic::DELIVERY_NMI);
+}
+
+/// A free HPET comparator that can deliver NMIs (0 and 1 may be routed
+/// to IRQ 0 and IRQ 8)
+fn hpet_comparator() -> Option<u8> {
+    if !hpet::is_present() {
+        return None;
+    }
+    (2..hpet::num_timers()).find(|&comparator| hpet::supports_fsb(comparator))
+}
+
+fn arm_comparator(comparator: u8) {
+    let ticks = HPET_TICKS.load(Ordering::Relaxed);
+    if hpet::supports_periodic(comparator) {
+        hpet::set_comparator(comparator, hpet::Mode::Periodic, ticks);
+    } else {
+        hpet::set_comparator(comparator, hpet::Mode::OneShot, hpet::counter() + ticks);
+    }
+}
+
+#[test_case]
+fn test_lockups_take_strikes() {
+    // not the CPU's own: the watchdog NMI may check that one meanwhile
+    let watchdog = CpuWatchdog::new();
+    assert!(!watchdog.check(7));
+    let strikes = STRIKE_LIMIT.load(Ordering::Relaxed);
+    for _ in 1..strikes {
+        assert!(!watchdog.check(7));
+    }
+    assert!(watchdog.check(7), "no lockup after {} strikes", strikes);
+    // reported only once
+    assert!(!watchdog.check(7));
+    // a timer interrupt ends the lockup
+    assert!(!watchdog.check(8));
+    assert_eq!(watchdog.strikes.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use blog_os::backtrace::Backtrace;
+use blog_os::symbols::{self, Location};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[inline(never)]
+fn inner() -> Backtrace {
+    Backtrace::capture()
+}
+
+#[inline(never)]
+fn outer() -> Backtrace {
+    let backtrace = inner();
+    // keeps the call from becoming a jump
+    assert!(!backtrace.frames().is_empty());
+    backtrace
+}
+
+#[test_case]
+fn backtrace_walks_the_callers() {
+    let backtrace = outer();
+    let frames = backtrace.frames();
+    assert!(frames.len() >= 3, "{:?}", backtrace);
+    // the first frame returns into `outer`, the next into this test
+    let outer_addr = outer as usize as u64;
+    assert!(frames[0] > outer_addr && frames[0] < outer_addr + 0x100);
+
+    if symbols::is_loaded() {
+        let (symbol, _) = symbols::lookup(frames[0]).expect("no symbol for outer");
+        assert!(symbol.name.ends_with("backtrace::outer"), "{}", symbol.name);
+        let (symbol, _) = symbols::lookup(frames[1]).expect("no symbol for the test");
+        assert!(symbol.name.ends_with("backtrace_walks_the_callers"), "{}", symbol.name);
+    }
+    blog_os::serial_println!("{}", backtrace);
+}
+
+#[test_case]
+fn locations_show_symbols() {
+    let addr = inner as usize as u64;
+    let text = format!("{}", Location(addr + 4));
+    assert!(text.starts_with(&format!("{:#018x}", addr + 4)));
+    if symbols::is_loaded() {
+        assert!(text.ends_with("backtrace::inner+0x4"), "{}", text);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
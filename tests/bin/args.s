@@ -0,0 +1,50 @@
+# Writes argv[1] and exits with argc * 1000 + envc * 100 + what the write
+# returned, or -1 if .bss is not zeroed or .data not writable.
+.intel_syntax noprefix
+
+.text
+.global _start
+_start:
+    mov rbx, [rsp]                  # argc
+    cmp qword ptr [rip + zeroed], 0
+    jne fail
+    add qword ptr [rip + answer], 1
+    cmp qword ptr [rip + answer], 43
+    jne fail
+
+    # count envp (after argv and its null pointer)
+    lea r12, [rsp + rbx * 8 + 16]
+    xor r13d, r13d
+1:  cmp qword ptr [r12 + r13 * 8], 0
+    je 2f
+    inc r13
+    jmp 1b
+
+2:  mov rdi, [rsp + 16]             # argv[1]
+    xor esi, esi
+3:  cmp byte ptr [rdi + rsi], 0
+    je 4f
+    inc rsi
+    jmp 3b
+4:  mov eax, 0                      # write
+    syscall
+
+    imul rdi, rbx, 1000
+    imul r13, r13, 100
+    add rdi, r13
+    add rdi, rax
+    mov eax, 1                      # exit
+    syscall
+
+fail:
+    mov rdi, -1
+    mov eax, 1
+    syscall
+
+.data
+answer:
+    .quad 42
+
+.bss
+zeroed:
+    .quad 0
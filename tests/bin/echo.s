@@ -0,0 +1,45 @@
+# Answers one call on handle 0 with the same bytes and exits with their
+# number.
+.intel_syntax noprefix
+
+.text
+.global _start
+_start:
+    mov eax, 8                      # recv
+    xor edi, edi
+    lea rsi, [rip + msg]
+    syscall
+    test rax, rax
+    js fail
+    mov r12, rax
+
+    mov eax, 10                     # reply
+    mov rdi, [rip + msg + 32]       # reply handle
+    mov qword ptr [rip + msg + 24], 0   # no handles back
+    lea rsi, [rip + msg]
+    syscall
+    test rax, rax
+    js fail
+
+    mov rdi, r12
+    mov eax, 1                      # exit
+    syscall
+
+fail:
+    mov rdi, rax
+    mov eax, 1                      # exit
+    syscall
+
+.data
+msg:
+    .quad buffer                    # data
+    .quad 64                        # data_len
+    .quad handles                   # handles
+    .quad 4                         # handles_len
+    .quad -1                        # reply
+
+.bss
+buffer:
+    .skip 64
+handles:
+    .skip 16
@@ -0,0 +1,11 @@
+# Exits with its process id.
+.intel_syntax noprefix
+
+.text
+.global _start
+_start:
+    mov eax, 5                      # getpid
+    syscall
+    mov rdi, rax
+    mov eax, 1                      # exit
+    syscall
@@ -0,0 +1,10 @@
+# Overwrites its own code, which has to page fault.
+.intel_syntax noprefix
+
+.text
+.global _start
+_start:
+    mov byte ptr [rip + _start], 0x90
+    mov edi, 0
+    mov eax, 1                      # exit
+    syscall
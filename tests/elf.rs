@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::thread;
+use blog_os::user::{self, elf::{self, ElfError}};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    blog_os::task::timer::init();
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+// built from tests/bin/*.s (see tests/bin/Makefile)
+static ARGS: &[u8] = include_bytes!("bin/args.elf");
+static READONLY: &[u8] = include_bytes!("bin/readonly.elf");
+
+#[test_case]
+fn segments_get_their_flags() {
+    let program = elf::load(ARGS, &["args"], &[]).expect("loading failed");
+    let space = &program.space;
+    // text: read and execute
+    let text = space.flags(program.entry).expect("text not mapped");
+    assert!(!text.contains(PageTableFlags::WRITABLE));
+    assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+    // data and bss: read and write
+    let data = space.flags(program.entry + 0x1000u64).expect("data not mapped");
+    assert!(data.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    assert!(space.check_access(program.stack.as_u64(), 8, true));
+}
+
+#[test_case]
+fn runs_with_arguments_and_environment() {
+    let code = thread::spawn(|| elf::exec(ARGS, &["args", "hello"], &["A=1"])).join();
+    // argc * 1000 + envc * 100 + bytes written
+    assert_eq!(code, Ok(2105));
+}
+
+#[test_case]
+fn text_is_read_only() {
+    let code = thread::spawn(|| elf::exec(READONLY, &["readonly"], &[])).join();
+    assert_eq!(code, Ok(user::fault_exit_code(14)));
+}
+
+#[test_case]
+fn bad_images_are_rejected() {
+    assert_eq!(elf::load(b"not an executable", &[], &[]).err(), Some(ElfError::BadHeader));
+    assert_eq!(elf::load(&ARGS[..100], &[], &[]).err(), Some(ElfError::BadHeader));
+
+    let mut image = [0u8; 4096];
+    image[..ARGS.len().min(4096)].copy_from_slice(&ARGS[..ARGS.len().min(4096)]);
+    // 32-bit class
+    image[4] = 1;
+    assert_eq!(elf::load(&image, &[], &[]).err(), Some(ElfError::Unsupported));
+}
+
+#[test_case]
+fn entry_must_be_executable() {
+    let mut image = ARGS.to_vec();
+    let u64_at = |image: &[u8], at: usize| {
+        u64::from_le_bytes(image[at..at + 8].try_into().unwrap())
+    };
+    let entry = u64_at(&image, 24);
+    let phoff = u64_at(&image, 32) as usize;
+    let phnum = u16::from_le_bytes([image[56], image[57]]) as usize;
+    // clear PF_X on the segment holding the entry point
+    for i in 0..phnum {
+        let at = phoff + i * 56;
+        let (vaddr, memsz) = (u64_at(&image, at + 16), u64_at(&image, at + 40));
+        if image[at] == 1 && vaddr <= entry && entry < vaddr + memsz {
+            image[at + 4] &= !1;
+        }
+    }
+    assert_eq!(elf::load(&image, &["args"], &[]).err(), Some(ElfError::BadHeader));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
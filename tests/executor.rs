@@ -0,0 +1,205 @@
+#![no_std]
+#![no_main]
+
+//! Runs the multi-core executor on all CPUs
+//!
+//! `Executor::run` never returns, so a driver task runs the cases one
+//! after the other and exits QEMU when all have passed.
+
+extern crate alloc;
+
+use blog_os::task::{executor::Executor, timer, Task};
+use blog_os::time::{Duration, Instant};
+use blog_os::{exit_qemu, print_test_passed, serial_print, smp, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+entry_point!(main);
+
+/// must match the `-smp` option in Cargo.toml
+const QEMU_CPUS: usize = 4;
+/// tasks that busy-wait to show they get spread over the cores
+const STEAL_TASKS: usize = 8;
+const NOT_YET: usize = usize::MAX;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    assert!(blog_os::acpi::init(), "no ACPI tables");
+    assert!(blog_os::apic::init(), "no local APIC");
+    timer::init();
+    smp::init(&mut mapper, &mut frame_allocator).expect("SMP init failed");
+    assert_eq!(smp::online_count(), QEMU_CPUS);
+
+    let mut executor = Executor::new();
+    // all spawned on the BSP, so only stealing moves them elsewhere
+    for _ in 0..STEAL_TASKS {
+        executor.spawn(Task::new(busy_task()));
+    }
+    executor.spawn(Task::new(waiting_task(&HOME, &HOME_SIGNAL)));
+    executor.spawn(Task::new(waiting_task(&WAKEUP, &WAKEUP_SIGNAL)));
+    executor.spawn(Task::new(driver()));
+    executor.run()
+}
+
+/// A future that completes once `fire` was called
+struct Signal {
+    fired: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Signal {
+    const fn new() -> Self {
+        Signal { fired: AtomicBool::new(false), waker: AtomicWaker::new() }
+    }
+
+    fn fire(&self) {
+        self.fired.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    fn wait(&'static self) -> impl Future<Output = ()> {
+        Wait(self)
+    }
+}
+
+struct Wait(&'static Signal);
+
+impl Future for Wait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.0.waker.register(cx.waker());
+        if self.0.fired.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Cores a waiting task was polled on before and after its signal
+struct Polls {
+    before: AtomicUsize,
+    after: AtomicUsize,
+}
+
+impl Polls {
+    const fn new() -> Self {
+        Polls { before: AtomicUsize::new(NOT_YET), after: AtomicUsize::new(NOT_YET) }
+    }
+}
+
+static HOME: Polls = Polls::new();
+static HOME_SIGNAL: Signal = Signal::new();
+static WAKEUP: Polls = Polls::new();
+static WAKEUP_SIGNAL: Signal = Signal::new();
+
+/// bit N: a busy task ran on CPU N
+static BUSY_CPUS: AtomicU64 = AtomicU64::new(0);
+static BUSY_DONE: AtomicUsize = AtomicUsize::new(0);
+
+async fn busy_task() {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(20) {}
+    BUSY_CPUS.fetch_or(1 << smp::current_cpu(), Ordering::Relaxed);
+    BUSY_DONE.fetch_add(1, Ordering::Release);
+}
+
+async fn waiting_task(polls: &'static Polls, signal: &'static Signal) {
+    polls.before.store(smp::current_cpu(), Ordering::Release);
+    signal.wait().await;
+    polls.after.store(smp::current_cpu(), Ordering::Release);
+}
+
+async fn driver() {
+    serial_print!("executor::idle_cores_steal_tasks... ");
+    let start = Instant::now();
+    while BUSY_DONE.load(Ordering::Acquire) < STEAL_TASKS {
+        assert!(start.elapsed() < Duration::from_secs(2), "busy tasks did not finish");
+        timer::sleep(Duration::from_millis(10)).await;
+    }
+    let cpus = BUSY_CPUS.load(Ordering::Relaxed).count_ones();
+    assert!(cpus > 1, "all busy tasks ran on one core");
+    print_test_passed();
+
+    serial_print!("executor::woken_task_returns_to_its_core... ");
+    let home = wait_for_first_poll(&HOME).await;
+    fire_from_other_core(home, &HOME_SIGNAL, &HOME).await;
+    assert_eq!(HOME.after.load(Ordering::Acquire), home, "task moved off its home core");
+    print_test_passed();
+
+    serial_print!("executor::halted_core_is_woken_by_ipi... ");
+    let home = wait_for_first_poll(&WAKEUP).await;
+    let waker = fire_from_other_core(home, &WAKEUP_SIGNAL, &WAKEUP).await;
+    assert!(RAN_WHILE_BUSY.load(Ordering::Acquire),
+            "task on halted CPU {} did not run while CPU {} was busy", home, waker);
+    print_test_passed();
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+async fn wait_for_first_poll(polls: &'static Polls) -> usize {
+    let start = Instant::now();
+    loop {
+        let cpu = polls.before.load(Ordering::Acquire);
+        if cpu != NOT_YET {
+            return cpu;
+        }
+        assert!(start.elapsed() < Duration::from_secs(1), "task was never polled");
+        timer::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// the waiting task ran while the core that woke it was still busy
+static RAN_WHILE_BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Let all cores halt, then fire `signal` from an AP other than `home` that
+/// keeps busy for a while, so only an IPI to `home` gets the task polled
+///
+/// Returns the core that fired.
+async fn fire_from_other_core(home: usize, signal: &'static Signal, polls: &'static Polls)
+    -> usize
+{
+    timer::sleep(Duration::from_millis(50)).await;
+    let waker = (1..QEMU_CPUS).find(|&cpu| cpu != home && cpu != smp::current_cpu())
+        .expect("no core to wake from");
+    RAN_WHILE_BUSY.store(false, Ordering::Release);
+    smp::run_on(waker, move || {
+        signal.fire();
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(20) {
+            if polls.after.load(Ordering::Acquire) != NOT_YET {
+                RAN_WHILE_BUSY.store(true, Ordering::Release);
+                break;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    while polls.after.load(Ordering::Acquire) == NOT_YET {
+        assert!(start.elapsed() < Duration::from_secs(1), "woken task never ran");
+        timer::sleep(Duration::from_millis(5)).await;
+    }
+    waker
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
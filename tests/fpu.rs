@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::{fpu, serial_print, thread};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    blog_os::task::timer::init();
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+/// Sum in floating point, yielding after every step so the other threads
+/// run in between and use the same registers
+fn sum_with_yields(step: f64) -> f64 {
+    let mut sum = 0.0;
+    for _ in 0..100 {
+        sum += step;
+        thread::yield_now();
+    }
+    sum
+}
+
+#[test_case]
+fn threads_keep_their_float_registers() {
+    let handles: Vec<_> = (1..=4)
+        .map(|n| thread::spawn(move || sum_with_yields(n as f64 * 0.5)))
+        .collect();
+    for (n, handle) in (1..=4).zip(handles) {
+        assert_eq!(handle.join(), n as f64 * 50.0);
+    }
+}
+
+#[target_feature(enable = "avx")]
+unsafe fn add_avx(a: &[f32; 8], b: &[f32; 8]) -> [f32; 8] {
+    use core::arch::x86_64::{_mm256_add_ps, _mm256_loadu_ps, _mm256_storeu_ps};
+
+    let mut sum = [0.0; 8];
+    let v = _mm256_add_ps(_mm256_loadu_ps(a.as_ptr()), _mm256_loadu_ps(b.as_ptr()));
+    _mm256_storeu_ps(sum.as_mut_ptr(), v);
+    sum
+}
+
+#[test_case]
+fn avx_section() {
+    if !fpu::has_avx() {
+        serial_print!("(no AVX) ");
+        return;
+    }
+    let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+    let b = [0.5; 8];
+    let section = fpu::kernel_fpu_begin();
+    let sum = unsafe { add_avx(&a, &b) };
+    fpu::kernel_fpu_end(section);
+    assert_eq!(sum, [1.5, 2.5, 3.5, 4.5, 5.5, 6.5, 7.5, 8.5]);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
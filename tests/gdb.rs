@@ -0,0 +1,185 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+use blog_os::gdb::{self, Connection};
+use blog_os::interrupts::TrapFrame;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+/// Plays the debugger: sends packets and acknowledges every reply
+///
+/// Every script ends with `D` (or `c`/`s` when the next one expects a stop
+/// reply), which ends `gdb::serve`.
+struct Script {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl Script {
+    fn new(commands: &[&str]) -> Self {
+        let mut input = VecDeque::new();
+        for command in commands {
+            input.extend(packet(command).bytes());
+        }
+        Script { input, output: Vec::new() }
+    }
+
+    /// The packets the stub sent, without framing
+    fn replies(&self) -> Vec<String> {
+        let text = core::str::from_utf8(&self.output).unwrap();
+        text.split('$').skip(1)
+            .map(|packet| {
+                let (data, sum) = packet.split_once('#').unwrap();
+                assert_eq!(&sum[..2], checksum(data), "bad checksum");
+                String::from(data)
+            })
+            .collect()
+    }
+}
+
+impl Connection for Script {
+    fn read(&mut self) -> u8 {
+        self.input.pop_front().expect("the stub wants more input")
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.output.push(byte);
+        // acknowledge a reply once its checksum is complete
+        let len = self.output.len();
+        if len >= 3 && self.output[len - 3] == b'#' && self.output.contains(&b'$') {
+            let last_start = self.output.iter().rposition(|&byte| byte == b'$').unwrap();
+            if last_start < len - 3 {
+                self.input.push_front(b'+');
+            }
+        }
+    }
+}
+
+fn checksum(data: &str) -> String {
+    format!("{:02x}", data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte)))
+}
+
+fn packet(data: &str) -> String {
+    format!("${}#{}", data, checksum(data))
+}
+
+fn frame() -> TrapFrame {
+    TrapFrame {
+        rax: 0x1122_3344_5566_7788,
+        rip: 0x1000,
+        rsp: 0x2000,
+        cs: 8,
+        ss: 0,
+        rflags: 0x202,
+        vector: 3,
+        ..TrapFrame::default()
+    }
+}
+
+#[test_case]
+fn registers_can_be_read_and_written() {
+    let mut frame = frame();
+    let mut script = Script::new(&["?", "g", "P1=efbeadde00000000", "p1", "D"]);
+    gdb::serve(&mut script, &mut frame);
+    let replies = script.replies();
+    assert_eq!(replies[0], "T05thread:1;");
+    assert!(replies[1].starts_with("8877665544332211"), "{}", replies[1]);
+    assert_eq!(replies[2], "OK");
+    assert_eq!(replies[3], "efbeadde00000000");
+    assert_eq!(replies[4], "OK");
+    assert_eq!(frame.rbx, 0xdead_beef);
+}
+
+static mut DATA: [u8; 4] = [1, 2, 3, 4];
+
+#[test_case]
+fn memory_can_be_read_and_written() {
+    let addr = unsafe { DATA.as_ptr() } as u64;
+    let mut frame = frame();
+    let mut script = Script::new(&[
+        &format!("m{:x},4", addr),
+        &format!("M{:x},2:aabb", addr),
+        "m0,1",
+        "D",
+    ]);
+    gdb::serve(&mut script, &mut frame);
+    let replies = script.replies();
+    assert_eq!(replies[0], "01020304");
+    assert_eq!(replies[1], "OK");
+    // page 0 is not mapped
+    assert_eq!(replies[2], "E0e");
+    assert_eq!(unsafe { DATA }, [0xaa, 0xbb, 3, 4]);
+}
+
+#[inline(never)]
+fn target() -> u32 {
+    core::hint::spin_loop();
+    42
+}
+
+#[test_case]
+fn breakpoints_patch_code_and_stop_on_it() {
+    let addr = target as usize as u64;
+    let original = unsafe { *(addr as *const u8) };
+
+    let mut frame = frame();
+    let mut script = Script::new(&[&format!("Z0,{:x},1", addr), "c"]);
+    gdb::serve(&mut script, &mut frame);
+    assert_eq!(script.replies(), ["OK"]);
+    assert_eq!(unsafe { *(addr as *const u8) }, 0xcc);
+
+    // stopped right behind the int3: reported at the breakpoint
+    let mut frame = TrapFrame { rip: addr + 1, ..frame };
+    let mut script = Script::new(&[&format!("z0,{:x},1", addr), "s"]);
+    gdb::serve(&mut script, &mut frame);
+    assert_eq!(script.replies(), ["T05thread:1;", "OK"]);
+    assert_eq!(frame.rip, addr);
+    assert_eq!(unsafe { *(addr as *const u8) }, original);
+    assert_ne!(frame.rflags & (1 << 8), 0, "step did not set the trap flag");
+    assert_eq!(target(), 42);
+
+    // detach so later tests start without a pending stop reply
+    let mut frame = TrapFrame { rflags: 0x202, ..frame };
+    let mut script = Script::new(&["D"]);
+    gdb::serve(&mut script, &mut frame);
+    assert_eq!(script.replies(), ["T05thread:1;", "OK"]);
+}
+
+#[test_case]
+fn unknown_packets_get_empty_replies() {
+    let mut frame = frame();
+    let mut script = Script::new(&["vMustReplyEmpty", "qSupported:swbreak+", "qC", "D"]);
+    gdb::serve(&mut script, &mut frame);
+    assert_eq!(script.replies(), ["", "PacketSize=1000", "QC1", "OK"]);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::task::timer::{self, hpet};
+use blog_os::time::{Duration, Instant};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    assert!(blog_os::acpi::init(), "no ACPI tables");
+    // the local APIC is left disabled so the HPET drives the timer
+    assert_eq!(timer::init(), timer::ClockEvent::Hpet);
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn main_counter_runs() {
+    assert!(hpet::is_present());
+    assert!(hpet::frequency() >= 10_000_000);
+    let start = hpet::counter();
+    while hpet::counter() == start {}
+}
+
+#[test_case]
+fn sub_millisecond_sleep() {
+    use core::future::Future;
+    use core::task::{Context, Poll};
+    use futures_util::task::noop_waker;
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let start = Instant::now();
+    let mut sleep = alloc::boxed::Box::pin(
+        timer::sleep(Duration::from_micros(500)));
+    while let Poll::Pending = sleep.as_mut().poll(&mut context) {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_micros(500));
+    assert!(elapsed < Duration::from_millis(5), "slept {:?}", elapsed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,174 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use blog_os::ipc::{self, Endpoint, Message, RecvError, TryRecvError, TrySendError};
+use blog_os::process::{self, Capability, Rights};
+use blog_os::thread;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    blog_os::task::timer::init();
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+// built from tests/bin/*.s (see tests/bin/Makefile)
+static ECHO: &[u8] = include_bytes!("bin/echo.elf");
+
+#[test_case]
+fn messages_arrive_in_order() {
+    let (a, b) = ipc::channel();
+    a.try_send(Message::new(vec![1])).ok().unwrap();
+    a.try_send(Message::new(vec![2])).ok().unwrap();
+    assert_eq!(b.try_recv().ok().unwrap().data, [1]);
+    assert_eq!(b.try_recv().ok().unwrap().data, [2]);
+    assert_eq!(b.try_recv().err(), Some(TryRecvError::Empty));
+}
+
+#[test_case]
+fn closed_peer_is_reported() {
+    let (a, b) = ipc::channel();
+    a.try_send(Message::new(vec![7])).ok().unwrap();
+    drop(a);
+    // what was sent before can still be received
+    assert_eq!(b.try_recv().ok().unwrap().data, [7]);
+    assert_eq!(b.try_recv().err(), Some(TryRecvError::Closed));
+    assert!(matches!(b.try_send(Message::new(Vec::new())), Err(TrySendError::Closed(_))));
+}
+
+#[test_case]
+fn senders_wait_for_room() {
+    let (a, b) = ipc::channel();
+    let count = ipc::QUEUE_CAPACITY * 3;
+    let sender = thread::spawn(move || {
+        for i in 0..count {
+            assert!(a.send(Message::new(vec![i as u8])).is_ok());
+        }
+    });
+    let received: Vec<u8> = (0..count).map(|_| b.recv().ok().unwrap().data[0]).collect();
+    sender.join();
+    assert!(received.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+    // the sender is gone
+    assert_eq!(b.recv().err(), Some(RecvError));
+}
+
+#[test_case]
+fn full_queue_is_reported() {
+    let (a, _b) = ipc::channel();
+    for _ in 0..ipc::QUEUE_CAPACITY {
+        assert!(a.try_send(Message::new(vec![0])).is_ok());
+    }
+    assert!(matches!(a.try_send(Message::new(vec![0])), Err(TrySendError::Full(_))));
+}
+
+#[test_case]
+fn capabilities_move_between_threads() {
+    let (a, b) = ipc::channel();
+    let (inner_a, inner_b) = ipc::channel();
+    let object: Arc<Endpoint> = Arc::new(inner_b);
+    a.send(Message::with_handles(vec![], vec![Capability::new(object, Rights::SEND)]))
+        .ok().unwrap();
+
+    let receiver = thread::spawn(move || {
+        let mut message = b.recv().ok().unwrap();
+        let capability = message.handles.pop().unwrap();
+        assert_eq!(capability.rights, Rights::SEND);
+        let endpoint: Arc<Endpoint> = capability.object.downcast().ok().unwrap();
+        endpoint.send(Message::new(b"through".to_vec())).ok().unwrap();
+    });
+    receiver.join();
+    assert_eq!(inner_a.recv().ok().unwrap().data, b"through");
+}
+
+#[test_case]
+fn call_gets_the_reply() {
+    let (client, server) = ipc::channel();
+    let server = thread::spawn(move || {
+        let mut request = server.recv().ok().unwrap();
+        assert!(request.expects_reply());
+        let mut answer = request.data.clone();
+        answer.reverse();
+        assert!(request.reply(Message::new(answer)).is_ok());
+        assert!(!request.expects_reply());
+    });
+    let answer = client.call(Message::new(b"abc".to_vec())).ok().unwrap();
+    assert_eq!(answer.data, b"cba");
+    server.join();
+}
+
+#[test_case]
+fn large_messages_stay_queued() {
+    let (a, b) = ipc::channel();
+    a.send(Message::new(vec![1; 100])).ok().unwrap();
+    assert_eq!(b.try_recv_limited(10, 0).err(),
+               Some(TryRecvError::TooLarge { data: 100, handles: 0 }));
+    assert_eq!(b.try_recv_limited(100, 0).ok().unwrap().data.len(), 100);
+    let too_large = Message::new(vec![0; ipc::MAX_DATA + 1]);
+    assert!(matches!(a.try_send(too_large), Err(TrySendError::TooLarge(_))));
+}
+
+#[test_case]
+fn futures_receive_and_call() {
+    let (a, b) = ipc::channel();
+    let sender = thread::spawn(move || {
+        thread::sleep(blog_os::time::Duration::from_millis(10));
+        a.send(Message::new(b"later".to_vec())).ok().unwrap();
+        a
+    });
+    let message = thread::block_on(b.recv_async()).ok().unwrap();
+    assert_eq!(message.data, b"later");
+
+    let a = sender.join();
+    let server = thread::spawn(move || {
+        let mut request = b.recv().ok().unwrap();
+        request.reply(Message::new(b"ok".to_vec())).ok().unwrap();
+    });
+    let answer = thread::block_on(a.call_async(Message::new(vec![]))).ok().unwrap();
+    assert_eq!(answer.data, b"ok");
+    server.join();
+}
+
+#[test_case]
+fn processes_answer_calls() {
+    let (client, server) = ipc::channel();
+    let pid = process::spawn_with_handles(
+        "echo", ECHO, &["echo"], &[],
+        vec![Capability::new(Arc::new(server), Rights::RECEIVE)],
+    ).expect("spawn failed");
+    let answer = client.call(Message::new(b"ping".to_vec())).ok().unwrap();
+    assert_eq!(answer.data, b"ping");
+    assert_eq!(process::wait(pid), Ok(4));
+    // the process closed its end
+    assert!(!client.is_peer_open());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
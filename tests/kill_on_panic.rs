@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+
+//! Panics in the threads of kernel processes
+//!
+//! The panic handler does what the kernel's does: `kill_on_panic` first,
+//! and giving up if that returns. A plain panic kills the process and the
+//! main thread goes on. One inside `with_handles` must not be killed
+//! (`exit` takes the handle table too), so that case ends in the panic
+//! handler.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::{exit_qemu, print_test_failed_because, print_test_name, print_test_passed};
+use blog_os::{memory, process, thread, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+entry_point!(main);
+
+/// the process panicking now should be left alone
+static EXPECT_NO_KILL: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    blog_os::task::timer::init();
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    print_test_name("kill_on_panic::panic_kills_the_process");
+    let pid = process::spawn_kernel("panics", Vec::new(), || panic!("on purpose"));
+    assert_eq!(process::wait(pid), Ok(process::PANIC_EXIT_CODE));
+    print_test_passed();
+
+    print_test_name("kill_on_panic::panic_in_with_handles_is_not_killed");
+    EXPECT_NO_KILL.store(true, Ordering::Relaxed);
+    let pid = process::spawn_kernel("panics holding handles", Vec::new(), || {
+        let process = process::current().expect("not in a process");
+        process.with_handles(|_| panic!("on purpose"));
+        0
+    });
+    let _ = process::wait(pid);
+    print_test_failed_because("process killed while it held its handle table");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    process::kill_on_panic(info);
+    if EXPECT_NO_KILL.load(Ordering::Relaxed) && process::current_pid().is_some() {
+        print_test_passed();
+        exit_qemu(QemuExitCode::Success);
+    }
+    blog_os::test_panic_handler(info)
+}
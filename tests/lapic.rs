@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::task::timer::{self, lapic};
+use blog_os::time::{self, Duration, Instant};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory;
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    blog_os::acpi::init();
+    assert!(blog_os::apic::init(), "no local APIC");
+    timer::init();
+
+    test_main();
+    loop {}
+}
+
+/// Count timer interrupts during `duration` without halting
+fn count_interrupts(duration: Duration) -> u64 {
+    let before = timer::ticks();
+    let start = Instant::now();
+    while start.elapsed() < duration {}
+    timer::ticks() - before
+}
+
+#[test_case]
+fn calibrated() {
+    assert!(lapic::frequency() > 0);
+}
+
+#[test_case]
+fn periodic_mode() {
+    lapic::set_periodic(Duration::from_millis(1));
+    let count = count_interrupts(Duration::from_millis(50));
+    lapic::stop();
+    assert!((40..=60).contains(&count), "{} interrupts in 50 ms", count);
+}
+
+#[test_case]
+fn oneshot_mode() {
+    lapic::set_oneshot(Duration::from_millis(5));
+    let count = count_interrupts(Duration::from_millis(20));
+    assert_eq!(count, 1);
+}
+
+#[test_case]
+fn tsc_deadline_mode() {
+    let hz = match time::tsc_frequency() {
+        Some(hz) if lapic::supports_tsc_deadline() => hz,
+        _ => return, // not available on this CPU
+    };
+    lapic::set_tsc_deadline(time::rdtsc() + hz / 200);
+    let count = count_interrupts(Duration::from_millis(20));
+    lapic::stop();
+    assert_eq!(count, 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
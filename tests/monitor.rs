@@ -0,0 +1,174 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{collections::VecDeque, format, string::String};
+use blog_os::interrupts::{InterruptIndex, TrapFrame};
+use blog_os::irqstat;
+use blog_os::monitor::{self, Console, Reason};
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::fmt;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+/// Types the commands and keeps what the monitor answers
+///
+/// Every script ends with `c`, which ends `monitor::run`.
+struct Script {
+    input: VecDeque<u8>,
+    output: String,
+}
+
+impl Script {
+    fn new(input: &str) -> Self {
+        Script { input: input.bytes().collect(), output: String::new() }
+    }
+}
+
+impl Console for Script {
+    fn read(&mut self) -> u8 {
+        self.input.pop_front().expect("the monitor wants more input")
+    }
+}
+
+impl fmt::Write for Script {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.output.push_str(s);
+        Ok(())
+    }
+}
+
+fn frame() -> TrapFrame {
+    TrapFrame {
+        rax: 0x1122_3344_5566_7788,
+        rip: 0x1000,
+        rsp: 0x2000,
+        cs: 8,
+        rflags: 0x202,
+        vector: 3,
+        ..TrapFrame::default()
+    }
+}
+
+fn run(input: &str) -> String {
+    let mut script = Script::new(input);
+    monitor::run(&mut script, &frame(), Reason::Breakpoint);
+    assert!(script.input.is_empty(), "the monitor continued early");
+    script.output
+}
+
+#[test_case]
+fn continue_returns() {
+    let output = run("c\n");
+    assert!(output.contains("monitor: breakpoint at 0x0000000000001000"), "{}", output);
+}
+
+#[test_case]
+fn registers_are_shown() {
+    // the typo is corrected with backspace
+    let output = run("regz\x08s\nc\n");
+    assert!(output.contains("rax 0x1122334455667788"), "{}", output);
+    assert!(output.contains("rflags 0x202"), "{}", output);
+}
+
+static DATA: [u8; 4] = *b"abc\0";
+
+#[test_case]
+fn memory_is_dumped() {
+    let addr = DATA.as_ptr() as u64;
+    let output = run(&format!("mem {:x} 4\nmem 0 2\nc\n", addr));
+    assert!(output.contains(&format!("{:016x}  61 62 63 00", addr)), "{}", output);
+    assert!(output.contains("abc."), "{}", output);
+    // page 0 is not mapped
+    assert!(output.contains("0000000000000000  ?? ??"), "{}", output);
+}
+
+#[test_case]
+fn page_tables_are_walked() {
+    let addr = DATA.as_ptr() as u64;
+    let output = run(&format!("pt {:x}\npt 0\nc\n", addr));
+    assert!(output.contains("P4["), "{}", output);
+    assert!(output.contains(&format!("{:#x} -> 0x", addr)), "{}", output);
+    assert!(output.contains("0x0 is not mapped"), "{}", output);
+}
+
+global_asm!("
+.global monitor_test_code
+monitor_test_code:
+    push rbp
+    mov rbp, rsp
+    pop rbp
+    ret
+");
+
+extern "C" {
+    fn monitor_test_code();
+}
+
+#[test_case]
+fn code_is_disassembled() {
+    let addr = monitor_test_code as usize as u64;
+    let output = run(&format!("dis {:x} 4\nc\n", addr));
+    for instruction in ["push rbp", "mov rbp, rsp", "pop rbp", "ret"] {
+        assert!(output.contains(instruction), "{}", output);
+    }
+}
+
+#[test_case]
+fn statistics_are_shown() {
+    let output = run("heap\nirq\nc\n");
+    let heap = format!("heap: {} bytes", blog_os::allocator::HEAP_SIZE);
+    assert!(output.contains(&heap), "{}", output);
+    assert!(output.contains("timer ticks: "), "{}", output);
+}
+
+#[test_case]
+fn interrupt_table_is_shown() {
+    // a timer interrupt since boot, so the timer has a row and a duration
+    let timer = InterruptIndex::Timer as u8;
+    let ticks = irqstat::total(timer);
+    while irqstat::total(timer) == ticks {
+        x86_64::instructions::hlt();
+    }
+    let output = run("interrupts\nc\n");
+    // the CPU names are right-aligned over the counts
+    assert!(output.contains(&format!("VEC {:>10}", "CPU0")), "{}", output);
+    assert!(output.contains("IRQ 0 timer"), "{}", output);
+    assert!(output.contains("handler time (us)"), "{}", output);
+    assert!(output.contains("PIC spurious IRQ 15"), "{}", output);
+}
+
+#[test_case]
+fn unknown_commands_are_reported() {
+    let output = run("frobnicate\nc\n");
+    assert!(output.contains("unknown command frobnicate"), "{}", output);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec};
+use blog_os::process::{self, Capability, Handle, HandleError, HandleTable, Rights};
+use blog_os::process::{State, WaitError};
+use blog_os::{memory, thread, user};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    blog_os::task::timer::init();
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+// built from tests/bin/*.s (see tests/bin/Makefile)
+static ARGS: &[u8] = include_bytes!("bin/args.elf");
+static GETPID: &[u8] = include_bytes!("bin/getpid.elf");
+static READONLY: &[u8] = include_bytes!("bin/readonly.elf");
+
+#[test_case]
+fn wait_returns_exit_code() {
+    let pid = process::spawn("args", ARGS, &["args", "hi"], &[]).expect("spawn failed");
+    assert_eq!(process::wait(pid), Ok(2002));
+    // collected
+    assert!(process::get(pid).is_none());
+    assert_eq!(process::wait(pid), Err(WaitError::NotChild));
+}
+
+#[test_case]
+fn processes_know_their_ids() {
+    let first = process::spawn("getpid", GETPID, &[], &[]).unwrap();
+    let second = process::spawn("getpid", GETPID, &[], &[]).unwrap();
+    assert_ne!(first, second);
+    assert_eq!(process::wait(second), Ok(second.as_u64() as i64));
+    assert_eq!(process::wait(first), Ok(first.as_u64() as i64));
+}
+
+#[test_case]
+fn exited_processes_stay_listed_until_collected() {
+    let pid = process::spawn("readonly", READONLY, &[], &[]).unwrap();
+    let process = process::get(pid).unwrap();
+    while process.state() == State::Running {
+        thread::yield_now();
+    }
+    let info = process::list().into_iter().find(|info| info.pid == pid)
+        .expect("exited process not listed");
+    assert_eq!(info.name, "readonly");
+    assert_eq!(info.parent, None);
+    assert_eq!(info.state, State::Exited(user::fault_exit_code(14)));
+    process::ps();
+
+    assert_eq!(process::wait(pid), Ok(user::fault_exit_code(14)));
+    assert!(process::list().iter().all(|info| info.pid != pid));
+}
+
+#[test_case]
+fn exit_gives_back_memory() {
+    // the first process leaves a thread stack behind for reuse
+    let pid = process::spawn("getpid", GETPID, &[], &[]).unwrap();
+    process::wait(pid).unwrap();
+    // give its thread time to finish switching out
+    thread::sleep(blog_os::time::Duration::from_millis(20));
+
+    let before = memory::frames_in_use();
+    let pid = process::spawn("args", ARGS, &["args", "x"], &["A=1", "B=2"]).unwrap();
+    assert!(memory::frames_in_use() > before);
+    process::wait(pid).unwrap();
+    assert_eq!(memory::frames_in_use(), before);
+}
+
+#[test_case]
+fn exit_closes_handles() {
+    let object = Arc::new(42u32);
+    let pid = process::spawn_with_handles(
+        "getpid", GETPID, &[], &[],
+        vec![Capability::new(object.clone(), Rights::ALL)],
+    ).unwrap();
+    let process = process::get(pid).unwrap();
+    process::wait(pid).unwrap();
+    assert_eq!(Arc::strong_count(&object), 1);
+    // nothing can be added once the handles are closed
+    let inserted = process.with_handles(|handles| {
+        handles.insert(Capability::new(object.clone(), Rights::ALL))
+    });
+    assert!(inserted.is_none());
+    assert_eq!(Arc::strong_count(&object), 1);
+}
+
+#[test_case]
+fn handles_are_reused() {
+    let mut table = HandleTable::new();
+    let first = table.insert(Capability::new(Arc::new(1u32), Rights::ALL));
+    let second = table.insert(Capability::new(Arc::new(2u64), Rights::ALL));
+    assert_eq!(table.get_as::<u32>(first, Rights::NONE).as_deref(), Ok(&1));
+    assert_eq!(table.get_as::<u32>(second, Rights::NONE).err(), Some(HandleError::WrongType));
+    assert!(table.remove(first).is_some());
+    assert!(table.get(first).is_none());
+    let third = table.insert(Capability::new(Arc::new(3u32), Rights::SEND));
+    assert_eq!(third, first);
+    assert_eq!(table.len(), 2);
+}
+
+#[test_case]
+fn rights_are_checked() {
+    let mut table = HandleTable::new();
+    let handle = table.insert(Capability::new(Arc::new(1u32), Rights::SEND | Rights::TRANSFER));
+    assert!(table.get_as::<u32>(handle, Rights::SEND).is_ok());
+    assert_eq!(table.get_as::<u32>(handle, Rights::RECEIVE).err(),
+               Some(HandleError::AccessDenied));
+    assert_eq!(table.get_as::<u32>(Handle::from_raw(7), Rights::NONE).err(),
+               Some(HandleError::BadHandle));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::profile;
+use blog_os::task::timer;
+use blog_os::time::{Duration, Instant};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    timer::init();
+
+    test_main();
+    loop {}
+}
+
+#[inline(never)]
+fn spin_for(duration: Duration) {
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn busy_code_gets_sampled() {
+    profile::start();
+    spin_for(Duration::from_millis(100));
+    profile::stop();
+
+    let samples = profile::collect();
+    // at the periodic PIT rate that is 10 samples, more when tickless
+    assert!(samples.kernel >= 5, "only {} samples", samples.kernel);
+    assert_eq!(samples.user, 0);
+    let counted: u64 = samples.hits.iter().map(|&(_, count)| count).sum();
+    assert_eq!(counted, samples.kernel);
+    assert!(samples.hits.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    profile::report(5);
+}
+
+#[test_case]
+fn stopped_profiler_takes_no_samples() {
+    let before = profile::collect().kernel;
+    spin_for(Duration::from_millis(30));
+    assert_eq!(profile::collect().kernel, before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
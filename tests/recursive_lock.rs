@@ -0,0 +1,30 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{print_test_name, print_test_passed, print_test_failed_because};
+use blog_os::{exit_qemu, serial_println, sync::IrqMutex, QemuExitCode};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(kernel_main);
+
+static LOCK: IrqMutex<u32> = IrqMutex::new(0);
+
+fn kernel_main(_boot_info: &'static BootInfo) -> ! {
+    print_test_name("recursive_lock::recursive_lock_panics");
+    if !cfg!(debug_assertions) {
+        // owners are only tracked in debug builds; this would spin forever
+        serial_println!("(release build, skipped)");
+        exit_qemu(QemuExitCode::Success);
+    }
+    let _outer = LOCK.lock();
+    let _inner = LOCK.lock();
+    print_test_failed_because("recursive lock did not panic");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    print_test_passed();
+    exit_qemu(QemuExitCode::Success);
+}
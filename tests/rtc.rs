@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::rtc::{self, DateTime};
+use blog_os::task::timer;
+use blog_os::time::{Duration, Instant, SystemTime};
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::Context;
+use futures_util::task::noop_waker_ref;
+
+entry_point!(main);
+
+/// must match the `-rtc base=` option in Cargo.toml
+const QEMU_RTC_BASE: DateTime = DateTime {
+    year: 2021, month: 12, day: 15, hour: 23, minute: 59, second: 58,
+};
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory;
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    timer::init();
+    rtc::init();
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn reads_qemu_base_time() {
+    let now = rtc::read_time();
+    let base = SystemTime::from(QEMU_RTC_BASE);
+    let elapsed = SystemTime::from(now).duration_since(base)
+        .expect("RTC is behind the QEMU base time");
+    assert!(elapsed < Duration::from_secs(5), "RTC reads {}", now);
+}
+
+#[test_case]
+fn system_time_follows_monotonic_clock() {
+    let start = SystemTime::now();
+    let instant = Instant::now();
+    while instant.elapsed() < Duration::from_millis(20) {}
+    let elapsed = start.elapsed().expect("system time went backwards");
+    assert!(elapsed >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn crosses_midnight() {
+    // the base time is 2 seconds before midnight, well within the timeout
+    let tomorrow = DateTime { day: 16, hour: 0, minute: 0, second: 0, ..QEMU_RTC_BASE };
+    let now = rtc::read_time();
+    assert!(now < tomorrow, "already past midnight at {}, nothing to check", now);
+    let start = Instant::now();
+    while rtc::read_time() < tomorrow {
+        assert!(start.elapsed() < Duration::from_secs(5), "RTC stopped");
+    }
+    assert_eq!(DateTime::from(SystemTime::now()).day, 16);
+}
+
+#[test_case]
+fn periodic_interrupt() {
+    if !rtc::enable_periodic(6) { // 1024 Hz
+        return; // IRQ 8 is routed to the HPET
+    }
+    let before = rtc::periodic_count();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(100) {}
+    rtc::disable_periodic();
+    let count = rtc::periodic_count() - before;
+    assert!((50..=150).contains(&count), "{} interrupts in 100 ms", count);
+}
+
+#[test_case]
+fn alarm_fires() {
+    let at = DateTime::from(SystemTime::from(rtc::read_time()) + Duration::from_secs(2));
+    if !rtc::set_alarm(at.hour, at.minute, at.second) {
+        return; // IRQ 8 is routed to the HPET
+    }
+    let mut alarm = rtc::alarm();
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let start = Instant::now();
+    while Pin::new(&mut alarm).poll(&mut cx).is_pending() {
+        assert!(start.elapsed() < Duration::from_secs(4), "no alarm for {}", at);
+    }
+    rtc::cancel_alarm();
+    assert!(rtc::read_time() >= at, "alarm for {} fired early", at);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::smp;
+use blog_os::time::{Duration, Instant};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+entry_point!(main);
+
+/// must match the `-smp` option in Cargo.toml
+const QEMU_CPUS: usize = 4;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    assert!(blog_os::acpi::init(), "no ACPI tables");
+    assert!(blog_os::apic::init(), "no local APIC");
+    blog_os::task::timer::init();
+    smp::init(&mut mapper, &mut frame_allocator).expect("SMP init failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn every_cpu_checks_in() {
+    assert_eq!(smp::cpu_count(), QEMU_CPUS);
+    assert_eq!(smp::online_count(), QEMU_CPUS);
+    for cpu in 0..QEMU_CPUS {
+        assert!(smp::is_online(cpu), "CPU {} is not online", cpu);
+    }
+}
+
+#[test_case]
+fn bsp_is_cpu_0() {
+    assert_eq!(smp::current_cpu(), 0);
+}
+
+#[test_case]
+fn work_runs_on_every_ap() {
+    static RAN_ON: [AtomicUsize; QEMU_CPUS] = [
+        AtomicUsize::new(usize::MAX), AtomicUsize::new(usize::MAX),
+        AtomicUsize::new(usize::MAX), AtomicUsize::new(usize::MAX),
+    ];
+
+    for cpu in 1..QEMU_CPUS {
+        smp::run_on(cpu, move || {
+            RAN_ON[cpu].store(smp::current_cpu(), Ordering::Release);
+        });
+    }
+
+    let start = Instant::now();
+    for cpu in 1..QEMU_CPUS {
+        while RAN_ON[cpu].load(Ordering::Acquire) == usize::MAX {
+            assert!(start.elapsed() < Duration::from_secs(1),
+                    "CPU {} did not run its work", cpu);
+        }
+        assert_eq!(RAN_ON[cpu].load(Ordering::Acquire), cpu);
+    }
+}
+
+#[test_case]
+fn every_cpu_has_a_percpu_block() {
+    use blog_os::percpu;
+
+    for cpu in 0..QEMU_CPUS {
+        let block = percpu::get(cpu).expect("no per-CPU block");
+        assert_eq!(block.cpu_id, cpu);
+        assert_eq!(block.apic_id, smp::apic_id(cpu));
+    }
+    assert_eq!(percpu::all().count(), QEMU_CPUS);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
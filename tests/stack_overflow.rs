@@ -2,24 +2,102 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+//! Overflows the kernel stack and each interrupt stack in turn
+//!
+//! Every overflow runs into a guard page, and the page fault handler (on
+//! its own interrupt stack) checks which stack it was. It then resumes at
+//! the next case on a fresh stack by rewriting its interrupt frame.
+
+use blog_os::gdt::{self, InterruptStack};
 use blog_os::{exit_qemu, QemuExitCode, serial_print, print_test_passed};
+use blog_os::print_test_failed_because;
 use bootloader::{BootInfo, entry_point};
+use core::arch::asm;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::VirtAddr;
 
 entry_point!(kernel_main);
 
-fn kernel_main(_boot_info: &'static BootInfo) -> ! {
-    serial_print!("stack_overflow::stack_overflow ... ");
+/// unmapped address the page fault case starts with
+const UNMAPPED: u64 = 0xdead_0000_0000;
+/// size of the stack each case starts on, in pages
+const CASE_STACK_PAGES: u64 = 8;
+
+struct Case {
+    name: &'static str,
+    trigger: fn(),
+    /// IST index of the stack that overflows (`None`: the kernel stack)
+    stack: Option<u16>,
+}
+
+const CASES: [Case; 6] = [
+    Case { name: "kernel_stack", trigger: stack_overflow, stack: None },
+    Case { name: "nmi_stack", trigger: nmi, stack: Some(gdt::NMI_IST_INDEX) },
+    Case {
+        name: "machine_check_stack",
+        trigger: machine_check,
+        stack: Some(gdt::MACHINE_CHECK_IST_INDEX),
+    },
+    Case { name: "debug_stack", trigger: debug, stack: Some(gdt::DEBUG_IST_INDEX) },
+    Case {
+        name: "page_fault_stack",
+        trigger: page_fault,
+        stack: Some(gdt::PAGE_FAULT_IST_INDEX),
+    },
+    Case {
+        name: "double_fault_stack",
+        trigger: double_fault,
+        stack: Some(gdt::DOUBLE_FAULT_IST_INDEX),
+    },
+];
+
+static CASE: AtomicUsize = AtomicUsize::new(0);
+/// top of the stack each case starts on
+static CASE_STACK: AtomicU64 = AtomicU64::new(0);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::{self, BootInfoFrameAllocator};
 
     blog_os::gdt::init();
     init_test_idt();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    gdt::init_stacks(&mut mapper, &mut frame_allocator)
+        .expect("interrupt stack allocation failed");
+    let stack = memory::alloc_stack(CASE_STACK_PAGES, &mut mapper, &mut frame_allocator)
+        .expect("stack allocation failed");
+    CASE_STACK.store(stack.as_u64(), Ordering::Relaxed);
 
-    // trigger a stack overflow
-    stack_overflow();
+    // the first case too, so the kernel stack overflow hits a known guard page
+    unsafe {
+        asm!("mov rsp, {stack}", "call {entry}",
+             stack = in(reg) stack.as_u64(), entry = in(reg) run_case as usize,
+             options(noreturn));
+    }
+}
 
-    panic!("Execution continued after stack overflow");
+/// Start the current case, or exit once all have passed
+///
+/// Runs on the case stack: `kernel_main` calls it there, and the page
+/// fault handler "returns" here with the stack reset.
+extern "C" fn run_case() -> ! {
+    match CASES.get(CASE.load(Ordering::Relaxed)) {
+        Some(case) => {
+            serial_print!("stack_overflow::{} ... ", case.name);
+            (case.trigger)();
+            panic!("Execution continued after stack overflow");
+        }
+        None => exit_qemu(QemuExitCode::Success),
+    }
 }
 
 #[allow(unconditional_recursion)]
@@ -28,13 +106,46 @@ fn stack_overflow() {
     volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
 }
 
+fn nmi() {
+    unsafe { asm!("int 2") };
+}
+
+fn machine_check() {
+    unsafe { asm!("int 18") };
+}
+
+fn debug() {
+    unsafe { asm!("int 1") };
+}
+
+fn page_fault() {
+    unsafe { core::ptr::read_volatile(UNMAPPED as *const u64) };
+}
+
+fn double_fault() {
+    // no error code gets pushed, but the handler never looks at it
+    unsafe { asm!("int 8") };
+}
+
 lazy_static! {
     static ref TEST_IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         unsafe {
             idt.double_fault
                 .set_handler_fn(test_double_fault_handler)
-                .set_stack_index(blog_os::gdt::DOUBLE_FAULT_IST_INDEX);
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(test_overflow_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(test_machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            idt.debug
+                .set_handler_fn(test_overflow_handler)
+                .set_stack_index(gdt::DEBUG_IST_INDEX);
+            idt.page_fault
+                .set_handler_fn(test_page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
         }
 
         idt
@@ -45,12 +156,59 @@ pub fn init_test_idt() {
     TEST_IDT.load();
 }
 
+extern "x86-interrupt" fn test_overflow_handler(_stack_frame: InterruptStackFrame) {
+    stack_overflow();
+}
+
+extern "x86-interrupt" fn test_machine_check_handler(
+    _stack_frame: InterruptStackFrame,
+) -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
 extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    let addr = Cr2::read();
+    if addr.as_u64() == UNMAPPED {
+        stack_overflow(); // the page fault case
+    }
+
+    let case = CASE.load(Ordering::Relaxed);
+    let stack = CASE_STACK.load(Ordering::Relaxed);
+    let overflowed = gdt::overflowed_stack(addr);
+    let in_guard_page = match CASES[case].stack {
+        Some(index) => overflowed == Some(InterruptStack { cpu: 0, index }),
+        None => {
+            let guard = stack - (CASE_STACK_PAGES + 1) * 4096;
+            overflowed.is_none() && (guard..guard + 4096).contains(&addr.as_u64())
+        }
+    };
+    if !in_guard_page {
+        print_test_failed_because("page fault outside the expected guard page");
+        exit_qemu(QemuExitCode::Failed);
+    }
     print_test_passed();
-    exit_qemu(QemuExitCode::Success);
+
+    // continue with the next case instead of returning into the overflow
+    CASE.store(case + 1, Ordering::Relaxed);
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(run_case as usize as u64);
+            // as if called: the return address slot is below the top
+            frame.stack_pointer = VirtAddr::new(stack - 8);
+        });
+    }
 }
 
 #[panic_handler]
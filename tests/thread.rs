@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use blog_os::softirq::{Priority, Softirq};
+use blog_os::thread::{self, WaitQueue};
+use blog_os::percpu;
+use blog_os::time::{Duration, Instant};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    blog_os::task::timer::init();
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn join_returns_result() {
+    let handle = thread::spawn(|| 6 * 7);
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn threads_get_their_own_names() {
+    let handle = thread::spawn_named("worker".into(), || {
+        thread::current().name() == "worker"
+    });
+    assert!(handle.join());
+    assert_eq!(thread::current().name(), "main");
+}
+
+#[test_case]
+fn yield_interleaves() {
+    static ORDER: spin::Mutex<Vec<usize>> = spin::Mutex::new(Vec::new());
+
+    let handles: Vec<_> = (0..2)
+        .map(|id| thread::spawn(move || {
+            for _ in 0..3 {
+                ORDER.lock().push(id);
+                thread::yield_now();
+            }
+        }))
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(*ORDER.lock(), [0, 1, 0, 1, 0, 1]);
+}
+
+#[test_case]
+fn sleep_blocks_for_duration() {
+    let start = Instant::now();
+    thread::sleep(Duration::from_millis(50));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(50), "woke after {:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(500), "woke after {:?}", elapsed);
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+
+    // never yields, so only the timer can get the main thread back
+    let spinner = thread::spawn(|| {
+        while !STOP.load(Ordering::Relaxed) {
+            SPINS.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    let start = Instant::now();
+    while SPINS.load(Ordering::Relaxed) == 0 {
+        assert!(start.elapsed() < Duration::from_secs(1), "spinner never ran");
+    }
+    STOP.store(true, Ordering::Relaxed);
+    spinner.join();
+}
+
+#[test_case]
+fn softirq_pass_is_not_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+    static SPINNER_RAN: AtomicBool = AtomicBool::new(false);
+    static SLOW: Softirq = Softirq::new("test slow", Priority::Low, || {
+        // three time slices: the timer interrupts meanwhile must not
+        // switch to the spinner
+        let spins = SPINS.load(Ordering::Relaxed);
+        let end = Instant::now() + Duration::from_millis(30);
+        while Instant::now() < end {
+            core::hint::spin_loop();
+        }
+        SPINNER_RAN.store(SPINS.load(Ordering::Relaxed) != spins, Ordering::Relaxed);
+    });
+
+    let spinner = thread::spawn(|| {
+        while !STOP.load(Ordering::Relaxed) {
+            SPINS.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    let interrupts = percpu::current().stats.interrupts.load(Ordering::Relaxed);
+    let runs = SLOW.stats().runs;
+    // runs right away, with interrupts enabled
+    SLOW.raise();
+    assert_eq!(SLOW.stats().runs, runs + 1);
+    assert!(percpu::current().stats.interrupts.load(Ordering::Relaxed) > interrupts,
+            "no timer interrupt during the softirq");
+    assert!(!SPINNER_RAN.load(Ordering::Relaxed), "softirq pass was preempted");
+    STOP.store(true, Ordering::Relaxed);
+    spinner.join();
+}
+
+#[test_case]
+fn wait_queue_wakes_waiters() {
+    static QUEUE: WaitQueue = WaitQueue::new();
+    let ready = Arc::new(AtomicBool::new(false));
+
+    let waiters: Vec<_> = (0..3)
+        .map(|_| {
+            let ready = ready.clone();
+            thread::spawn(move || QUEUE.wait_until(|| ready.load(Ordering::Acquire)))
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(20));
+    ready.store(true, Ordering::Release);
+    QUEUE.notify_all();
+    for waiter in waiters {
+        waiter.join();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
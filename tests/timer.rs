@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use blog_os::task::timer::{self, ClockEvent};
+use blog_os::time::{Duration, Instant};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::task::noop_waker;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    timer::init();
+
+    test_main();
+    loop {}
+}
+
+/// Poll all sleeps until they complete, halting in between
+///
+/// Returns the time at which each one completed.
+fn run_sleeps(mut sleeps: Vec<Pin<Box<timer::Sleep>>>) -> Vec<Instant> {
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut done: Vec<Option<Instant>> = sleeps.iter().map(|_| None).collect();
+
+    while done.iter().any(Option::is_none) {
+        for (sleep, done) in sleeps.iter_mut().zip(done.iter_mut()) {
+            if done.is_none() {
+                if let Poll::Ready(()) = sleep.as_mut().poll(&mut context) {
+                    *done = Some(Instant::now());
+                }
+            }
+        }
+        x86_64::instructions::hlt();
+    }
+    done.into_iter().map(Option::unwrap).collect()
+}
+
+#[test_case]
+fn wakeup_on_time() {
+    let sleep = Box::pin(timer::sleep(Duration::from_millis(20)));
+    let deadline = sleep.deadline();
+    let woken = run_sleeps(alloc::vec![sleep])[0];
+    assert!(woken >= deadline);
+    let slack = match timer::clock_event() {
+        ClockEvent::Periodic => Duration::from_millis(1000 / timer::PERIODIC_HZ as u64 + 1),
+        _ => Duration::from_millis(2),
+    };
+    assert!(woken - deadline < slack, "woke {:?} late", woken - deadline);
+}
+
+#[test_case]
+fn many_sleepers() {
+    let sleeps: Vec<_> = [30, 10, 20, 5]
+        .iter()
+        .map(|&ms| Box::pin(timer::sleep(Duration::from_millis(ms))))
+        .collect();
+    let deadlines: Vec<_> = sleeps.iter().map(|sleep| sleep.deadline()).collect();
+    let woken = run_sleeps(sleeps);
+    for (woken, deadline) in woken.iter().zip(deadlines.iter()) {
+        assert!(woken >= deadline);
+    }
+}
+
+#[test_case]
+fn one_interrupt_per_sleep() {
+    if timer::clock_event() == ClockEvent::Periodic {
+        return; // no one-shot clock event device
+    }
+    let before = timer::ticks();
+    run_sleeps(alloc::vec![Box::pin(timer::sleep(Duration::from_millis(20)))]);
+    assert_eq!(timer::ticks() - before, 1);
+}
+
+#[test_case]
+fn idle_fires_no_interrupts() {
+    if timer::clock_event() == ClockEvent::Periodic {
+        return; // no one-shot clock event device
+    }
+    // let any interrupt armed by an earlier test go off first
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(60) {}
+
+    let before = timer::ticks();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(100) {}
+    assert_eq!(timer::ticks(), before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,197 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use blog_os::{syscall, thread, user::{self, AddressSpace}};
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    blog_os::acpi::init();
+    blog_os::apic::init();
+    blog_os::task::timer::init();
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+// User programs: position independent, copied to user memory to run.
+// Syscall numbers as in `blog_os::syscall`.
+global_asm!(r#"
+.global user_hello, user_hello_end
+user_hello:
+    lea rdi, [rip + hello_message]
+    lea rsi, [rip + hello_message_end]
+    sub rsi, rdi
+    mov eax, 0
+    syscall
+    mov rdi, rax
+    mov eax, 1
+    syscall
+hello_message:
+    .ascii "hello from ring 3\n"
+hello_message_end:
+user_hello_end:
+
+.global user_clock, user_clock_end
+user_clock:
+    xor edi, edi
+    mov eax, 4
+    syscall
+    mov rbx, rax
+    mov edi, 20000000
+    mov eax, 2
+    syscall
+    mov eax, 3
+    syscall
+    xor edi, edi
+    mov eax, 4
+    syscall
+    sub rax, rbx
+    mov rdi, rax
+    mov eax, 1
+    syscall
+user_clock_end:
+
+.global user_bad_syscall, user_bad_syscall_end
+user_bad_syscall:
+    mov eax, 99
+    syscall
+    mov rdi, rax
+    mov eax, 1
+    syscall
+user_bad_syscall_end:
+
+.global user_bad_pointer, user_bad_pointer_end
+user_bad_pointer:
+    mov rdi, -4096
+    mov esi, 1
+    mov eax, 0
+    syscall
+    mov rdi, rax
+    mov eax, 1
+    syscall
+user_bad_pointer_end:
+
+.global user_hlt, user_hlt_end
+user_hlt:
+    hlt
+user_hlt_end:
+
+.global user_read_kernel, user_read_kernel_end
+user_read_kernel:
+    mov rax, -4096
+    mov rax, [rax]
+user_read_kernel_end:
+"#);
+
+macro_rules! program {
+    ($start:ident, $end:ident) => {{
+        extern "C" {
+            static $start: u8;
+            static $end: u8;
+        }
+        unsafe {
+            let start = &$start as *const u8;
+            let len = &$end as *const u8 as usize - start as usize;
+            core::slice::from_raw_parts(start, len)
+        }
+    }};
+}
+
+const PAGE: u64 = 4096;
+
+/// Copy `code` to the start of a new address space (with a stack page above
+/// it), run it in a new thread and return its exit code
+fn run(code: &'static [u8]) -> i64 {
+    assert!(code.len() as u64 <= PAGE);
+    let space = AddressSpace::new().expect("no memory for an address space");
+    let entry = VirtAddr::new(user::USER_START);
+    let stack = VirtAddr::new(base + PAGE);
+    space.map(entry, 1, PageTableFlags::empty()).expect("mapping code failed");
+    space.map(stack, 1, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        .expect("mapping stack failed");
+    assert!(space.write(entry, code));
+    let space = Arc::new(space);
+    thread::spawn(move || unsafe { user::run(space, entry, stack + PAGE) }).join()
+}
+
+#[test_case]
+fn write_returns_length() {
+    let code = program!(user_hello, user_hello_end);
+    assert_eq!(run(code), "hello from ring 3\n".len() as i64);
+}
+
+#[test_case]
+fn sleep_and_time() {
+    let code = program!(user_clock, user_clock_end);
+    let elapsed = run(code);
+    assert!(elapsed >= 20_000_000, "slept {} ns", elapsed);
+    assert!(elapsed < 500_000_000, "slept {} ns", elapsed);
+}
+
+#[test_case]
+fn unknown_syscall_fails() {
+    let code = program!(user_bad_syscall, user_bad_syscall_end);
+    assert_eq!(run(code), -syscall::ENOSYS);
+}
+
+#[test_case]
+fn kernel_pointer_is_rejected() {
+    let code = program!(user_bad_pointer, user_bad_pointer_end);
+    assert_eq!(run(code), -syscall::EFAULT);
+}
+
+#[test_case]
+fn privileged_instruction_faults() {
+    let code = program!(user_hlt, user_hlt_end);
+    // general protection fault
+    assert_eq!(run(code), user::fault_exit_code(13));
+}
+
+#[test_case]
+fn address_spaces_are_separate() {
+    let code = program!(user_hello, user_hello_end);
+    let first = AddressSpace::new().unwrap();
+    let second = AddressSpace::new().unwrap();
+    let base = VirtAddr::new(user::USER_START);
+    first.map(base, 1, PageTableFlags::empty()).unwrap();
+    assert!(first.write(base, code));
+    assert!(first.check_access(base.as_u64(), code.len() as u64, false));
+    assert!(!first.check_access(base.as_u64(), 1, true));
+    assert!(!second.check_access(base.as_u64(), 1, false));
+}
+
+#[test_case]
+fn kernel_memory_is_protected() {
+    let code = program!(user_read_kernel, user_read_kernel_end);
+    // page fault
+    assert_eq!(run(code), user::fault_exit_code(14));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
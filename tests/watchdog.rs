@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::{task::timer, watchdog};
+use blog_os::time::{Duration, Instant};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory;
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    blog_os::acpi::init();
+    assert!(blog_os::apic::init(), "no local APIC");
+    timer::init();
+
+    test_main();
+    loop {}
+}
+
+/// Spin until `done` or `timeout` passed; false on timeout
+fn wait_for(timeout: Duration, done: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while !done() {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+#[test_case]
+fn reports_arrive_with_interrupts_disabled() {
+    let before = watchdog::reports();
+    let arrived = interrupts::without_interrupts(|| {
+        assert!(watchdog::request_report(0));
+        wait_for(Duration::from_millis(100), || watchdog::reports() > before)
+    });
+    assert!(arrived, "no report from the NMI handler");
+}
+
+/// short enough to spin with interrupts off for well below a second
+const TEST_PERIOD: Duration = Duration::from_millis(100);
+const TEST_STRIKES: u32 = 2;
+
+#[test_case]
+fn lockups_are_detected() {
+    // QEMU gets an HPET that can send NMIs with `hpet.msi=on`
+    let source = watchdog::start_with(TEST_PERIOD, TEST_STRIKES).expect("no NMI source");
+    let before = watchdog::lockups();
+    let timeout = TEST_PERIOD * (TEST_STRIKES + 2);
+    let detected = interrupts::without_interrupts(|| {
+        wait_for(timeout, || watchdog::lockups() > before)
+    });
+    watchdog::stop();
+    assert!(detected, "no lockup reported by {:?}", source);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
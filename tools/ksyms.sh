@@ -0,0 +1,45 @@
+#!/bin/sh
+# Fill the .ksyms section of a kernel ELF with its function symbols (see
+# src/symbols.rs): one "<address> <size> <name>" line per symbol, sorted
+# by address, padded with zeros to the size of the section.
+#
+# usage: tools/ksyms.sh <kernel ELF>
+# NM and OBJCOPY may name other tools than the system nm and objcopy.
+
+set -e
+
+kernel="$1"
+nm="${NM:-nm}"
+objcopy="${OBJCOPY:-objcopy}"
+
+size=$("$objcopy" --dump-section .ksyms=/dev/stdout "$kernel" 2>/dev/null | wc -c)
+if [ "$size" -eq 0 ]; then
+    echo "ksyms: $kernel has no .ksyms section" >&2
+    exit 1
+fi
+
+table=$(mktemp)
+trap 'rm -f "$table"' EXIT
+
+"$nm" --defined-only --numeric-sort --print-size --demangle "$kernel" |
+    awk '
+        # "address size type name" or "address type name"
+        $2 ~ /^[tTwW]$/ { fields = 2; size = "0000000000000000" }
+        $3 ~ /^[tTwW]$/ { fields = 3; size = $2 }
+        fields {
+            name = $0
+            for (i = 0; i < fields; i++)
+                sub(/^[^ ]+ /, "", name)
+            print $1, size, name
+            fields = 0
+        }
+    ' |
+    sed -E 's/::h[0-9a-f]{16}$//' > "$table"
+
+used=$(wc -c < "$table")
+if [ "$used" -ge "$size" ]; then
+    echo "ksyms: symbol table needs $used bytes, .ksyms has $size" >&2
+    exit 1
+fi
+truncate -s "$size" "$table"
+"$objcopy" --update-section .ksyms="$table" "$kernel"
@@ -0,0 +1,6 @@
+#!/bin/sh
+# Cargo runner: add the symbol table to the kernel, then boot it
+set -e
+
+"$(dirname "$0")/ksyms.sh" "$1"
+exec bootimage runner "$@"
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Run all tests twice: as built by default, and with the `lockdep` feature,
+# whose own tests only exist in that build
+set -e
+
+cd "$(dirname "$0")/.."
+cargo test "$@"
+cargo test --features lockdep "$@"